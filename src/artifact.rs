@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Artifacts at least this large are gzipped by `write_artifact`.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 64 * 1024;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Writes a persisted artifact such as a cassette, an archive or a checkpoint. The data is
+/// buffered up to the threshold; past it, it is gzipped as it is written instead of held in memory.
+/// Readers don't need to know which happened, `open_artifact` detects gzip by its magic bytes.
+/// Call `finish` once done, small artifacts are only written then.
+pub struct ArtifactWriter {
+    threshold: usize,
+    state: Option<State>,
+}
+
+enum State {
+    Buffering(Vec<u8>, File),
+    Compressing(GzEncoder<BufWriter<File>>),
+}
+
+impl ArtifactWriter {
+    pub fn create(path: impl AsRef<Path>, threshold: usize) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self {
+            threshold,
+            state: Some(State::Buffering(Vec::new(), file)),
+        })
+    }
+
+    /// Flushes the artifact. Returns whether it was compressed.
+    pub fn finish(mut self) -> io::Result<bool> {
+        match self.state.take() {
+            Some(State::Buffering(buffer, mut file)) => {
+                file.write_all(&buffer)?;
+                Ok(false)
+            }
+            Some(State::Compressing(encoder)) => {
+                encoder.finish()?.flush()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl Write for ArtifactWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self.state.take() {
+            Some(State::Buffering(mut buffer, file)) => {
+                buffer.extend_from_slice(data);
+                if buffer.len() < self.threshold {
+                    self.state = Some(State::Buffering(buffer, file));
+                } else {
+                    let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+                    encoder.write_all(&buffer)?;
+                    self.state = Some(State::Compressing(encoder));
+                }
+                Ok(data.len())
+            }
+            Some(State::Compressing(mut encoder)) => {
+                let written = encoder.write(data);
+                self.state = Some(State::Compressing(encoder));
+                written
+            }
+            None => Err(io::Error::other("the artifact is already finished")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.state {
+            Some(State::Compressing(encoder)) => encoder.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes an artifact, gzipped when it is at least `threshold` bytes.
+pub fn write_artifact(path: impl AsRef<Path>, data: &[u8], threshold: usize) -> io::Result<()> {
+    let mut writer = ArtifactWriter::create(path, threshold)?;
+    writer.write_all(data)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Opens an artifact for streaming, decompressing it if it was gzipped.
+pub fn open_artifact(path: impl AsRef<Path>) -> io::Result<Box<dyn Read + Send>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(GzDecoder::new(reader)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Reads a whole artifact, decompressing it if it was gzipped.
+pub fn read_artifact(path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    open_artifact(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("mimicr-artifact-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn it_should_only_compress_artifacts_past_the_threshold() {
+        let small = temp_path("small");
+        write_artifact(&small, b"{\"pages\": {}}", 1024).unwrap();
+        assert_eq!(std::fs::read(&small).unwrap(), b"{\"pages\": {}}");
+        assert_eq!(read_artifact(&small).unwrap(), b"{\"pages\": {}}");
+
+        let large = temp_path("large");
+        let data = "https://example.com/page\n".repeat(1000);
+        write_artifact(&large, data.as_bytes(), 1024).unwrap();
+        let stored = std::fs::read(&large).unwrap();
+        assert!(stored.starts_with(&GZIP_MAGIC));
+        assert!(stored.len() < data.len() / 10);
+        assert_eq!(read_artifact(&large).unwrap(), data.as_bytes());
+
+        let _ = std::fs::remove_file(small);
+        let _ = std::fs::remove_file(large);
+    }
+
+    #[test]
+    fn it_should_stream_chunks_into_the_encoder() {
+        let path = temp_path("stream");
+        let mut writer = ArtifactWriter::create(&path, 16).unwrap();
+        for i in 0..100 {
+            writeln!(writer, "record {}", i).unwrap();
+        }
+        assert!(writer.finish().unwrap());
+
+        let text = String::from_utf8(read_artifact(&path).unwrap()).unwrap();
+        assert_eq!(text.lines().count(), 100);
+        assert_eq!(text.lines().last(), Some("record 99"));
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,415 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// The `prev_hash` of the first entry of a log.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// A request as recorded by an `AuditLog`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// The position of the entry in the log, starting at 0.
+    pub seq: u64,
+    /// When the response, or the error, was received, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub step: String,
+    pub method: String,
+    pub url: String,
+    /// The SHA-256 of the request body, `None` without a body or for multipart bodies.
+    pub body_sha256: Option<String>,
+    /// The `Identity` the request was sent as.
+    pub identity: Option<String>,
+    /// The status of the response, `None` if the request failed.
+    pub status: Option<u16>,
+    /// The `hash` of the previous entry.
+    pub prev_hash: String,
+    /// The SHA-256 of the previous hash and every other field of this entry.
+    pub hash: String,
+}
+
+impl AuditEntry {
+    /// Computes what the hash of the entry should be.
+    fn compute_hash(&self) -> String {
+        let fields = [
+            self.prev_hash.as_str(),
+            &self.seq.to_string(),
+            &self.timestamp_ms.to_string(),
+            &self.step,
+            &self.method,
+            &self.url,
+            self.body_sha256.as_deref().unwrap_or_default(),
+            self.identity.as_deref().unwrap_or_default(),
+            &self.status.map(|s| s.to_string()).unwrap_or_default(),
+        ];
+        // lengths first, so no field can be shifted into its neighbour
+        let mut text = String::new();
+        for field in fields {
+            text.push_str(&format!("{}:{}\n", field.len(), field));
+        }
+        sha256_hex(text.as_bytes())
+    }
+}
+
+/// A request to record, see `AuditLog::record`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuditRecord<'a> {
+    pub step: &'a str,
+    pub method: &'a str,
+    pub url: &'a str,
+    pub body: Option<&'a [u8]>,
+    pub identity: Option<&'a str>,
+    pub status: Option<u16>,
+}
+
+/// An entry of an audit log that isn't what was recorded, see `verify_audit_chain`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditError {
+    /// The position of the first entry that doesn't verify.
+    pub seq: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "audit entry {} was tampered with: {}",
+            self.seq, self.reason
+        )
+    }
+}
+
+impl Error for AuditError {}
+
+enum Sink {
+    Memory(Vec<AuditEntry>),
+    File(File),
+}
+
+struct Chain {
+    next_seq: u64,
+    last_hash: String,
+    sink: Sink,
+}
+
+/// An append-only log of every request a worker sent, see `Worker::set_audit_log`. Each entry
+/// holds the hash of the previous one, so removing, reordering or editing entries after the fact
+/// breaks the chain, which `verify_audit_chain` detects. File logs are written as JSON lines,
+/// one entry per request, and continue the chain of an existing file.
+pub struct AuditLog {
+    chain: Mutex<Chain>,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AuditLog {
+    /// Keeps the entries in memory, see `entries`.
+    pub fn new() -> Self {
+        Self::with_sink(0, GENESIS_HASH.to_string(), Sink::Memory(vec![]))
+    }
+
+    /// Appends the entries to a file, after verifying the entries it already has.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let path = path.as_ref();
+        let mut entries = vec![];
+        if path.exists() {
+            entries = read_audit_log(path)?;
+            verify_audit_chain(&entries)?;
+        }
+        let (next_seq, last_hash) = match entries.last() {
+            Some(last) => (last.seq + 1, last.hash.clone()),
+            None => (0, GENESIS_HASH.to_string()),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::with_sink(next_seq, last_hash, Sink::File(file)))
+    }
+
+    fn with_sink(next_seq: u64, last_hash: String, sink: Sink) -> Self {
+        Self {
+            chain: Mutex::new(Chain {
+                next_seq,
+                last_hash,
+                sink,
+            }),
+        }
+    }
+
+    /// Returns the entries of an in-memory log. File logs are read with `read_audit_log`.
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        match &self.chain.lock().unwrap().sink {
+            Sink::Memory(entries) => entries.clone(),
+            Sink::File(_) => vec![],
+        }
+    }
+
+    /// Returns the hash of the last entry, e.g. to publish it somewhere the bots can't write.
+    pub fn last_hash(&self) -> String {
+        self.chain.lock().unwrap().last_hash.clone()
+    }
+
+    /// Appends an entry, failing if it couldn't be written to the file. The chain only moves on
+    /// once the entry is written.
+    pub(crate) fn record(&self, record: AuditRecord) -> std::io::Result<AuditEntry> {
+        let mut chain = self.chain.lock().unwrap();
+        let mut entry = AuditEntry {
+            seq: chain.next_seq,
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            step: record.step.to_string(),
+            method: record.method.to_string(),
+            url: record.url.to_string(),
+            body_sha256: record.body.map(sha256_hex),
+            identity: record.identity.map(str::to_string),
+            status: record.status,
+            prev_hash: chain.last_hash.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash();
+
+        match &mut chain.sink {
+            Sink::Memory(entries) => entries.push(entry.clone()),
+            Sink::File(file) => {
+                let mut line = serde_json::to_string(&entry)?;
+                line.push('\n');
+                file.write_all(line.as_bytes())?;
+                file.flush()?;
+            }
+        }
+        chain.next_seq += 1;
+        chain.last_hash = entry.hash.clone();
+        Ok(entry)
+    }
+}
+
+/// Reads the entries of a file written by `AuditLog::open`.
+pub fn read_audit_log(
+    path: impl AsRef<Path>,
+) -> Result<Vec<AuditEntry>, Box<dyn Error + Send + Sync>> {
+    let file = File::open(path)?;
+    let mut entries = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+/// Checks that the entries form an unbroken chain from the start of a log: in sequence, each
+/// linked to the previous one and hashing to its own hash.
+pub fn verify_audit_chain(entries: &[AuditEntry]) -> Result<(), AuditError> {
+    let mut prev_hash = GENESIS_HASH;
+    for (seq, entry) in entries.iter().enumerate() {
+        let error = |reason: &str| AuditError {
+            seq: seq as u64,
+            reason: reason.to_string(),
+        };
+        if entry.seq != seq as u64 {
+            return Err(error("it's out of sequence"));
+        }
+        if entry.prev_hash != prev_hash {
+            return Err(error("it doesn't link to the previous entry"));
+        }
+        if entry.compute_hash() != entry.hash {
+            return Err(error("its hash doesn't match its fields"));
+        }
+        prev_hash = &entry.hash;
+    }
+    Ok(())
+}
+
+/// The SHA-256 digest of the data, in lowercase hex.
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    sha256(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256, see FIPS 180-4.
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (h, g, f, e) = (g, f, e, d.wrapping_add(t1));
+            (d, c, b, a) = (c, b, a, t1.wrapping_add(t2));
+        }
+        for (value, add) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0u8; 32];
+    for (bytes, value) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login(status: Option<u16>) -> AuditRecord<'static> {
+        AuditRecord {
+            step: "Login",
+            method: "POST",
+            url: "https://shop.example/login",
+            body: Some(b"user=jane"),
+            identity: Some("jane"),
+            status,
+        }
+    }
+
+    #[test]
+    fn it_should_hash_with_sha256() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let long = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        assert_eq!(
+            sha256_hex(long),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn it_should_pad_every_length_with_sha256() {
+        // the length fits in the last block at 55 bytes, not at 56, and 64 fill a block
+        let vectors = [
+            (
+                55,
+                "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318",
+            ),
+            (
+                56,
+                "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a",
+            ),
+            (
+                64,
+                "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb",
+            ),
+            (
+                1_000_000,
+                "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0",
+            ),
+        ];
+        for (len, expected) in vectors {
+            assert_eq!(sha256_hex(&vec![b'a'; len]), expected, "{} bytes", len);
+        }
+        let two_blocks = b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu";
+        assert_eq!(
+            sha256_hex(two_blocks),
+            "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1"
+        );
+    }
+
+    #[test]
+    fn it_should_detect_tampered_entries() {
+        let log = AuditLog::new();
+        log.record(login(Some(200))).unwrap();
+        log.record(login(None)).unwrap();
+        log.record(login(Some(302))).unwrap();
+        let entries = log.entries();
+        assert_eq!(entries[0].prev_hash, GENESIS_HASH);
+        assert_eq!(entries[1].prev_hash, entries[0].hash);
+        assert_eq!(entries[2].hash, log.last_hash());
+        assert_eq!(entries[0].body_sha256, Some(sha256_hex(b"user=jane")));
+        assert!(verify_audit_chain(&entries).is_ok());
+
+        let mut edited = entries.clone();
+        edited[1].url = "https://shop.example/innocent".to_string();
+        assert_eq!(verify_audit_chain(&edited).unwrap_err().seq, 1);
+        let mut removed = entries.clone();
+        removed.remove(1);
+        assert_eq!(verify_audit_chain(&removed).unwrap_err().seq, 1);
+        let mut rehashed = entries;
+        rehashed[0].status = Some(500);
+        rehashed[0].hash = rehashed[0].compute_hash();
+        assert_eq!(verify_audit_chain(&rehashed).unwrap_err().seq, 1);
+    }
+
+    #[test]
+    fn it_should_continue_the_chain_of_a_file() {
+        let path = std::env::temp_dir().join("mimicr-audit-test.jsonl");
+        let _ = std::fs::remove_file(&path);
+        AuditLog::open(&path)
+            .unwrap()
+            .record(login(Some(200)))
+            .unwrap();
+        let log = AuditLog::open(&path).unwrap();
+        log.record(login(Some(200))).unwrap();
+        assert!(log.entries().is_empty());
+
+        let entries = read_audit_log(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].seq, 1);
+        assert!(verify_audit_chain(&entries).is_ok());
+
+        // a log edited on disk is refused
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, text.replace("\"status\":200", "\"status\":201")).unwrap();
+        assert!(AuditLog::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::{CancellationToken, Identity, IdentityPool, RunSummary, Worker};
+
+/// The store key a bot finds its current seed under, e.g. the url or account it works on.
+pub const SEED_KEY: &str = "seed";
+
+/// One seed run by a bot of a `BotPool`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeedRun {
+    pub seed: String,
+    /// The index of the bot that ran the seed.
+    pub bot: usize,
+    pub summary: RunSummary,
+}
+
+/// The outcome of every seed run by `BotPool::run`, in the order they finished.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SwarmReport {
+    pub runs: Vec<SeedRun>,
+}
+
+impl SwarmReport {
+    pub fn is_success(&self) -> bool {
+        self.runs.iter().all(|run| run.summary.is_success())
+    }
+
+    pub fn succeeded(&self) -> Vec<&SeedRun> {
+        self.runs
+            .iter()
+            .filter(|run| run.summary.is_success())
+            .collect()
+    }
+
+    pub fn failed(&self) -> Vec<&SeedRun> {
+        self.runs
+            .iter()
+            .filter(|run| !run.summary.is_success())
+            .collect()
+    }
+
+    /// Returns the run of a seed, if it was run.
+    pub fn run_for(&self, seed: &str) -> Option<&SeedRun> {
+        self.runs.iter().find(|run| run.seed == seed)
+    }
+}
+
+/// Runs many bots at once against a shared queue of seeds. Every bot is a `Worker` built by the
+/// pool's factory, so it has its own session and cookie jar, and runs the seeds it takes from
+/// the queue one after the other, from the start step, with the seed in its store under
+/// `SEED_KEY`. Share rate limiters, metrics and the like between the bots by cloning their
+/// `Arc` into the factory.
+pub struct BotPool {
+    build: Arc<dyn Fn() -> Worker + Send + Sync>,
+    start_step: String,
+    concurrency: usize,
+    identities: Vec<Identity>,
+    cancel: Option<CancellationToken>,
+}
+
+impl BotPool {
+    /// Runs 4 bots built by `build`, starting every seed at `start_step`.
+    pub fn new(start_step: &str, build: impl Fn() -> Worker + Send + Sync + 'static) -> Self {
+        Self {
+            build: Arc::new(build),
+            start_step: start_step.to_string(),
+            concurrency: 4,
+            identities: vec![],
+            cancel: None,
+        }
+    }
+
+    /// Sets the number of bots running at once.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Gives each bot one of the identities, e.g. its own proxy, in turn. Bots share an
+    /// identity when there are more bots than identities.
+    pub fn with_identities(mut self, identities: Vec<Identity>) -> Self {
+        self.identities = identities;
+        self
+    }
+
+    /// Stops every bot with the token, see `Worker::shutdown`. The seeds no bot started are
+    /// left out of the report.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    /// Runs every seed and waits for all of them. A seed that fails doesn't stop its bot, which
+    /// moves on to the next seed; the report has the summary of each seed.
+    pub async fn run(&self, seeds: Vec<String>) -> SwarmReport {
+        let queue = Arc::new(Mutex::new(VecDeque::from(seeds)));
+        let bots = self.concurrency.min(queue.lock().unwrap().len());
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for bot in 0..bots {
+            let mut worker = (self.build)();
+            if !self.identities.is_empty() {
+                let identity = self.identities[bot % self.identities.len()].clone();
+                worker.set_identity_pool(Some(Arc::new(IdentityPool::new(vec![identity]))));
+            }
+            if let Some(token) = &self.cancel {
+                worker.set_cancellation_token(token.clone());
+            }
+            let queue = queue.clone();
+            let start_step = self.start_step.clone();
+            tasks.spawn(async move {
+                let mut runs = vec![];
+                while !worker.is_cancelled() {
+                    let Some(seed) = queue.lock().unwrap().pop_front() else {
+                        break;
+                    };
+                    worker.store_mut().set(SEED_KEY, seed.clone());
+                    let summary = worker.run(&start_step).await;
+                    runs.push(SeedRun { seed, bot, summary });
+                }
+                runs
+            });
+        }
+
+        let mut report = SwarmReport::default();
+        while let Some(joined) = tasks.join_next().await {
+            // a panicking step is a bug in the bot, which should surface
+            report
+                .runs
+                .extend(joined.expect("a bot of the pool panicked"));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+    use crate::{Context, Request, StepError, Stepable};
+    use async_trait::async_trait;
+    use reqwest::Method;
+
+    struct VisitStep {
+        base: String,
+    }
+
+    #[async_trait]
+    impl Stepable for VisitStep {
+        fn name(&self) -> String {
+            "Visit".to_string()
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, self.base.clone())
+        }
+
+        fn on_request_with(&self, ctx: &Context) -> Request {
+            let seed: &String = ctx.get_store().get(SEED_KEY).unwrap();
+            Request::new(Method::GET, format!("{}/{}", self.base, seed))
+        }
+
+        async fn on_success(&self, _ctx: &mut Context) {}
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+    }
+
+    #[tokio::test]
+    async fn it_should_run_every_seed_in_isolated_sessions() {
+        // every bot gets a cookie on its first request, and sends it on the next ones
+        let server = TestServer::start(|req| {
+            let res = match req.path.as_str() {
+                "/broken" => TestResponse::status(500, "oops"),
+                _ => TestResponse::ok("page"),
+            };
+            match req.header("cookie") {
+                Some(_) => res,
+                None => res.with_header("set-cookie", "sid=1"),
+            }
+        })
+        .await;
+        let base = server.url("");
+        let pool = BotPool::new("Visit", move || {
+            let mut worker = Worker::new();
+            worker.add_step(VisitStep { base: base.clone() });
+            worker
+        })
+        .with_concurrency(3);
+
+        let seeds: Vec<String> = ["a", "b", "c", "broken", "d", "e", "f"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let report = pool.run(seeds).await;
+        assert_eq!(report.runs.len(), 7);
+        assert_eq!(report.failed().len(), 1);
+        assert!(!report.run_for("broken").unwrap().summary.is_success());
+        assert!(!report.is_success());
+
+        let bots: std::collections::HashSet<usize> = report.runs.iter().map(|r| r.bot).collect();
+        assert_eq!(bots.len(), 3);
+        let first_visits = server
+            .requests()
+            .iter()
+            .filter(|req| req.header("cookie").is_none())
+            .count();
+        assert_eq!(first_visits, 3);
+    }
+}
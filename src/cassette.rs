@@ -0,0 +1,524 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{RequestBuilder, Url};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::har::text_headers;
+use crate::singleflight::{SharedError, SharedResponse, SharedResult};
+use crate::{
+    read_artifact, write_artifact, ResponseInfo, Scrubber, StepError, DEFAULT_COMPRESSION_THRESHOLD,
+};
+
+/// A request as stored in a cassette.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CassetteRequest {
+    pub method: String,
+    pub url: String,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+impl CassetteRequest {
+    pub fn new(method: &str, url: &str) -> Self {
+        Self {
+            method: method.to_uppercase(),
+            url: url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_body(mut self, body: &str) -> Self {
+        self.body = Some(body.to_string());
+        self
+    }
+
+    fn from_reqwest(req: &reqwest::Request) -> Self {
+        Self {
+            method: req.method().to_string(),
+            url: req.url().to_string(),
+            headers: text_headers(req.headers()),
+            body: req
+                .body()
+                .and_then(|body| body.as_bytes())
+                .map(|body| String::from_utf8_lossy(body).into_owned()),
+        }
+    }
+}
+
+/// A response as stored in a cassette. Bodies are stored as text, so binary bodies don't
+/// replay byte for byte.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CassetteResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    #[serde(default)]
+    pub body: String,
+}
+
+/// A recorded request and the response it got.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Interaction {
+    pub request: CassetteRequest,
+    pub response: CassetteResponse,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CassetteFile {
+    interactions: Vec<Interaction>,
+}
+
+/// What a `Cassette` does with the requests sent through it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Sends every request and records it with its response.
+    Record,
+    /// Serves the recorded responses. Requests without a recording are sent.
+    Replay,
+    /// Serves the recorded responses. Requests without a recording fail with
+    /// `StepError::UnrecordedRequest` instead of reaching the network.
+    Strict,
+}
+
+/// Records the requests of a requester and their responses to a file, and serves them back so
+/// bot tests run without live servers. Set it with `Context::set_cassette`. Requests are matched
+/// with a `RequestMatcher`; identical requests, e.g. polling, replay their recordings in order
+/// and then keep replaying the last one. Downloads aren't recorded.
+#[derive(Debug)]
+pub struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+    matcher: RequestMatcher,
+    scrubber: Option<Scrubber>,
+    interactions: Mutex<Vec<Interaction>>,
+    /// The recordings that were already replayed.
+    replayed: Mutex<HashSet<usize>>,
+}
+
+impl Cassette {
+    /// Records to `path`, written by `save`.
+    pub fn record(path: impl AsRef<Path>) -> Self {
+        Self::new(path.as_ref(), CassetteMode::Record, vec![])
+    }
+
+    /// Replays the cassette recorded at `path`.
+    pub fn replay(path: impl AsRef<Path>) -> io::Result<Self> {
+        let data = read_artifact(path.as_ref())?;
+        let file: CassetteFile = serde_json::from_slice(&data)?;
+        Ok(Self::new(
+            path.as_ref(),
+            CassetteMode::Replay,
+            file.interactions,
+        ))
+    }
+
+    fn new(path: &Path, mode: CassetteMode, interactions: Vec<Interaction>) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            mode,
+            matcher: RequestMatcher::new(),
+            scrubber: None,
+            interactions: Mutex::new(interactions),
+            replayed: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Fails requests without a recording instead of sending them. Only applies to replays.
+    pub fn strict(mut self) -> Self {
+        if self.mode == CassetteMode::Replay {
+            self.mode = CassetteMode::Strict;
+        }
+        self
+    }
+
+    pub fn with_matcher(mut self, matcher: RequestMatcher) -> Self {
+        self.matcher = matcher;
+        self
+    }
+
+    /// Scrubs the recording when it is saved. Scrubbed query parameters have to be ignored by
+    /// the matcher for the requests to replay.
+    pub fn with_scrubber(mut self, scrubber: Scrubber) -> Self {
+        self.scrubber = Some(scrubber);
+        self
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn interactions(&self) -> Vec<Interaction> {
+        self.interactions.lock().unwrap().clone()
+    }
+
+    /// Writes the recorded interactions to the cassette's file, gzipped when large.
+    pub fn save(&self) -> io::Result<()> {
+        let mut interactions = self.interactions();
+        if let Some(scrubber) = &self.scrubber {
+            interactions.iter_mut().for_each(|i| scrub(scrubber, i));
+        }
+        let data = serde_json::to_vec_pretty(&CassetteFile { interactions })?;
+        write_artifact(&self.path, &data, DEFAULT_COMPRESSION_THRESHOLD)
+    }
+
+    /// Sends a request through the cassette: replays its recording, or sends and records it.
+    pub(crate) async fn fetch(
+        &self,
+        req_builder: RequestBuilder,
+        buffer: &mut BytesMut,
+    ) -> SharedResult {
+        let (client, req) = req_builder.build_split();
+        let req = req.map_err(|err| SharedError::from_reqwest(&err))?;
+        let live = CassetteRequest::from_reqwest(&req);
+
+        if self.mode != CassetteMode::Record {
+            if let Some(res) = self.replay_response(&live) {
+                return Ok(res);
+            }
+            if self.mode == CassetteMode::Strict {
+                return Err(SharedError {
+                    error: StepError::UnrecordedRequest(format!("{} {}", live.method, live.url)),
+                    partial: None,
+                    connecting: false,
+                });
+            }
+        }
+
+        let result = crate::worker::fetch(RequestBuilder::from_parts(client, req), buffer).await;
+        if let (CassetteMode::Record, Ok(res)) = (self.mode, &result) {
+            let response = CassetteResponse {
+                status: res.info.status(),
+                headers: text_headers(res.info.headers()),
+                body: String::from_utf8_lossy(&res.body).into_owned(),
+            };
+            self.interactions.lock().unwrap().push(Interaction {
+                request: live,
+                response,
+            });
+        }
+        result
+    }
+
+    /// Returns the first matching recording that wasn't replayed yet, or else the last one.
+    fn replay_response(&self, live: &CassetteRequest) -> Option<SharedResponse> {
+        let interactions = self.interactions.lock().unwrap();
+        let mut replayed = self.replayed.lock().unwrap();
+        let matching: Vec<usize> = (0..interactions.len())
+            .filter(|i| self.matcher.matches(&interactions[*i].request, live))
+            .collect();
+        let index = *matching
+            .iter()
+            .find(|i| !replayed.contains(i))
+            .or(matching.last())?;
+        replayed.insert(index);
+
+        let recorded = &interactions[index].response;
+        let mut headers = HeaderMap::new();
+        for (name, value) in &recorded.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        Some(SharedResponse {
+            info: ResponseInfo::new(recorded.status, headers, live.url.clone()),
+            body: bytes::Bytes::from(recorded.body.clone().into_bytes()),
+        })
+    }
+}
+
+fn scrub(scrubber: &Scrubber, interaction: &mut Interaction) {
+    let request = &mut interaction.request;
+    request.url = scrubber.scrub_url(&request.url);
+    scrubber.scrub_headers(&mut request.headers);
+    if let Some(body) = &mut request.body {
+        *body = String::from_utf8_lossy(&scrubber.scrub_body(body.as_bytes())).into_owned();
+    }
+
+    let response = &mut interaction.response;
+    scrubber.scrub_headers(&mut response.headers);
+    response.body =
+        String::from_utf8_lossy(&scrubber.scrub_body(response.body.as_bytes())).into_owned();
+}
+
+/// Decides whether a live request matches a recorded one during replay.
+/// By default the method and the full url must match, with query parameters in any order.
+/// Volatile parts such as timestamps or nonces can be ignored so recordings keep replaying.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestMatcher {
+    method: bool,
+    ignored_params: Vec<String>,
+    body_paths: Vec<String>,
+    body: bool,
+}
+
+impl Default for RequestMatcher {
+    fn default() -> Self {
+        RequestMatcher::new()
+    }
+}
+
+impl RequestMatcher {
+    pub fn new() -> Self {
+        Self {
+            method: true,
+            ignored_params: vec![],
+            body_paths: vec![],
+            body: false,
+        }
+    }
+
+    /// Matches requests regardless of their method.
+    pub fn ignore_method(mut self) -> Self {
+        self.method = false;
+        self
+    }
+
+    /// Ignores a query parameter, e.g. `_` or `timestamp`.
+    pub fn ignore_query_param(mut self, name: &str) -> Self {
+        self.ignored_params.push(name.to_string());
+        self
+    }
+
+    /// Requires the JSON bodies to have the same value at a path such as `$.query.term` or
+    /// `$.items[0].id`. Can be called multiple times.
+    pub fn match_body_path(mut self, path: &str) -> Self {
+        self.body_paths.push(path.to_string());
+        self
+    }
+
+    /// Requires the bodies to be identical.
+    pub fn match_body(mut self) -> Self {
+        self.body = true;
+        self
+    }
+
+    pub fn matches(&self, recorded: &CassetteRequest, live: &CassetteRequest) -> bool {
+        if self.method && !recorded.method.eq_ignore_ascii_case(&live.method) {
+            return false;
+        }
+        if self.url_key(&recorded.url) != self.url_key(&live.url) {
+            return false;
+        }
+        if self.body && recorded.body != live.body {
+            return false;
+        }
+        if self.body_paths.is_empty() {
+            return true;
+        }
+
+        let parse = |body: &Option<String>| {
+            body.as_deref()
+                .and_then(|b| serde_json::from_str::<Value>(b).ok())
+        };
+        let (Some(recorded), Some(live)) = (parse(&recorded.body), parse(&live.body)) else {
+            return false;
+        };
+        self.body_paths
+            .iter()
+            .all(|path| select_path(&recorded, path) == select_path(&live, path))
+    }
+
+    /// Returns the first recorded request matching the live one.
+    pub fn find<'a>(
+        &self,
+        recorded: &'a [CassetteRequest],
+        live: &CassetteRequest,
+    ) -> Option<&'a CassetteRequest> {
+        recorded.iter().find(|r| self.matches(r, live))
+    }
+
+    /// The url without fragment or ignored params, with the remaining params sorted.
+    fn url_key(&self, url: &str) -> String {
+        let Ok(mut parsed) = Url::parse(url) else {
+            return url.to_string();
+        };
+        parsed.set_fragment(None);
+
+        let mut pairs: Vec<(String, String)> = parsed
+            .query_pairs()
+            .filter(|(k, _)| !self.ignored_params.iter().any(|p| p == k))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        pairs.sort();
+
+        parsed.set_query(None);
+        if !pairs.is_empty() {
+            parsed.query_pairs_mut().extend_pairs(pairs);
+        }
+        parsed.to_string()
+    }
+}
+
+/// Selects a value with a simple path: `$`, `.field` and `[index]` segments.
+fn select_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let path = path.strip_prefix('$').unwrap_or(path);
+    let mut current = value;
+
+    for segment in path.split('.').filter(|s| !s.is_empty()) {
+        let (field, indexes) = match segment.find('[') {
+            Some(i) => (&segment[..i], &segment[i..]),
+            None => (segment, ""),
+        };
+        if !field.is_empty() {
+            current = current.get(field)?;
+        }
+        for index in indexes.split('[').filter(|s| !s.is_empty()) {
+            current = current.get(index.trim_end_matches(']').parse::<usize>().ok()?)?;
+        }
+    }
+
+    Some(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+    use crate::{HttpRequester, MimicBody, Request};
+    use reqwest::Method;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    async fn send(requester: &HttpRequester, req: Request) -> SharedResult {
+        let builder = requester.build_reqwest(req).unwrap();
+        requester.execute(builder, &mut BytesMut::new(), None).await
+    }
+
+    #[tokio::test]
+    async fn it_should_record_and_replay_without_the_network() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let counter = polls.clone();
+        let server = TestServer::start(move |req| match req.path.as_str() {
+            "/poll" => TestResponse::ok(&(counter.fetch_add(1, Ordering::SeqCst) + 1).to_string()),
+            _ => TestResponse::status(
+                201,
+                &format!("created {}", String::from_utf8_lossy(&req.body)),
+            )
+            .with_header("x-id", "7"),
+        })
+        .await;
+        let path = std::env::temp_dir().join("mimicr-cassette-test.json");
+        let create = Request::new(Method::POST, server.url("/items"))
+            .with_body(MimicBody::from_text("shoes".to_string()));
+        let poll = Request::new(Method::GET, server.url("/poll"));
+
+        let mut requester = HttpRequester::new();
+        let cassette = Arc::new(Cassette::record(&path));
+        requester.set_cassette(Some(cassette.clone()));
+        send(&requester, create.clone()).await.unwrap();
+        send(&requester, poll.clone()).await.unwrap();
+        send(&requester, poll.clone()).await.unwrap();
+        cassette.save().unwrap();
+        assert_eq!(cassette.interactions().len(), 3);
+        assert_eq!(server.hits(), 3);
+
+        let matcher = RequestMatcher::new().match_body();
+        let cassette = Cassette::replay(&path)
+            .unwrap()
+            .with_matcher(matcher)
+            .strict();
+        assert_eq!(cassette.mode(), CassetteMode::Strict);
+        requester.set_cassette(Some(Arc::new(cassette)));
+        let res = send(&requester, create.clone()).await.unwrap();
+        assert_eq!(res.info.status(), 201);
+        assert_eq!(res.info.headers()["x-id"], "7");
+        assert_eq!(res.body, "created shoes");
+        let mut bodies = vec![];
+        for _ in 0..3 {
+            bodies.push(send(&requester, poll.clone()).await.unwrap().body);
+        }
+        assert_eq!(bodies, vec!["1", "2", "2"]);
+        assert_eq!(server.hits(), 3);
+
+        let boots = create.with_body(MimicBody::from_text("boots".to_string()));
+        let err = send(&requester, boots.clone()).await.unwrap_err();
+        assert!(matches!(err.error, StepError::UnrecordedRequest(_)));
+
+        requester.set_cassette(Some(Arc::new(Cassette::replay(&path).unwrap())));
+        assert_eq!(send(&requester, boots).await.unwrap().body, "created shoes");
+        let live = Request::new(Method::GET, server.url("/other"));
+        assert_eq!(send(&requester, live).await.unwrap().info.status(), 201);
+        assert_eq!(server.hits(), 4);
+    }
+
+    #[test]
+    fn it_should_scrub_recordings_when_saved() {
+        let path = std::env::temp_dir().join("mimicr-cassette-scrub-test.json");
+        let cassette =
+            Cassette::record(&path).with_scrubber(Scrubber::new().with_json_field("token"));
+        cassette.interactions.lock().unwrap().push(Interaction {
+            request: CassetteRequest {
+                headers: vec![("authorization".to_string(), "Bearer abc".to_string())],
+                ..CassetteRequest::new("GET", "https://example.com/")
+            },
+            response: CassetteResponse {
+                status: 200,
+                headers: vec![],
+                body: r#"{"token":"secret"}"#.to_string(),
+            },
+        });
+        cassette.save().unwrap();
+
+        let saved = Cassette::replay(&path).unwrap().interactions().remove(0);
+        assert_eq!(saved.request.headers[0].1, crate::REDACTED);
+        assert!(!saved.response.body.contains("secret"));
+    }
+
+    #[test]
+    fn it_should_ignore_volatile_query_params() {
+        let recorded =
+            CassetteRequest::new("GET", "https://example.com/search?q=shoes&ts=1&page=2");
+        let live = CassetteRequest::new("get", "https://example.com/search?page=2&ts=99&q=shoes");
+
+        assert!(!RequestMatcher::new().matches(&recorded, &live));
+        assert!(RequestMatcher::new()
+            .ignore_query_param("ts")
+            .matches(&recorded, &live));
+        assert!(!RequestMatcher::new()
+            .ignore_query_param("ts")
+            .matches(&recorded, &CassetteRequest::new("POST", &live.url)));
+    }
+
+    #[test]
+    fn it_should_match_on_body_paths() {
+        let matcher = RequestMatcher::new().match_body_path("$.query.term");
+        let recorded = CassetteRequest::new("POST", "https://example.com/api")
+            .with_body(r#"{"query": {"term": "shoes"}, "nonce": "a1"}"#);
+        let live = CassetteRequest::new("POST", "https://example.com/api")
+            .with_body(r#"{"nonce": "b2", "query": {"term": "shoes"}}"#);
+        let other = CassetteRequest::new("POST", "https://example.com/api")
+            .with_body(r#"{"query": {"term": "boots"}}"#);
+
+        assert!(matcher.matches(&recorded, &live));
+        assert!(!matcher.matches(&recorded, &other));
+        assert!(!RequestMatcher::new().match_body().matches(&recorded, &live));
+        assert_eq!(
+            matcher
+                .find(&[other.clone(), recorded.clone()], &live)
+                .unwrap(),
+            &recorded
+        );
+    }
+
+    #[test]
+    fn it_should_select_array_indexes() {
+        let value: Value = serde_json::from_str(r#"{"items": [{"id": 1}, {"id": 2}]}"#).unwrap();
+        assert_eq!(select_path(&value, "$.items[1].id"), Some(&Value::from(2)));
+        assert_eq!(select_path(&value, "$.items[5].id"), None);
+    }
+}
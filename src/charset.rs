@@ -0,0 +1,146 @@
+use encoding_rs::{Encoding, BIG5, EUC_JP, EUC_KR, GBK, SHIFT_JIS, UTF_8, WINDOWS_1252};
+
+/// How much of a page is searched for a `<meta charset>`, like browsers do.
+const META_PRESCAN_BYTES: usize = 1024;
+
+/// The encodings tried, in order, on bodies that declare none and aren't UTF-8.
+const GUESSED_ENCODINGS: &[&Encoding] = &[SHIFT_JIS, EUC_JP, GBK, EUC_KR, BIG5];
+
+/// Detects the encoding of a response body: from its byte order mark, the charset of its
+/// `Content-Type`, or a `<meta charset>` or XML declaration near its start. Bodies declaring
+/// nothing are UTF-8 if they're valid UTF-8, else the first CJK encoding decoding them into
+/// CJK text only, else windows-1252, the superset of ISO-8859-1 browsers read it as.
+pub fn detect_encoding(content_type: Option<&str>, body: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(body) {
+        return encoding;
+    }
+    let declared = content_type
+        .and_then(charset_param)
+        .and_then(|label| Encoding::for_label(label.as_bytes()));
+    if let Some(encoding) = declared {
+        return encoding;
+    }
+    let meta = meta_charset(body).and_then(|label| Encoding::for_label(label.as_bytes()));
+    if let Some(encoding) = meta {
+        // a page can't declare UTF-16 from inside itself, browsers read it as UTF-8
+        return encoding.output_encoding();
+    }
+
+    if std::str::from_utf8(body).is_ok() {
+        return UTF_8;
+    }
+    GUESSED_ENCODINGS
+        .iter()
+        .copied()
+        .find(|encoding| decodes_to_cjk(encoding, body))
+        .unwrap_or(WINDOWS_1252)
+}
+
+/// Returns the `charset` parameter of a `Content-Type` value.
+fn charset_param(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches(['"', '\'']).to_string())
+    })
+}
+
+/// Finds the charset declared by `<meta charset="...">`, `<meta http-equiv="Content-Type"
+/// content="...; charset=...">` or `<?xml encoding="..."?>` at the start of a page.
+fn meta_charset(body: &[u8]) -> Option<String> {
+    let head = &body[..body.len().min(META_PRESCAN_BYTES)];
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+
+    if let Some(declaration) = head.strip_prefix("<?xml") {
+        let declaration = &declaration[..declaration.find("?>")?];
+        return attribute(declaration, "encoding");
+    }
+    head.match_indices("<meta").find_map(|(start, _)| {
+        let tag = &head[start..];
+        let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+        attribute(tag, "charset").or_else(|| charset_param(&attribute(tag, "content")?))
+    })
+}
+
+/// Returns the value of an attribute of a tag, quoted or not.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    // skips `<meta`, then reads `name=value` pairs, so names inside values don't match
+    let mut rest = tag.trim_start_matches(|c: char| !c.is_whitespace());
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        if rest.is_empty() {
+            return None;
+        }
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        let (key, after) = rest.split_at(end);
+        rest = after.trim_start();
+        let Some(value) = rest.strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let (value, after) = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split_once(quote).unwrap_or((&value[1..], "")),
+            _ => value.split_at(value.find(char::is_whitespace).unwrap_or(value.len())),
+        };
+        if key == name {
+            return Some(value.trim().to_string());
+        }
+        rest = after;
+    }
+}
+
+/// Whether the body decodes without errors, and every character outside ASCII is CJK text:
+/// kana, CJK ideographs and punctuation, hangul or fullwidth forms.
+fn decodes_to_cjk(encoding: &'static Encoding, body: &[u8]) -> bool {
+    let Some(text) = encoding.decode_without_bom_handling_and_without_replacement(body) else {
+        return false;
+    };
+    text.chars().filter(|c| !c.is_ascii()).all(|c| {
+        matches!(c as u32,
+            0x3000..=0x30ff | 0x3130..=0x318f | 0x3400..=0x4dbf | 0x4e00..=0x9fff
+            | 0xac00..=0xd7af | 0xff01..=0xff5e)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_use_the_declared_charset() {
+        let (latin, _, _) = WINDOWS_1252.encode("café");
+        assert_eq!(
+            detect_encoding(Some("text/html; charset=ISO-8859-1"), &latin),
+            WINDOWS_1252
+        );
+        assert_eq!(
+            detect_encoding(Some("text/html; charset=\"Shift_JIS\""), b"abc"),
+            SHIFT_JIS
+        );
+
+        let page = b"<html><head><META CHARSET='gbk'></head>";
+        assert_eq!(detect_encoding(Some("text/html"), page), GBK);
+        let page = br#"<meta http-equiv="Content-Type" content="text/html; charset=euc-kr">"#;
+        assert_eq!(detect_encoding(None, page), EUC_KR);
+        let feed = br#"<?xml version="1.0" encoding="windows-1252"?><rss>"#;
+        assert_eq!(detect_encoding(None, feed), WINDOWS_1252);
+        assert_eq!(detect_encoding(None, b"\xef\xbb\xbfhello"), UTF_8);
+        // a page can't declare UTF-16 about itself
+        assert_eq!(detect_encoding(None, b"<meta charset=utf-16>"), UTF_8);
+    }
+
+    #[test]
+    fn it_should_guess_undeclared_charsets() {
+        assert_eq!(detect_encoding(None, "こんにちは".as_bytes()), UTF_8);
+
+        let (japanese, _, _) = SHIFT_JIS.encode("こんにちは、世界");
+        assert_eq!(detect_encoding(None, &japanese), SHIFT_JIS);
+        let (chinese, _, _) = GBK.encode("你好，世界");
+        assert_eq!(detect_encoding(None, &chinese), GBK);
+        let (latin, _, _) = WINDOWS_1252.encode("déjà vu, café");
+        assert_eq!(detect_encoding(None, &latin), WINDOWS_1252);
+    }
+}
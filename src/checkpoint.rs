@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::Store;
+
+/// Why a checkpoint couldn't be resumed, see `Worker::resume`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckpointError {
+    pub reason: String,
+}
+
+impl CheckpointError {
+    pub(crate) fn new(reason: impl Into<String>) -> Self {
+        Self {
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unable to resume the checkpoint: {}", self.reason)
+    }
+}
+
+impl Error for CheckpointError {}
+
+/// The state of a worker's session, saved to pick a long flow up where it stopped, e.g. after a
+/// deploy. It's stamped with the version of the flow that saved it, so a flow whose step names or
+/// store schema changed since migrates it with `Worker::on_migrate` instead of resuming with
+/// state it no longer understands.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The flow version of the worker that saved it, see `Worker::set_flow_version`.
+    pub flow_version: u32,
+    /// The step to resume at, `None` if the flow was done.
+    pub next_step: Option<String>,
+    /// The string and JSON values of the store. Values of other types aren't kept.
+    pub store: BTreeMap<String, Value>,
+    /// The cookie jar, as exported by `PartitionedCookieStore::export_session`.
+    pub cookies: String,
+    /// When it was saved, in seconds since the Unix epoch.
+    pub saved_at: u64,
+}
+
+impl Checkpoint {
+    pub(crate) fn capture(
+        flow_version: u32,
+        next_step: Option<&str>,
+        store: &Store,
+        cookies: Vec<u8>,
+    ) -> Self {
+        let values = store
+            .keys()
+            .into_iter()
+            .filter_map(|key| {
+                let value = match store.get::<String>(key) {
+                    Some(text) => Value::String(text.clone()),
+                    None => store.get::<Value>(key)?.clone(),
+                };
+                Some((key.to_string(), value))
+            })
+            .collect();
+
+        Self {
+            flow_version,
+            next_step: next_step.map(str::to_string),
+            store: values,
+            cookies: String::from_utf8_lossy(&cookies).into_owned(),
+            saved_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    /// Puts the values back into a store: strings as `String`, anything else as a JSON `Value`.
+    pub(crate) fn restore_store(&self, store: &mut Store) {
+        for (key, value) in &self.store {
+            match value {
+                Value::String(text) => store.set(key, text.clone()),
+                value => store.set(key, value.clone()),
+            }
+        }
+    }
+
+    /// Renames a step, for migrations of flows whose steps were renamed.
+    pub fn rename_step(&mut self, from: &str, to: &str) {
+        if self.next_step.as_deref() == Some(from) {
+            self.next_step = Some(to.to_string());
+        }
+    }
+
+    /// Renames a store key, for migrations of flows whose store schema changed.
+    pub fn rename_key(&mut self, from: &str, to: &str) {
+        if let Some(value) = self.store.remove(from) {
+            self.store.insert(to.to_string(), value);
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let data = crate::read_artifact(path)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Large checkpoints are gzipped, see `write_artifact`.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let data = serde_json::to_vec_pretty(self)?;
+        crate::write_artifact(path, &data, crate::DEFAULT_COMPRESSION_THRESHOLD)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn it_should_keep_the_serializable_values_of_the_store() {
+        let mut store = Store::new();
+        store.set("token", "abc".to_string());
+        store.set("cart", json!({ "items": 2 }));
+        store.set("attempts", 3u32);
+
+        let mut checkpoint = Checkpoint::capture(2, Some("Checkout"), &store, vec![]);
+        assert_eq!(checkpoint.store.len(), 2);
+        checkpoint.rename_key("cart", "basket");
+        checkpoint.rename_step("Checkout", "Pay");
+        assert_eq!(checkpoint.next_step.as_deref(), Some("Pay"));
+
+        let path = std::env::temp_dir().join("mimicr-checkpoint-test.json");
+        checkpoint.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap();
+        assert_eq!(loaded, checkpoint);
+
+        let mut store = Store::new();
+        loaded.restore_store(&mut store);
+        assert_eq!(store.get::<String>("token").unwrap(), "abc");
+        assert_eq!(store.get::<Value>("basket").unwrap()["items"], 2);
+    }
+}
@@ -1,10 +1,65 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use reqwest::Proxy;
 
+use crate::{HostGuard, RedirectPolicy};
+
 #[derive(Clone)]
 pub struct ClientSettings {
     proxy: Option<Proxy>,
     user_agent: Option<String>,
     gzip: bool,
+    socket: SocketOptions,
+    ip_preference: IpPreference,
+    http1_only: bool,
+    redirect_policy: RedirectPolicy,
+    host_guard: Option<Arc<HostGuard>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+/// Which addresses of a resolved host are connected to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum IpPreference {
+    /// The resolver's order, reqwest's default.
+    #[default]
+    Any,
+    V4Only,
+    V6Only,
+    /// Tries the addresses of this family first and falls back to the other one.
+    Prefer(IpFamily),
+}
+
+/// Socket and connection pool tuning applied to every connection of a client.
+/// `None` keeps reqwest's default. The IP TTL can't be set, reqwest's connector doesn't expose it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SocketOptions {
+    /// Disables Nagle's algorithm, on by default.
+    pub nodelay: bool,
+    /// Sends TCP keepalive probes after the connection has been idle this long, so middleboxes
+    /// don't drop idle pooled connections.
+    pub keepalive: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    /// Closes pooled connections that have been idle this long.
+    pub pool_idle_timeout: Option<Duration>,
+    pub pool_max_idle_per_host: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            connect_timeout: None,
+            pool_idle_timeout: None,
+            pool_max_idle_per_host: None,
+        }
+    }
 }
 
 impl Default for ClientSettings {
@@ -19,6 +74,11 @@ impl ClientSettings {
             proxy: None,
             user_agent: None,
             gzip: true,
+            socket: SocketOptions::default(),
+            ip_preference: IpPreference::Any,
+            http1_only: false,
+            redirect_policy: RedirectPolicy::Follow,
+            host_guard: None,
         }
     }
 
@@ -58,4 +118,75 @@ impl ClientSettings {
     pub fn is_compressed(&self) -> bool {
         self.gzip
     }
+
+    pub fn set_tcp_nodelay(&mut self, nodelay: bool) -> &mut Self {
+        self.socket.nodelay = nodelay;
+        self
+    }
+
+    pub fn set_tcp_keepalive(&mut self, keepalive: Option<Duration>) -> &mut Self {
+        self.socket.keepalive = keepalive;
+        self
+    }
+
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.socket.connect_timeout = timeout;
+        self
+    }
+
+    pub fn set_pool_idle_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.socket.pool_idle_timeout = timeout;
+        self
+    }
+
+    pub fn set_pool_max_idle_per_host(&mut self, max: Option<usize>) -> &mut Self {
+        self.socket.pool_max_idle_per_host = max;
+        self
+    }
+
+    pub fn socket_options(&self) -> &SocketOptions {
+        &self.socket
+    }
+
+    /// Sets the address family of the session. Requests can override it with
+    /// `Request::with_ip_preference`. Hosts given as IP addresses are connected to as is.
+    pub fn set_ip_preference(&mut self, preference: IpPreference) -> &mut Self {
+        self.ip_preference = preference;
+        self
+    }
+
+    pub fn ip_preference(&self) -> IpPreference {
+        self.ip_preference
+    }
+
+    /// Set by the worker for hosts that were downgraded after HTTP/2 errors.
+    pub(crate) fn set_http1_only(&mut self, http1_only: bool) -> &mut Self {
+        self.http1_only = http1_only;
+        self
+    }
+
+    pub fn is_http1_only(&self) -> bool {
+        self.http1_only
+    }
+
+    /// Sets how the redirects of requests are followed. The context sets it from each
+    /// request's `Request::with_redirect_policy`.
+    pub fn set_redirect_policy(&mut self, policy: RedirectPolicy) -> &mut Self {
+        self.redirect_policy = policy;
+        self
+    }
+
+    pub fn redirect_policy(&self) -> RedirectPolicy {
+        self.redirect_policy
+    }
+
+    /// Set by the worker, so the addresses its host guard checked are the ones connected to.
+    pub(crate) fn set_host_guard(&mut self, guard: Option<Arc<HostGuard>>) -> &mut Self {
+        self.host_guard = guard;
+        self
+    }
+
+    pub fn host_guard(&self) -> Option<&Arc<HostGuard>> {
+        self.host_guard.as_ref()
+    }
 }
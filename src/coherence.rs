@@ -0,0 +1,315 @@
+use std::fmt;
+
+use reqwest::header::HeaderMap;
+
+use crate::Request;
+
+/// What the worker should do when a request fails a coherence check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoherenceMode {
+    /// Record the issues on the `Context` and send the request anyway.
+    Warn,
+    /// Refuse to send the request and call the step's `on_error`.
+    Error,
+}
+
+/// A single inconsistency found between a request's headers and the expected fingerprint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoherenceIssue {
+    /// A Chromium user agent without the `sec-ch-ua` client hints Chromium always sends.
+    MissingClientHints,
+    /// Client hints sent with a user agent that never sends them (Firefox, Safari).
+    UnexpectedClientHints(String),
+    /// The `sec-ch-ua-platform` hint doesn't match the platform in the user agent.
+    PlatformMismatch(String, String),
+    /// The `sec-ch-ua-mobile` hint doesn't match the user agent.
+    MobileMismatch,
+    /// The major version in `sec-ch-ua` doesn't match the user agent.
+    VersionMismatch(String, String),
+    /// The `User-Agent` header and the client user agent are different.
+    UserAgentMismatch(String, String),
+    /// `Accept-Language` doesn't start with one of the expected languages (e.g. the proxy geo).
+    LanguageMismatch(String, Vec<String>),
+}
+
+impl fmt::Display for CoherenceIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CoherenceIssue::MissingClientHints => {
+                write!(f, "Chromium user agent is missing sec-ch-ua client hints")
+            }
+            CoherenceIssue::UnexpectedClientHints(browser) => {
+                write!(f, "{} does not send sec-ch-ua client hints", browser)
+            }
+            CoherenceIssue::PlatformMismatch(hint, ua) => write!(
+                f,
+                "sec-ch-ua-platform {} does not match user agent platform {}",
+                hint, ua
+            ),
+            CoherenceIssue::MobileMismatch => {
+                write!(f, "sec-ch-ua-mobile does not match the user agent")
+            }
+            CoherenceIssue::VersionMismatch(hint, ua) => write!(
+                f,
+                "sec-ch-ua version {} does not match user agent version {}",
+                hint, ua
+            ),
+            CoherenceIssue::UserAgentMismatch(header, client) => write!(
+                f,
+                "User-Agent header {} does not match client user agent {}",
+                header, client
+            ),
+            CoherenceIssue::LanguageMismatch(lang, expected) => write!(
+                f,
+                "Accept-Language {} does not match expected languages {:?}",
+                lang, expected
+            ),
+        }
+    }
+}
+
+/// Checks that a request's headers are internally consistent with the fingerprint it claims.
+#[derive(Debug, Clone)]
+pub struct CoherenceValidator {
+    mode: CoherenceMode,
+    expected_languages: Vec<String>,
+}
+
+impl Default for CoherenceValidator {
+    fn default() -> Self {
+        CoherenceValidator::new(CoherenceMode::Warn)
+    }
+}
+
+impl CoherenceValidator {
+    pub fn new(mode: CoherenceMode) -> Self {
+        Self {
+            mode,
+            expected_languages: vec![],
+        }
+    }
+
+    /// Sets the languages `Accept-Language` must start with, e.g. `["de"]` for a German proxy.
+    pub fn with_expected_languages(mut self, languages: Vec<String>) -> Self {
+        self.expected_languages = languages;
+        self
+    }
+
+    pub fn mode(&self) -> CoherenceMode {
+        self.mode
+    }
+
+    /// Validates a request, using the client user agent when no `User-Agent` header is set.
+    pub fn validate(&self, req: &Request) -> Vec<CoherenceIssue> {
+        let headers = req.headers().unwrap_or_default();
+        let mut issues = vec![];
+
+        let header_ua = header_str(&headers, "user-agent");
+        if let (Some(header), Some(client)) = (&header_ua, req.user_agent()) {
+            if header != &client {
+                issues.push(CoherenceIssue::UserAgentMismatch(header.clone(), client));
+            }
+        }
+
+        if let Some(ua) = header_ua.or(req.user_agent()) {
+            issues.extend(self.validate_user_agent(&ua, &headers));
+        }
+
+        if !self.expected_languages.is_empty() {
+            if let Some(lang) = header_str(&headers, "accept-language") {
+                let primary = lang.to_lowercase();
+                let matches = self
+                    .expected_languages
+                    .iter()
+                    .any(|expected| primary.starts_with(&expected.to_lowercase()));
+                if !matches {
+                    issues.push(CoherenceIssue::LanguageMismatch(
+                        lang,
+                        self.expected_languages.clone(),
+                    ));
+                }
+            }
+        }
+
+        issues
+    }
+
+    fn validate_user_agent(&self, ua: &str, headers: &HeaderMap) -> Vec<CoherenceIssue> {
+        let mut issues = vec![];
+        let hints = header_str(headers, "sec-ch-ua");
+
+        match browser_of(ua) {
+            Browser::Chromium(version) => {
+                let Some(hints) = hints else {
+                    issues.push(CoherenceIssue::MissingClientHints);
+                    return issues;
+                };
+
+                if !hints.contains(&format!("v=\"{}\"", version)) {
+                    let hint_version = hints
+                        .split("v=\"")
+                        .nth(1)
+                        .and_then(|rest| rest.split('"').next())
+                        .unwrap_or_default()
+                        .to_string();
+                    issues.push(CoherenceIssue::VersionMismatch(hint_version, version));
+                }
+
+                if let Some(platform) = header_str(headers, "sec-ch-ua-platform") {
+                    let platform = platform.trim_matches('"').to_string();
+                    let ua_platform = platform_of(ua);
+                    if !platform.eq_ignore_ascii_case(ua_platform) {
+                        issues.push(CoherenceIssue::PlatformMismatch(
+                            platform,
+                            ua_platform.to_string(),
+                        ));
+                    }
+                }
+
+                if let Some(mobile) = header_str(headers, "sec-ch-ua-mobile") {
+                    if (mobile == "?1") != ua.contains("Mobile") {
+                        issues.push(CoherenceIssue::MobileMismatch);
+                    }
+                }
+            }
+            Browser::Other(name) => {
+                if hints.is_some() {
+                    issues.push(CoherenceIssue::UnexpectedClientHints(name.to_string()));
+                }
+            }
+            Browser::Unknown => {}
+        }
+
+        issues
+    }
+}
+
+enum Browser {
+    Chromium(String),
+    Other(&'static str),
+    Unknown,
+}
+
+fn browser_of(ua: &str) -> Browser {
+    if ua.contains("Firefox/") {
+        return Browser::Other("Firefox");
+    }
+    if let Some(rest) = ua.split("Chrome/").nth(1) {
+        let major = rest.split('.').next().unwrap_or_default();
+        return Browser::Chromium(major.to_string());
+    }
+    if ua.contains("Safari/") {
+        return Browser::Other("Safari");
+    }
+    Browser::Unknown
+}
+
+fn platform_of(ua: &str) -> &'static str {
+    if ua.contains("Windows") {
+        "Windows"
+    } else if ua.contains("Android") {
+        "Android"
+    } else if ua.contains("iPhone") || ua.contains("iPad") {
+        "iOS"
+    } else if ua.contains("Mac OS X") {
+        "macOS"
+    } else if ua.contains("CrOS") {
+        "Chrome OS"
+    } else if ua.contains("Linux") {
+        "Linux"
+    } else {
+        "Unknown"
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Method;
+
+    use super::*;
+
+    const CHROME_UA: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/116.0.0.0 Safari/537.36";
+    const FIREFOX_UA: &str =
+        "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:118.0) Gecko/20100101 Firefox/118.0";
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (key, value) in pairs {
+            headers.insert(*key, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn request(headers: HeaderMap) -> Request {
+        Request::new(Method::GET, "https://test.com".to_string()).with_headers(headers)
+    }
+
+    #[test]
+    fn it_should_pass_a_coherent_chrome_request() {
+        let headers = headers(&[
+            ("User-Agent", CHROME_UA),
+            (
+                "sec-ch-ua",
+                "\"Chromium\";v=\"116\", \"Google Chrome\";v=\"116\"",
+            ),
+            ("sec-ch-ua-mobile", "?0"),
+            ("sec-ch-ua-platform", "\"Windows\""),
+            ("Accept-Language", "de-DE,de;q=0.9"),
+        ]);
+
+        let validator = CoherenceValidator::new(CoherenceMode::Error)
+            .with_expected_languages(vec!["de".to_string()]);
+        assert!(validator.validate(&request(headers)).is_empty());
+    }
+
+    #[test]
+    fn it_should_flag_chrome_without_client_hints() {
+        let headers = headers(&[("User-Agent", CHROME_UA)]);
+        let issues = CoherenceValidator::default().validate(&request(headers));
+        assert_eq!(issues, vec![CoherenceIssue::MissingClientHints]);
+    }
+
+    #[test]
+    fn it_should_flag_mismatched_hints_and_language() {
+        let headers = headers(&[
+            ("User-Agent", CHROME_UA),
+            ("sec-ch-ua", "\"Chromium\";v=\"120\""),
+            ("sec-ch-ua-mobile", "?1"),
+            ("sec-ch-ua-platform", "\"macOS\""),
+            ("Accept-Language", "en-US,en;q=0.9"),
+        ]);
+
+        let validator =
+            CoherenceValidator::default().with_expected_languages(vec!["fr".to_string()]);
+        let issues = validator.validate(&request(headers));
+        assert_eq!(issues.len(), 4);
+        assert!(issues.contains(&CoherenceIssue::MobileMismatch));
+        assert!(issues.contains(&CoherenceIssue::VersionMismatch(
+            "120".to_string(),
+            "116".to_string()
+        )));
+    }
+
+    #[test]
+    fn it_should_flag_client_hints_on_firefox_and_ua_mismatch() {
+        let headers = headers(&[
+            ("User-Agent", FIREFOX_UA),
+            ("sec-ch-ua", "\"Chromium\";v=\"116\""),
+        ]);
+        let req = request(headers).with_user_agent("reqwest".to_string());
+
+        let issues = CoherenceValidator::default().validate(&req);
+        assert_eq!(issues.len(), 2);
+        assert_eq!(
+            issues[1].to_string(),
+            "Firefox does not send sec-ch-ua client hints"
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use std::time::SystemTime;
+
+use serde_json::json;
+
+use crate::data_url::encode_base64;
+use crate::har::iso_8601;
+use crate::uuid::uuid_v4;
+
+/// Consent cookies are kept for a year, like the platforms do.
+const CONSENT_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+/// The OneTrust cookie categories: strictly necessary, performance, functional and targeting.
+const ONETRUST_GROUPS: &[&str] = &["C0001", "C0002", "C0003", "C0004"];
+
+/// The purposes of Didomi's default notice, the IAB TCF purposes it shows.
+const DIDOMI_PURPOSES: &[&str] = &[
+    "cookies",
+    "select_basic_ads",
+    "create_ads_profile",
+    "select_personalized_ads",
+    "create_content_profile",
+    "select_personalized_content",
+    "measure_ad_performance",
+    "measure_content_performance",
+    "market_research",
+    "improve_products",
+];
+
+/// The consent given to a banner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsentChoice {
+    /// Accepts every category, what gets the full page on most sites.
+    AcceptAll,
+    /// Rejects everything but the strictly necessary cookies.
+    NecessaryOnly,
+}
+
+/// A consent-management platform showing a cookie banner, recognized by the scripts it loads.
+/// Instead of clicking through the banner, the bot answers it with the cookies the platform's
+/// script would have set, see `Context::negotiate_consent`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsentPlatform {
+    OneTrust,
+    Didomi,
+}
+
+impl ConsentPlatform {
+    /// Detects the platform a page loads, if any.
+    pub fn detect(body: &str) -> Option<Self> {
+        let body = body.to_ascii_lowercase();
+        let markers: [(Self, &[&str]); 2] = [
+            (
+                Self::OneTrust,
+                &["cdn.cookielaw.org", "onetrust-banner-sdk", "optanon"],
+            ),
+            (
+                Self::Didomi,
+                &["sdk.privacy-center.org", "didomiconfig", "didomi-host"],
+            ),
+        ];
+        markers
+            .into_iter()
+            .find(|(_, markers)| markers.iter().any(|marker| body.contains(marker)))
+            .map(|(platform, _)| platform)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::OneTrust => "OneTrust",
+            Self::Didomi => "Didomi",
+        }
+    }
+
+    /// Returns the `Set-Cookie` headers storing the choice for `domain` and its subdomains.
+    pub fn consent_cookies(&self, domain: &str, choice: ConsentChoice) -> Vec<String> {
+        let now = iso_8601(SystemTime::now());
+        let cookies = match self {
+            Self::OneTrust => {
+                let groups: Vec<String> = ONETRUST_GROUPS
+                    .iter()
+                    .enumerate()
+                    .map(|(i, group)| {
+                        let allowed = i == 0 || choice == ConsentChoice::AcceptAll;
+                        format!("{}%3A{}", group, u8::from(allowed))
+                    })
+                    .collect();
+                let consent = format!(
+                    "isGpcEnabled=0&datestamp={}&version=202310.1.0&isIABGlobal=false&hosts=\
+                     &consentId={}&interactionCount=1&landingPath=NotLandingPage&groups={}\
+                     &AwaitingReconsent=false",
+                    now.replace(':', "%3A"),
+                    uuid_v4(),
+                    groups.join("%2C")
+                );
+                vec![("OptanonAlertBoxClosed", now), ("OptanonConsent", consent)]
+            }
+            Self::Didomi => {
+                let (purposes, vendors) = match choice {
+                    ConsentChoice::AcceptAll => (
+                        json!({ "enabled": DIDOMI_PURPOSES }),
+                        json!({ "enabled": [] }),
+                    ),
+                    ConsentChoice::NecessaryOnly => (
+                        json!({ "disabled": DIDOMI_PURPOSES }),
+                        json!({ "disabled": [] }),
+                    ),
+                };
+                let token = json!({
+                    "user_id": uuid_v4(),
+                    "created": now,
+                    "updated": now,
+                    "vendors": vendors,
+                    "purposes": purposes,
+                    "version": 2,
+                });
+                vec![("didomi_token", encode_base64(token.to_string().as_bytes()))]
+            }
+        };
+
+        cookies
+            .into_iter()
+            .map(|(name, value)| {
+                format!(
+                    "{}={}; Domain={}; Path=/; Max-Age={}",
+                    name, value, domain, CONSENT_MAX_AGE
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+    use crate::{decode_base64, Context, Request};
+    use reqwest::Method;
+
+    #[test]
+    fn it_should_detect_the_platforms() {
+        let onetrust = r#"<script src="https://cdn.cookielaw.org/scripttemplates/otSDKStub.js">"#;
+        assert_eq!(
+            ConsentPlatform::detect(onetrust),
+            Some(ConsentPlatform::OneTrust)
+        );
+        let didomi = r#"<script>window.didomiConfig = { app: {} };</script>"#;
+        assert_eq!(
+            ConsentPlatform::detect(didomi),
+            Some(ConsentPlatform::Didomi)
+        );
+        assert_eq!(ConsentPlatform::detect("<html></html>"), None);
+    }
+
+    #[test]
+    fn it_should_synthesize_the_consent_cookies() {
+        let cookies =
+            ConsentPlatform::OneTrust.consent_cookies("example.com", ConsentChoice::NecessaryOnly);
+        assert_eq!(cookies.len(), 2);
+        assert!(cookies[0].starts_with("OptanonAlertBoxClosed=20"));
+        assert!(cookies[1].contains("groups=C0001%3A1%2CC0002%3A0%2CC0003%3A0%2CC0004%3A0&"));
+        assert!(cookies[1].ends_with("; Domain=example.com; Path=/; Max-Age=31536000"));
+
+        let cookies =
+            ConsentPlatform::Didomi.consent_cookies("example.com", ConsentChoice::AcceptAll);
+        let token = cookies[0]
+            .strip_prefix("didomi_token=")
+            .and_then(|cookie| cookie.split(';').next())
+            .unwrap();
+        let token: serde_json::Value =
+            serde_json::from_slice(&decode_base64(token).unwrap()).unwrap();
+        assert_eq!(token["purposes"]["enabled"][0], "cookies");
+        assert_eq!(token["user_id"].as_str().unwrap().len(), 36);
+    }
+
+    #[tokio::test]
+    async fn it_should_get_past_the_banner() {
+        let server = TestServer::start(|req| match req.header("cookie") {
+            Some(cookies) if cookies.contains("OptanonConsent=") => TestResponse::ok("content"),
+            _ => TestResponse::ok(r#"<div id="onetrust-banner-sdk"></div>"#),
+        })
+        .await;
+        let page = Request::new(Method::GET, server.url("/"));
+
+        let mut ctx = Context::new();
+        let banner = ctx.fan_out(vec![page.clone()], 1).await.unwrap().remove(0);
+        ctx.set_response_body(banner.body);
+        ctx.set_response_info(Some(banner.info));
+        assert_eq!(
+            ctx.negotiate_consent(ConsentChoice::AcceptAll),
+            Some(ConsentPlatform::OneTrust)
+        );
+
+        let page = ctx.fan_out(vec![page], 1).await.unwrap().remove(0);
+        assert_eq!(page.body_text(), "content");
+    }
+}
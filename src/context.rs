@@ -1,30 +1,69 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::sync::Arc;
 
 use encoding_rs::{Encoding, UTF_8};
-use reqwest::RequestBuilder;
+use reqwest::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, RANGE, SET_COOKIE, USER_AGENT,
+};
+use reqwest::{Method, Proxy, RequestBuilder, Version};
 use serde::de::DeserializeOwned;
+use serde_json::Value;
 
-use crate::{HttpRequester, Request};
+use crate::{
+    detect_encoding, Cassette, ChildResponse, ClientSettings, CoherenceIssue, ConsentChoice,
+    ConsentPlatform, Cors, DelayedQueue, DownloadReport, Environment, FanOutError, HarRecorder,
+    HttpRequester, PageClassifier, PageMatch, PartitionedCookieStore, Preflight, RateLimitBudget,
+    RedirectHop, Request, StepError, Store, TimeoutInfo,
+};
 
 /// The context for the bots current step's execution.
 /// This is passed to the step's `on_success` and `on_error` methods.
 pub struct Context {
-    /// The original request struct.
-    request: Request,
+    /// The original request struct. Shared with the snapshots taken of the context.
+    request: Arc<Request>,
     /// The next step to be executed.
     current_step: Option<String>,
+    /// The label of the step variant being executed, if the step has variants.
+    current_variant: Option<String>,
     /// The HTTP requester which manages cookie store and client settings.
     http_requester: HttpRequester,
     /// The request builder from reqwest.
     request_builder: Option<RequestBuilder>,
     /// The response from the request.
     response_body: Option<bytes::Bytes>,
+    /// The status, headers and final url of the response.
+    response: Option<ResponseInfo>,
     /// The next step to be executed.
     next_step: Option<String>,
+    /// Steps scheduled to run later, see `schedule_step`.
+    delayed_steps: DelayedQueue,
     /// If status codes are provided, then the response status code must be in the list.
     status_codes: Option<Vec<u16>>,
     /// The time elapsed in milliseconds for the request.
     time_elapsed: u64,
+    /// Header coherence issues found for the current request.
+    coherence_issues: Vec<CoherenceIssue>,
+    /// The id of the `Identity` the current request was sent as, if an identity pool is used.
+    current_identity: Option<String>,
+    /// The priority inherited by every request of the flow, see `set_priority`.
+    priority: u8,
+    /// The environment the flow was loaded for, see `Worker::set_environment`.
+    environment: Option<Environment>,
+    /// State passed between steps, kept for as long as the worker.
+    store: Store,
+    /// Whether `body_json` strips XSSI prefixes.
+    strip_xssi: bool,
+    download: Option<DownloadReport>,
+    /// The timeout the current step's `on_timeout` is called for.
+    timeout: Option<TimeoutInfo>,
+    /// Whether the response was shared from an identical in-flight request of another worker.
+    coalesced: bool,
+    /// Whether the body is only the part of a broken response received before the error.
+    partial_body: bool,
+    /// Whether the request was retried over HTTP/1.1 after an HTTP/2 error.
+    downgraded: bool,
 }
 
 impl Default for Context {
@@ -40,14 +79,28 @@ impl Context {
         let request_builder = http_requester.build_reqwest(request.clone()).unwrap();
 
         Context {
-            request,
+            request: Arc::new(request),
             current_step: None,
+            current_variant: None,
             http_requester,
             request_builder: Some(request_builder),
             response_body: None,
+            response: None,
             next_step: None,
+            delayed_steps: DelayedQueue::new(),
             status_codes: None,
             time_elapsed: 0,
+            coherence_issues: vec![],
+            current_identity: None,
+            priority: 0,
+            environment: None,
+            store: Store::new(),
+            strip_xssi: false,
+            download: None,
+            timeout: None,
+            coalesced: false,
+            partial_body: false,
+            downgraded: false,
         }
     }
 
@@ -61,6 +114,16 @@ impl Context {
         self.current_step.clone()
     }
 
+    /// Sets the current step variant.
+    pub fn set_current_variant(&mut self, variant: Option<String>) {
+        self.current_variant = variant;
+    }
+
+    /// Gets the label of the current step variant, if the step was registered with variants.
+    pub fn get_current_variant(&self) -> Option<String> {
+        self.current_variant.clone()
+    }
+
     /// Sets the next step.
     pub fn set_next_step(&mut self, step: String) {
         self.next_step = Some(step);
@@ -76,6 +139,19 @@ impl Context {
         self.next_step.clone()
     }
 
+    /// Runs a step once `delay` has passed and the flow has no next step, see `Worker::run`.
+    pub fn schedule_step(&mut self, step: String, delay: std::time::Duration) {
+        self.delayed_steps.push(&step, delay);
+    }
+
+    pub fn get_delayed_steps(&self) -> &DelayedQueue {
+        &self.delayed_steps
+    }
+
+    pub fn get_delayed_steps_mut(&mut self) -> &mut DelayedQueue {
+        &mut self.delayed_steps
+    }
+
     /// Get the time elapsed in milliseconds.
     pub fn get_time_elapsed(&self) -> u64 {
         self.time_elapsed
@@ -90,6 +166,85 @@ impl Context {
     pub fn get_time_elapsed_as_string(&self) -> String {
         format!("{} ms", self.time_elapsed)
     }
+    /// Sets the header coherence issues found for the current request.
+    pub fn set_coherence_issues(&mut self, issues: Vec<CoherenceIssue>) {
+        self.coherence_issues = issues;
+    }
+
+    /// Gets the header coherence issues found for the current request.
+    pub fn get_coherence_issues(&self) -> &Vec<CoherenceIssue> {
+        &self.coherence_issues
+    }
+
+    pub fn set_current_identity(&mut self, identity: Option<String>) {
+        self.current_identity = identity;
+    }
+
+    /// Returns the id of the identity the current request was sent as.
+    pub fn get_current_identity(&self) -> Option<String> {
+        self.current_identity.clone()
+    }
+
+    /// Returns the proxy the current request was sent through, e.g. to report it as burned
+    /// from `on_error`. This includes the proxy of the identity the request was sent as.
+    pub fn get_current_proxy(&self) -> Option<&Proxy> {
+        self.http_requester.settings.proxy()
+    }
+
+    /// Returns the request of the current step, as the worker sends it.
+    pub fn get_request(&self) -> &Request {
+        &self.request
+    }
+
+    /// Returns the user agent the current request was sent with, from its `User-Agent` header
+    /// or else from the request's or identity's user agent.
+    pub fn get_current_user_agent(&self) -> Option<String> {
+        self.request
+            .headers()
+            .and_then(|h| h.get(USER_AGENT)?.to_str().ok().map(|ua| ua.to_string()))
+            .or_else(|| self.http_requester.settings.user_agent().cloned())
+    }
+
+    /// Sets the priority of the flow. Every following step, including sub-flows chained with
+    /// `set_next_step`, inherits it unless its request sets its own priority.
+    pub fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+    }
+
+    pub fn get_priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub fn set_environment(&mut self, environment: Option<Environment>) {
+        self.environment = environment;
+    }
+
+    /// Returns the environment, e.g. to read a credential in a callback.
+    pub fn get_environment(&self) -> Option<&Environment> {
+        self.environment.as_ref()
+    }
+
+    /// Returns the values set by previous steps, e.g. `get_store().get::<String>("csrf")`.
+    pub fn get_store(&self) -> &Store {
+        &self.store
+    }
+
+    /// The store is kept when the session is reset. Clear the values that belong to a session,
+    /// like tokens, in `Worker::on_session_rotate`.
+    pub fn get_store_mut(&mut self) -> &mut Store {
+        &mut self.store
+    }
+
+    /// Sets whether the response was coalesced with another worker's request.
+    pub fn set_coalesced(&mut self, coalesced: bool) {
+        self.coalesced = coalesced;
+    }
+
+    /// Returns true if the response came from another worker's identical in-flight request.
+    pub fn is_coalesced(&self) -> bool {
+        self.coalesced
+    }
+
     /// Sets the request builder.
     pub fn set_request_builder(&mut self, req_builder: RequestBuilder) {
         self.request_builder = Some(req_builder);
@@ -100,6 +255,112 @@ impl Context {
         self.request_builder.take()
     }
 
+    /// Returns the HTTP requester of the current session.
+    pub fn get_http_requester(&self) -> &HttpRequester {
+        &self.http_requester
+    }
+
+    /// Sends several requests at once, e.g. the detail pages of a listing, with at most
+    /// `concurrency` in flight. They share the session's cookies, cassette and HAR recorder.
+    /// Fails with a `FanOutError` listing the outcome of every request if any of them failed.
+    pub async fn fan_out(
+        &self,
+        requests: Vec<Request>,
+        concurrency: usize,
+    ) -> Result<Vec<ChildResponse>, FanOutError> {
+        crate::fan_out::fan_out(&self.http_requester, requests, concurrency).await
+    }
+
+    /// Asks for the size, `Accept-Ranges`, `Last-Modified` and `ETag` of a file with a `HEAD`
+    /// request in the session, so a download step can pick its strategy before the full `GET`.
+    /// Servers refusing `HEAD` are probed with `probe_range` instead.
+    pub async fn preflight_head(&self, url: &str) -> Result<Preflight, StepError> {
+        let head = Request::new(Method::HEAD, url.to_string());
+        match crate::fan_out::send(&mut self.http_requester.clone(), head).await {
+            Ok(res) => Ok(Preflight::from_head(&res.info)),
+            Err(StepError::StatusCodeNotFound(405 | 501, _)) => self.probe_range(url).await,
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Asks for the first byte of a file with a `Range` request in the session, which tells its
+    /// size and whether the server answers ranges at all. A server ignoring the range sends the
+    /// whole file.
+    pub async fn probe_range(&self, url: &str) -> Result<Preflight, StepError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, HeaderValue::from_static("bytes=0-0"));
+        let probe = Request::new(Method::GET, url.to_string())
+            .with_headers(headers)
+            .with_status_codes(vec![200, 206]);
+        let res = crate::fan_out::send(&mut self.http_requester.clone(), probe).await?;
+        Ok(Preflight::from_range_probe(&res.info))
+    }
+
+    /// Sends a request in the session to a streaming endpoint answering with newline delimited
+    /// JSON, and returns its records while they arrive instead of reading the whole body. The
+    /// request timeout only covers the response headers, see `NdjsonStream` for its limits.
+    pub async fn ndjson_stream<T: DeserializeOwned>(
+        &self,
+        req: Request,
+    ) -> Result<crate::NdjsonStream<T>, StepError> {
+        crate::ndjson::open(&mut self.http_requester.clone(), req).await
+    }
+
+    /// Sends an `OPTIONS` request in the session, from the origin if given, to learn the methods
+    /// and CORS policy of an endpoint, see `ResponseInfo::allow` and `ResponseInfo::cors`. With
+    /// a method, it's sent as a CORS preflight asking for that method.
+    pub async fn probe_options(
+        &self,
+        url: &str,
+        origin: Option<&str>,
+        method: Option<&Method>,
+    ) -> Result<ResponseInfo, StepError> {
+        let mut headers = HeaderMap::new();
+        let value = |text: &str| {
+            HeaderValue::from_str(text).map_err(|err| StepError::ReqwestError(err.to_string()))
+        };
+        if let Some(origin) = origin {
+            headers.insert(ORIGIN, value(origin)?);
+        }
+        if let Some(method) = method {
+            headers.insert(ACCESS_CONTROL_REQUEST_METHOD, value(method.as_str())?);
+        }
+        let options = Request::new(Method::OPTIONS, url.to_string()).with_headers(headers);
+        let res = crate::fan_out::send(&mut self.http_requester.clone(), options).await?;
+        Ok(res.info)
+    }
+
+    /// Records the requests of the session to a cassette, or replays them from it. The cassette
+    /// is kept when the session is reset.
+    pub fn set_cassette(&mut self, cassette: Option<Arc<Cassette>>) {
+        self.http_requester.set_cassette(cassette);
+    }
+
+    /// Records the requests of the session to a HAR recorder. The recorder is kept when the
+    /// session is reset.
+    pub fn set_har_recorder(&mut self, recorder: Option<Arc<HarRecorder>>) {
+        self.http_requester.set_har_recorder(recorder);
+    }
+
+    /// Swaps in another session's requester and its cookie jar, returning the current one.
+    pub fn replace_http_requester(&mut self, requester: HttpRequester) -> HttpRequester {
+        std::mem::replace(&mut self.http_requester, requester)
+    }
+
+    pub fn get_client_settings(&self) -> &ClientSettings {
+        &self.http_requester.settings
+    }
+
+    /// Returns the client settings of the current session, e.g. to tune socket options.
+    pub fn get_client_settings_mut(&mut self) -> &mut ClientSettings {
+        &mut self.http_requester.settings
+    }
+
+    /// Returns the tags of the current request.
+    pub fn get_tags(&self) -> &BTreeMap<String, String> {
+        self.request.tags()
+    }
+
     pub fn get_url(&self) -> String {
         self.request.url().clone()
     }
@@ -121,9 +382,113 @@ impl Context {
         self.response_body = Some(res);
     }
 
+    pub fn set_downgraded(&mut self, downgraded: bool) {
+        self.downgraded = downgraded;
+    }
+
+    /// Sets the outcome of the current request's download. The worker clears it before each
+    /// request.
+    pub fn set_download_report(&mut self, report: Option<DownloadReport>) {
+        self.download = report;
+    }
+
+    /// Returns where the current request's body was streamed to, how much of it and how fast,
+    /// if it was sent `with_download`.
+    pub fn download_report(&self) -> Option<&DownloadReport> {
+        self.download.as_ref()
+    }
+
+    /// Sets the timeout of the current request. The worker sets it before calling `on_timeout`
+    /// and clears it before each request.
+    pub fn set_timeout_info(&mut self, info: Option<TimeoutInfo>) {
+        self.timeout = info;
+    }
+
+    /// Returns which timeout fired, after how long, and on which attempt, when called from a
+    /// step's `on_timeout`.
+    pub fn timeout_info(&self) -> Option<&TimeoutInfo> {
+        self.timeout.as_ref()
+    }
+
+    /// Returns true if the current request was retried over HTTP/1.1 after an HTTP/2 error,
+    /// see `Worker::set_http2_fallback`.
+    pub fn is_downgraded(&self) -> bool {
+        self.downgraded
+    }
+
+    /// Rebuilds the request builder of the current request, e.g. after changing client settings.
+    pub fn rebuild_request(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let req = self.request.as_ref().clone();
+        self.update_from_request(req)
+    }
+
+    /// Sets the status, headers and final url of the response. The worker sets them before the
+    /// step's callbacks run, and clears them before each request.
+    pub fn set_response_info(&mut self, info: Option<ResponseInfo>) {
+        self.response = info;
+    }
+
+    pub fn response_info(&self) -> Option<&ResponseInfo> {
+        self.response.as_ref()
+    }
+
+    /// Returns the status code of the response, if one was received.
+    pub fn status(&self) -> Option<u16> {
+        self.response.as_ref().map(|r| r.status())
+    }
+
+    /// Returns the headers of the response, if one was received.
+    pub fn headers(&self) -> Option<&HeaderMap> {
+        self.response.as_ref().map(|r| r.headers())
+    }
+
+    /// Returns the budget announced by the rate-limit headers of the response, if it has any.
+    pub fn rate_limit_budget(&self) -> Option<RateLimitBudget> {
+        RateLimitBudget::from_headers(self.headers()?)
+    }
+
+    /// Whether a `Request::with_conditional` came back `304 Not Modified`: the page didn't change
+    /// since the last time, and the body is empty.
+    pub fn not_modified(&self) -> bool {
+        self.status() == Some(304)
+    }
+
+    /// Returns the redirects followed to get the response, e.g. every hop of an OAuth flow with
+    /// its status and `Set-Cookie` headers. Empty without a response.
+    pub fn redirect_chain(&self) -> &[RedirectHop] {
+        self.response
+            .as_ref()
+            .map(|info| info.redirects())
+            .unwrap_or_default()
+    }
+
+    /// Returns the url of the response after redirects, if one was received.
+    pub fn final_url(&self) -> Option<&str> {
+        self.response.as_ref().map(|r| r.final_url())
+    }
+
+    /// Marks the body as the part of a broken response received before the error.
+    pub fn set_partial_body(&mut self, partial: bool) {
+        self.partial_body = partial;
+    }
+
+    /// Returns true if the body is incomplete, e.g. in `on_error` after the connection closed
+    /// early. The error is one of the protocol errors, see `StepError::is_protocol_error`.
+    pub fn is_partial_body(&self) -> bool {
+        self.partial_body
+    }
+
+    /// Takes the response body out of the context, leaving none.
+    /// The worker does this before each request so the read buffer can be reused.
+    pub fn take_response_body(&mut self) -> Option<bytes::Bytes> {
+        self.partial_body = false;
+        self.response_body.take()
+    }
+
     /// Returns the response body as bytes.
     /// This is the base format for the response body. All other methods are convenience methods.
-    pub fn body_bytes(&self) -> Result<bytes::Bytes, Box<dyn Error>> {
+    /// `Bytes` is reference counted, so this doesn't copy the body.
+    pub fn body_bytes(&self) -> Result<bytes::Bytes, Box<dyn Error + Send + Sync>> {
         if self.response_body.is_none() {
             return Err(Self::no_body_error());
         }
@@ -131,63 +496,520 @@ impl Context {
         Ok(self.response_body.clone().unwrap())
     }
 
+    /// Returns a borrowed view of the response body.
+    pub fn body_slice(&self) -> Result<&[u8], Box<dyn Error + Send + Sync>> {
+        match &self.response_body {
+            Some(body) => Ok(body.as_ref()),
+            None => Err(Self::no_body_error()),
+        }
+    }
+
+    /// Returns the response body as text, borrowed from the body when it is valid UTF-8. The
+    /// body is decoded with its charset, see `body_encoding`.
+    pub fn body_str(&self) -> Result<Cow<'_, str>, Box<dyn Error + Send + Sync>> {
+        let body = self.body_slice()?;
+        let (text, _, _) = self.body_encoding()?.decode(body);
+
+        Ok(text)
+    }
+
+    /// Returns the encoding of the response body, from its `Content-Type`, its
+    /// `<meta charset>` or else a guess, see `detect_encoding`.
+    pub fn body_encoding(&self) -> Result<&'static Encoding, Box<dyn Error + Send + Sync>> {
+        let content_type = self
+            .response
+            .as_ref()
+            .and_then(|r| r.header("content-type"));
+        Ok(detect_encoding(content_type, self.body_slice()?))
+    }
+
     /// Returns the response body as text. This is a convenience method for `encoding_rs::decode`.
-    pub fn body_text(&self) -> Result<String, Box<dyn Error>> {
+    /// Prefer `body_str` to avoid copying the body.
+    pub fn body_text(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        Ok(self.body_str()?.into_owned())
+    }
+
+    /// Returns the response body as JSON. This is a convenience method for `serde_json::from_slice`.
+    /// XSSI prefixes are stripped first when `set_strip_xssi` is enabled.
+    pub async fn body_json<T: DeserializeOwned>(&self) -> Result<T, Box<dyn Error + Send + Sync>> {
+        serde_json::from_slice(self.json_body()?)
+            .map_err(|err| -> Box<dyn Error + Send + Sync> { Box::new(err) })
+    }
+
+    /// Returns the values a JSONPath like `$.data.items[*].id` selects in the JSON body, without
+    /// deserializing it into a type, see `json_path`.
+    pub fn json_path(&self, path: &str) -> Result<Vec<Value>, Box<dyn Error + Send + Sync>> {
+        let body: Value = serde_json::from_slice(self.json_body()?)?;
+        Ok(crate::json_path(&body, path)?
+            .into_iter()
+            .cloned()
+            .collect())
+    }
+
+    /// Returns the value a JSON pointer like `/data/items/0/id` points at in the JSON body.
+    pub fn json_pointer(
+        &self,
+        pointer: &str,
+    ) -> Result<Option<Value>, Box<dyn Error + Send + Sync>> {
+        let mut body: Value = serde_json::from_slice(self.json_body()?)?;
+        Ok(body.pointer_mut(pointer).map(Value::take))
+    }
+
+    /// Returns the groups of the first match of a regex in the response body, e.g. the
+    /// `token` of `csrf_token" value="(?P<token>[^"]+)"`, or `None` without a match.
+    pub fn extract(
+        &self,
+        pattern: &str,
+    ) -> Result<Option<crate::Captures>, Box<dyn Error + Send + Sync>> {
+        let body = self.body_str()?;
+        Ok(crate::extract::captures(pattern, &body, false)?.pop())
+    }
+
+    /// Returns the groups of every match of a regex in the response body, in order.
+    pub fn extract_all(
+        &self,
+        pattern: &str,
+    ) -> Result<Vec<crate::Captures>, Box<dyn Error + Send + Sync>> {
+        let body = self.body_str()?;
+        Ok(crate::extract::captures(pattern, &body, true)?)
+    }
+
+    /// Validates the JSON body against the schema of the request, if it has one.
+    pub(crate) fn check_response_schema(&self) -> Result<(), StepError> {
+        let Some(schema) = self.get_request().response_schema() else {
+            return Ok(());
+        };
+        let body: Value = self
+            .json_body()
+            .and_then(|body| Ok(serde_json::from_slice(body)?))
+            .map_err(|err| StepError::Parse(err.to_string()))?;
+        let violations = crate::validate_schema(schema, &body);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(StepError::SchemaMismatch(violations))
+        }
+    }
+
+    /// Returns the body to parse as JSON, without its XSSI prefix when `set_strip_xssi` is on.
+    fn json_body(&self) -> Result<&[u8], Box<dyn Error + Send + Sync>> {
+        let body = self
+            .response_body
+            .as_ref()
+            .ok_or_else(Self::no_body_error)?;
+        Ok(if self.strip_xssi {
+            crate::strip_xssi_prefix(body)
+        } else {
+            body
+        })
+    }
+
+    /// Makes `body_json` strip anti JSON hijacking prefixes like `)]}'` and `while(1);`, see
+    /// `XSSI_PREFIXES`. The setting is kept across steps.
+    pub fn set_strip_xssi(&mut self, strip: bool) {
+        self.strip_xssi = strip;
+    }
+
+    /// Decodes every `data:` URL of the response body, e.g. inline images, see `find_data_urls`.
+    pub fn data_urls(&self) -> Result<Vec<crate::DataUrl>, Box<dyn Error + Send + Sync>> {
+        Ok(crate::find_data_urls(&self.body_str()?))
+    }
+
+    /// Compares the response with the templates of the current step, e.g. to tell a block page
+    /// served with a 200 from the real page, see `PageClassifier`.
+    pub fn classify_page(&self, classifier: &PageClassifier) -> Option<PageMatch> {
+        let step = self.get_current_step().unwrap_or_default();
+        classifier.classify(&step, &self.body_str().ok()?)
+    }
+
+    /// Answers the cookie banner of the response, if it comes from a known consent-management
+    /// platform, by storing the cookies the banner would have set in the session's jar. Returns
+    /// the platform, so the step can request the page again to get its real content.
+    pub fn negotiate_consent(&self, choice: ConsentChoice) -> Option<ConsentPlatform> {
+        let platform = ConsentPlatform::detect(&self.body_str().ok()?)?;
+        let url = self
+            .final_url()
+            .map(str::to_string)
+            .unwrap_or(self.get_url());
+        let url = reqwest::Url::parse(&url).ok()?;
+        let domain = PartitionedCookieStore::partition_key(url.host_str()?);
+
+        let cookies: Vec<HeaderValue> = platform
+            .consent_cookies(&domain, choice)
+            .iter()
+            .filter_map(|cookie| HeaderValue::from_str(cookie).ok())
+            .collect();
+        reqwest::cookie::CookieStore::set_cookies(
+            self.http_requester.cookie_jar().as_ref(),
+            &mut cookies.iter(),
+            &url,
+        );
+        Some(platform)
+    }
+
+    /// Returns the entries of an RSS, Atom or JSON feed response.
+    #[cfg(feature = "feed")]
+    pub fn body_feed(&self) -> Result<Vec<crate::FeedEntry>, Box<dyn Error + Send + Sync>> {
         if self.response_body.is_none() {
             return Err(Self::no_body_error());
         }
 
-        let encoding = Encoding::for_label(b"utf-8").unwrap_or(UTF_8);
-        let (text, _, _) = encoding.decode(self.response_body.as_ref().unwrap());
+        crate::feed::parse_feed(self.response_body.as_ref().unwrap())
+    }
 
-        Ok(text.to_string())
+    /// Extracts the JSON-LD blocks, OpenGraph/meta tags and microdata embedded in an HTML response.
+    #[cfg(feature = "html")]
+    pub fn structured_data(&self) -> Result<crate::StructuredData, Box<dyn Error + Send + Sync>> {
+        let text = self.body_str()?;
+        Ok(crate::html::extract_structured_data(&text))
     }
 
-    /// Returns the response body as JSON. This is a convenience method for `serde_json::from_slice`.
-    pub async fn body_json<T: DeserializeOwned>(&self) -> Result<T, Box<dyn Error>> {
+    /// Returns the first element of an HTML response matching a CSS selector, e.g.
+    /// `ctx.select("input[name=csrf]")?.and_then(|input| input.attr("value"))`.
+    #[cfg(feature = "html")]
+    pub fn select(
+        &self,
+        selector: &str,
+    ) -> Result<Option<crate::HtmlElement>, Box<dyn Error + Send + Sync>> {
+        Ok(self.select_all(selector)?.into_iter().next())
+    }
+
+    /// Returns every element of an HTML response matching a CSS selector, in document order.
+    #[cfg(feature = "html")]
+    pub fn select_all(
+        &self,
+        selector: &str,
+    ) -> Result<Vec<crate::HtmlElement>, Box<dyn Error + Send + Sync>> {
+        crate::html::select_all(&self.body_str()?, selector)
+    }
+
+    /// Returns the forms of an HTML response, with actions resolved against the response's url.
+    #[cfg(feature = "html")]
+    pub fn forms(&self) -> Result<Vec<crate::Form>, Box<dyn Error + Send + Sync>> {
+        self.select_forms("form")
+    }
+
+    /// Returns the first form matching a CSS selector, e.g. `ctx.form("form#login")`, to fill in
+    /// and send back with `Form::to_request`.
+    #[cfg(feature = "html")]
+    pub fn form(
+        &self,
+        selector: &str,
+    ) -> Result<Option<crate::Form>, Box<dyn Error + Send + Sync>> {
+        Ok(self.select_forms(selector)?.into_iter().next())
+    }
+
+    #[cfg(feature = "html")]
+    fn select_forms(
+        &self,
+        selector: &str,
+    ) -> Result<Vec<crate::Form>, Box<dyn Error + Send + Sync>> {
+        let base_url = self
+            .final_url()
+            .map(|url| url.to_string())
+            .unwrap_or_else(|| self.get_url());
+        crate::Form::select(&self.body_str()?, &base_url, selector)
+    }
+
+    /// Returns the text of a PDF response, with pages separated by form feeds.
+    #[cfg(feature = "pdf")]
+    pub fn body_pdf_text(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        Ok(self.body_pdf_pages()?.join("\x0c"))
+    }
+
+    /// Returns the text of each page of a PDF response.
+    #[cfg(feature = "pdf")]
+    pub fn body_pdf_pages(&self) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
         if self.response_body.is_none() {
             return Err(Self::no_body_error());
         }
 
-        serde_json::from_slice(self.response_body.as_ref().unwrap())
-            .map_err(|err| -> Box<dyn std::error::Error> { Box::new(err) })
+        crate::pdf::extract_pdf_pages(self.response_body.as_ref().unwrap())
     }
 
-    fn no_body_error() -> Box<dyn Error> {
-        Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
+    /// Returns the dimensions and format of an image response.
+    #[cfg(feature = "image")]
+    pub fn image_info(&self) -> Result<crate::ImageInfo, Box<dyn Error + Send + Sync>> {
+        crate::media::image_info(self.body_slice()?)
+    }
+
+    /// Returns the perceptual hash of an image response, for change tracking and deduplication.
+    #[cfg(feature = "image")]
+    pub fn image_hash(&self) -> Result<crate::ImageHash, Box<dyn Error + Send + Sync>> {
+        crate::media::image_hash(self.body_slice()?)
+    }
+
+    /// Returns a PNG thumbnail of an image response that fits within the given size.
+    #[cfg(feature = "image")]
+    pub fn image_thumbnail(
+        &self,
+        max_width: u32,
+        max_height: u32,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        crate::media::image_thumbnail(self.body_slice()?, max_width, max_height)
+    }
+
+    /// Detects the natural language of the response body, ignoring HTML markup.
+    /// Returns `None` when the body is too short or ambiguous to tell.
+    #[cfg(feature = "language")]
+    pub fn detect_language(&self) -> Result<Option<whatlang::Info>, Box<dyn Error + Send + Sync>> {
+        let text = self.body_str()?;
+        Ok(whatlang::detect(&visible_text(&text)))
+    }
+
+    /// Returns a read-only snapshot of the current step's request and response, e.g. for observers,
+    /// sinks or a run history. The request and body are shared rather than copied.
+    pub fn snapshot(&self) -> ContextSnapshot {
+        ContextSnapshot {
+            request: self.request.clone(),
+            step: self.current_step.clone(),
+            variant: self.current_variant.clone(),
+            body: self.response_body.clone(),
+            response: self.response.clone(),
+            time_elapsed: self.time_elapsed,
+            identity: self.current_identity.clone(),
+            coalesced: self.coalesced,
+        }
+    }
+
+    fn no_body_error() -> Box<dyn Error + Send + Sync> {
+        Box::new(std::io::Error::other(
             "No body has been set from the request.",
         ))
     }
 
+    /// Starts a new session with an empty cookie jar and default client settings.
+    /// Socket options and the IP preference are kept.
+    pub fn reset_session(&mut self) {
+        let socket = *self.http_requester.settings.socket_options();
+        let ip_preference = self.http_requester.settings.ip_preference();
+        let cassette = self.http_requester.cassette();
+        let har = self.http_requester.har_recorder();
+        self.http_requester = HttpRequester::new();
+        self.http_requester.set_cassette(cassette);
+        self.http_requester.set_har_recorder(har);
+        self.get_client_settings_mut()
+            .set_ip_preference(ip_preference)
+            .set_tcp_nodelay(socket.nodelay)
+            .set_tcp_keepalive(socket.keepalive)
+            .set_connect_timeout(socket.connect_timeout)
+            .set_pool_idle_timeout(socket.pool_idle_timeout)
+            .set_pool_max_idle_per_host(socket.pool_max_idle_per_host);
+        self.current_identity = None;
+    }
+
     /// Updates the context from the request.
     /// This is useful for updating the success status codes, proxy, user agent, and compression settings.
-    pub fn update_from_request(&mut self, req: Request) -> Result<(), Box<dyn Error>> {
+    pub fn update_from_request(
+        &mut self,
+        req: Request,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
         self.http_requester.settings.set_proxy(req.proxy());
         self.http_requester
             .settings
             .set_user_agent(req.user_agent());
         self.http_requester
             .settings
-            .set_compression(req.is_compressed());
+            .set_compression(req.is_compressed())
+            .set_redirect_policy(req.redirect_policy().unwrap_or_default());
 
         self.status_codes = req.status_codes().clone();
 
         if let Ok(builder) = self.http_requester.build_reqwest(req.clone()) {
             self.request_builder = Some(builder);
         } else {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Unable to build request",
-            )));
+            return Err(Box::new(std::io::Error::other("Unable to build request")));
         }
 
-        self.request = req;
+        self.request = Arc::new(req);
 
         Ok(())
     }
 }
 
+/// The status, headers and url after redirects of a response.
+#[derive(Debug, Clone)]
+pub struct ResponseInfo {
+    status: u16,
+    headers: HeaderMap,
+    final_url: String,
+    version: Version,
+    redirects: Vec<RedirectHop>,
+}
+
+impl ResponseInfo {
+    pub fn new(status: u16, headers: HeaderMap, final_url: String) -> Self {
+        Self {
+            status,
+            headers,
+            final_url,
+            version: Version::HTTP_11,
+            redirects: vec![],
+        }
+    }
+
+    pub fn with_redirects(mut self, redirects: Vec<RedirectHop>) -> Self {
+        self.redirects = redirects;
+        self
+    }
+
+    /// Returns the redirects followed to get the response, in order.
+    pub fn redirects(&self) -> &[RedirectHop] {
+        &self.redirects
+    }
+
+    pub fn with_version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Returns the HTTP version the response was received over, HTTP/1.1 unless set.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Returns the first value of a header, if it is valid text.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    pub fn final_url(&self) -> &str {
+        &self.final_url
+    }
+
+    /// Returns the `Set-Cookie` headers of the response. The cookies are already in the jar.
+    pub fn set_cookies(&self) -> Vec<&str> {
+        self.headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect()
+    }
+
+    /// Returns the methods of the `Allow` header, e.g. of an `OPTIONS` or 405 response.
+    pub fn allow(&self) -> Vec<Method> {
+        crate::cors::allowed_methods(&self.headers)
+    }
+
+    /// Returns the `Access-Control-*` headers of the response.
+    pub fn cors(&self) -> Cors {
+        Cors::from_headers(&self.headers)
+    }
+}
+
+/// A cheap to clone, read-only copy of a `Context`, see `Context::snapshot`.
+#[derive(Debug, Clone)]
+pub struct ContextSnapshot {
+    request: Arc<Request>,
+    step: Option<String>,
+    variant: Option<String>,
+    body: Option<bytes::Bytes>,
+    response: Option<ResponseInfo>,
+    time_elapsed: u64,
+    identity: Option<String>,
+    coalesced: bool,
+}
+
+impl ContextSnapshot {
+    pub fn request(&self) -> &Request {
+        &self.request
+    }
+
+    pub fn step(&self) -> Option<&str> {
+        self.step.as_deref()
+    }
+
+    pub fn variant(&self) -> Option<&str> {
+        self.variant.as_deref()
+    }
+
+    pub fn url(&self) -> &str {
+        self.request.url()
+    }
+
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        self.request.tags()
+    }
+
+    pub fn body(&self) -> Option<&bytes::Bytes> {
+        self.body.as_ref()
+    }
+
+    /// Returns the body as text, borrowed from the body when it is valid UTF-8.
+    pub fn body_str(&self) -> Option<Cow<'_, str>> {
+        self.body.as_ref().map(|body| UTF_8.decode(body).0)
+    }
+
+    pub fn response(&self) -> Option<&ResponseInfo> {
+        self.response.as_ref()
+    }
+
+    pub fn time_elapsed(&self) -> u64 {
+        self.time_elapsed
+    }
+
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    pub fn is_coalesced(&self) -> bool {
+        self.coalesced
+    }
+}
+
+/// Strips tags, scripts and styles from an HTML document, leaving roughly what a user would read.
+#[cfg(feature = "language")]
+fn visible_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        text.push(' ');
+        rest = &rest[start..];
+
+        let starts_with = |tag: &str| {
+            rest.as_bytes()
+                .get(..tag.len())
+                .is_some_and(|start| start.eq_ignore_ascii_case(tag.as_bytes()))
+        };
+        let closing = if starts_with("<script") {
+            Some("</script>")
+        } else if starts_with("<style") {
+            Some("</style>")
+        } else {
+            None
+        };
+
+        // compared in place, lowercasing the rest of the page for every tag is quadratic
+        let end = match closing {
+            Some(tag) => rest
+                .as_bytes()
+                .windows(tag.len())
+                .position(|window| window.eq_ignore_ascii_case(tag.as_bytes()))
+                .map(|i| i + tag.len()),
+            None => rest.find('>').map(|i| i + 1),
+        };
+
+        match end {
+            Some(end) => rest = &rest[end..],
+            None => {
+                rest = "";
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -220,6 +1042,30 @@ mod tests {
         assert_eq!(err.to_string(), "No body has been set from the request.");
     }
 
+    #[test]
+    fn context_should_expose_the_response_info() {
+        let mut ctx = Context::new();
+        assert!(ctx.status().is_none());
+
+        let mut headers = HeaderMap::new();
+        headers.append(SET_COOKIE, "a=1".parse().unwrap());
+        headers.append(SET_COOKIE, "b=2; Path=/".parse().unwrap());
+        ctx.set_response_info(Some(ResponseInfo::new(
+            201,
+            headers,
+            "https://example.com/done".to_string(),
+        )));
+
+        assert_eq!(ctx.status(), Some(201));
+        assert_eq!(ctx.final_url(), Some("https://example.com/done"));
+        assert_eq!(ctx.headers().unwrap().len(), 2);
+        assert_eq!(
+            ctx.response_info().unwrap().set_cookies(),
+            vec!["a=1", "b=2; Path=/"]
+        );
+        assert_eq!(ctx.snapshot().response().unwrap().status(), 201);
+    }
+
     #[tokio::test]
     async fn context_body_json_should_mock_response_and_get_name() {
         let mut ctx = Context::new();
@@ -230,6 +1076,177 @@ mod tests {
         assert_eq!(json["name"], "test");
     }
 
+    #[test]
+    fn context_snapshot_should_share_the_request_and_body() {
+        let mut ctx = Context::new();
+        ctx.update_from_request(
+            Request::new(reqwest::Method::GET, "https://example.com/".to_string())
+                .with_tag("category", "shoes"),
+        )
+        .unwrap();
+        ctx.set_current_step("Search".to_string());
+        ctx.set_response_body(bytes::Bytes::from(vec![b'a'; 1024]));
+
+        let snapshot = ctx.snapshot();
+        let copy = snapshot.clone();
+        assert_eq!(copy.step(), Some("Search"));
+        assert_eq!(copy.url(), "https://example.com/");
+        assert_eq!(copy.tags()["category"], "shoes");
+        assert!(std::ptr::eq(snapshot.request(), copy.request()));
+        assert_eq!(
+            copy.body().unwrap().as_ptr(),
+            ctx.body_slice().unwrap().as_ptr()
+        );
+
+        ctx.set_response_body(bytes::Bytes::from_static(b"next"));
+        assert_eq!(copy.body_str().unwrap().len(), 1024);
+    }
+
+    #[test]
+    fn context_body_str_should_borrow_utf8_bodies() {
+        let mut ctx = Context::new();
+        ctx.set_response_body(bytes::Bytes::from_static("héllo".as_bytes()));
+
+        assert!(matches!(ctx.body_str().unwrap(), Cow::Borrowed("héllo")));
+        assert_eq!(ctx.body_slice().unwrap(), "héllo".as_bytes());
+        assert!(ctx.take_response_body().is_some());
+        assert!(ctx.body_slice().is_err());
+    }
+
+    #[test]
+    fn context_body_text_should_decode_the_response_charset() {
+        let mut ctx = Context::new();
+        let (body, _, _) = encoding_rs::SHIFT_JIS.encode("こんにちは");
+        ctx.set_response_body(bytes::Bytes::from(body.into_owned()));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "content-type",
+            "text/html; charset=Shift_JIS".parse().unwrap(),
+        );
+        ctx.set_response_info(Some(ResponseInfo::new(
+            200,
+            headers,
+            "https://example.jp/".to_string(),
+        )));
+
+        assert_eq!(ctx.body_encoding().unwrap(), encoding_rs::SHIFT_JIS);
+        assert_eq!(ctx.body_text().unwrap(), "こんにちは");
+    }
+
+    #[cfg(feature = "language")]
+    #[test]
+    fn context_should_detect_language_of_html_body() {
+        let mut ctx = Context::new();
+        let res = bytes::Bytes::from_static(
+            b"<html><head><style>body { color: red; }</style><script>var x = 1;</script></head>
+            <body><p>Der schnelle braune Fuchs springt \xc3\xbcber den faulen Hund und l\xc3\xa4uft dann weiter in den Wald.</p></body></html>",
+        );
+        ctx.set_response_body(res);
+
+        let info = ctx.detect_language().unwrap().unwrap();
+        assert_eq!(info.lang(), whatlang::Lang::Deu);
+    }
+
+    #[cfg(feature = "language")]
+    #[test]
+    fn visible_text_should_skip_scripts_and_styles_in_any_case() {
+        let html = "<P>Hallo</P><SCRIPT>var x;</Script><Style>p {}</STYLE>Welt<script>";
+        let text = visible_text(html);
+        assert_eq!(
+            text.split_whitespace().collect::<Vec<_>>(),
+            vec!["Hallo", "Welt"]
+        );
+    }
+
+    #[cfg(feature = "html")]
+    #[test]
+    fn context_should_select_elements_of_html_body() {
+        let mut ctx = Context::new();
+        ctx.set_response_body(bytes::Bytes::from_static(
+            b"<form><input name=\"csrf\" value=\"abc123\"><button>Log  in</button></form>",
+        ));
+
+        let csrf = ctx.select("input[name=csrf]").unwrap().unwrap();
+        assert_eq!(csrf.attr("value"), Some("abc123"));
+        assert_eq!(ctx.select_all("form > *").unwrap().len(), 2);
+        assert_eq!(ctx.select("button").unwrap().unwrap().text, "Log in");
+        assert!(ctx.select("table").unwrap().is_none());
+    }
+
+    #[cfg(feature = "html")]
+    #[test]
+    fn context_should_resolve_forms_against_the_response_url() {
+        let mut ctx = Context::new();
+        ctx.set_response_info(Some(ResponseInfo::new(
+            200,
+            HeaderMap::new(),
+            "https://example.com/account/login".to_string(),
+        )));
+        ctx.set_response_body(bytes::Bytes::from_static(
+            b"<form id=\"login\" method=\"post\" action=\"session\"><input type=\"hidden\" name=\"csrf\" value=\"abc\"></form>",
+        ));
+
+        let form = ctx.form("#login").unwrap().unwrap();
+        assert_eq!(form.action(), "https://example.com/account/session");
+        assert_eq!(form.field("csrf"), Some("abc"));
+        assert_eq!(ctx.forms().unwrap().len(), 1);
+        assert!(ctx.form("#signup").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn context_body_json_should_strip_xssi_prefixes_when_enabled() {
+        let mut ctx = Context::new();
+        ctx.set_response_body(bytes::Bytes::from_static(b")]}'\n{\"name\": \"mimicr\"}"));
+        assert!(ctx.body_json::<serde_json::Value>().await.is_err());
+
+        ctx.set_strip_xssi(true);
+        let json: serde_json::Value = ctx.body_json().await.unwrap();
+        assert_eq!(json["name"], "mimicr");
+
+        ctx.set_response_body(bytes::Bytes::from_static(b"while(1);[1, 2]"));
+        let json: Vec<u8> = ctx.body_json().await.unwrap();
+        assert_eq!(json, vec![1, 2]);
+    }
+
+    #[test]
+    fn context_should_extract_regex_captures_from_the_body() {
+        let mut ctx = Context::new();
+        assert!(ctx.extract("x").is_err());
+
+        let body = br#"<input name="csrf_token" value="t0k3n"><b>$12</b> <b>$30</b>"#;
+        ctx.set_response_body(bytes::Bytes::from_static(body));
+        let token = ctx
+            .extract(r#"csrf_token" value="(?P<token>[^"]+)""#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(token.name("token"), Some("t0k3n"));
+        assert!(ctx.extract("missing").unwrap().is_none());
+
+        let prices = ctx.extract_all(r"\$(\d+)").unwrap();
+        let prices: Vec<&str> = prices.iter().filter_map(|c| c.value()).collect();
+        assert_eq!(prices, vec!["12", "30"]);
+    }
+
+    #[test]
+    fn context_should_extract_json_values_by_path_and_pointer() {
+        let mut ctx = Context::new();
+        assert!(ctx.json_path("$.data").is_err());
+
+        let body = br#"{"data": {"items": [{"id": "a1"}, {"id": "b2"}], "next": null}}"#;
+        ctx.set_response_body(bytes::Bytes::from_static(body));
+        assert_eq!(
+            ctx.json_path("$.data.items[*].id").unwrap(),
+            vec![Value::from("a1"), Value::from("b2")]
+        );
+        assert!(ctx.json_path("data.items").is_err());
+        assert_eq!(
+            ctx.json_pointer("/data/items/1/id").unwrap(),
+            Some(Value::from("b2"))
+        );
+        assert_eq!(ctx.json_pointer("/data/next").unwrap(), Some(Value::Null));
+        assert_eq!(ctx.json_pointer("/data/missing").unwrap(), None);
+    }
+
     #[tokio::test]
     async fn context_body_json_should_return_error_if_invalid_json() {
         let mut ctx = Context::new();
@@ -0,0 +1,257 @@
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// The file formats cookies are exported to and imported from, see
+/// `PartitionedCookieStore::export_cookies`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CookieFormat {
+    /// The `cookies.txt` format of curl, wget and the browser extensions exporting it.
+    Netscape,
+    /// A JSON array in the format of the Cookie-Editor and EditThisCookie browser extensions.
+    Json,
+}
+
+/// A cookie as the file formats know it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CookieRecord {
+    pub name: String,
+    pub value: String,
+    /// The domain without its leading dot.
+    pub domain: String,
+    #[serde(default)]
+    pub host_only: bool,
+    #[serde(default = "root_path")]
+    pub path: String,
+    #[serde(default)]
+    pub secure: bool,
+    #[serde(default)]
+    pub http_only: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub same_site: Option<String>,
+    #[serde(default)]
+    pub session: bool,
+    /// When it expires, in seconds since the Unix epoch. Browsers export fractions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiration_date: Option<f64>,
+}
+
+fn root_path() -> String {
+    "/".to_string()
+}
+
+impl CookieRecord {
+    /// Returns the url the cookie is set from, and its `Set-Cookie` header. Expired cookies
+    /// return `None`.
+    pub fn to_set_cookie(&self) -> Option<(String, String)> {
+        let mut header = format!("{}={}; Path={}", self.name, self.value, self.path);
+        if !self.host_only {
+            header.push_str(&format!("; Domain={}", self.domain));
+        }
+        if let (false, Some(expires)) = (self.session, self.expiration_date) {
+            let max_age = expires as i64 - now();
+            if max_age <= 0 {
+                return None;
+            }
+            header.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if self.secure {
+            header.push_str("; Secure");
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        let same_site = match self.same_site.as_deref().map(str::to_ascii_lowercase) {
+            Some(same_site) if same_site == "lax" => Some("Lax"),
+            Some(same_site) if same_site == "strict" => Some("Strict"),
+            Some(same_site) if same_site == "none" || same_site == "no_restriction" => Some("None"),
+            _ => None,
+        };
+        if let Some(same_site) = same_site {
+            header.push_str(&format!("; SameSite={}", same_site));
+        }
+
+        let url = format!("https://{}{}", self.domain, self.path);
+        Some((url, header))
+    }
+}
+
+pub(crate) fn format_cookies(cookies: &[CookieRecord], format: CookieFormat) -> String {
+    match format {
+        CookieFormat::Json => serde_json::to_string_pretty(cookies).unwrap_or_default(),
+        CookieFormat::Netscape => {
+            let mut text = String::from("# Netscape HTTP Cookie File\n");
+            for cookie in cookies {
+                let domain = if cookie.host_only {
+                    cookie.domain.clone()
+                } else {
+                    format!(".{}", cookie.domain)
+                };
+                let line = [
+                    if cookie.http_only {
+                        format!("#HttpOnly_{}", domain)
+                    } else {
+                        domain
+                    },
+                    flag(!cookie.host_only),
+                    cookie.path.clone(),
+                    flag(cookie.secure),
+                    cookie.expiration_date.unwrap_or(0.0).to_string(),
+                    cookie.name.clone(),
+                    cookie.value.clone(),
+                ];
+                text.push_str(&line.join("\t"));
+                text.push('\n');
+            }
+            text
+        }
+    }
+}
+
+pub(crate) fn parse_cookies(
+    text: &str,
+    format: CookieFormat,
+) -> Result<Vec<CookieRecord>, Box<dyn Error + Send + Sync>> {
+    let mut cookies: Vec<CookieRecord> = match format {
+        CookieFormat::Json => serde_json::from_str(text)?,
+        CookieFormat::Netscape => text
+            .lines()
+            .enumerate()
+            .filter_map(|(i, line)| {
+                let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+                    Some(line) => (line, true),
+                    None => (line, false),
+                };
+                let line = line.trim_end_matches('\r');
+                if line.trim().is_empty() || line.starts_with('#') {
+                    None
+                } else {
+                    Some(parse_netscape_line(line, http_only, i + 1))
+                }
+            })
+            .collect::<Result<_, _>>()?,
+    };
+    for cookie in &mut cookies {
+        if let Some(domain) = cookie.domain.strip_prefix('.') {
+            cookie.domain = domain.to_string();
+        }
+    }
+    Ok(cookies)
+}
+
+fn parse_netscape_line(
+    line: &str,
+    http_only: bool,
+    number: usize,
+) -> Result<CookieRecord, Box<dyn Error + Send + Sync>> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let [domain, subdomains, path, secure, expires, name, value] = fields[..] else {
+        return Err(format!("line {} doesn't have 7 tab separated fields", number).into());
+    };
+    let expires: f64 = expires
+        .parse()
+        .map_err(|_| format!("line {} has an invalid expiry `{}`", number, expires))?;
+
+    Ok(CookieRecord {
+        name: name.to_string(),
+        value: value.to_string(),
+        domain: domain.to_string(),
+        host_only: !subdomains.eq_ignore_ascii_case("TRUE"),
+        path: path.to_string(),
+        secure: secure.eq_ignore_ascii_case("TRUE"),
+        http_only,
+        same_site: None,
+        session: expires == 0.0,
+        expiration_date: (expires != 0.0).then_some(expires),
+    })
+}
+
+fn flag(value: bool) -> String {
+    if value { "TRUE" } else { "FALSE" }.to_string()
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Parses the `2024-05-01T12:00:00Z` timestamps of the cookie store into seconds since the Unix
+/// epoch.
+pub(crate) fn parse_utc(text: &str) -> Option<i64> {
+    let text = text.strip_suffix('Z')?;
+    let (date, time) = text.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let mut time = time.splitn(3, ':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+
+    // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    Some(days * 86_400 + hour * 3600 + minute * 60 + second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOKIES_TXT: &str = "# Netscape HTTP Cookie File
+# https://curl.se/docs/http-cookies.html
+
+.example.com\tTRUE\t/\tTRUE\t4102444800\tsid\tabc123
+#HttpOnly_shop.example.com\tFALSE\t/cart\tFALSE\t0\tcart\t42
+";
+
+    #[test]
+    fn it_should_parse_netscape_cookies() {
+        let cookies = parse_cookies(COOKIES_TXT, CookieFormat::Netscape).unwrap();
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(cookies[0].domain, "example.com");
+        assert!(!cookies[0].host_only && cookies[0].secure);
+        assert_eq!(cookies[0].expiration_date, Some(4102444800.0));
+        assert!(cookies[1].host_only && cookies[1].http_only && cookies[1].session);
+        assert_eq!(cookies[1].path, "/cart");
+
+        let (url, header) = cookies[1].to_set_cookie().unwrap();
+        assert_eq!(url, "https://shop.example.com/cart");
+        assert_eq!(header, "cart=42; Path=/cart; HttpOnly");
+
+        let text = format_cookies(&cookies, CookieFormat::Netscape);
+        assert_eq!(
+            parse_cookies(&text, CookieFormat::Netscape).unwrap(),
+            cookies
+        );
+        assert!(parse_cookies("example.com\tTRUE\t/", CookieFormat::Netscape).is_err());
+    }
+
+    #[test]
+    fn it_should_parse_browser_json_cookies() {
+        let json = r#"[{"domain": ".example.com", "expirationDate": 4102444800.5, "hostOnly": false,
+            "httpOnly": true, "name": "sid", "path": "/", "sameSite": "no_restriction",
+            "secure": true, "session": false, "storeId": "0", "value": "abc123"}]"#;
+        let cookies = parse_cookies(json, CookieFormat::Json).unwrap();
+        let (_, header) = cookies[0].to_set_cookie().unwrap();
+        assert!(header.starts_with("sid=abc123; Path=/; Domain=example.com; Max-Age="));
+        assert!(header.ends_with("; Secure; HttpOnly; SameSite=None"));
+
+        let mut expired = cookies[0].clone();
+        expired.expiration_date = Some(1.0);
+        assert_eq!(expired.to_set_cookie(), None);
+    }
+
+    #[test]
+    fn it_should_parse_store_timestamps() {
+        assert_eq!(parse_utc("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_utc("2100-01-01T00:00:00Z"), Some(4102444800));
+        assert_eq!(parse_utc("2024-02-29T12:30:15Z"), Some(1709209815));
+        assert_eq!(parse_utc("yesterday"), None);
+    }
+}
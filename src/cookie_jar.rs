@@ -0,0 +1,599 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use reqwest::header::HeaderValue;
+use reqwest::Url;
+use reqwest_cookie_store::{CookieStore, RawCookie};
+use serde_json::Value;
+
+use crate::cookie_file::{format_cookies, parse_cookies, parse_utc, CookieRecord};
+use crate::CookieFormat;
+
+/// The partition, domain, path and name of a cookie.
+type CookieKey = (String, String, String, String);
+
+/// A cookie store partitioned by registrable domain (eTLD+1).
+/// Every site gets its own `CookieStore`, so a cookie can never be sent to a site other than the
+/// one that set it, even when a request is misconfigured.
+/// Expired cookies are pruned every minute as cookies are set, and the jar can be capped with
+/// `set_max_cookies`, evicting the least recently used cookies first.
+#[derive(Debug, Default)]
+pub struct PartitionedCookieStore {
+    partitions: Mutex<HashMap<String, CookieStore>>,
+    limits: Mutex<JarLimits>,
+    usage: Mutex<Usage>,
+}
+
+#[derive(Debug)]
+struct JarLimits {
+    max_cookies: Option<usize>,
+    max_per_partition: Option<usize>,
+    prune_interval: Option<Duration>,
+    last_pruned: Instant,
+}
+
+impl Default for JarLimits {
+    fn default() -> Self {
+        Self {
+            max_cookies: None,
+            max_per_partition: None,
+            prune_interval: Some(Duration::from_secs(60)),
+            last_pruned: Instant::now(),
+        }
+    }
+}
+
+/// When each cookie was last set or sent, as a counter rather than a time so ties can't happen.
+#[derive(Debug, Default)]
+struct Usage {
+    tick: u64,
+    last_used: HashMap<CookieKey, u64>,
+}
+
+impl Usage {
+    fn touch(&mut self, key: CookieKey) {
+        self.tick += 1;
+        self.last_used.insert(key, self.tick);
+    }
+}
+
+/// Returns the `CookieKey` of a stored cookie. A macro because `cookie_store::Cookie` isn't
+/// re-exported by `reqwest_cookie_store`.
+macro_rules! cookie_key {
+    ($partition:expr, $cookie:expr) => {
+        (
+            $partition.to_string(),
+            $cookie.domain.as_cow().unwrap_or_default().to_string(),
+            AsRef::<str>::as_ref(&$cookie.path).to_string(),
+            $cookie.name().to_string(),
+        )
+    };
+}
+
+impl PartitionedCookieStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the partition a host belongs to, e.g. `www.example.co.uk` -> `example.co.uk`.
+    /// IP addresses and hosts without a public suffix are their own partition.
+    pub fn partition_key(host: &str) -> String {
+        let host = host.trim_end_matches('.').to_lowercase();
+        if host.parse::<IpAddr>().is_ok() {
+            return host;
+        }
+
+        psl::domain_str(&host)
+            .map(|d| d.to_string())
+            .unwrap_or(host)
+    }
+
+    fn partition_for_url(url: &Url) -> Option<String> {
+        url.host_str().map(Self::partition_key)
+    }
+
+    /// Returns the partition keys that currently hold cookies.
+    pub fn partitions(&self) -> Vec<String> {
+        let partitions = self.partitions.lock().unwrap();
+        let mut keys: Vec<String> = partitions
+            .iter()
+            .filter(|(_, store)| store.iter_any().next().is_some())
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// Exports the unexpired persistent cookies of the partition `domain` belongs to as JSON lines.
+    pub fn export_partition(&self, domain: &str) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+        let partitions = self.partitions.lock().unwrap();
+        if let Some(store) = partitions.get(&Self::partition_key(domain)) {
+            store.save_json(&mut buffer).unwrap();
+        }
+        buffer
+    }
+
+    /// Exports the unexpired persistent cookies of every partition as JSON lines.
+    pub fn export_all(&self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+        let partitions = self.partitions.lock().unwrap();
+        let mut keys: Vec<&String> = partitions.keys().collect();
+        keys.sort();
+        for key in keys {
+            partitions[key].save_json(&mut buffer).unwrap();
+        }
+        buffer
+    }
+
+    /// Exports every cookie of the jar as JSON lines, including session cookies, to save the
+    /// session and restore it with `import_session`.
+    pub fn export_session(&self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+        let partitions = self.partitions.lock().unwrap();
+        let mut keys: Vec<&String> = partitions.keys().collect();
+        keys.sort();
+        for key in keys {
+            partitions[key]
+                .save_incl_expired_and_nonpersistent_json(&mut buffer)
+                .unwrap();
+        }
+        buffer
+    }
+
+    /// Replaces every partition with cookies exported by `export_session` or `export_all`.
+    /// Expired cookies are skipped. Returns the number of cookies imported.
+    pub fn import_session(&self, json: &[u8]) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let loaded = CookieStore::load_json(json).map_err(|err| err.to_string())?;
+        let mut grouped: HashMap<String, Vec<_>> = HashMap::new();
+        for cookie in loaded.iter_any() {
+            if let Some(domain) = cookie.domain.as_cow() {
+                let key = Self::partition_key(domain.trim_start_matches('.'));
+                grouped.entry(key).or_default().push(cookie.clone());
+            }
+        }
+        let count = grouped.values().map(Vec::len).sum();
+
+        let mut partitions = self.partitions.lock().unwrap();
+        partitions.clear();
+        for (key, cookies) in grouped {
+            let store = CookieStore::from_cookies(
+                cookies.into_iter().map(Ok::<_, std::convert::Infallible>),
+                false,
+            )
+            .unwrap();
+            partitions.insert(key, store);
+        }
+        self.usage.lock().unwrap().last_used.clear();
+
+        Ok(count)
+    }
+
+    /// Exports every unexpired cookie of the jar, session cookies included, e.g. to load them
+    /// into a browser or another tool.
+    pub fn export_cookies(&self, format: CookieFormat) -> String {
+        let partitions = self.partitions.lock().unwrap();
+        let mut keys: Vec<&String> = partitions.keys().collect();
+        keys.sort();
+        let records: Vec<CookieRecord> = keys
+            .into_iter()
+            .flat_map(|key| partitions[key].iter_unexpired())
+            .map(|cookie| {
+                // the domain and expiry types of the store aren't exported, but serialize
+                let domain = serde_json::to_value(&cookie.domain).unwrap_or_default();
+                let expires = serde_json::to_value(&cookie.expires).unwrap_or_default();
+                let expires = expires["AtUtc"].as_str().and_then(parse_utc);
+                CookieRecord {
+                    name: cookie.name().to_string(),
+                    value: cookie.value().to_string(),
+                    domain: cookie.domain.as_cow().unwrap_or_default().to_string(),
+                    host_only: matches!(domain, Value::Object(ref map) if map.contains_key("HostOnly")),
+                    path: AsRef::<str>::as_ref(&cookie.path).to_string(),
+                    secure: cookie.secure() == Some(true),
+                    http_only: cookie.http_only() == Some(true),
+                    same_site: cookie.same_site().map(|same_site| same_site.to_string()),
+                    session: expires.is_none(),
+                    expiration_date: expires.map(|expires| expires as f64),
+                }
+            })
+            .collect();
+        format_cookies(&records, format)
+    }
+
+    /// Adds cookies exported by a browser, curl or `export_cookies` to the jar, e.g. a session
+    /// harvested by hand. Expired cookies are skipped. Returns the number of cookies imported.
+    pub fn import_cookies(
+        &self,
+        text: &str,
+        format: CookieFormat,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        use reqwest::cookie::CookieStore as _;
+
+        let mut count = 0;
+        for record in parse_cookies(text, format)? {
+            let Some((url, header)) = record.to_set_cookie() else {
+                continue;
+            };
+            let url = Url::parse(&url).map_err(|err| format!("invalid cookie domain: {}", err))?;
+            let header = HeaderValue::from_str(&header)
+                .map_err(|_| format!("invalid value of cookie {}", record.name))?;
+            self.set_cookies(&mut [&header].into_iter(), &url);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Replaces the partition `domain` belongs to with cookies exported by `export_partition`.
+    /// Cookies that belong to another partition are ignored. Returns the number of cookies imported.
+    pub fn import_partition(
+        &self,
+        domain: &str,
+        json: &[u8],
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let key = Self::partition_key(domain);
+        let loaded = CookieStore::load_json(json).map_err(|err| err.to_string())?;
+
+        let cookies: Vec<_> = loaded
+            .iter_any()
+            .filter(|cookie| {
+                cookie
+                    .domain
+                    .as_cow()
+                    .map(|d| Self::partition_key(d.trim_start_matches('.')) == key)
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect();
+        let count = cookies.len();
+
+        let store = CookieStore::from_cookies(
+            cookies.into_iter().map(Ok::<_, std::convert::Infallible>),
+            false,
+        )
+        .unwrap();
+        self.partitions.lock().unwrap().insert(key, store);
+
+        Ok(count)
+    }
+
+    /// Removes every cookie of the partition `domain` belongs to.
+    pub fn clear_partition(&self, domain: &str) {
+        let key = Self::partition_key(domain);
+        self.partitions.lock().unwrap().remove(&key);
+        self.usage
+            .lock()
+            .unwrap()
+            .last_used
+            .retain(|(partition, _, _, _), _| partition != &key);
+    }
+
+    /// Caps the number of cookies in the jar. The least recently set or sent cookies are evicted
+    /// once a response sets more.
+    pub fn set_max_cookies(&self, max: Option<usize>) {
+        self.limits.lock().unwrap().max_cookies = max;
+    }
+
+    /// Caps the number of cookies of each site, like browsers do.
+    pub fn set_max_cookies_per_partition(&self, max: Option<usize>) {
+        self.limits.lock().unwrap().max_per_partition = max;
+    }
+
+    /// Sets how often expired cookies are pruned as cookies are set, every minute by default.
+    /// `None` only prunes on `prune_expired`.
+    pub fn set_prune_interval(&self, interval: Option<Duration>) {
+        self.limits.lock().unwrap().prune_interval = interval;
+    }
+
+    /// Returns the number of cookies in the jar, including expired ones not pruned yet.
+    pub fn len(&self) -> usize {
+        let partitions = self.partitions.lock().unwrap();
+        partitions
+            .values()
+            .map(|store| store.iter_any().count())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every expired cookie and returns how many were removed.
+    pub fn prune_expired(&self) -> usize {
+        let mut partitions = self.partitions.lock().unwrap();
+        let expired = expired_keys(&partitions);
+        self.limits.lock().unwrap().last_pruned = Instant::now();
+        self.remove_all(&mut partitions, &expired);
+        expired.len()
+    }
+
+    fn remove_all(&self, partitions: &mut HashMap<String, CookieStore>, keys: &[CookieKey]) {
+        let mut usage = self.usage.lock().unwrap();
+        for key in keys {
+            let (partition, domain, path, name) = key;
+            if let Some(store) = partitions.get_mut(partition) {
+                store.remove(domain, path, name);
+            }
+            usage.last_used.remove(key);
+        }
+        partitions.retain(|_, store| store.iter_any().next().is_some());
+    }
+
+    /// Prunes expired cookies when they are due, then evicts cookies over the caps.
+    fn enforce_limits(&self, partitions: &mut HashMap<String, CookieStore>) {
+        let (max_cookies, max_per_partition, prune) = {
+            let limits = self.limits.lock().unwrap();
+            let prune = limits
+                .prune_interval
+                .is_some_and(|interval| limits.last_pruned.elapsed() >= interval);
+            (limits.max_cookies, limits.max_per_partition, prune)
+        };
+
+        if prune {
+            let expired = expired_keys(partitions);
+            self.limits.lock().unwrap().last_pruned = Instant::now();
+            self.remove_all(partitions, &expired);
+        }
+
+        let mut evicted = vec![];
+        {
+            let usage = self.usage.lock().unwrap();
+            let last_used = |key: &CookieKey| usage.last_used.get(key).copied().unwrap_or(0);
+
+            let mut kept = vec![];
+            for (partition, store) in partitions.iter() {
+                let mut keys: Vec<CookieKey> = store
+                    .iter_any()
+                    .map(|cookie| cookie_key!(partition, cookie))
+                    .collect();
+                keys.sort_by_key(|key| std::cmp::Reverse(last_used(key)));
+                if let Some(max) = max_per_partition {
+                    if keys.len() > max {
+                        evicted.extend(keys.split_off(max));
+                    }
+                }
+                kept.extend(keys);
+            }
+
+            if let Some(max) = max_cookies {
+                if kept.len() > max {
+                    kept.sort_by_key(|key| std::cmp::Reverse(last_used(key)));
+                    evicted.extend(kept.split_off(max));
+                }
+            }
+        }
+        self.remove_all(partitions, &evicted);
+    }
+}
+
+fn expired_keys(partitions: &HashMap<String, CookieStore>) -> Vec<CookieKey> {
+    partitions
+        .iter()
+        .flat_map(|(partition, store)| {
+            store
+                .iter_any()
+                .filter(|cookie| cookie.is_expired())
+                .map(|cookie| cookie_key!(partition, cookie))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+impl reqwest::cookie::CookieStore for PartitionedCookieStore {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        let Some(key) = Self::partition_for_url(url) else {
+            return;
+        };
+
+        let cookies: Vec<RawCookie<'static>> = cookie_headers
+            .filter_map(|val| {
+                std::str::from_utf8(val.as_bytes())
+                    .ok()
+                    .and_then(|s| RawCookie::parse(s).ok())
+                    .map(|c| c.into_owned())
+            })
+            .collect();
+        let names: Vec<String> = cookies.iter().map(|c| c.name().to_string()).collect();
+
+        let mut partitions = self.partitions.lock().unwrap();
+        let store = partitions
+            .entry(key.clone())
+            .or_insert_with(|| CookieStore::new(None));
+        store.store_response_cookies(cookies.into_iter(), url);
+
+        {
+            let mut usage = self.usage.lock().unwrap();
+            for cookie in store.iter_any() {
+                if names.iter().any(|name| name == cookie.name()) && cookie.domain.matches(url) {
+                    usage.touch(cookie_key!(&key, cookie));
+                }
+            }
+        }
+        self.enforce_limits(&mut partitions);
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let key = Self::partition_for_url(url)?;
+        let partitions = self.partitions.lock().unwrap();
+        let store = partitions.get(&key)?;
+
+        let cookies = store.matches(url);
+        let s = cookies
+            .iter()
+            .map(|cookie| format!("{}={}", cookie.name(), cookie.value()))
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        let mut usage = self.usage.lock().unwrap();
+        for cookie in cookies {
+            usage.touch(cookie_key!(&key, cookie));
+        }
+
+        if s.is_empty() {
+            return None;
+        }
+
+        HeaderValue::from_maybe_shared(Bytes::from(s)).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::cookie::CookieStore as _;
+
+    use super::*;
+
+    fn set(store: &PartitionedCookieStore, url: &str, cookie: &'static str) {
+        let header = HeaderValue::from_static(cookie);
+        store.set_cookies(&mut [&header].into_iter(), &Url::parse(url).unwrap());
+    }
+
+    fn get(store: &PartitionedCookieStore, url: &str) -> Option<String> {
+        store
+            .cookies(&Url::parse(url).unwrap())
+            .map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn it_should_round_trip_cookie_files() {
+        let store = PartitionedCookieStore::new();
+        set(
+            &store,
+            "https://www.example.com/",
+            "sid=abc; Domain=example.com; Max-Age=3600",
+        );
+        set(
+            &store,
+            "https://shop.test.com/cart",
+            "cart=42; Path=/cart; HttpOnly",
+        );
+
+        for format in [CookieFormat::Netscape, CookieFormat::Json] {
+            let exported = store.export_cookies(format);
+            let restored = PartitionedCookieStore::new();
+            assert_eq!(restored.import_cookies(&exported, format).unwrap(), 2);
+            assert_eq!(
+                get(&restored, "https://api.example.com/").as_deref(),
+                Some("sid=abc")
+            );
+            assert_eq!(
+                get(&restored, "https://shop.test.com/cart/items").as_deref(),
+                Some("cart=42")
+            );
+            assert_eq!(get(&restored, "https://www.test.com/cart"), None);
+        }
+    }
+
+    #[test]
+    fn it_should_compute_partition_keys() {
+        assert_eq!(
+            PartitionedCookieStore::partition_key("www.example.co.uk"),
+            "example.co.uk"
+        );
+        assert_eq!(
+            PartitionedCookieStore::partition_key("API.Example.com."),
+            "example.com"
+        );
+        assert_eq!(
+            PartitionedCookieStore::partition_key("127.0.0.1"),
+            "127.0.0.1"
+        );
+    }
+
+    #[test]
+    fn it_should_share_cookies_within_a_partition_only() {
+        let store = PartitionedCookieStore::new();
+        set(
+            &store,
+            "https://login.example.com/",
+            "session=abc; Domain=example.com; Max-Age=3600",
+        );
+        set(&store, "https://other.org/", "tracking=1; Max-Age=3600");
+
+        assert_eq!(
+            get(&store, "https://shop.example.com/").unwrap(),
+            "session=abc"
+        );
+        assert_eq!(get(&store, "https://other.org/").unwrap(), "tracking=1");
+        assert!(get(&store, "https://example.net/").is_none());
+        assert_eq!(store.partitions(), vec!["example.com", "other.org"]);
+    }
+
+    #[test]
+    fn it_should_evict_the_least_recently_used_cookies() {
+        let store = PartitionedCookieStore::new();
+        store.set_max_cookies_per_partition(Some(2));
+        store.set_max_cookies(Some(3));
+
+        set(&store, "https://example.com/", "session=abc; Max-Age=3600");
+        set(
+            &store,
+            "https://example.com/",
+            "_ga=1; Path=/track; Max-Age=3600",
+        );
+        // sending the session makes the tracking cookie the least recently used one
+        get(&store, "https://example.com/");
+        set(&store, "https://example.com/", "_fbp=2; Max-Age=3600");
+
+        let sent = get(&store, "https://example.com/track").unwrap();
+        assert!(sent.contains("session=abc") && sent.contains("_fbp=2"));
+        assert!(!sent.contains("_ga"));
+
+        set(&store, "https://other.org/", "a=1; Max-Age=3600");
+        set(&store, "https://other.org/", "b=2; Max-Age=3600");
+        assert_eq!(store.len(), 3);
+        assert!(get(&store, "https://other.org/").unwrap().contains("b=2"));
+    }
+
+    #[test]
+    fn it_should_prune_expired_cookies() {
+        let store = PartitionedCookieStore::new();
+        set(&store, "https://example.com/", "session=abc; Max-Age=3600");
+        set(&store, "https://example.com/", "old=1; Max-Age=1");
+        set(&store, "https://other.org/", "gone=1; Max-Age=1");
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(store.len(), 3);
+
+        assert_eq!(store.prune_expired(), 2);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.partitions(), vec!["example.com"]);
+
+        store.set_prune_interval(Some(Duration::ZERO));
+        set(&store, "https://example.com/", "short=1; Max-Age=1");
+        std::thread::sleep(Duration::from_millis(1100));
+        set(&store, "https://example.com/", "fresh=1; Max-Age=3600");
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn it_should_export_and_import_a_single_partition() {
+        let store = PartitionedCookieStore::new();
+        set(&store, "https://example.com/", "session=abc; Max-Age=3600");
+        set(&store, "https://other.org/", "tracking=1; Max-Age=3600");
+
+        let exported = store.export_partition("www.example.com");
+        assert!(String::from_utf8_lossy(&exported).contains("session=abc"));
+        assert!(!String::from_utf8_lossy(&exported).contains("tracking"));
+
+        let restored = PartitionedCookieStore::new();
+        assert_eq!(
+            restored.import_partition("example.com", &exported).unwrap(),
+            1
+        );
+        assert_eq!(
+            restored.import_partition("other.org", &exported).unwrap(),
+            0,
+            "cookies from another partition are ignored"
+        );
+        assert_eq!(
+            get(&restored, "https://example.com/").unwrap(),
+            "session=abc"
+        );
+
+        restored.clear_partition("example.com");
+        assert!(get(&restored, "https://example.com/").is_none());
+    }
+}
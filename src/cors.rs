@@ -0,0 +1,146 @@
+use std::time::Duration;
+
+use reqwest::header::{
+    HeaderMap, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, ALLOW,
+};
+use reqwest::Method;
+
+/// The `Access-Control-*` headers of a response, e.g. of an `OPTIONS` preflight sent with
+/// `Context::probe_options`, telling which origins, methods and headers an API lets browsers
+/// use cross-origin.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cors {
+    /// The allowed origin, `*` for any.
+    pub allow_origin: Option<String>,
+    pub allow_credentials: bool,
+    pub allow_methods: Vec<Method>,
+    /// The allowed request headers, lowercased. `*` allows any.
+    pub allow_headers: Vec<String>,
+    /// The response headers scripts may read, lowercased.
+    pub expose_headers: Vec<String>,
+    /// How long browsers may cache the preflight.
+    pub max_age: Option<Duration>,
+}
+
+impl Cors {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let text = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        let names = |name| {
+            list(headers, name)
+                .into_iter()
+                .map(|value| value.to_ascii_lowercase())
+                .collect()
+        };
+        Self {
+            allow_origin: text(ACCESS_CONTROL_ALLOW_ORIGIN).map(|v| v.trim().to_string()),
+            allow_credentials: text(ACCESS_CONTROL_ALLOW_CREDENTIALS)
+                .is_some_and(|v| v.trim().eq_ignore_ascii_case("true")),
+            allow_methods: methods(headers, ACCESS_CONTROL_ALLOW_METHODS),
+            allow_headers: names(ACCESS_CONTROL_ALLOW_HEADERS),
+            expose_headers: names(ACCESS_CONTROL_EXPOSE_HEADERS),
+            max_age: text(ACCESS_CONTROL_MAX_AGE)
+                .and_then(|v| v.trim().parse().ok())
+                .map(Duration::from_secs),
+        }
+    }
+
+    /// Whether the response has any CORS headers at all.
+    pub fn is_enabled(&self) -> bool {
+        self.allow_origin.is_some()
+    }
+
+    /// Whether scripts of the origin, e.g. `https://app.example`, may read the response. A `*`
+    /// doesn't cover credentialed requests, like browsers.
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        match self.allow_origin.as_deref() {
+            Some("*") => !self.allow_credentials,
+            Some(allowed) => allowed.eq_ignore_ascii_case(origin),
+            None => false,
+        }
+    }
+
+    /// Whether the method may be sent cross-origin. GET, HEAD and POST always may.
+    pub fn allows_method(&self, method: &Method) -> bool {
+        matches!(*method, Method::GET | Method::HEAD | Method::POST)
+            || self
+                .allow_methods
+                .iter()
+                .any(|m| m == method || m.as_str() == "*")
+    }
+
+    pub fn allows_header(&self, name: &str) -> bool {
+        self.allow_headers
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Returns the methods listed in a header like `Allow: GET, HEAD, PROPFIND`, keeping custom
+/// ones and skipping invalid tokens.
+fn methods(headers: &HeaderMap, name: impl reqwest::header::AsHeaderName) -> Vec<Method> {
+    list(headers, name)
+        .into_iter()
+        .filter_map(|token| Method::from_bytes(token.as_bytes()).ok())
+        .collect()
+}
+
+/// Returns the `Allow` methods of a response, e.g. to an `OPTIONS` request.
+pub(crate) fn allowed_methods(headers: &HeaderMap) -> Vec<Method> {
+    methods(headers, ALLOW)
+}
+
+/// Splits the comma separated values of every occurrence of a header.
+fn list(headers: &HeaderMap, name: impl reqwest::header::AsHeaderName) -> Vec<String> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_read_the_cors_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ALLOW, "GET, HEAD, OPTIONS, PROPFIND".parse().unwrap());
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_ORIGIN,
+            "https://app.example".parse().unwrap(),
+        );
+        headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, "true".parse().unwrap());
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, "PUT,DELETE".parse().unwrap());
+        headers.append(
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            "Content-Type".parse().unwrap(),
+        );
+        headers.append(ACCESS_CONTROL_ALLOW_HEADERS, "X-Api-Key".parse().unwrap());
+        headers.insert(ACCESS_CONTROL_MAX_AGE, "600".parse().unwrap());
+
+        let allowed = allowed_methods(&headers);
+        assert_eq!(allowed.len(), 4);
+        assert_eq!(allowed[3].as_str(), "PROPFIND");
+
+        let cors = Cors::from_headers(&headers);
+        assert!(cors.is_enabled());
+        assert!(cors.allows_origin("https://app.example"));
+        assert!(!cors.allows_origin("https://evil.example"));
+        assert!(cors.allows_method(&Method::DELETE));
+        assert!(!cors.allows_method(&Method::PATCH));
+        assert!(cors.allows_header("x-api-key"));
+        assert_eq!(cors.max_age, Some(Duration::from_secs(600)));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, "*".parse().unwrap());
+        assert!(Cors::from_headers(&headers).allows_origin("https://any.example"));
+        assert!(!Cors::from_headers(&HeaderMap::new()).is_enabled());
+    }
+}
@@ -0,0 +1,314 @@
+use std::error::Error;
+use std::fmt;
+
+use encoding_rs::{Encoding, UTF_8};
+use serde::de::DeserializeOwned;
+
+/// The MIME type of a `data:` URL which doesn't name one.
+const DEFAULT_MIME: &str = "text/plain";
+
+/// Why a `data:` URL or base64 blob couldn't be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataUrlError {
+    pub reason: String,
+}
+
+impl DataUrlError {
+    fn new(reason: impl Into<String>) -> Self {
+        Self {
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for DataUrlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid data url: {}", self.reason)
+    }
+}
+
+impl Error for DataUrlError {}
+
+/// The payload of a `data:` URL or of a base64 blob found in a page, e.g. an inline image or the
+/// JSON a challenge page embeds, with its MIME type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataUrl {
+    mime: String,
+    charset: Option<String>,
+    data: bytes::Bytes,
+}
+
+impl DataUrl {
+    /// Parses a `data:[<mime>][;charset=<charset>][;base64],<data>` URL. The data is
+    /// percent-decoded unless it's base64.
+    pub fn parse(url: &str) -> Result<Self, DataUrlError> {
+        let url = url.trim();
+        let rest = url
+            .get(..5)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("data:"))
+            .map(|_| &url[5..])
+            .ok_or_else(|| DataUrlError::new("missing the data: scheme"))?;
+        let (header, payload) = rest
+            .split_once(',')
+            .ok_or_else(|| DataUrlError::new("missing the `,` before the data"))?;
+
+        let mut params = header.split(';').map(str::trim);
+        let mime = params.next().unwrap_or_default().to_ascii_lowercase();
+        let mut charset = None;
+        let mut base64 = false;
+        for param in params {
+            if param.eq_ignore_ascii_case("base64") {
+                base64 = true;
+            } else if let Some((name, value)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("charset") {
+                    charset = Some(value.trim().trim_matches('"').to_string());
+                }
+            }
+        }
+
+        let payload = percent_decode(payload);
+        let data = if base64 {
+            decode_base64(&String::from_utf8_lossy(&payload))?
+        } else {
+            payload
+        };
+        let charset = if mime.is_empty() && charset.is_none() {
+            Some("US-ASCII".to_string())
+        } else {
+            charset
+        };
+        Ok(Self {
+            mime: if mime.is_empty() {
+                DEFAULT_MIME.to_string()
+            } else {
+                mime
+            },
+            charset,
+            data: data.into(),
+        })
+    }
+
+    /// Decodes a bare base64 blob, e.g. the argument of an `atob` call, guessing its MIME type
+    /// from its first bytes. Unknown payloads are `application/octet-stream`.
+    pub fn from_base64(text: &str) -> Result<Self, DataUrlError> {
+        let data = decode_base64(text)?;
+        Ok(Self {
+            mime: sniff_mime(&data).to_string(),
+            charset: None,
+            data: data.into(),
+        })
+    }
+
+    /// Returns the MIME type, without its parameters.
+    pub fn mime(&self) -> &str {
+        &self.mime
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.charset.as_deref()
+    }
+
+    pub fn data(&self) -> &bytes::Bytes {
+        &self.data
+    }
+
+    pub fn into_data(self) -> bytes::Bytes {
+        self.data
+    }
+
+    pub fn is_image(&self) -> bool {
+        self.mime.starts_with("image/")
+    }
+
+    /// Decodes the data as text with its charset, UTF-8 if it has none or an unknown one.
+    pub fn text(&self) -> String {
+        let encoding = self
+            .charset
+            .as_deref()
+            .and_then(|charset| Encoding::for_label(charset.as_bytes()))
+            .unwrap_or(UTF_8);
+        encoding.decode(&self.data).0.into_owned()
+    }
+
+    /// Deserializes the data as JSON.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, Box<dyn Error + Send + Sync>> {
+        Ok(serde_json::from_slice(&self.data)?)
+    }
+}
+
+/// Finds and decodes every `data:` URL in a page, e.g. the `src` of inline images and the
+/// `url(...)` of inline stylesheets. URLs that can't be decoded are skipped.
+pub fn find_data_urls(text: &str) -> Vec<DataUrl> {
+    let lower = text.to_ascii_lowercase();
+    let mut urls = Vec::new();
+    let mut from = 0;
+    while let Some(found) = lower[from..].find("data:") {
+        let start = from + found;
+        let len = text[start..]
+            .find(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | ')' | '<' | '>'))
+            .unwrap_or(text.len() - start);
+        if let Ok(url) = DataUrl::parse(&text[start..start + len]) {
+            urls.push(url);
+        }
+        from = start + len.max(5);
+    }
+    urls
+}
+
+/// Decodes standard or URL-safe base64, with or without padding. Whitespace, e.g. line breaks
+/// of wrapped blobs, is ignored.
+pub fn decode_base64(text: &str) -> Result<Vec<u8>, DataUrlError> {
+    let mut data = Vec::with_capacity(text.len() * 3 / 4);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    let mut padding = 0;
+    for byte in text.bytes().filter(|b| !b.is_ascii_whitespace()) {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => {
+                padding += 1;
+                continue;
+            }
+            _ => {
+                let reason = format!("`{}` isn't a base64 character", byte as char);
+                return Err(DataUrlError::new(reason));
+            }
+        };
+        if padding > 0 {
+            return Err(DataUrlError::new("data after the base64 padding"));
+        }
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            data.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    // a single character left over can't encode a byte
+    if bits >= 6 {
+        return Err(DataUrlError::new("truncated base64"));
+    }
+    Ok(data)
+}
+
+/// Encodes data as padded standard base64.
+pub(crate) fn encode_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let buffer = chunk.iter().enumerate().fold(0u32, |buffer, (i, byte)| {
+            buffer | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(buffer >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn percent_decode(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// Guesses the MIME type of a payload from its magic bytes.
+fn sniff_mime(data: &[u8]) -> &'static str {
+    let first = data.iter().find(|b| !b.is_ascii_whitespace());
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [0xff, 0xd8, 0xff, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        [b'%', b'P', b'D', b'F', ..] => "application/pdf",
+        [0x1f, 0x8b, ..] => "application/gzip",
+        _ if matches!(first, Some(b'{' | b'['))
+            && serde_json::from_slice::<serde_json::Value>(data).is_ok() =>
+        {
+            "application/json"
+        }
+        _ if std::str::from_utf8(data).is_ok() => DEFAULT_MIME,
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_decode_data_urls() {
+        let png = DataUrl::parse("data:image/png;base64,iVBORw0KGgo=").unwrap();
+        assert_eq!(png.mime(), "image/png");
+        assert!(png.is_image());
+        assert_eq!(&png.data()[..4], b"\x89PNG");
+
+        let text = DataUrl::parse("data:,Hello%2C%20World%21").unwrap();
+        assert_eq!(text.mime(), "text/plain");
+        assert_eq!(text.charset(), Some("US-ASCII"));
+        assert_eq!(text.text(), "Hello, World!");
+
+        let latin = DataUrl::parse("DATA:text/plain;charset=ISO-8859-1,caf%E9").unwrap();
+        assert_eq!(latin.text(), "café");
+
+        assert!(DataUrl::parse("https://example.com/").is_err());
+        assert!(DataUrl::parse("data:text/plain").is_err());
+        assert!(DataUrl::parse("data:;base64,a!b").is_err());
+    }
+
+    #[test]
+    fn it_should_decode_base64_blobs() {
+        assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(decode_base64("aGVsbG8").unwrap(), b"hello");
+        assert_eq!(decode_base64("aGVs\nbG8=").unwrap(), b"hello");
+        assert_eq!(decode_base64("-_8=").unwrap(), vec![0xfb, 0xff]);
+        assert!(decode_base64("aGVsbG8=x").is_err());
+        assert!(decode_base64("a").is_err());
+        for data in [&b""[..], b"h", b"he", b"hel", b"hello"] {
+            assert_eq!(decode_base64(&encode_base64(data)).unwrap(), data);
+        }
+        assert_eq!(encode_base64(b"hello"), "aGVsbG8=");
+
+        let json = DataUrl::from_base64("eyJ0b2tlbiI6ImFiYyJ9").unwrap();
+        assert_eq!(json.mime(), "application/json");
+        let value: serde_json::Value = json.json().unwrap();
+        assert_eq!(value["token"], "abc");
+    }
+
+    #[test]
+    fn it_should_find_the_data_urls_of_a_page() {
+        let page = r#"<img src="data:image/gif;base64,R0lGODlhAQABAAAAACw=">
+            <div style="background: url(data:text/plain,bg)"></div>
+            <a href='data:broken'>"#;
+        let urls = find_data_urls(page);
+        assert_eq!(urls.len(), 2);
+        assert_eq!(urls[0].mime(), "image/gif");
+        assert_eq!(urls[1].text(), "bg");
+    }
+}
@@ -0,0 +1,88 @@
+/// A pattern of hosts to scope middleware and profiles to, see `Worker::before_request_for`:
+/// `example.com` for that host only, `*.example.com` for its subdomains at any depth, or `*`
+/// for every host. Hosts are compared case-insensitively.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DomainPattern {
+    pattern: String,
+}
+
+impl DomainPattern {
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.trim().trim_end_matches('.').to_ascii_lowercase(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        if self.pattern == "*" {
+            return true;
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        match self.pattern.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => host == self.pattern,
+        }
+    }
+
+    /// Ranks overlapping patterns: more labels first, and a host before its subdomains.
+    fn specificity(&self) -> usize {
+        if self.pattern == "*" {
+            return 0;
+        }
+        match self.pattern.strip_prefix("*.") {
+            Some(domain) => domain.split('.').count() * 2,
+            None => self.pattern.split('.').count() * 2 + 1,
+        }
+    }
+}
+
+impl From<&str> for DomainPattern {
+    fn from(pattern: &str) -> Self {
+        Self::new(pattern)
+    }
+}
+
+/// Returns the value of the most specific pattern matching the host.
+pub(crate) fn most_specific<'a, T>(scoped: &'a [(DomainPattern, T)], host: &str) -> Option<&'a T> {
+    scoped
+        .iter()
+        .filter(|(pattern, _)| pattern.matches(host))
+        .max_by_key(|(pattern, _)| pattern.specificity())
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_match_hosts_by_pattern() {
+        let subdomains = DomainPattern::new("*.Example.com");
+        assert!(subdomains.matches("api.example.com"));
+        assert!(subdomains.matches("a.b.EXAMPLE.com."));
+        assert!(!subdomains.matches("example.com"));
+        assert!(!subdomains.matches("badexample.com"));
+        assert!(DomainPattern::new("example.com").matches("example.com"));
+        assert!(!DomainPattern::new("example.com").matches("www.example.com"));
+        assert!(DomainPattern::new("*").matches("anything.test"));
+
+        let scoped = vec![
+            (DomainPattern::new("*"), "any"),
+            (DomainPattern::new("*.example.com"), "subdomain"),
+            (DomainPattern::new("api.example.com"), "api"),
+        ];
+        assert_eq!(most_specific(&scoped, "api.example.com"), Some(&"api"));
+        assert_eq!(
+            most_specific(&scoped, "www.example.com"),
+            Some(&"subdomain")
+        );
+        assert_eq!(most_specific(&scoped, "other.test"), Some(&"any"));
+        assert_eq!(most_specific(&scoped[1..], "other.test"), None);
+    }
+}
@@ -0,0 +1,428 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use reqwest::header::{
+    ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE, LAST_MODIFIED,
+    RANGE,
+};
+use reqwest::{RequestBuilder, StatusCode};
+use tokio::io::AsyncWriteExt;
+
+use crate::redirect::{self, RedirectPolicy};
+use crate::singleflight::{SharedError, SharedResponse, SharedResult};
+use crate::{HostGuard, ResponseInfo, StepError};
+
+/// Called after every chunk written to the file.
+pub type ProgressFn = dyn Fn(&DownloadProgress) + Send + Sync;
+
+/// Streams a successful response body to a file instead of into the context. Attach it with
+/// `Request::with_download`. Error responses are still read into the context, so error handlers
+/// can see them.
+#[derive(Clone)]
+pub struct Download {
+    path: PathBuf,
+    resume: bool,
+    progress: Option<Arc<ProgressFn>>,
+    /// The `ETag` or `Last-Modified` of the file, shared by the clones of the download.
+    validator: Arc<Mutex<Option<String>>>,
+}
+
+impl std::fmt::Debug for Download {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Download")
+            .field("path", &self.path)
+            .field("resume", &self.resume)
+            .field("progress", &self.progress.is_some())
+            .field("validator", &self.validator())
+            .finish()
+    }
+}
+
+/// How much of a download has been written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadProgress {
+    /// The size of the file so far, including a resumed part.
+    pub downloaded: u64,
+    /// The size of the whole file, if the server sent a length.
+    pub total: Option<u64>,
+}
+
+/// The outcome of a download, see `Context::download_report`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadReport {
+    pub path: PathBuf,
+    /// The bytes received by this request.
+    pub bytes: u64,
+    /// The size of the partial file the download resumed from, 0 if it started over.
+    pub resumed_from: u64,
+    pub elapsed_ms: u64,
+    pub bytes_per_sec: u64,
+}
+
+impl Download {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            resume: false,
+            progress: None,
+            validator: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Continues a partial file with a `Range` request, including on retries. Servers that
+    /// ignore the range answer with the whole body, which replaces the file. The range is sent
+    /// with an `If-Range` of the file's validator, so a file that changed is downloaded again.
+    pub fn resumable(mut self) -> Self {
+        self.resume = true;
+        self
+    }
+
+    /// Sets the `ETag` or `Last-Modified` of a partial file from an earlier run, see `validator`.
+    pub fn with_validator(self, validator: &str) -> Self {
+        *self.validator.lock().unwrap() = Some(validator.to_string());
+        self
+    }
+
+    /// Returns the strong `ETag`, or else the `Last-Modified`, of the last response streamed to
+    /// the file, to resume it in a later run.
+    pub fn validator(&self) -> Option<String> {
+        self.validator.lock().unwrap().clone()
+    }
+
+    pub fn with_progress(
+        mut self,
+        progress: impl Fn(&DownloadProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_resumable(&self) -> bool {
+        self.resume
+    }
+}
+
+/// What a `HEAD` or range probe tells about a file before downloading it, see
+/// `Context::preflight_head`, e.g. to download large files in parts or skip unchanged ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preflight {
+    pub status: u16,
+    /// The size of the whole file, if the server told.
+    pub content_length: Option<u64>,
+    /// Whether the server answers `Range` requests, so downloads can be resumed or split.
+    pub accepts_ranges: bool,
+    pub last_modified: Option<String>,
+    pub etag: Option<String>,
+    pub content_type: Option<String>,
+    pub final_url: String,
+}
+
+impl Preflight {
+    /// Reads the answer to a `HEAD` request.
+    pub(crate) fn from_head(info: &ResponseInfo) -> Self {
+        let accepts_ranges = info
+            .header(ACCEPT_RANGES.as_str())
+            .is_some_and(|ranges| ranges.trim().eq_ignore_ascii_case("bytes"));
+        Self {
+            content_length: info
+                .header(CONTENT_LENGTH.as_str())
+                .and_then(|length| length.trim().parse().ok()),
+            accepts_ranges,
+            ..Self::from_info(info)
+        }
+    }
+
+    /// Reads the answer to a `Range: bytes=0-0` request: a `206` with the size in its
+    /// `Content-Range`, or a `200` from a server ignoring ranges.
+    pub(crate) fn from_range_probe(info: &ResponseInfo) -> Self {
+        if info.status() != StatusCode::PARTIAL_CONTENT.as_u16() {
+            return Self {
+                accepts_ranges: false,
+                ..Self::from_head(info)
+            };
+        }
+        // `bytes 0-0/1234`, or `bytes 0-0/*` for an unknown size
+        let content_length = info
+            .header(CONTENT_RANGE.as_str())
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, total)| total.trim().parse().ok());
+        Self {
+            content_length,
+            accepts_ranges: true,
+            ..Self::from_info(info)
+        }
+    }
+
+    fn from_info(info: &ResponseInfo) -> Self {
+        let header = |name: &str| info.header(name).map(str::to_string);
+        Self {
+            status: info.status(),
+            content_length: None,
+            accepts_ranges: false,
+            last_modified: header(LAST_MODIFIED.as_str()),
+            etag: header(ETAG.as_str()),
+            content_type: header(CONTENT_TYPE.as_str()),
+            final_url: info.final_url().to_string(),
+        }
+    }
+
+    /// Whether a download could be resumed or split into parts.
+    pub fn supports_ranges(&self) -> bool {
+        self.accepts_ranges && self.content_length.is_some()
+    }
+
+    /// Returns the `Range` headers splitting the file into parts of at most `size` bytes, to
+    /// download them in parallel. Empty if the server doesn't answer ranges.
+    pub fn ranges(&self, size: u64) -> Vec<String> {
+        let (Some(length), true) = (self.content_length, self.accepts_ranges) else {
+            return vec![];
+        };
+        let size = size.max(1);
+        (0..length)
+            .step_by(size as usize)
+            .map(|start| format!("bytes={}-{}", start, (start + size).min(length) - 1))
+            .collect()
+    }
+}
+
+/// Sends the request and streams a successful body to the download's file. Redirects are
+/// followed with the policy, and checked by the guard, like `HttpRequester::execute` does.
+pub(crate) async fn download(
+    req_builder: RequestBuilder,
+    download: &Download,
+    policy: RedirectPolicy,
+    guard: Option<&HostGuard>,
+) -> (SharedResult, Option<DownloadReport>) {
+    let offset = if download.resume {
+        tokio::fs::metadata(&download.path)
+            .await
+            .map_or(0, |meta| meta.len())
+    } else {
+        0
+    };
+    let req_builder = match (offset, download.validator()) {
+        (0, _) => req_builder,
+        (offset, validator) => {
+            let req_builder = req_builder.header(RANGE, format!("bytes={}-", offset));
+            match validator {
+                Some(validator) => req_builder.header(IF_RANGE, validator),
+                None => req_builder,
+            }
+        }
+    };
+
+    let stop_watch = std::time::Instant::now();
+    let (client, req) = req_builder.build_split();
+    let mut req = match req {
+        Ok(req) => req,
+        Err(err) => return (Err(SharedError::from_reqwest(&err)), None),
+    };
+    let mut hops = vec![];
+    let (mut res, info) = loop {
+        // the range is kept on every hop, like browsers do
+        let next = req.try_clone();
+        let res = match client.execute(req).await {
+            Ok(res) => res,
+            Err(err) => return (Err(SharedError::from_reqwest(&err)), None),
+        };
+        let info = ResponseInfo::new(
+            res.status().as_u16(),
+            res.headers().clone(),
+            res.url().to_string(),
+        )
+        .with_version(res.version());
+        match redirect::next_request(policy, guard, &mut hops, &info, next).await {
+            Ok(Some(next)) => req = next,
+            Ok(None) => break (res, info.with_redirects(hops)),
+            Err(error) => {
+                let err = SharedError {
+                    error,
+                    partial: None,
+                    connecting: false,
+                };
+                let info = info.with_redirects(hops);
+                return (Err(err.with_partial(info, Default::default())), None);
+            }
+        }
+    };
+
+    if res.status() == StatusCode::RANGE_NOT_SATISFIABLE && offset > 0 {
+        // `bytes */1234`: the range starts past the end of a file that is already whole
+        let length = info
+            .header(CONTENT_RANGE.as_str())
+            .and_then(|range| range.strip_prefix("bytes */"))
+            .and_then(|length| length.trim().parse::<u64>().ok());
+        if length == Some(offset) {
+            let info = ResponseInfo::new(
+                StatusCode::PARTIAL_CONTENT.as_u16(),
+                info.headers().clone(),
+                info.final_url().to_string(),
+            )
+            .with_version(info.version())
+            .with_redirects(info.redirects().to_vec());
+            let report = DownloadReport {
+                path: download.path.clone(),
+                bytes: 0,
+                resumed_from: offset,
+                elapsed_ms: stop_watch.elapsed().as_millis() as u64,
+                bytes_per_sec: 0,
+            };
+            let res = SharedResponse {
+                info,
+                body: Default::default(),
+            };
+            return (Ok(res), Some(report));
+        }
+    }
+
+    if !res.status().is_success() {
+        let body = match res.bytes().await {
+            Ok(body) => body,
+            Err(err) => return (Err(SharedError::from_reqwest(&err)), None),
+        };
+        return (Ok(SharedResponse { info, body }), None);
+    }
+
+    let resumed_from = match res.status() {
+        StatusCode::PARTIAL_CONTENT => offset,
+        _ => 0,
+    };
+    let mut file = match open(&download.path, resumed_from > 0).await {
+        Ok(file) => file,
+        Err(err) => return (Err(io_error(&download.path, err)), None),
+    };
+    let etag = info
+        .header(ETAG.as_str())
+        .filter(|etag| !etag.starts_with("W/"));
+    if let Some(validator) = etag.or_else(|| info.header(LAST_MODIFIED.as_str())) {
+        *download.validator.lock().unwrap() = Some(validator.to_string());
+    }
+
+    let total = res.content_length().map(|length| resumed_from + length);
+    let mut bytes = 0;
+    loop {
+        match res.chunk().await {
+            Ok(Some(chunk)) => {
+                if let Err(err) = file.write_all(&chunk).await {
+                    return (Err(io_error(&download.path, err)), None);
+                }
+                bytes += chunk.len() as u64;
+                if let Some(progress) = &download.progress {
+                    progress(&DownloadProgress {
+                        downloaded: resumed_from + bytes,
+                        total,
+                    });
+                }
+            }
+            Ok(None) => break,
+            Err(err) => {
+                // the bytes written so far are kept, so a resumable download can continue
+                let _ = file.flush().await;
+                let error = SharedError::from_reqwest(&err).with_partial(info, Default::default());
+                return (Err(error), None);
+            }
+        }
+    }
+    if let Err(err) = file.flush().await {
+        return (Err(io_error(&download.path, err)), None);
+    }
+
+    let elapsed = stop_watch.elapsed();
+    let report = DownloadReport {
+        path: download.path.clone(),
+        bytes,
+        resumed_from,
+        elapsed_ms: elapsed.as_millis() as u64,
+        bytes_per_sec: (bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64,
+    };
+    let res = SharedResponse {
+        info,
+        body: Default::default(),
+    };
+    (Ok(res), Some(report))
+}
+
+async fn open(path: &Path, append: bool) -> std::io::Result<tokio::fs::File> {
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .await
+}
+
+fn io_error(path: &Path, err: std::io::Error) -> SharedError {
+    SharedError {
+        error: StepError::Download(format!("{}: {}", path.display(), err)),
+        partial: None,
+        connecting: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_server::{TestResponse, TestServer};
+    use crate::{Context, StepError};
+
+    #[tokio::test]
+    async fn it_should_preflight_files_before_downloading_them() {
+        let server = TestServer::start(|req| match (req.method.as_str(), req.path.as_str()) {
+            ("HEAD", "/video.mp4") => TestResponse::ok("")
+                .with_header("Content-Length", "2500")
+                .with_header("Accept-Ranges", "bytes")
+                .with_header("Last-Modified", "Wed, 01 May 2024 12:00:00 GMT"),
+            ("HEAD", _) => TestResponse::status(405, ""),
+            (_, "/archive.zip") => TestResponse::status(206, "P")
+                .with_header("Content-Range", "bytes 0-0/1200")
+                .with_header("ETag", "\"v2\""),
+            _ => TestResponse::ok("the whole file"),
+        })
+        .await;
+        let ctx = Context::new();
+
+        let video = ctx.preflight_head(&server.url("/video.mp4")).await.unwrap();
+        assert_eq!(video.content_length, Some(2500));
+        assert!(video.supports_ranges());
+        assert_eq!(
+            video.last_modified.as_deref(),
+            Some("Wed, 01 May 2024 12:00:00 GMT")
+        );
+        assert_eq!(
+            video.ranges(1000),
+            vec!["bytes=0-999", "bytes=1000-1999", "bytes=2000-2499"]
+        );
+
+        // HEAD is refused, so the range probe tells
+        let archive = ctx
+            .preflight_head(&server.url("/archive.zip"))
+            .await
+            .unwrap();
+        assert_eq!((archive.status, archive.content_length), (206, Some(1200)));
+        assert!(archive.supports_ranges());
+        assert_eq!(archive.etag.as_deref(), Some("\"v2\""));
+
+        let page = ctx.probe_range(&server.url("/page")).await.unwrap();
+        assert!(!page.accepts_ranges && page.ranges(10).is_empty());
+        assert_eq!(page.content_length, Some(14));
+    }
+
+    #[tokio::test]
+    async fn it_should_not_preflight_hosts_the_guard_blocks() {
+        let server = TestServer::start(|_| TestResponse::ok("")).await;
+        let mut ctx = Context::new();
+        ctx.get_client_settings_mut()
+            .set_host_guard(Some(std::sync::Arc::new(crate::HostGuard::new())));
+
+        let url = server.url("/video.mp4");
+        let head = ctx.preflight_head(&url).await.unwrap_err();
+        assert!(matches!(head, StepError::BlockedHost(_)), "{}", head);
+        let probe = ctx.probe_range(&url).await.unwrap_err();
+        assert!(matches!(probe, StepError::BlockedHost(_)), "{}", probe);
+        assert_eq!(server.hits(), 0);
+    }
+}
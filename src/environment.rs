@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{RateLimit, RateLimiter, Request};
+
+/// Set this environment variable to pick the overlay used by `EnvironmentOverlays::select_from_env`.
+pub const ENVIRONMENT_ENV: &str = "MIMICR_ENV";
+
+/// The settings a flow runs with in one environment, e.g. staging or production.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Environment {
+    /// Prepended to request urls that start with `/`.
+    #[serde(default)]
+    base_url: Option<String>,
+    /// Credential names mapped to the environment variables holding them. Only the variable
+    /// names are stored, so flow files can be committed.
+    #[serde(default)]
+    credentials: BTreeMap<String, String>,
+    #[serde(default)]
+    rate_limit: Option<EnvironmentRateLimit>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct EnvironmentRateLimit {
+    burst: u32,
+    per_second: f64,
+    #[serde(default)]
+    smooth: bool,
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = Some(base_url.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Reads the credential `name` from the environment variable `variable`.
+    pub fn with_credential(mut self, name: &str, variable: &str) -> Self {
+        self.credentials
+            .insert(name.to_string(), variable.to_string());
+        self
+    }
+
+    pub fn with_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(EnvironmentRateLimit {
+            burst: limit.burst(),
+            per_second: limit.refill_rate(),
+            smooth: limit.is_smoothed(),
+        });
+        self
+    }
+
+    pub fn base_url(&self) -> Option<&str> {
+        self.base_url.as_deref()
+    }
+
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit.map(|limit| {
+            let rate_limit = RateLimit::new(limit.burst, limit.per_second);
+            if limit.smooth {
+                rate_limit.smoothed()
+            } else {
+                rate_limit
+            }
+        })
+    }
+
+    /// Returns a rate limiter using the environment's limit for every host.
+    pub fn rate_limiter(&self) -> Option<RateLimiter> {
+        self.rate_limit()
+            .map(|limit| RateLimiter::new().with_default_limit(limit))
+    }
+
+    /// Returns the value of a credential, or `None` if it isn't declared or its variable isn't set.
+    pub fn credential(&self, name: &str) -> Option<String> {
+        std::env::var(self.credentials.get(name)?).ok()
+    }
+
+    /// Joins a path to the base url. Absolute urls and urls without a base are returned as is.
+    pub fn url(&self, path: &str) -> String {
+        match (&self.base_url, path.starts_with('/')) {
+            (Some(base), true) => format!("{}{}", base.trim_end_matches('/'), path),
+            _ => path.to_string(),
+        }
+    }
+
+    /// Resolves a request's url against the base url.
+    pub fn resolve(&self, req: Request) -> Request {
+        let url = self.url(req.url());
+        req.with_url(url)
+    }
+
+    /// Returns this environment with the settings of `overlay` on top.
+    pub fn overlay(&self, overlay: &Environment) -> Environment {
+        let mut credentials = self.credentials.clone();
+        credentials.extend(overlay.credentials.clone());
+        Environment {
+            base_url: overlay.base_url.clone().or_else(|| self.base_url.clone()),
+            credentials,
+            rate_limit: overlay.rate_limit.or(self.rate_limit),
+        }
+    }
+}
+
+/// The base settings of a flow and the overlays of each environment, e.g.
+/// `{"base_url": "https://staging.example.com", "environments": {"prod": {"base_url": "https://example.com"}}}`.
+/// Pick one when the flow is loaded with `select`, then pass it to `Worker::set_environment`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EnvironmentOverlays {
+    #[serde(flatten)]
+    base: Environment,
+    #[serde(default)]
+    environments: BTreeMap<String, Environment>,
+}
+
+impl EnvironmentOverlays {
+    pub fn new(base: Environment) -> Self {
+        Self {
+            base,
+            environments: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_environment(mut self, name: &str, overlay: Environment) -> Self {
+        self.environments.insert(name.to_string(), overlay);
+        self
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    /// Returns the names of the environments, sorted.
+    pub fn names(&self) -> Vec<&String> {
+        self.environments.keys().collect()
+    }
+
+    /// Returns the base settings with the overlay of `name` on top. Unknown names are an error
+    /// rather than falling back to the base, so a typo can't run a flow against the wrong site.
+    pub fn select(&self, name: &str) -> Result<Environment, Box<dyn Error + Send + Sync>> {
+        match self.environments.get(name) {
+            Some(overlay) => Ok(self.base.overlay(overlay)),
+            None => Err(Box::new(std::io::Error::other(format!(
+                "unknown environment {:?}, expected one of {:?}",
+                name,
+                self.names()
+            )))),
+        }
+    }
+
+    /// Selects the environment named by `MIMICR_ENV`, or the base settings when it isn't set.
+    pub fn select_from_env(&self) -> Result<Environment, Box<dyn Error + Send + Sync>> {
+        match std::env::var(ENVIRONMENT_ENV) {
+            Ok(name) => self.select(&name),
+            Err(_) => Ok(self.base.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Method;
+
+    const FLOW: &str = r#"{
+        "base_url": "https://staging.example.com",
+        "credentials": {"password": "MIMICR_TEST_STAGING_PASSWORD"},
+        "rate_limit": {"burst": 10, "per_second": 5.0},
+        "environments": {
+            "staging": {},
+            "prod": {
+                "base_url": "https://example.com/",
+                "credentials": {"password": "MIMICR_TEST_PROD_PASSWORD"},
+                "rate_limit": {"burst": 1, "per_second": 0.5}
+            }
+        }
+    }"#;
+
+    #[test]
+    fn it_should_overlay_the_selected_environment() {
+        let overlays = EnvironmentOverlays::from_json(FLOW).unwrap();
+        assert_eq!(overlays.names(), vec!["prod", "staging"]);
+
+        let staging = overlays.select("staging").unwrap();
+        assert_eq!(staging.url("/login"), "https://staging.example.com/login");
+        assert_eq!(staging.rate_limit(), Some(RateLimit::new(10, 5.0)));
+
+        let prod = overlays.select("prod").unwrap();
+        assert_eq!(prod.url("/login"), "https://example.com/login");
+        assert_eq!(
+            prod.url("https://cdn.example.com/a.js"),
+            "https://cdn.example.com/a.js"
+        );
+        assert_eq!(prod.rate_limit(), Some(RateLimit::new(1, 0.5)));
+
+        assert!(overlays.select("production").is_err());
+    }
+
+    #[test]
+    fn it_should_read_credentials_by_reference() {
+        std::env::set_var("MIMICR_TEST_PROD_PASSWORD", "hunter2");
+        let prod = EnvironmentOverlays::from_json(FLOW)
+            .unwrap()
+            .select("prod")
+            .unwrap();
+
+        assert_eq!(prod.credential("password").unwrap(), "hunter2");
+        assert!(prod.credential("api_key").is_none());
+        assert!(!serde_json::to_string(&prod).unwrap().contains("hunter2"));
+
+        let req = prod.resolve(Request::new(Method::GET, "/account".to_string()));
+        assert_eq!(req.url(), "https://example.com/account");
+    }
+}
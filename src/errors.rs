@@ -1,11 +1,215 @@
 use std::error::Error;
 use std::fmt;
 
-#[derive(Debug, Clone)]
+use crate::{GuardViolation, SchemaViolation};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum StepError {
+    /// Any other error of the HTTP client.
     ReqwestError(String),
+    /// The connection couldn't be established, e.g. it was refused or the proxy is down.
+    Connect(String),
+    /// The host name couldn't be resolved.
+    Dns(String),
+    /// The TLS handshake failed, e.g. on an invalid certificate.
+    Tls(String),
+    /// The body couldn't be decoded, e.g. a broken gzip stream.
+    BodyDecode(String),
+    /// The body was received but couldn't be parsed, e.g. invalid JSON.
+    Parse(String),
     StepNotFound(String),
     StatusCodeNotFound(i32, Vec<u16>),
+    IncoherentRequest(Vec<String>),
+    BlockedHost(String),
+    Timeout,
+    /// The response headers were larger than the client accepts.
+    OversizedHeaders,
+    InvalidChunkedEncoding(String),
+    /// The connection closed before the whole response was received.
+    PrematureClose(String),
+    /// Any other response that isn't valid HTTP.
+    MalformedResponse(String),
+    /// An HTTP/2 error such as a GOAWAY, a stream reset or a protocol error.
+    Http2(String),
+    /// The body of a download couldn't be written to its file.
+    Download(String),
+    /// A strict `Cassette` has no recording of the request.
+    UnrecordedRequest(String),
+    /// A request redirected more often than its `RedirectPolicy` follows, e.g. in a loop.
+    TooManyRedirects(String),
+    /// The JSON body doesn't match the request's `with_response_schema`.
+    SchemaMismatch(Vec<SchemaViolation>),
+    /// The request was aborted by `Worker::shutdown`.
+    Cancelled,
+}
+
+impl StepError {
+    /// Classifies a reqwest error, recognizing timeouts and protocol-level response problems.
+    pub fn from_reqwest(err: &reqwest::Error) -> Self {
+        if err.is_timeout() {
+            return StepError::Timeout;
+        }
+        if err.is_connect() {
+            return Self::from_connect_error(err);
+        }
+        if err.is_decode() {
+            return StepError::BodyDecode(error_chain(err));
+        }
+
+        Self::from_cause(err.source()).unwrap_or_else(|| StepError::ReqwestError(err.to_string()))
+    }
+
+    /// Tells DNS and TLS failures from other connect errors by their causes, which reqwest
+    /// doesn't expose as types.
+    fn from_connect_error(err: &reqwest::Error) -> Self {
+        // refused by the worker's host guard when the host was resolved to connect
+        let mut cause = err.source();
+        while let Some(source) = cause {
+            if let Some(violation) = source.downcast_ref::<GuardViolation>() {
+                return StepError::BlockedHost(violation.to_string());
+            }
+            cause = source.source();
+        }
+
+        let message = error_chain(err);
+        let lower = message.to_lowercase();
+        let is_dns = [
+            "dns error",
+            "failed to lookup address",
+            "name or service not known",
+        ]
+        .iter()
+        .any(|needle| lower.contains(needle));
+        let is_tls = ["certificate", "tls", "ssl", "handshake"]
+            .iter()
+            .any(|needle| lower.contains(needle));
+
+        match (is_dns, is_tls) {
+            (true, _) => StepError::Dns(message),
+            (_, true) => StepError::Tls(message),
+            _ => StepError::Connect(message),
+        }
+    }
+
+    /// Classifies the first recognized cause of an error chain.
+    fn from_cause(mut source: Option<&(dyn Error + 'static)>) -> Option<Self> {
+        while let Some(cause) = source {
+            if let Some(h2) = cause.downcast_ref::<h2::Error>() {
+                return Some(StepError::Http2(h2.to_string()));
+            }
+            if let Some(hyper) = cause.downcast_ref::<hyper::Error>() {
+                if hyper.is_parse_too_large() {
+                    return Some(StepError::OversizedHeaders);
+                }
+                if hyper.is_incomplete_message() {
+                    return Some(StepError::PrematureClose(hyper.to_string()));
+                }
+                if hyper.is_parse() {
+                    return Some(StepError::MalformedResponse(hyper.to_string()));
+                }
+            }
+            if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+                let message = io.to_string();
+                if message.to_lowercase().contains("chunk")
+                    && io.kind() != std::io::ErrorKind::UnexpectedEof
+                {
+                    return Some(StepError::InvalidChunkedEncoding(message));
+                }
+                if io.kind() == std::io::ErrorKind::UnexpectedEof {
+                    return Some(StepError::PrematureClose(message));
+                }
+            }
+            source = cause.source();
+        }
+
+        None
+    }
+
+    /// Returns the `StepError` behind an error returned by `Worker::try_step`, if it is one.
+    pub fn downcast<'a>(err: &'a (dyn Error + Send + Sync + 'static)) -> Option<&'a StepError> {
+        err.downcast_ref::<StepError>()
+    }
+
+    /// Returns the unexpected status code, if the error is one.
+    pub fn status_code(&self) -> Option<u16> {
+        match self {
+            StepError::StatusCodeNotFound(code, _) => u16::try_from(*code).ok(),
+            _ => None,
+        }
+    }
+
+    /// Whether trying again may succeed: timeouts, network errors, `408`, `425`, `429` and `5xx`.
+    /// Blocks other than rate limiting, client errors, TLS, decoding and configuration errors
+    /// aren't retryable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            StepError::Timeout
+            | StepError::ReqwestError(_)
+            | StepError::Connect(_)
+            | StepError::Dns(_)
+            | StepError::PrematureClose(_)
+            | StepError::Http2(_) => true,
+            StepError::StatusCodeNotFound(..) => self
+                .status_code()
+                .is_some_and(|code| matches!(code, 408 | 425 | 429) || (500..600).contains(&code)),
+            StepError::Tls(_)
+            | StepError::BodyDecode(_)
+            | StepError::Parse(_)
+            | StepError::StepNotFound(_)
+            | StepError::IncoherentRequest(_)
+            | StepError::BlockedHost(_)
+            | StepError::OversizedHeaders
+            | StepError::InvalidChunkedEncoding(_)
+            | StepError::MalformedResponse(_)
+            | StepError::Download(_)
+            | StepError::UnrecordedRequest(_)
+            | StepError::TooManyRedirects(_)
+            | StepError::SchemaMismatch(_)
+            | StepError::Cancelled => false,
+        }
+    }
+
+    /// Whether the target refused the bot: `401`, `403`, `429` or `451`.
+    pub fn is_block(&self) -> bool {
+        self.status_code()
+            .is_some_and(|code| matches!(code, 401 | 403 | 429 | 451))
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, StepError::Timeout)
+    }
+
+    /// Whether no connection was established: connect, DNS and TLS errors. The request never
+    /// reached the target, so it's safe to send again whatever its method.
+    pub fn is_connect_error(&self) -> bool {
+        matches!(
+            self,
+            StepError::Connect(_) | StepError::Dns(_) | StepError::Tls(_)
+        )
+    }
+
+    /// Whether the response itself was broken, e.g. truncated or with invalid framing.
+    pub fn is_protocol_error(&self) -> bool {
+        matches!(
+            self,
+            StepError::OversizedHeaders
+                | StepError::InvalidChunkedEncoding(_)
+                | StepError::PrematureClose(_)
+                | StepError::MalformedResponse(_)
+        )
+    }
+
+    /// Whether the response had a `4xx` status code.
+    pub fn is_client_error(&self) -> bool {
+        self.status_code()
+            .is_some_and(|code| (400..500).contains(&code))
+    }
+
+    /// Whether the response had a `5xx` status code.
+    pub fn is_server_error(&self) -> bool {
+        self.status_code()
+            .is_some_and(|code| (500..600).contains(&code))
+    }
 }
 
 impl fmt::Display for StepError {
@@ -13,6 +217,11 @@ impl fmt::Display for StepError {
         match self {
             StepError::StepNotFound(step_name) => write!(f, "Step not found: {}", step_name),
             StepError::ReqwestError(err) => write!(f, "Reqwest error: {}", err),
+            StepError::Connect(err) => write!(f, "Connection failed: {}", err),
+            StepError::Dns(err) => write!(f, "DNS resolution failed: {}", err),
+            StepError::Tls(err) => write!(f, "TLS error: {}", err),
+            StepError::BodyDecode(err) => write!(f, "Body decoding failed: {}", err),
+            StepError::Parse(err) => write!(f, "Parse error: {}", err),
             StepError::StatusCodeNotFound(code, expected_codes) => {
                 write!(
                     f,
@@ -20,8 +229,117 @@ impl fmt::Display for StepError {
                     code, expected_codes
                 )
             }
+            StepError::IncoherentRequest(issues) => {
+                write!(f, "Incoherent request headers: {}", issues.join("; "))
+            }
+            StepError::BlockedHost(reason) => write!(f, "Blocked request: {}", reason),
+            StepError::Timeout => write!(f, "Request timed out"),
+            StepError::OversizedHeaders => write!(f, "Response headers are too large"),
+            StepError::InvalidChunkedEncoding(err) => {
+                write!(f, "Invalid chunked encoding: {}", err)
+            }
+            StepError::PrematureClose(err) => write!(f, "Connection closed early: {}", err),
+            StepError::MalformedResponse(err) => write!(f, "Malformed response: {}", err),
+            StepError::Http2(err) => write!(f, "HTTP/2 error: {}", err),
+            StepError::Download(err) => write!(f, "Download error: {}", err),
+            StepError::UnrecordedRequest(req) => write!(f, "No recording of {}", req),
+            StepError::TooManyRedirects(url) => write!(f, "Too many redirects at {}", url),
+            StepError::Cancelled => write!(f, "Request cancelled"),
+            StepError::SchemaMismatch(violations) => {
+                let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+                write!(
+                    f,
+                    "Response doesn't match its schema: {}",
+                    violations.join("; ")
+                )
+            }
         }
     }
 }
 
 impl Error for StepError {}
+
+/// Joins the messages of an error and its causes, e.g. `error sending request: dns error: ...`.
+fn error_chain(err: &(dyn Error + 'static)) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(cause) = source {
+        let text = cause.to_string();
+        // hyper repeats the message of its cause
+        if !message.ends_with(&text) {
+            message.push_str(": ");
+            message.push_str(&text);
+        }
+        source = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_classify_errors() {
+        let status = |code| StepError::StatusCodeNotFound(code, vec![200]);
+
+        assert!(StepError::Timeout.is_retryable() && StepError::Timeout.is_timeout());
+        assert!(status(503).is_retryable() && status(503).is_server_error());
+        assert!(status(429).is_retryable() && status(429).is_block());
+        assert!(!status(403).is_retryable() && status(403).is_block());
+        assert!(!status(404).is_retryable() && !status(404).is_block());
+        assert!(status(404).is_client_error());
+        assert!(!StepError::BlockedHost("private".to_string()).is_retryable());
+    }
+
+    #[test]
+    fn it_should_recognize_http2_errors() {
+        let go_away = h2::Error::from(h2::Reason::PROTOCOL_ERROR);
+        let err = StepError::from_cause(Some(&go_away)).unwrap();
+        assert!(matches!(err, StepError::Http2(_)));
+        assert!(err.is_retryable());
+
+        let eof = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "early eof");
+        assert!(matches!(
+            StepError::from_cause(Some(&eof)),
+            Some(StepError::PrematureClose(_))
+        ));
+        assert_eq!(StepError::from_cause(None), None);
+    }
+
+    #[tokio::test]
+    async fn it_should_categorize_connect_errors() {
+        let send = |url: String| async move {
+            let err = reqwest::get(url).await.unwrap_err();
+            StepError::from_reqwest(&err)
+        };
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+        let err = send(format!("http://{}/", closed)).await;
+        assert!(matches!(err, StepError::Connect(_)), "{:?}", err);
+        assert!(err.is_retryable() && err.is_connect_error());
+
+        // plain HTTP where TLS is expected
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let plain = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        });
+        let err = send(format!("https://{}/", plain)).await;
+        assert!(matches!(err, StepError::Tls(_)), "{:?}", err);
+        assert!(!err.is_retryable() && err.is_connect_error());
+    }
+
+    #[test]
+    fn it_should_downcast_boxed_errors() {
+        let err: Box<dyn Error + Send + Sync> = Box::new(StepError::Timeout);
+        assert!(StepError::downcast(err.as_ref()).unwrap().is_timeout());
+
+        let err: Box<dyn Error + Send + Sync> = Box::new(std::io::Error::other("other"));
+        assert!(StepError::downcast(err.as_ref()).is_none());
+    }
+}
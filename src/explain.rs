@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use reqwest::header::{HeaderValue, ACCEPT, ACCEPT_ENCODING, COOKIE, HOST, USER_AGENT};
+use serde_derive::Serialize;
+
+use crate::{Request, Worker};
+
+/// What a worker would send for a request once the identity, referrer chain and cookies are
+/// applied, see `Request::explain`. Prints as an HTTP-like dump and serializes to JSON.
+/// Cookie and authorization values are shown as is, scrub them before sharing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Explanation {
+    pub method: String,
+    pub url: String,
+    /// The headers in the order they are sent, including the ones reqwest adds.
+    pub headers: Vec<(String, String)>,
+    pub proxy: Option<String>,
+    pub timeout_ms: Option<u64>,
+    /// The size of the body, if it isn't streamed or multipart.
+    pub body_bytes: Option<usize>,
+    pub multipart: bool,
+    pub status_codes: Option<Vec<u16>>,
+    pub priority: u8,
+    pub cost: u32,
+    pub tags: BTreeMap<String, String>,
+    /// Everything else that affects the request, e.g. a host guard violation.
+    pub notes: Vec<String>,
+}
+
+impl Explanation {
+    pub(crate) fn new(
+        req: &Request,
+        cookies: Option<HeaderValue>,
+        flow_priority: u8,
+        notes: Vec<String>,
+    ) -> Self {
+        let mut headers = req.headers().unwrap_or_default();
+        if let Some(user_agent) = req.user_agent() {
+            if let Ok(value) = HeaderValue::from_str(&user_agent) {
+                headers.entry(USER_AGENT).or_insert(value);
+            }
+        }
+        headers
+            .entry(ACCEPT)
+            .or_insert(HeaderValue::from_static("*/*"));
+        if req.is_compressed() {
+            headers
+                .entry(ACCEPT_ENCODING)
+                .or_insert(HeaderValue::from_static("gzip"));
+        }
+        if let Some(cookies) = cookies {
+            headers.entry(COOKIE).or_insert(cookies);
+        }
+        if let Some(host) = req
+            .host_header()
+            .and_then(|h| HeaderValue::from_str(h).ok())
+        {
+            headers.insert(HOST, host);
+        }
+        let mut notes = notes;
+        if let Some(addr) = req.connect_to() {
+            notes.push(format!(
+                "connects to {} instead of resolving the host",
+                addr
+            ));
+        }
+
+        Self {
+            method: req.method().to_string(),
+            url: req.url().clone(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| {
+                    (
+                        k.to_string(),
+                        String::from_utf8_lossy(v.as_bytes()).to_string(),
+                    )
+                })
+                .collect(),
+            proxy: req.proxy().map(|p| format!("{:?}", p)),
+            timeout_ms: req.timeout().map(|t| t.as_millis() as u64),
+            body_bytes: req.body().and_then(|body| body.as_bytes().map(|b| b.len())),
+            multipart: req.multipart().is_some(),
+            status_codes: req.status_codes(),
+            priority: req.priority().unwrap_or(flow_priority),
+            cost: req.cost(),
+            tags: req.tags().clone(),
+            notes,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or(serde_json::Value::Null)
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} {}", self.method, self.url)?;
+        for (name, value) in &self.headers {
+            writeln!(f, "{}: {}", name, value)?;
+        }
+        if let Some(bytes) = self.body_bytes {
+            writeln!(f, "\n<{} bytes>", bytes)?;
+        } else if self.multipart {
+            writeln!(f, "\n<multipart form>")?;
+        }
+
+        writeln!(f)?;
+        match &self.proxy {
+            Some(proxy) => writeln!(f, "proxy: {}", proxy)?,
+            None => writeln!(f, "proxy: none")?,
+        }
+        if let Some(timeout) = self.timeout_ms {
+            writeln!(f, "timeout: {}ms", timeout)?;
+        }
+        if let Some(codes) = &self.status_codes {
+            writeln!(f, "expected status codes: {:?}", codes)?;
+        }
+        writeln!(f, "priority: {}, cost: {}", self.priority, self.cost)?;
+        for (key, value) in &self.tags {
+            writeln!(f, "tag: {}={}", key, value)?;
+        }
+        for note in &self.notes {
+            writeln!(f, "note: {}", note)?;
+        }
+        Ok(())
+    }
+}
+
+impl Request {
+    /// Describes exactly what `worker` would send for this request, without sending it.
+    /// Useful to compare the bot's request with the browser's when a target blocks only the bot.
+    pub fn explain(&self, worker: &Worker) -> Explanation {
+        worker.explain(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Method;
+
+    use super::*;
+    use crate::{hdr, ReferrerChain};
+
+    #[test]
+    fn it_should_explain_what_will_be_sent() {
+        let mut worker = Worker::new();
+        worker.set_referrer_chain(Some(
+            ReferrerChain::new().with_entry("https://example.com/search"),
+        ));
+        worker.ctx.set_priority(3);
+
+        let req = Request::new(Method::POST, "https://example.com/cart".to_string())
+            .with_headers(hdr!("Content-Type: application/json"))
+            .with_user_agent("agent/1.0".to_string())
+            .with_tag("category", "checkout");
+        let explanation = req.explain(&worker);
+
+        assert_eq!(explanation.method, "POST");
+        assert_eq!(explanation.priority, 3);
+        let header = |name: &str| {
+            explanation
+                .headers
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(header("referer"), Some("https://example.com/search"));
+        assert_eq!(header("user-agent"), Some("agent/1.0"));
+        assert_eq!(header("accept-encoding"), Some("gzip"));
+
+        let text = explanation.to_string();
+        assert!(text.starts_with("POST https://example.com/cart\n"));
+        assert!(text.contains("tag: category=checkout"));
+        assert_eq!(explanation.to_json()["tags"]["category"], "checkout");
+    }
+
+    #[test]
+    fn it_should_note_host_guard_violations() {
+        let mut worker = Worker::new();
+        worker.set_host_guard(Some(crate::HostGuard::new()));
+
+        let req = Request::new(Method::GET, "http://127.0.0.1/admin".to_string());
+        let explanation = req.explain(&worker);
+        assert!(explanation.notes[0].starts_with("blocked by the host guard"));
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The groups of a regex match on a response body, see `Context::extract`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Captures {
+    /// The whole match, then every group, `None` for groups that didn't participate.
+    groups: Vec<Option<String>>,
+    names: Arc<HashMap<String, usize>>,
+}
+
+impl Captures {
+    pub(crate) fn new(captures: &regex::Captures, names: Arc<HashMap<String, usize>>) -> Self {
+        Self {
+            groups: captures
+                .iter()
+                .map(|group| group.map(|m| m.as_str().to_string()))
+                .collect(),
+            names,
+        }
+    }
+
+    /// Returns the whole match.
+    pub fn matched(&self) -> &str {
+        self.get(0).unwrap_or_default()
+    }
+
+    /// Returns a group by its index, 0 being the whole match.
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.groups.get(index)?.as_deref()
+    }
+
+    /// Returns a named group, e.g. `token` of `(?P<token>[^"]+)`.
+    pub fn name(&self, name: &str) -> Option<&str> {
+        self.get(*self.names.get(name)?)
+    }
+
+    /// Returns the value of the first group, or the whole match of a pattern without groups:
+    /// what most extractions are after.
+    pub fn value(&self) -> Option<&str> {
+        match self.groups.len() {
+            1 => self.get(0),
+            _ => self.get(1),
+        }
+    }
+
+    /// Returns the named groups that matched.
+    pub fn named(&self) -> HashMap<&str, &str> {
+        self.names
+            .iter()
+            .filter_map(|(name, &index)| Some((name.as_str(), self.get(index)?)))
+            .collect()
+    }
+
+    /// Returns the number of groups, the whole match included.
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+/// Returns the first match of the pattern in the text, or every match with `all`.
+pub(crate) fn captures(
+    pattern: &str,
+    text: &str,
+    all: bool,
+) -> Result<Vec<Captures>, regex::Error> {
+    let regex = regex::Regex::new(pattern)?;
+    let names: Arc<HashMap<String, usize>> = Arc::new(
+        regex
+            .capture_names()
+            .enumerate()
+            .filter_map(|(index, name)| Some((name?.to_string(), index)))
+            .collect(),
+    );
+    let matches = regex
+        .captures_iter(text)
+        .map(|captures| Captures::new(&captures, names.clone()));
+    Ok(if all {
+        matches.collect()
+    } else {
+        matches.take(1).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = r#"<input name="csrf_token" value="t0k3n">
+        <a data-sku="A-1" href="/p/1">Lamp</a> <a data-sku="B-2" href="/p/2">Desk</a>"#;
+
+    #[test]
+    fn it_should_capture_groups() {
+        let token = &captures(r#"csrf_token" value="(?P<token>[^"]+)""#, PAGE, false).unwrap()[0];
+        assert_eq!(token.name("token"), Some("t0k3n"));
+        assert_eq!(token.value(), Some("t0k3n"));
+        assert_eq!(token.matched(), r#"csrf_token" value="t0k3n""#);
+
+        let links = captures(r#"data-sku="(?P<sku>[^"]+)" href="([^"]+)""#, PAGE, true).unwrap();
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[1].name("sku"), Some("B-2"));
+        assert_eq!(links[1].get(2), Some("/p/2"));
+        assert_eq!(links[0].named().get("sku"), Some(&"A-1"));
+        assert_eq!(links[0].name("missing"), None);
+
+        let plain = &captures(r"/p/\d+", PAGE, false).unwrap()[0];
+        assert_eq!(plain.value(), Some("/p/1"));
+        assert!(captures(r"(unclosed", PAGE, false).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use tokio::sync::Semaphore;
+
+use crate::{HostGuard, HttpRequester, Request, ResponseInfo, StepError};
+
+/// The response of one request of a fan-out, see `Context::fan_out`.
+#[derive(Debug, Clone)]
+pub struct ChildResponse {
+    pub info: ResponseInfo,
+    pub body: bytes::Bytes,
+}
+
+impl ChildResponse {
+    pub fn status(&self) -> u16 {
+        self.info.status()
+    }
+
+    pub fn body_text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// What happened to one request of a fan-out.
+#[derive(Debug, Clone)]
+pub struct ChildOutcome {
+    /// The position of the request in the fan-out.
+    pub index: usize,
+    pub url: String,
+    pub result: Result<ChildResponse, StepError>,
+}
+
+impl ChildOutcome {
+    pub fn is_success(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+/// Returned by `Context::fan_out` when some of its requests failed. Lists the outcome of every
+/// request, successful ones included, so a handler can keep what succeeded and retry or report
+/// the rest instead of throwing the whole fan-out away.
+#[derive(Debug, Clone)]
+pub struct FanOutError {
+    outcomes: Vec<ChildOutcome>,
+}
+
+impl FanOutError {
+    /// Returns the outcome of every request, in the order they were given.
+    pub fn outcomes(&self) -> &[ChildOutcome] {
+        &self.outcomes
+    }
+
+    pub fn into_outcomes(self) -> Vec<ChildOutcome> {
+        self.outcomes
+    }
+
+    /// Returns the requests that succeeded, with their responses.
+    pub fn successes(&self) -> impl Iterator<Item = (&ChildOutcome, &ChildResponse)> {
+        self.outcomes
+            .iter()
+            .filter_map(|outcome| outcome.result.as_ref().ok().map(|res| (outcome, res)))
+    }
+
+    /// Returns the requests that failed, with their errors.
+    pub fn failures(&self) -> impl Iterator<Item = (&ChildOutcome, &StepError)> {
+        self.outcomes
+            .iter()
+            .filter_map(|outcome| outcome.result.as_ref().err().map(|err| (outcome, err)))
+    }
+
+    pub fn failed_count(&self) -> usize {
+        self.failures().count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.outcomes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outcomes.is_empty()
+    }
+}
+
+impl fmt::Display for FanOutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} requests failed",
+            self.failed_count(),
+            self.len()
+        )?;
+        if let Some((outcome, err)) = self.failures().next() {
+            write!(f, ", first {}: {}", outcome.url, err)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for FanOutError {}
+
+/// Sends every request with at most `concurrency` in flight and collects their outcomes in the
+/// order of the requests. A request fails when it gets no response, or a status its
+/// `with_status_codes` doesn't expect, 2xx by default.
+pub(crate) async fn fan_out(
+    requester: &HttpRequester,
+    requests: Vec<Request>,
+    concurrency: usize,
+) -> Result<Vec<ChildResponse>, FanOutError> {
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = tokio::task::JoinSet::new();
+    for (index, req) in requests.into_iter().enumerate() {
+        let mut requester = requester.clone();
+        let permits = permits.clone();
+        tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            let url = req.url().clone();
+            let result = send(&mut requester, req).await;
+            ChildOutcome { index, url, result }
+        });
+    }
+
+    let mut outcomes = Vec::new();
+    while let Some(joined) = tasks.join_next().await {
+        // the tasks don't panic but on a bug, which should surface
+        outcomes.push(joined.expect("fan-out request panicked"));
+    }
+    outcomes.sort_by_key(|outcome| outcome.index);
+
+    if outcomes.iter().all(ChildOutcome::is_success) {
+        return Ok(outcomes
+            .into_iter()
+            .filter_map(|outcome| outcome.result.ok())
+            .collect());
+    }
+    Err(FanOutError { outcomes })
+}
+
+pub(crate) async fn send(
+    requester: &mut HttpRequester,
+    req: Request,
+) -> Result<ChildResponse, StepError> {
+    apply_request_settings(requester, &req);
+    let guard = requester.settings.host_guard().cloned();
+    check_guard(guard.as_deref(), &req).await?;
+    let codes = req.status_codes().unwrap_or_default();
+    let builder = requester
+        .build_reqwest(req)
+        .map_err(|err| StepError::from_reqwest(&err))?;
+    let res = requester
+        .execute(builder, &mut BytesMut::new(), guard.as_deref())
+        .await
+        .map_err(|err| err.error)?;
+
+    check_status(res.info.status(), codes)?;
+    Ok(ChildResponse {
+        info: res.info,
+        body: res.body,
+    })
+}
+
+/// Like a step, a request brings its own proxy, user agent, compression and redirect policy.
+pub(crate) fn apply_request_settings(requester: &mut HttpRequester, req: &Request) {
+    if let Some(proxy) = req.proxy() {
+        requester.settings.set_proxy(Some(proxy));
+    }
+    if let Some(user_agent) = req.user_agent() {
+        requester.settings.set_user_agent(Some(user_agent));
+    }
+    requester
+        .settings
+        .set_compression(req.is_compressed())
+        .set_redirect_policy(req.redirect_policy().unwrap_or_default());
+}
+
+/// Refuses a request the guard doesn't allow, checking the address it connects to if pinned.
+/// The resolver only sees hostnames, so IP literals and proxied requests are checked here.
+pub(crate) async fn check_guard(guard: Option<&HostGuard>, req: &Request) -> Result<(), StepError> {
+    let Some(guard) = guard else {
+        return Ok(());
+    };
+    let checked = match req.connect_to() {
+        Some(addr) => guard.check_addr(req.url(), addr),
+        None => guard.check(req.url()).await,
+    };
+    checked.map_err(|violation| StepError::BlockedHost(violation.to_string()))
+}
+
+/// Fails unless the status is one of the expected codes, or a 2xx without any.
+pub(crate) fn check_status(status: u16, codes: Vec<u16>) -> Result<(), StepError> {
+    let expected = if codes.is_empty() {
+        (200..300).contains(&status)
+    } else {
+        codes.contains(&status)
+    };
+    if expected {
+        Ok(())
+    } else {
+        Err(StepError::StatusCodeNotFound(status as i32, codes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+    use crate::Context;
+    use reqwest::Method;
+
+    #[tokio::test]
+    async fn it_should_list_every_outcome_when_some_requests_fail() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/items/3" | "/items/5" => TestResponse::status(500, "oops"),
+            path => TestResponse::ok(path),
+        })
+        .await;
+        let requests = (0..8)
+            .map(|i| Request::new(Method::GET, server.url(&format!("/items/{}", i))))
+            .collect();
+
+        let ctx = Context::new();
+        let err = ctx.fan_out(requests, 3).await.unwrap_err();
+        assert_eq!((err.len(), err.failed_count()), (8, 2));
+        let failed: Vec<usize> = err.failures().map(|(outcome, _)| outcome.index).collect();
+        assert_eq!(failed, vec![3, 5]);
+        let bodies: Vec<String> = err.successes().map(|(_, res)| res.body_text()).collect();
+        assert_eq!(bodies[0], "/items/0");
+        assert_eq!(bodies.len(), 6);
+        assert!(err
+            .to_string()
+            .starts_with("2 of 8 requests failed, first "));
+        assert!(matches!(
+            err.failures().next().unwrap().1,
+            StepError::StatusCodeNotFound(500, _)
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_should_return_the_responses_in_order_when_all_succeed() {
+        let server = TestServer::start(|req| TestResponse::ok(&req.path)).await;
+        let requests = vec![
+            Request::new(Method::GET, server.url("/a")),
+            Request::new(Method::GET, server.url("/b")).with_status_codes(vec![200]),
+        ];
+
+        let responses = Context::new().fan_out(requests, 8).await.unwrap();
+        let bodies: Vec<String> = responses.iter().map(ChildResponse::body_text).collect();
+        assert_eq!(bodies, vec!["/a", "/b"]);
+    }
+
+    #[tokio::test]
+    async fn it_should_refuse_requests_the_host_guard_blocks() {
+        let server = TestServer::start(|_| {
+            TestResponse::status(302, "").with_header("Location", "http://127.0.0.1:1/admin")
+        })
+        .await;
+        let mut ctx = Context::new();
+        ctx.get_client_settings_mut()
+            .set_host_guard(Some(Arc::new(HostGuard::new().without_resolving())));
+        // the server is only reached by name, its address being private itself
+        let moved = server.url("/moved").replace("127.0.0.1", "localhost");
+        let requests = vec![
+            Request::new(Method::GET, "http://169.254.169.254/latest".to_string()),
+            Request::new(Method::GET, moved),
+        ];
+
+        let err = ctx.fan_out(requests, 2).await.unwrap_err();
+        for (_, err) in err.failures() {
+            assert!(matches!(err, StepError::BlockedHost(_)), "{}", err);
+        }
+        assert_eq!((err.failed_count(), server.hits()), (2, 1));
+    }
+}
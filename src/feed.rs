@@ -0,0 +1,102 @@
+use std::error::Error;
+
+use chrono::{DateTime, Utc};
+
+/// A single item of an RSS, Atom or JSON feed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedEntry {
+    pub id: String,
+    pub title: Option<String>,
+    pub link: Option<String>,
+    /// When the entry was published, falling back to when it was last updated.
+    pub published: Option<DateTime<Utc>>,
+    pub summary: Option<String>,
+}
+
+/// Parses a feed document into its entries, in document order.
+pub fn parse_feed(body: &[u8]) -> Result<Vec<FeedEntry>, Box<dyn Error + Send + Sync>> {
+    let feed = feed_rs::parser::parse(body)?;
+
+    Ok(feed
+        .entries
+        .into_iter()
+        .map(|entry| {
+            let link = entry
+                .links
+                .iter()
+                .find(|l| l.rel.as_deref().unwrap_or("alternate") == "alternate")
+                .or(entry.links.first())
+                .map(|l| l.href.clone());
+
+            FeedEntry {
+                id: entry.id,
+                title: entry.title.map(|t| t.content),
+                link,
+                published: entry.published.or(entry.updated),
+                summary: entry.summary.map(|t| t.content),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_parse_rss_items() {
+        let rss = br#"<?xml version="1.0"?>
+            <rss version="2.0"><channel><title>News</title>
+                <item>
+                    <title>First</title>
+                    <link>https://example.com/first</link>
+                    <pubDate>Mon, 02 Oct 2023 10:00:00 GMT</pubDate>
+                </item>
+                <item><title>Second</title><link>https://example.com/second</link></item>
+            </channel></rss>"#;
+
+        let entries = parse_feed(rss).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].title.as_deref(), Some("First"));
+        assert_eq!(
+            entries[0].link.as_deref(),
+            Some("https://example.com/first")
+        );
+        assert_eq!(
+            entries[0].published.unwrap().to_rfc3339(),
+            "2023-10-02T10:00:00+00:00"
+        );
+        assert!(entries[1].published.is_none());
+    }
+
+    #[test]
+    fn it_should_parse_atom_entries() {
+        let atom = br#"<?xml version="1.0" encoding="utf-8"?>
+            <feed xmlns="http://www.w3.org/2005/Atom">
+                <title>Blog</title>
+                <id>urn:blog</id>
+                <updated>2023-10-02T10:00:00Z</updated>
+                <entry>
+                    <title>Post</title>
+                    <id>urn:post:1</id>
+                    <link rel="edit" href="https://example.com/edit/1"/>
+                    <link href="https://example.com/post/1"/>
+                    <updated>2023-10-01T08:30:00Z</updated>
+                </entry>
+            </feed>"#;
+
+        let entries = parse_feed(atom).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, "urn:post:1");
+        assert_eq!(
+            entries[0].link.as_deref(),
+            Some("https://example.com/post/1")
+        );
+        assert!(entries[0].published.is_some());
+    }
+
+    #[test]
+    fn it_should_error_on_invalid_feeds() {
+        assert!(parse_feed(b"this is not a feed").is_err());
+    }
+}
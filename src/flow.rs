@@ -0,0 +1,249 @@
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::{read_artifact, Context, Request, StepError, Stepable};
+
+/// Url extensions of static assets, left out by `Flow::without_assets`.
+const ASSET_EXTENSIONS: &[&str] = &[
+    "css", "js", "mjs", "map", "png", "jpg", "jpeg", "gif", "webp", "avif", "svg", "ico", "woff",
+    "woff2", "ttf", "otf", "eot", "mp4", "webm", "mp3",
+];
+
+/// A step sending a fixed request, then continuing with the next step of its flow. Failed
+/// requests end the run.
+#[derive(Debug, Clone)]
+pub struct RequestStep {
+    name: String,
+    request: Request,
+    next: Option<String>,
+}
+
+impl RequestStep {
+    pub fn new(name: &str, request: Request) -> Self {
+        Self {
+            name: name.to_string(),
+            request,
+            next: None,
+        }
+    }
+
+    /// Continues with this step once the request succeeded.
+    pub fn with_next(mut self, next: &str) -> Self {
+        self.next = Some(next.to_string());
+        self
+    }
+
+    pub fn request(&self) -> &Request {
+        &self.request
+    }
+
+    pub fn next(&self) -> Option<&str> {
+        self.next.as_deref()
+    }
+}
+
+#[async_trait]
+impl Stepable for RequestStep {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn on_request(&self) -> Request {
+        self.request.clone()
+    }
+
+    async fn on_success(&self, ctx: &mut Context) {
+        if let Some(next) = &self.next {
+            ctx.set_next_step(next.clone());
+        }
+    }
+
+    async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+    async fn on_timeout(&self, _ctx: &mut Context) {}
+}
+
+/// A sequence of named requests run one after the other, e.g. a browsing session imported with
+/// `from_har` to bootstrap a bot. Add it to a worker with `steps` and run it from `first_step`,
+/// then replace the steps that need logic, like reading a token, with your own.
+#[derive(Debug, Clone, Default)]
+pub struct Flow {
+    requests: Vec<(String, Request)>,
+}
+
+impl Flow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a request, run after the ones already added.
+    pub fn with_request(mut self, name: &str, request: Request) -> Self {
+        self.requests.push((name.to_string(), request));
+        self
+    }
+
+    /// Loads the requests of a HAR file recorded by a browser, in the order they were sent.
+    /// The steps are named after their position, method and path, e.g. `03 POST /login`.
+    pub fn from_har(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let data = read_artifact(path)?;
+        Self::from_har_json(&serde_json::from_slice(&data)?)
+    }
+
+    /// Like `from_har`, with the HAR document already parsed.
+    pub fn from_har_json(har: &serde_json::Value) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let entries = har
+            .pointer("/log/entries")
+            .and_then(|entries| entries.as_array())
+            .ok_or_else(|| std::io::Error::other("HAR without log.entries"))?;
+
+        let mut entries: Vec<&serde_json::Value> = entries.iter().collect();
+        // browsers write entries in the order they were sent, but not every tool does
+        entries.sort_by_key(|entry| entry.get("startedDateTime").and_then(|t| t.as_str()));
+
+        let mut flow = Self::new();
+        for (i, entry) in entries.into_iter().enumerate() {
+            let request = Request::from_har_entry(entry)?;
+            let path = reqwest::Url::parse(request.url())
+                .map(|url| url.path().to_string())
+                .unwrap_or_else(|_| request.url().clone());
+            let name = format!("{:02} {} {}", i + 1, request.method(), path);
+            flow = flow.with_request(&name, request);
+        }
+        Ok(flow)
+    }
+
+    /// Leaves out the requests for stylesheets, scripts, images, fonts and media, which a bot
+    /// rarely needs to send.
+    pub fn without_assets(self) -> Self {
+        self.retain(|_, request| {
+            let extension = reqwest::Url::parse(request.url()).ok().and_then(|url| {
+                let path = url.path().to_ascii_lowercase();
+                path.rsplit_once('.').map(|(_, ext)| ext.to_string())
+            });
+            !extension.is_some_and(|ext| ASSET_EXTENSIONS.contains(&ext.as_str()))
+        })
+    }
+
+    /// Keeps only the requests matching the predicate, e.g. the requests to the target's host.
+    pub fn retain(mut self, keep: impl Fn(&str, &Request) -> bool) -> Self {
+        self.requests.retain(|(name, request)| keep(name, request));
+        self
+    }
+
+    pub fn requests(&self) -> &[(String, Request)] {
+        &self.requests
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Returns the name of the step the flow starts with.
+    pub fn first_step(&self) -> Option<&str> {
+        self.requests.first().map(|(name, _)| name.as_str())
+    }
+
+    /// Returns a `RequestStep` for every request, each continuing with the next one.
+    pub fn steps(&self) -> Vec<Arc<dyn Stepable>> {
+        let nexts = self.requests.iter().skip(1).map(|(name, _)| Some(name));
+        self.requests
+            .iter()
+            .zip(nexts.chain(std::iter::once(None)))
+            .map(|((name, request), next)| {
+                let step = RequestStep::new(name, request.clone());
+                let step = match next {
+                    Some(next) => step.with_next(next),
+                    None => step,
+                };
+                Arc::new(step) as Arc<dyn Stepable>
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+    use crate::Worker;
+    use reqwest::Method;
+    use serde_json::json;
+
+    fn entry(started: &str, method: &str, url: &str) -> serde_json::Value {
+        json!({
+            "startedDateTime": started,
+            "request": {
+                "method": method,
+                "url": url,
+                "headers": [
+                    { "name": ":authority", "value": "shop.example" },
+                    { "name": "accept", "value": "text/html" },
+                    { "name": "cookie", "value": "sid=stale" },
+                ],
+            },
+        })
+    }
+
+    #[test]
+    fn it_should_convert_har_entries_to_requests() {
+        let mut login = entry(
+            "2024-01-01T00:00:01.000Z",
+            "POST",
+            "https://shop.example/login",
+        );
+        login["request"]["postData"] = json!({
+            "mimeType": "application/x-www-form-urlencoded",
+            "params": [{ "name": "user", "value": "jane doe" }, { "name": "pass", "value": "a&b" }],
+        });
+        let req = Request::from_har_entry(&login).unwrap();
+        assert_eq!(req.method(), Method::POST);
+        let headers = req.headers().unwrap();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["accept"], "text/html");
+        let body = req.body().unwrap();
+        assert_eq!(body.as_bytes().unwrap(), b"user=jane+doe&pass=a%26b");
+
+        assert!(Request::from_har_entry(&json!({ "request": { "url": "/" } })).is_err());
+    }
+
+    #[test]
+    fn it_should_load_a_flow_from_a_har() {
+        let har = json!({ "log": { "entries": [
+            entry("2024-01-01T00:00:02.000Z", "GET", "https://shop.example/account"),
+            entry("2024-01-01T00:00:01.000Z", "POST", "https://shop.example/login"),
+            entry("2024-01-01T00:00:03.000Z", "GET", "https://cdn.example/app.JS?v=2"),
+        ]}});
+        let path = std::env::temp_dir().join("mimicr-flow-test.har");
+        std::fs::write(&path, har.to_string()).unwrap();
+
+        let flow = Flow::from_har(&path).unwrap();
+        let names: Vec<&str> = flow.requests().iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["01 POST /login", "02 GET /account", "03 GET /app.JS"]
+        );
+        assert_eq!(flow.without_assets().len(), 2);
+        assert!(Flow::from_har_json(&json!({})).is_err());
+    }
+
+    #[tokio::test]
+    async fn it_should_run_the_steps_in_order() {
+        let server = TestServer::start(|_| TestResponse::ok("ok")).await;
+        let flow = Flow::new()
+            .with_request("Home", Request::new(Method::GET, server.url("/")))
+            .with_request("Search", Request::new(Method::GET, server.url("/search")));
+
+        let mut worker = Worker::new();
+        worker.add_many_steps(flow.steps());
+        let summary = worker.run(flow.first_step().unwrap()).await;
+        assert_eq!(summary.step_names(), vec!["Home", "Search"]);
+        assert_eq!(server.hits(), 2);
+    }
+}
@@ -0,0 +1,254 @@
+use std::error::Error;
+
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use reqwest::{Method, Url};
+use scraper::{ElementRef, Html, Selector};
+
+use crate::request::MimicBody;
+use crate::Request;
+
+/// An HTML form with the fields a browser would submit, e.g. hidden CSRF tokens and view state.
+/// Set the user's fields with `set`, then send `to_request()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Form {
+    action: String,
+    method: Method,
+    fields: Vec<(String, String)>,
+}
+
+impl Form {
+    /// Parses every form of a document. Relative actions are resolved against `base_url`, the url
+    /// the document was loaded from.
+    pub fn parse_all(html: &str, base_url: &str) -> Vec<Form> {
+        Self::select(html, base_url, "form").unwrap_or_default()
+    }
+
+    /// Parses the forms matching a CSS selector, e.g. `form#login`.
+    pub fn select(
+        html: &str,
+        base_url: &str,
+        selector: &str,
+    ) -> Result<Vec<Form>, Box<dyn Error + Send + Sync>> {
+        let parsed = Selector::parse(selector).map_err(|err| {
+            std::io::Error::other(format!("invalid selector {:?}: {}", selector, err))
+        })?;
+        let document = Html::parse_document(html);
+
+        Ok(document
+            .select(&parsed)
+            .filter(|element| element.value().name() == "form")
+            .map(|form| Self::from_element(form, base_url))
+            .collect())
+    }
+
+    fn from_element(form: ElementRef, base_url: &str) -> Form {
+        let element = form.value();
+        let action = element.attr("action").unwrap_or_default().trim();
+        let action = match Url::parse(base_url).and_then(|base| base.join(action)) {
+            Ok(url) => url.to_string(),
+            Err(_) => action.to_string(),
+        };
+        let method = match element.attr("method") {
+            Some(method) if method.eq_ignore_ascii_case("post") => Method::POST,
+            _ => Method::GET,
+        };
+
+        let controls = Selector::parse("input, select, textarea").unwrap();
+        let fields = form
+            .select(&controls)
+            .filter_map(|control| {
+                let value = control.value();
+                let name = value.attr("name")?;
+                if name.is_empty() || value.attr("disabled").is_some() {
+                    return None;
+                }
+                Some((name.to_string(), control_value(control)?))
+            })
+            .collect();
+
+        Form {
+            action,
+            method,
+            fields,
+        }
+    }
+
+    pub fn action(&self) -> &str {
+        &self.action
+    }
+
+    pub fn method(&self) -> Method {
+        self.method.clone()
+    }
+
+    pub fn fields(&self) -> &[(String, String)] {
+        &self.fields
+    }
+
+    /// Returns the value of the first field with this name.
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Sets a field, replacing the value of the first field with this name or adding it.
+    pub fn set(mut self, name: &str, value: &str) -> Self {
+        match self.fields.iter_mut().find(|(field, _)| field == name) {
+            Some((_, current)) => *current = value.to_string(),
+            None => self.fields.push((name.to_string(), value.to_string())),
+        }
+        self
+    }
+
+    /// Removes every field with this name.
+    pub fn remove(mut self, name: &str) -> Self {
+        self.fields.retain(|(field, _)| field != name);
+        self
+    }
+
+    /// Returns the fields as `application/x-www-form-urlencoded`.
+    pub fn encoded(&self) -> String {
+        let mut url = Url::parse("http://form/").unwrap();
+        url.query_pairs_mut().extend_pairs(&self.fields);
+        url.query().unwrap_or_default().to_string()
+    }
+
+    /// Returns the request submitting the form: a GET with the fields in the query, or a POST
+    /// with the encoded fields as its body.
+    pub fn to_request(&self) -> Request {
+        if self.method != Method::POST {
+            let mut url = Url::parse(&self.action);
+            return match &mut url {
+                Ok(url) => {
+                    url.set_query(None);
+                    url.query_pairs_mut().extend_pairs(&self.fields);
+                    Request::new(Method::GET, url.to_string())
+                }
+                Err(_) => Request::new(Method::GET, self.action.clone()),
+            };
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        Request::new(Method::POST, self.action.clone())
+            .with_headers(headers)
+            .with_body(MimicBody::from_text(self.encoded()))
+    }
+}
+
+/// Returns what a browser submits for a control, or `None` if it submits nothing.
+fn control_value(control: ElementRef) -> Option<String> {
+    let value = control.value();
+    match value.name() {
+        "textarea" => Some(control.text().collect()),
+        "select" => {
+            let options = Selector::parse("option").unwrap();
+            let mut options = control.select(&options);
+            let first = options.next()?;
+            let selected = std::iter::once(first)
+                .chain(options)
+                .find(|option| option.value().attr("selected").is_some())
+                .unwrap_or(first);
+            Some(match selected.value().attr("value") {
+                Some(value) => value.to_string(),
+                None => selected.text().collect::<String>().trim().to_string(),
+            })
+        }
+        _ => {
+            let kind = value.attr("type").unwrap_or("text").to_ascii_lowercase();
+            match kind.as_str() {
+                "submit" | "button" | "image" | "reset" | "file" => None,
+                "checkbox" | "radio" if value.attr("checked").is_none() => None,
+                "checkbox" | "radio" => Some(value.attr("value").unwrap_or("on").to_string()),
+                _ => Some(value.attr("value").unwrap_or_default().to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGIN_PAGE: &str = r#"<html><body>
+        <form id="search" action="/search"><input name="q" value="shoes"></form>
+        <form id="login" action="session?next=%2F" method="POST">
+            <input type="hidden" name="csrf" value="abc123">
+            <input type="hidden" name="__VIEWSTATE" value="dDw+">
+            <input name="username">
+            <input type="password" name="password">
+            <input type="checkbox" name="remember" checked>
+            <input type="checkbox" name="newsletter" value="yes">
+            <input name="disabled" value="x" disabled>
+            <select name="lang"><option value="en">English</option><option value="de" selected>Deutsch</option></select>
+            <textarea name="note">hi there</textarea>
+            <input type="submit" name="go" value="Log in">
+        </form>
+    </body></html>"#;
+
+    #[test]
+    fn it_should_parse_the_fields_a_browser_submits() {
+        let forms = Form::parse_all(LOGIN_PAGE, "https://example.com/account/login");
+        assert_eq!(forms.len(), 2);
+
+        let login = &forms[1];
+        assert_eq!(login.method(), Method::POST);
+        assert_eq!(
+            login.action(),
+            "https://example.com/account/session?next=%2F"
+        );
+        let names: Vec<&str> = login
+            .fields()
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "csrf",
+                "__VIEWSTATE",
+                "username",
+                "password",
+                "remember",
+                "lang",
+                "note"
+            ]
+        );
+        assert_eq!(login.field("remember"), Some("on"));
+        assert_eq!(login.field("lang"), Some("de"));
+        assert_eq!(login.field("note"), Some("hi there"));
+    }
+
+    #[test]
+    fn it_should_build_the_submitting_request() {
+        let login = Form::select(LOGIN_PAGE, "https://example.com/login", "form#login")
+            .unwrap()
+            .remove(0)
+            .set("username", "jane")
+            .set("password", "p&ss word")
+            .remove("note");
+
+        let req = login.to_request();
+        assert_eq!(req.method(), Method::POST);
+        assert_eq!(
+            req.headers().unwrap()["content-type"],
+            "application/x-www-form-urlencoded"
+        );
+        assert_eq!(
+            login.encoded(),
+            "csrf=abc123&__VIEWSTATE=dDw%2B&username=jane&password=p%26ss+word&remember=on&lang=de"
+        );
+
+        let search = Form::parse_all(LOGIN_PAGE, "https://example.com/login").remove(0);
+        let req = search.set("q", "blue shoes").to_request();
+        assert_eq!(req.method(), Method::GET);
+        assert_eq!(req.url(), "https://example.com/search?q=blue+shoes");
+
+        assert!(Form::select(LOGIN_PAGE, "https://example.com/", "form[").is_err());
+    }
+}
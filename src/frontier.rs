@@ -0,0 +1,213 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+use reqwest::Url;
+
+/// Limits what a frontier-driven crawl may visit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CrawlScope {
+    same_host: bool,
+    path_prefixes: Vec<String>,
+    max_depth: Option<u32>,
+    max_pages_per_host: Option<usize>,
+}
+
+impl CrawlScope {
+    /// Creates a scope without any limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only follows links to the hosts of the seed urls.
+    pub fn same_host_only(mut self) -> Self {
+        self.same_host = true;
+        self
+    }
+
+    /// Only follows links whose path starts with one of the prefixes, e.g. `/blog/`.
+    pub fn with_path_prefix(mut self, prefix: &str) -> Self {
+        self.path_prefixes.push(prefix.to_string());
+        self
+    }
+
+    /// Seeds are at depth 0, the links found on them at depth 1 and so on.
+    pub fn with_max_depth(mut self, depth: u32) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    pub fn with_max_pages_per_host(mut self, pages: usize) -> Self {
+        self.max_pages_per_host = Some(pages);
+        self
+    }
+}
+
+/// A url waiting to be crawled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrontierEntry {
+    pub url: String,
+    pub depth: u32,
+}
+
+#[derive(Debug, Default)]
+struct FrontierState {
+    queue: VecDeque<FrontierEntry>,
+    seen: HashSet<String>,
+    seed_hosts: HashSet<String>,
+    pages_per_host: HashMap<String, usize>,
+}
+
+/// A breadth-first queue of urls to crawl that only admits urls within its `CrawlScope` and never
+/// admits the same url twice. Share it between link-following steps with an `Arc`.
+#[derive(Debug)]
+pub struct Frontier {
+    scope: CrawlScope,
+    state: Mutex<FrontierState>,
+}
+
+impl Frontier {
+    pub fn new(scope: CrawlScope) -> Self {
+        Self {
+            scope,
+            state: Mutex::new(FrontierState::default()),
+        }
+    }
+
+    pub fn scope(&self) -> &CrawlScope {
+        &self.scope
+    }
+
+    /// Adds a starting url. Its host is allowed when the scope is `same_host_only`.
+    pub fn add_seed(&self, url: &str) -> bool {
+        if let Some(host) = Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_string))
+        {
+            self.state.lock().unwrap().seed_hosts.insert(host);
+        }
+        self.admit(url, 0)
+    }
+
+    /// Adds a link found on `parent`. Relative links are resolved against the parent's url.
+    /// Returns false if the link was already seen or is out of scope.
+    pub fn add_link(&self, parent: &FrontierEntry, href: &str) -> bool {
+        let url = match Url::parse(&parent.url).and_then(|base| base.join(href)) {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+        self.admit(url.as_str(), parent.depth + 1)
+    }
+
+    /// Takes the next url to crawl.
+    pub fn pop(&self) -> Option<FrontierEntry> {
+        self.state.lock().unwrap().queue.pop_front()
+    }
+
+    /// Returns the number of urls waiting to be crawled.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of urls admitted so far, including the ones already popped.
+    pub fn seen(&self) -> usize {
+        self.state.lock().unwrap().seen.len()
+    }
+
+    fn admit(&self, url: &str, depth: u32) -> bool {
+        let Ok(mut url) = Url::parse(url) else {
+            return false;
+        };
+        if !matches!(url.scheme(), "http" | "https") {
+            return false;
+        }
+        url.set_fragment(None);
+        let Some(host) = url.host_str().map(str::to_string) else {
+            return false;
+        };
+
+        if self.scope.max_depth.is_some_and(|max| depth > max) {
+            return false;
+        }
+        if !self.scope.path_prefixes.is_empty()
+            && !self
+                .scope
+                .path_prefixes
+                .iter()
+                .any(|p| url.path().starts_with(p))
+        {
+            return false;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if self.scope.same_host && !state.seed_hosts.contains(&host) {
+            return false;
+        }
+        if state.seen.contains(url.as_str()) {
+            return false;
+        }
+        let pages = state.pages_per_host.get(&host).copied().unwrap_or(0);
+        if self
+            .scope
+            .max_pages_per_host
+            .is_some_and(|max| pages >= max)
+        {
+            return false;
+        }
+
+        state.seen.insert(url.to_string());
+        state.pages_per_host.insert(host, pages + 1);
+        state.queue.push_back(FrontierEntry {
+            url: url.to_string(),
+            depth,
+        });
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_resolve_and_deduplicate_links() {
+        let frontier = Frontier::new(CrawlScope::new());
+        assert!(frontier.add_seed("https://example.com/blog/"));
+
+        let seed = frontier.pop().unwrap();
+        assert!(frontier.add_link(&seed, "post-1#comments"));
+        assert!(!frontier.add_link(&seed, "/blog/post-1"));
+        assert!(!frontier.add_link(&seed, "mailto:hi@example.com"));
+
+        let link = frontier.pop().unwrap();
+        assert_eq!(link.url, "https://example.com/blog/post-1");
+        assert_eq!(link.depth, 1);
+        assert!(frontier.is_empty());
+    }
+
+    #[test]
+    fn it_should_enforce_the_scope() {
+        let scope = CrawlScope::new()
+            .same_host_only()
+            .with_path_prefix("/docs/")
+            .with_max_depth(1)
+            .with_max_pages_per_host(3);
+        let frontier = Frontier::new(scope);
+        frontier.add_seed("https://example.com/docs/");
+        let seed = frontier.pop().unwrap();
+
+        assert!(!frontier.add_link(&seed, "https://other.com/docs/"));
+        assert!(!frontier.add_link(&seed, "/pricing"));
+        assert!(frontier.add_link(&seed, "/docs/a"));
+
+        let child = frontier.pop().unwrap();
+        assert!(!frontier.add_link(&child, "/docs/deeper"));
+
+        assert!(frontier.add_link(&seed, "/docs/b"));
+        assert!(!frontier.add_link(&seed, "/docs/c"));
+        assert_eq!(frontier.seen(), 3);
+    }
+}
@@ -0,0 +1,401 @@
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::header::{HeaderMap, CONTENT_TYPE, COOKIE, LOCATION, SET_COOKIE};
+use serde_json::{json, Value};
+
+use crate::singleflight::SharedResult;
+use crate::Scrubber;
+
+/// A request as it was sent, with the headers reqwest adds itself, e.g. the cookies.
+#[derive(Debug, Clone)]
+pub(crate) struct HarRequest {
+    pub method: String,
+    pub url: String,
+    pub http_version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>,
+}
+
+/// A recorded request and its response, or the error it failed with.
+#[derive(Debug, Clone)]
+struct HarEntry {
+    started: SystemTime,
+    time: Duration,
+    request: HarRequest,
+    status: u16,
+    http_version: String,
+    headers: Vec<(String, String)>,
+    body: String,
+    /// The size of the body, also when it isn't kept.
+    size: usize,
+    error: Option<String>,
+}
+
+/// Records every request a worker sends, with its headers, body, timing and response, and
+/// exports them as a HAR 1.2 file, e.g. to compare the bot's traffic with a browser's in the
+/// browser's developer tools. Set it with `Worker::set_har_recorder`.
+/// Every hop of a redirect is an entry of its own, its `redirectURL` naming the next one.
+/// Downloads aren't recorded.
+#[derive(Debug, Default)]
+pub struct HarRecorder {
+    entries: Mutex<Vec<HarEntry>>,
+    skip_bodies: bool,
+    scrubber: Option<Scrubber>,
+}
+
+impl HarRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Leaves the response bodies out, keeping only their size.
+    pub fn without_bodies(mut self) -> Self {
+        self.skip_bodies = true;
+        self
+    }
+
+    /// Scrubs the headers, urls and bodies when the HAR is exported.
+    pub fn with_scrubber(mut self, scrubber: Scrubber) -> Self {
+        self.scrubber = Some(scrubber);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub(crate) fn record(
+        &self,
+        request: HarRequest,
+        started: SystemTime,
+        time: Duration,
+        result: &SharedResult,
+    ) {
+        let entry = match result {
+            Ok(res) => HarEntry {
+                started,
+                time,
+                status: res.info.status(),
+                http_version: format!("{:?}", res.info.version()),
+                headers: text_headers(res.info.headers()),
+                body: if self.skip_bodies {
+                    String::new()
+                } else {
+                    String::from_utf8_lossy(&res.body).into_owned()
+                },
+                size: res.body.len(),
+                error: None,
+                request,
+            },
+            Err(err) => HarEntry {
+                started,
+                time,
+                status: 0,
+                http_version: request.http_version.clone(),
+                headers: vec![],
+                body: String::new(),
+                size: 0,
+                error: Some(err.error.to_string()),
+                request,
+            },
+        };
+        self.entries.lock().unwrap().push(entry);
+    }
+
+    /// Returns the recorded requests as a HAR document.
+    pub fn to_har(&self) -> Value {
+        let entries: Vec<Value> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| self.entry_json(entry))
+            .collect();
+        json!({
+            "log": {
+                "version": "1.2",
+                "creator": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "pages": [],
+                "entries": entries,
+            }
+        })
+    }
+
+    /// Writes the HAR document to a file.
+    pub fn export(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(&self.to_har())?)
+    }
+
+    fn entry_json(&self, entry: &HarEntry) -> Value {
+        let mut entry = entry.clone();
+        if let Some(scrubber) = &self.scrubber {
+            let request = &mut entry.request;
+            request.url = scrubber.scrub_url(&request.url);
+            scrubber.scrub_headers(&mut request.headers);
+            if let Some(body) = &mut request.body {
+                *body = String::from_utf8_lossy(&scrubber.scrub_body(body.as_bytes())).into_owned();
+            }
+            scrubber.scrub_headers(&mut entry.headers);
+            entry.body =
+                String::from_utf8_lossy(&scrubber.scrub_body(entry.body.as_bytes())).into_owned();
+        }
+
+        let request = &entry.request;
+        let query: Vec<Value> = reqwest::Url::parse(&request.url)
+            .map(|url| {
+                url.query_pairs()
+                    .map(|(name, value)| json!({ "name": name, "value": value }))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut request_json = json!({
+            "method": request.method,
+            "url": request.url,
+            "httpVersion": request.http_version,
+            "cookies": cookies(&request.headers, COOKIE.as_str()),
+            "headers": headers_json(&request.headers),
+            "queryString": query,
+            "headersSize": -1,
+            "bodySize": request.body.as_ref().map_or(0, |body| body.len()),
+        });
+        if let Some(body) = &request.body {
+            request_json["postData"] = json!({
+                "mimeType": header(&request.headers, CONTENT_TYPE.as_str()).unwrap_or_default(),
+                "text": body,
+            });
+        }
+
+        let size = entry.size;
+        let mut content = json!({
+            "size": size,
+            "mimeType": header(&entry.headers, CONTENT_TYPE.as_str()).unwrap_or_default(),
+        });
+        if !self.skip_bodies {
+            content["text"] = Value::from(entry.body.clone());
+        }
+        let status_text = reqwest::StatusCode::from_u16(entry.status)
+            .ok()
+            .and_then(|status| status.canonical_reason())
+            .unwrap_or_default();
+        let time = entry.time.as_secs_f64() * 1000.0;
+
+        let mut json = json!({
+            "startedDateTime": iso_8601(entry.started),
+            "time": time,
+            "request": request_json,
+            "response": {
+                "status": entry.status,
+                "statusText": status_text,
+                "httpVersion": entry.http_version,
+                "cookies": cookies(&entry.headers, SET_COOKIE.as_str()),
+                "headers": headers_json(&entry.headers),
+                "content": content,
+                "redirectURL": header(&entry.headers, LOCATION.as_str()).unwrap_or_default(),
+                "headersSize": -1,
+                "bodySize": size,
+            },
+            "cache": {},
+            // only the whole time is measured
+            "timings": { "send": 0, "wait": time, "receive": 0 },
+        });
+        if let Some(error) = &entry.error {
+            json["response"]["_error"] = Value::from(error.clone());
+        }
+        json
+    }
+}
+
+pub(crate) fn text_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn headers_json(headers: &[(String, String)]) -> Vec<Value> {
+    headers
+        .iter()
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect()
+}
+
+/// Returns the name and value of the cookies in `Cookie` or `Set-Cookie` headers.
+fn cookies(headers: &[(String, String)], name: &str) -> Vec<Value> {
+    let pairs = headers
+        .iter()
+        .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+        .flat_map(|(_, value)| match name {
+            "cookie" => value.split(';').collect::<Vec<_>>(),
+            _ => value.split(';').take(1).collect(),
+        });
+    pairs
+        .filter_map(|pair| pair.trim().split_once('='))
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect()
+}
+
+/// Formats a time as UTC, e.g. `2024-01-31T12:00:00.000Z`.
+pub(crate) fn iso_8601(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+    use crate::{Context, MimicBody, Request, Worker};
+    use reqwest::Method;
+    use std::sync::Arc;
+
+    #[test]
+    fn it_should_format_utc_dates() {
+        assert_eq!(iso_8601(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(iso_8601(time), "2024-02-29T12:34:56.789Z");
+    }
+
+    #[tokio::test]
+    async fn it_should_export_every_request_as_har() {
+        let server = TestServer::start(|req| {
+            if req.path.starts_with("/login") {
+                TestResponse::ok("welcome")
+                    .with_header("Set-Cookie", "sid=abc; Path=/")
+                    .with_header("Content-Type", "text/plain")
+            } else {
+                TestResponse::status(404, "missing")
+            }
+        })
+        .await;
+        let recorder = Arc::new(HarRecorder::new().with_scrubber(Scrubber::new()));
+        let mut worker = Worker::new();
+        worker.set_har_recorder(Some(recorder.clone()));
+
+        let login = Request::new(Method::POST, server.url("/login?next=home"))
+            .with_body(MimicBody::from_text("user=jane".to_string()))
+            .with_user_agent("mimicr-test".to_string());
+        send(&mut worker.ctx, login).await;
+        send(
+            &mut worker.ctx,
+            Request::new(Method::GET, server.url("/orders")),
+        )
+        .await;
+        assert_eq!(recorder.len(), 2);
+
+        let har = recorder.to_har();
+        assert_eq!(har["log"]["version"], "1.2");
+        let login = &har["log"]["entries"][0];
+        assert_eq!(login["request"]["method"], "POST");
+        assert_eq!(login["request"]["queryString"][0]["value"], "home");
+        assert_eq!(login["request"]["postData"]["text"], "user=jane");
+        assert_eq!(login["response"]["status"], 200);
+        assert_eq!(login["response"]["content"]["text"], "welcome");
+        assert_eq!(login["response"]["content"]["mimeType"], "text/plain");
+        assert!(login["startedDateTime"].as_str().unwrap().ends_with('Z'));
+        let set_cookie = vec![("set-cookie".to_string(), "sid=abc; Path=/".to_string())];
+        assert_eq!(
+            cookies(&set_cookie, "set-cookie"),
+            vec![json!({ "name": "sid", "value": "abc" })]
+        );
+
+        let orders = &har["log"]["entries"][1];
+        assert_eq!(orders["response"]["status"], 404);
+        assert_eq!(orders["response"]["statusText"], "Not Found");
+        let header = |name: &str| {
+            orders["request"]["headers"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|h| h["name"] == name)
+                .map(|h| h["value"].clone())
+        };
+        // the cookie the client sends is recorded, then scrubbed on export
+        assert_eq!(header("cookie"), Some(Value::from(crate::REDACTED)));
+
+        let path = std::env::temp_dir().join("mimicr-har-test.har");
+        recorder.export(&path).unwrap();
+        // compared as text, parsing the timings back may round them
+        let exported = std::fs::read(&path).unwrap();
+        assert_eq!(exported, serde_json::to_vec_pretty(&har).unwrap());
+    }
+
+    #[tokio::test]
+    async fn it_should_record_every_hop_of_a_redirect() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/old" => TestResponse::status(302, "").with_header("Location", "/new"),
+            _ => TestResponse::ok("moved"),
+        })
+        .await;
+        let recorder = Arc::new(HarRecorder::new());
+        let mut worker = Worker::new();
+        worker.set_har_recorder(Some(recorder.clone()));
+        send(
+            &mut worker.ctx,
+            Request::new(Method::GET, server.url("/old")),
+        )
+        .await;
+
+        let har = recorder.to_har();
+        let entries = har["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["request"]["url"], server.url("/old"));
+        assert_eq!(entries[0]["response"]["status"], 302);
+        assert_eq!(entries[0]["response"]["redirectURL"], "/new");
+        assert_eq!(entries[1]["request"]["url"], server.url("/new"));
+        assert_eq!(entries[1]["response"]["content"]["text"], "moved");
+    }
+
+    async fn send(ctx: &mut Context, req: Request) {
+        ctx.update_from_request(req).unwrap();
+        let builder = ctx.get_request_builder().unwrap();
+        let _ = ctx
+            .get_http_requester()
+            .execute(builder, &mut bytes::BytesMut::new(), None)
+            .await;
+    }
+}
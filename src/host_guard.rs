@@ -0,0 +1,328 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use reqwest::Url;
+
+/// A CIDR range such as `10.0.0.0/8` or `fc00::/7`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    /// Parses `addr/prefix`. A bare address is a range of one.
+    pub fn parse(range: &str) -> Option<Self> {
+        let (addr, prefix) = match range.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+            None => (range.parse::<IpAddr>().ok()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+
+        (prefix <= max).then_some(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(range) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(range) & mask == u128::from(*ip) & mask
+            }
+            // check v4 ranges against the v4 address a v6 address embeds too
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match embedded_ipv4(ip) {
+                Some(v4) => self.contains(&IpAddr::V4(v4)),
+                None => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Returns the v4 address of a v4-mapped (`::ffff:127.0.0.1`), NAT64 (`64:ff9b::7f00:1`) or
+/// 6to4 (`2002:7f00:1::`) address, which reach that v4 address.
+fn embedded_ipv4(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let bits = u128::from(*ip);
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        Some(v4)
+    } else if bits >> 32 == 0x64_ff9b << 64 {
+        Some(Ipv4Addr::from(bits as u32))
+    } else if bits >> 112 == 0x2002 {
+        Some(Ipv4Addr::from((bits >> 80) as u32))
+    } else {
+        None
+    }
+}
+
+/// Loopback, RFC1918, link-local, CGNAT, unique local and other non-public ranges.
+const PRIVATE_RANGES: [&str; 14] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/127",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+/// Why a url was refused by a `HostGuard`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuardViolation {
+    InvalidUrl(String),
+    SchemeNotAllowed(String),
+    HostNotAllowed(String),
+    HostDenied(String),
+    /// The host is, or resolves to, a blocked address.
+    AddressDenied(String, IpAddr),
+    ResolveFailed(String),
+}
+
+impl fmt::Display for GuardViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GuardViolation::InvalidUrl(url) => write!(f, "invalid url {}", url),
+            GuardViolation::SchemeNotAllowed(scheme) => {
+                write!(f, "scheme {} is not allowed", scheme)
+            }
+            GuardViolation::HostNotAllowed(host) => write!(f, "host {} is not allowed", host),
+            GuardViolation::HostDenied(host) => write!(f, "host {} is denied", host),
+            GuardViolation::AddressDenied(host, ip) => {
+                write!(f, "host {} resolves to blocked address {}", host, ip)
+            }
+            GuardViolation::ResolveFailed(host) => write!(f, "unable to resolve host {}", host),
+        }
+    }
+}
+
+impl std::error::Error for GuardViolation {}
+
+/// Checks urls against scheme, host and IP rules before they are requested, so a worker driven by
+/// untrusted input can't be used to reach internal services (SSRF).
+/// Hostnames are resolved and every resolved address is checked, and so is every redirect a
+/// worker follows. A worker checks the addresses again when it resolves a host to connect, so a
+/// host can't pass with a public address and connect to a private one (DNS rebinding), and
+/// checks the address of `Request::with_connect_to` instead of the host. Requests sent through a
+/// proxy are resolved by the proxy, only their url is checked.
+#[derive(Debug, Clone)]
+pub struct HostGuard {
+    schemes: Vec<String>,
+    allowed_hosts: Vec<String>,
+    denied_hosts: Vec<String>,
+    denied_ranges: Vec<IpRange>,
+    resolve: bool,
+}
+
+impl Default for HostGuard {
+    fn default() -> Self {
+        HostGuard::new()
+    }
+}
+
+impl HostGuard {
+    /// Allows only http and https and blocks every private or local address.
+    pub fn new() -> Self {
+        Self::permissive().block_private_ranges()
+    }
+
+    /// Allows http and https to any host.
+    pub fn permissive() -> Self {
+        Self {
+            schemes: vec!["http".to_string(), "https".to_string()],
+            allowed_hosts: vec![],
+            denied_hosts: vec![],
+            denied_ranges: vec![],
+            resolve: true,
+        }
+    }
+
+    pub fn with_schemes(mut self, schemes: Vec<&str>) -> Self {
+        self.schemes = schemes.iter().map(|s| s.to_lowercase()).collect();
+        self
+    }
+
+    /// Only allows this host and its subdomains. Can be called multiple times.
+    pub fn allow_host(mut self, host: &str) -> Self {
+        self.allowed_hosts.push(host.to_lowercase());
+        self
+    }
+
+    /// Denies this host and its subdomains.
+    pub fn deny_host(mut self, host: &str) -> Self {
+        self.denied_hosts.push(host.to_lowercase());
+        self
+    }
+
+    /// Denies a CIDR range, e.g. `203.0.113.0/24`. Invalid ranges are ignored.
+    pub fn deny_range(mut self, range: &str) -> Self {
+        if let Some(range) = IpRange::parse(range) {
+            self.denied_ranges.push(range);
+        }
+        self
+    }
+
+    pub fn block_private_ranges(mut self) -> Self {
+        for range in PRIVATE_RANGES {
+            self = self.deny_range(range);
+        }
+        self
+    }
+
+    /// Disables DNS resolution, only checking literal IP hosts against the denied ranges.
+    pub fn without_resolving(mut self) -> Self {
+        self.resolve = false;
+        self
+    }
+
+    /// Checks the url without resolving hostnames.
+    pub fn check_url(&self, url: &str) -> Result<(Url, String), GuardViolation> {
+        let parsed = Url::parse(url).map_err(|_| GuardViolation::InvalidUrl(url.to_string()))?;
+        if !self.schemes.iter().any(|s| s == parsed.scheme()) {
+            return Err(GuardViolation::SchemeNotAllowed(
+                parsed.scheme().to_string(),
+            ));
+        }
+
+        let host = match parsed.host_str() {
+            Some(host) => host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_lowercase(),
+            None => return Err(GuardViolation::InvalidUrl(url.to_string())),
+        };
+
+        if self.denied_hosts.iter().any(|h| matches_host(&host, h)) {
+            return Err(GuardViolation::HostDenied(host));
+        }
+        if !self.allowed_hosts.is_empty()
+            && !self.allowed_hosts.iter().any(|h| matches_host(&host, h))
+        {
+            return Err(GuardViolation::HostNotAllowed(host));
+        }
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            self.check_ip(&host, ip)?;
+        }
+
+        Ok((parsed, host))
+    }
+
+    /// Checks the url and every address its host resolves to.
+    pub async fn check(&self, url: &str) -> Result<(), GuardViolation> {
+        let (parsed, host) = self.check_url(url)?;
+        if !self.resolve || host.parse::<IpAddr>().is_ok() {
+            return Ok(());
+        }
+
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        let addrs = tokio::net::lookup_host((host.as_str(), port))
+            .await
+            .map_err(|_| GuardViolation::ResolveFailed(host.clone()))?;
+        for addr in addrs {
+            self.check_ip(&host, addr.ip())?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether hostnames are resolved and their addresses checked, see `without_resolving`.
+    pub fn resolves(&self) -> bool {
+        self.resolve
+    }
+
+    /// Checks the address a url is sent to instead of its host's, see `Request::with_connect_to`.
+    pub fn check_addr(&self, url: &str, addr: SocketAddr) -> Result<(), GuardViolation> {
+        let (_, host) = self.check_url(url)?;
+        self.check_ip(&host, addr.ip())
+    }
+
+    pub(crate) fn check_ip(&self, host: &str, ip: IpAddr) -> Result<(), GuardViolation> {
+        if self.denied_ranges.iter().any(|r| r.contains(&ip)) {
+            Err(GuardViolation::AddressDenied(host.to_string(), ip))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn matches_host(host: &str, rule: &str) -> bool {
+    host == rule || host.ends_with(&format!(".{}", rule))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_match_ip_ranges() {
+        let range = IpRange::parse("172.16.0.0/12").unwrap();
+        assert!(range.contains(&"172.31.255.1".parse().unwrap()));
+        assert!(!range.contains(&"172.32.0.1".parse().unwrap()));
+        assert!(IpRange::parse("127.0.0.0/8")
+            .unwrap()
+            .contains(&"::ffff:127.0.0.1".parse().unwrap()));
+        let loopback = IpRange::parse("127.0.0.0/8").unwrap();
+        assert!(loopback.contains(&"64:ff9b::7f00:1".parse().unwrap()));
+        assert!(loopback.contains(&"2002:7f00:1::".parse().unwrap()));
+        assert!(!loopback.contains(&"64:ff9b::5db8:d822".parse().unwrap()));
+        assert!(!loopback.contains(&"2002:5db8:d822::1".parse().unwrap()));
+        assert!(IpRange::parse("10.0.0.0/33").is_none());
+    }
+
+    #[test]
+    fn it_should_refuse_private_addresses_and_schemes() {
+        let guard = HostGuard::new();
+        assert!(guard.check_url("https://93.184.216.34/").is_ok());
+        assert!(matches!(
+            guard.check_url("http://169.254.169.254/latest/meta-data"),
+            Err(GuardViolation::AddressDenied(_, _))
+        ));
+        assert!(matches!(
+            guard.check_url("http://[::1]:8080/"),
+            Err(GuardViolation::AddressDenied(_, _))
+        ));
+        assert!(matches!(
+            guard.check_url("file:///etc/passwd"),
+            Err(GuardViolation::SchemeNotAllowed(_))
+        ));
+    }
+
+    #[test]
+    fn it_should_apply_host_rules() {
+        let guard = HostGuard::permissive()
+            .allow_host("example.com")
+            .deny_host("admin.example.com");
+        assert!(guard.check_url("https://www.example.com/").is_ok());
+        assert_eq!(
+            guard.check_url("https://notexample.com/").unwrap_err(),
+            GuardViolation::HostNotAllowed("notexample.com".to_string())
+        );
+        assert!(matches!(
+            guard.check_url("https://x.admin.example.com/"),
+            Err(GuardViolation::HostDenied(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_should_check_resolved_addresses() {
+        let guard = HostGuard::new();
+        assert!(matches!(
+            guard.check("http://localhost:8080/").await,
+            Err(GuardViolation::AddressDenied(_, _))
+        ));
+        assert!(HostGuard::new()
+            .without_resolving()
+            .check("http://localhost:8080/")
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use scraper::{ElementRef, Html, Selector};
+use serde_json::{Map, Value};
+
+/// Machine readable data embedded in an HTML page.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StructuredData {
+    /// Every `<script type="application/ld+json">` block that parsed as JSON.
+    pub json_ld: Vec<Value>,
+    /// OpenGraph properties, e.g. `og:title`. Repeated properties keep the first value.
+    pub opengraph: BTreeMap<String, String>,
+    /// Other `<meta name=".." content="..">` tags, e.g. `description` or `twitter:card`.
+    pub meta: BTreeMap<String, String>,
+    /// Top level microdata items as JSON objects with an `@type` and their properties.
+    pub microdata: Vec<Value>,
+}
+
+impl StructuredData {
+    /// Returns true if the page didn't expose any structured data.
+    pub fn is_empty(&self) -> bool {
+        self.json_ld.is_empty()
+            && self.opengraph.is_empty()
+            && self.meta.is_empty()
+            && self.microdata.is_empty()
+    }
+
+    /// Returns every item as a single JSON object keyed by source.
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "json_ld": self.json_ld,
+            "opengraph": self.opengraph,
+            "meta": self.meta,
+            "microdata": self.microdata,
+        })
+    }
+}
+
+/// An element matched by a CSS selector, copied out of the parsed document.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HtmlElement {
+    /// The tag name, e.g. `a`.
+    pub name: String,
+    /// The text of the element and its descendants, with whitespace collapsed.
+    pub text: String,
+    pub inner_html: String,
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl HtmlElement {
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(|value| value.as_str())
+    }
+}
+
+/// Returns every element of an HTML document matching a CSS selector, in document order.
+pub fn select_all(
+    html: &str,
+    selector: &str,
+) -> Result<Vec<HtmlElement>, Box<dyn Error + Send + Sync>> {
+    let parsed = Selector::parse(selector).map_err(|err| {
+        std::io::Error::other(format!("invalid selector {:?}: {}", selector, err))
+    })?;
+    let document = Html::parse_document(html);
+
+    Ok(document
+        .select(&parsed)
+        .map(|element| HtmlElement {
+            name: element.value().name().to_string(),
+            text: collapse_whitespace(element),
+            inner_html: element.inner_html(),
+            attributes: element
+                .value()
+                .attrs()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        })
+        .collect())
+}
+
+/// Extracts JSON-LD, OpenGraph/meta tags and microdata from an HTML document.
+pub fn extract_structured_data(html: &str) -> StructuredData {
+    let document = Html::parse_document(html);
+    let mut data = StructuredData::default();
+
+    let json_ld = Selector::parse(r#"script[type="application/ld+json"]"#).unwrap();
+    for script in document.select(&json_ld) {
+        let text: String = script.text().collect();
+        match serde_json::from_str::<Value>(text.trim()) {
+            // a block may hold a list of objects or a @graph, flatten both
+            Ok(Value::Array(items)) => data.json_ld.extend(items),
+            Ok(value) => match value.get("@graph") {
+                Some(Value::Array(items)) => data.json_ld.extend(items.clone()),
+                _ => data.json_ld.push(value),
+            },
+            Err(_) => continue,
+        }
+    }
+
+    let meta = Selector::parse("meta[content]").unwrap();
+    for tag in document.select(&meta) {
+        let element = tag.value();
+        let content = element.attr("content").unwrap_or_default().to_string();
+        if let Some(property) = element.attr("property") {
+            if property.starts_with("og:") {
+                data.opengraph
+                    .entry(property.to_string())
+                    .or_insert(content);
+                continue;
+            }
+        }
+        if let Some(name) = element.attr("name").or(element.attr("property")) {
+            data.meta.entry(name.to_lowercase()).or_insert(content);
+        }
+    }
+
+    let items = Selector::parse("[itemscope]").unwrap();
+    for item in document.select(&items) {
+        if item.value().attr("itemprop").is_none() {
+            data.microdata.push(microdata_item(item));
+        }
+    }
+
+    data
+}
+
+fn microdata_item(item: ElementRef) -> Value {
+    let mut object = Map::new();
+    if let Some(item_type) = item.value().attr("itemtype") {
+        object.insert("@type".to_string(), Value::String(item_type.to_string()));
+    }
+
+    collect_properties(item, &mut object);
+    Value::Object(object)
+}
+
+/// Walks the item's descendants, stopping at nested items which own their own properties.
+fn collect_properties(element: ElementRef, object: &mut Map<String, Value>) {
+    for child in element.children().filter_map(ElementRef::wrap) {
+        let value = child.value();
+        let nested = value.attr("itemscope").is_some();
+
+        if let Some(props) = value.attr("itemprop") {
+            let property_value = if nested {
+                microdata_item(child)
+            } else {
+                Value::String(property_value(child))
+            };
+
+            for name in props.split_whitespace() {
+                match object.get_mut(name) {
+                    Some(Value::Array(values)) => values.push(property_value.clone()),
+                    Some(existing) => {
+                        *existing = Value::Array(vec![existing.clone(), property_value.clone()])
+                    }
+                    None => {
+                        object.insert(name.to_string(), property_value.clone());
+                    }
+                }
+            }
+        }
+
+        if !nested {
+            collect_properties(child, object);
+        }
+    }
+}
+
+fn property_value(element: ElementRef) -> String {
+    let value = element.value();
+    let attr = match value.name() {
+        "meta" => value.attr("content"),
+        "a" | "link" | "area" => value.attr("href"),
+        "img" | "audio" | "video" | "source" | "iframe" | "embed" => value.attr("src"),
+        "object" => value.attr("data"),
+        "time" => value.attr("datetime"),
+        "data" | "meter" => value.attr("value"),
+        _ => None,
+    };
+
+    match attr {
+        Some(attr) => attr.to_string(),
+        None => collapse_whitespace(element),
+    }
+}
+
+fn collapse_whitespace(element: ElementRef) -> String {
+    element
+        .text()
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRODUCT_PAGE: &str = r#"<html><head>
+        <meta property="og:title" content="Blue Shoes">
+        <meta property="og:image" content="https://example.com/shoes.jpg">
+        <meta name="Description" content="Comfortable shoes">
+        <meta name="twitter:card" content="summary">
+        <script type="application/ld+json">{"@context": "https://schema.org", "@type": "Product", "name": "Blue Shoes"}</script>
+        <script type="application/ld+json">{"@graph": [{"@type": "Organization"}, {"@type": "WebSite"}]}</script>
+        <script type="application/ld+json">{ broken json</script>
+    </head><body>
+        <div itemscope itemtype="https://schema.org/Product">
+            <span itemprop="name">Blue   Shoes</span>
+            <a itemprop="url" href="https://example.com/shoes">link</a>
+            <div itemprop="offers" itemscope itemtype="https://schema.org/Offer">
+                <meta itemprop="price" content="59.99">
+                <span itemprop="priceCurrency">USD</span>
+            </div>
+            <span itemprop="color">blue</span>
+            <span itemprop="color">navy</span>
+        </div>
+    </body></html>"#;
+
+    #[test]
+    fn it_should_extract_json_ld_blocks() {
+        let data = extract_structured_data(PRODUCT_PAGE);
+        assert_eq!(data.json_ld.len(), 3);
+        assert_eq!(data.json_ld[0]["name"], "Blue Shoes");
+        assert_eq!(data.json_ld[2]["@type"], "WebSite");
+    }
+
+    #[test]
+    fn it_should_extract_opengraph_and_meta_tags() {
+        let data = extract_structured_data(PRODUCT_PAGE);
+        assert_eq!(data.opengraph["og:title"], "Blue Shoes");
+        assert_eq!(data.opengraph.len(), 2);
+        assert_eq!(data.meta["description"], "Comfortable shoes");
+        assert_eq!(data.meta["twitter:card"], "summary");
+    }
+
+    #[test]
+    fn it_should_extract_nested_microdata() {
+        let data = extract_structured_data(PRODUCT_PAGE);
+        assert_eq!(data.microdata.len(), 1);
+
+        let product = &data.microdata[0];
+        assert_eq!(product["@type"], "https://schema.org/Product");
+        assert_eq!(product["name"], "Blue Shoes");
+        assert_eq!(product["url"], "https://example.com/shoes");
+        assert_eq!(product["offers"]["price"], "59.99");
+        assert_eq!(product["offers"]["priceCurrency"], "USD");
+        assert_eq!(product["color"], serde_json::json!(["blue", "navy"]));
+        assert!(product.get("priceCurrency").is_none());
+    }
+
+    #[test]
+    fn it_should_select_elements_by_css_selector() {
+        let links = select_all(PRODUCT_PAGE, "a[itemprop=url]").unwrap();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].name, "a");
+        assert_eq!(links[0].text, "link");
+        assert_eq!(links[0].attr("href"), Some("https://example.com/shoes"));
+
+        let offers = select_all(PRODUCT_PAGE, "[itemprop=offers]").unwrap();
+        assert_eq!(offers[0].text, "USD");
+        assert!(offers[0].inner_html.contains("content=\"59.99\""));
+
+        let colors = select_all(PRODUCT_PAGE, "span[itemprop=color]").unwrap();
+        let texts: Vec<&str> = colors.iter().map(|color| color.text.as_str()).collect();
+        assert_eq!(texts, vec!["blue", "navy"]);
+
+        assert!(select_all(PRODUCT_PAGE, "table").unwrap().is_empty());
+        assert!(select_all(PRODUCT_PAGE, "a[").is_err());
+    }
+
+    #[test]
+    fn it_should_return_empty_data_for_plain_pages() {
+        let data = extract_structured_data("<html><body><p>hello</p></body></html>");
+        assert!(data.is_empty());
+        assert_eq!(data.to_json()["json_ld"], serde_json::json!([]));
+    }
+}
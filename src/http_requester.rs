@@ -1,18 +1,57 @@
-use std::sync::Arc;
-use std::time::Duration;
-
-use reqwest::header::HeaderMap;
-use reqwest::{Body, Client, IntoUrl, Method, RequestBuilder, Response};
-use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use bytes::BytesMut;
+use hyper::client::connect::dns::Name;
+use reqwest::cookie::CookieStore;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, COOKIE, HOST, USER_AGENT};
+use reqwest::{Body, Client, IntoUrl, Method, RequestBuilder, Response, Url};
 
 // http_requester.rs
-use crate::client_settings::ClientSettings;
+use crate::cassette::Cassette;
+use crate::client_settings::{ClientSettings, IpFamily, IpPreference, SocketOptions};
+use crate::cookie_file::CookieFormat;
+use crate::cookie_jar::PartitionedCookieStore;
+use crate::har::{text_headers, HarRecorder, HarRequest};
+use crate::host_guard::HostGuard;
+use crate::redirect;
 use crate::request::Request;
+use crate::singleflight::{SharedError, SharedResult};
+
+/// The urls cache is cleared when it grows past this many entries.
+const MAX_CACHED_URLS: usize = 1024;
 
 #[derive(Clone)]
 pub struct HttpRequester {
-    cookie_store: Arc<CookieStoreMutex>,
+    cookie_store: Arc<PartitionedCookieStore>,
     pub settings: Box<ClientSettings>,
+    cache: Arc<Mutex<RequestCache>>,
+    cassette: Option<Arc<Cassette>>,
+    har: Option<Arc<HarRecorder>>,
+}
+
+/// The user agent, compression, socket options, IP preference and HTTP/1.1-only settings,
+/// whether the client follows redirects itself, and the address of its host guard.
+type ClientKey = (
+    Option<String>,
+    bool,
+    SocketOptions,
+    IpPreference,
+    bool,
+    bool,
+    Option<usize>,
+);
+
+/// The parts of a request that are expensive to build and identical across executions of a step.
+#[derive(Default)]
+struct RequestCache {
+    /// Clients without a proxy, by user agent and compression. Proxied clients are always built
+    /// because `Proxy` can't be compared, and two proxies may differ only by their credentials.
+    clients: HashMap<ClientKey, Client>,
+    urls: HashMap<String, Url>,
 }
 
 impl Default for HttpRequester {
@@ -29,15 +68,105 @@ impl HttpRequester {
         Self {
             cookie_store,
             settings: Box::new(settings),
+            cache: Arc::new(Mutex::new(RequestCache::default())),
+            cassette: None,
+            har: None,
+        }
+    }
+
+    /// Creates a requester with its own cookie jar and connection pool, configured like `settings`.
+    pub fn with_settings(settings: ClientSettings) -> Self {
+        Self {
+            settings: Box::new(settings),
+            ..Self::new()
+        }
+    }
+
+    /// Returns a client with all of the internal client settings, reusing a previously built one
+    /// when the settings match. Clients share the cookie store and their connection pool.
+    fn build_client(&self) -> Result<Client, reqwest::Error> {
+        self.client_for(self.settings.ip_preference(), true)
+    }
+
+    /// Clients that don't follow redirects leave them to `execute`, which keeps every hop.
+    fn client_for(
+        &self,
+        ip_preference: IpPreference,
+        follows_redirects: bool,
+    ) -> Result<Client, reqwest::Error> {
+        if self.settings.proxy().is_some() {
+            return self.new_client(ip_preference, None, follows_redirects);
+        }
+
+        let key = (
+            self.settings.user_agent().cloned(),
+            self.settings.is_compressed(),
+            *self.settings.socket_options(),
+            ip_preference,
+            self.settings.is_http1_only(),
+            follows_redirects,
+            // the cached client keeps its guard alive, so the address isn't reused
+            self.settings
+                .host_guard()
+                .map(|guard| Arc::as_ptr(guard) as usize),
+        );
+        if let Some(client) = self.cache.lock().unwrap().clients.get(&key) {
+            return Ok(client.clone());
         }
+
+        let client = self.new_client(ip_preference, None, follows_redirects)?;
+        self.cache
+            .lock()
+            .unwrap()
+            .clients
+            .insert(key, client.clone());
+        Ok(client)
+    }
+
+    /// Parses a url once, later requests to the same url reuse it.
+    fn parse_url(&self, url: &str) -> Option<Url> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(parsed) = cache.urls.get(url) {
+            return Some(parsed.clone());
+        }
+
+        let parsed = Url::parse(url).ok()?;
+        if cache.urls.len() >= MAX_CACHED_URLS {
+            cache.urls.clear();
+        }
+        cache.urls.insert(url.to_string(), parsed.clone());
+        Some(parsed)
     }
 
     /// Builds a client with all of the internal client settings.
     /// We are unable to attach proxies, gzip, etc. with a client that has already been initialized.
-    fn build_client(&self) -> Result<Client, reqwest::Error> {
+    /// `connect_to` pins a host to an address, see `Request::with_connect_to`.
+    fn new_client(
+        &self,
+        ip_preference: IpPreference,
+        connect_to: Option<(&str, SocketAddr)>,
+        follows_redirects: bool,
+    ) -> Result<Client, reqwest::Error> {
         let mut builder = Client::builder()
             .cookie_provider(std::sync::Arc::clone(&self.cookie_store))
             .gzip(self.settings.is_compressed());
+        if !follows_redirects {
+            builder = builder.redirect(reqwest::redirect::Policy::none());
+        }
+
+        let socket = self.settings.socket_options();
+        builder = builder
+            .tcp_nodelay(socket.nodelay)
+            .tcp_keepalive(socket.keepalive);
+        if let Some(timeout) = socket.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(timeout) = socket.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(max) = socket.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
 
         if let Some(proxy) = self.settings.proxy() {
             builder = builder.proxy(proxy.clone());
@@ -47,6 +176,27 @@ impl HttpRequester {
             builder = builder.user_agent(ua.clone());
         }
 
+        if self.settings.is_http1_only() {
+            builder = builder.http1_only();
+        }
+
+        // a proxy resolves the hosts it connects to
+        let guard = self
+            .settings
+            .host_guard()
+            .filter(|guard| guard.resolves() && self.settings.proxy().is_none())
+            .cloned();
+        if ip_preference != IpPreference::Any || guard.is_some() {
+            builder = builder.dns_resolver(Arc::new(Resolver {
+                preference: ip_preference,
+                guard,
+            }));
+        }
+
+        if let Some((host, addr)) = connect_to {
+            builder = builder.resolve(host, addr);
+        }
+
         builder.build()
     }
 
@@ -79,13 +229,26 @@ impl HttpRequester {
         Ok(res)
     }
 
-    /// Sends a request with all of the internal client settings.
+    /// Sends a request with all of the internal client settings. Its redirects are followed by
+    /// `execute`, or by the download streaming it.
     pub fn build_reqwest(&self, req: Request) -> Result<RequestBuilder, reqwest::Error> {
-        let client = &self.build_client()?;
-
-        let mut client = client
-            .request(req.method(), req.url())
-            .timeout(Duration::new(30, 0));
+        let ip_preference = req.ip_preference().unwrap_or(self.settings.ip_preference());
+        let url = self.parse_url(req.url());
+        // clients pinned to an address aren't cached, their pool only serves that address
+        let pinned = req
+            .connect_to()
+            .zip(url.as_ref().and_then(|url| url.host_str()));
+        let client = &match pinned {
+            Some((addr, host)) => self.new_client(ip_preference, Some((host, addr)), false)?,
+            None => self.client_for(ip_preference, false)?,
+        };
+
+        let mut client = match url {
+            Some(url) => client.request(req.method(), url),
+            // let reqwest report the invalid url when the request is sent
+            None => client.request(req.method(), req.url()),
+        }
+        .timeout(Duration::new(30, 0));
 
         match req.timeout() {
             Some(to) => client = client.timeout(to),
@@ -95,35 +258,249 @@ impl HttpRequester {
         if let Some(h) = req.headers() {
             client = client.headers(h);
         }
+        if let Some(host) = req
+            .host_header()
+            .and_then(|h| HeaderValue::from_str(h).ok())
+        {
+            client = client.header(HOST, host);
+        }
         if let Some(b) = req.body() {
             client = client.body(b);
         }
-        if let Some(f) = req.multipart() {
-            client = client.multipart(f);
+        let has_content_type = req.headers().is_some_and(|h| h.contains_key(CONTENT_TYPE));
+        if let Some(content_type) = req.content_type().filter(|_| !has_content_type) {
+            client = client.header(CONTENT_TYPE, content_type);
+        }
+        if let Some(form) = req.multipart_form() {
+            client = match form.encode() {
+                Some((content_type, body)) => client.header(CONTENT_TYPE, content_type).body(body),
+                None => client.multipart(form.clone().into()),
+            };
         }
 
         Ok(client)
     }
 
+    /// Opens a connection to each origin ahead of time with a `HEAD` request, so the first real
+    /// request skips the DNS, TCP and TLS handshakes. HTTP/2 is used when the server negotiates it.
+    /// Connections are only reused by requests without a proxy, see `client_for`.
+    /// Returns the first error, after every origin was tried.
+    pub async fn prewarm(&self, origins: &[&str]) -> Result<(), reqwest::Error> {
+        // the client `build_reqwest` picks, so its pool keeps the connections
+        let client = self.client_for(self.settings.ip_preference(), false)?;
+        let mut tasks = tokio::task::JoinSet::new();
+        for origin in origins {
+            let request = client.head(*origin).timeout(Duration::new(30, 0));
+            tasks.spawn(async move { request.send().await.map(|_| ()) });
+        }
+
+        let mut result = Ok(());
+        while let Some(joined) = tasks.join_next().await {
+            if let Ok(Err(err)) = joined {
+                result = result.and(Err(err));
+            }
+        }
+        result
+    }
+
+    /// Records the requests sent by the worker to a cassette, or replays them from it.
+    pub fn set_cassette(&mut self, cassette: Option<Arc<Cassette>>) {
+        self.cassette = cassette;
+    }
+
+    pub fn cassette(&self) -> Option<Arc<Cassette>> {
+        self.cassette.clone()
+    }
+
+    /// Records every request sent by the worker and its response, see `HarRecorder`.
+    pub fn set_har_recorder(&mut self, recorder: Option<Arc<HarRecorder>>) {
+        self.har = recorder;
+    }
+
+    pub fn har_recorder(&self) -> Option<Arc<HarRecorder>> {
+        self.har.clone()
+    }
+
+    /// Sends a built request and reads its response, following its redirects with the
+    /// `RedirectPolicy` of the settings. The hops are kept in the response's `redirects`. Every
+    /// hop is checked by the guard before it's followed.
+    pub(crate) async fn execute(
+        &self,
+        req_builder: RequestBuilder,
+        buffer: &mut BytesMut,
+        guard: Option<&HostGuard>,
+    ) -> SharedResult {
+        let (client, req) = req_builder.build_split();
+        let mut req = req.map_err(|err| SharedError::from_reqwest(&err))?;
+        let policy = self.settings.redirect_policy();
+        let mut hops = vec![];
+        loop {
+            // kept to send again if redirected, requests with a streamed body can't be
+            let next = req.try_clone();
+            let mut res = self
+                .send_hop(RequestBuilder::from_parts(client.clone(), req), buffer)
+                .await?;
+            match redirect::next_request(policy, guard, &mut hops, &res.info, next).await {
+                Ok(Some(next)) => req = next,
+                Ok(None) => {
+                    res.info = res.info.with_redirects(hops);
+                    return Ok(res);
+                }
+                Err(error) => {
+                    let err = SharedError {
+                        error,
+                        partial: None,
+                        connecting: false,
+                    };
+                    res.info = res.info.with_redirects(hops);
+                    return Err(err.with_partial(res.info, res.body));
+                }
+            }
+        }
+    }
+
+    /// Sends a single request, through the cassette if there is one, and records it to the HAR
+    /// recorder.
+    async fn send_hop(&self, req_builder: RequestBuilder, buffer: &mut BytesMut) -> SharedResult {
+        let Some(har) = &self.har else {
+            return self.send(req_builder, buffer).await;
+        };
+
+        let (client, req) = req_builder.build_split();
+        let req = req.map_err(|err| SharedError::from_reqwest(&err))?;
+        let request = self.har_request(&req);
+        let started = SystemTime::now();
+        let stop_watch = std::time::Instant::now();
+        let result = self
+            .send(RequestBuilder::from_parts(client, req), buffer)
+            .await;
+        har.record(request, started, stop_watch.elapsed(), &result);
+        result
+    }
+
+    /// Describes a request as it is sent, with the user agent and cookies the client adds.
+    fn har_request(&self, req: &reqwest::Request) -> HarRequest {
+        let mut headers = text_headers(req.headers());
+        let has = |headers: &[(String, String)], name: &str| {
+            headers
+                .iter()
+                .any(|(header, _)| header.eq_ignore_ascii_case(name))
+        };
+        if let (Some(user_agent), false) = (
+            self.settings.user_agent(),
+            has(&headers, USER_AGENT.as_str()),
+        ) {
+            headers.push((USER_AGENT.to_string(), user_agent.clone()));
+        }
+        if !has(&headers, COOKIE.as_str()) {
+            if let Some(cookies) = self.cookie_store.cookies(req.url()) {
+                let cookies = String::from_utf8_lossy(cookies.as_bytes()).into_owned();
+                headers.push((COOKIE.to_string(), cookies));
+            }
+        }
+
+        HarRequest {
+            method: req.method().to_string(),
+            url: req.url().to_string(),
+            http_version: format!("{:?}", req.version()),
+            headers,
+            body: req
+                .body()
+                .and_then(|body| body.as_bytes())
+                .map(|body| String::from_utf8_lossy(body).into_owned()),
+        }
+    }
+
+    async fn send(&self, req_builder: RequestBuilder, buffer: &mut BytesMut) -> SharedResult {
+        match &self.cassette {
+            Some(cassette) => cassette.fetch(req_builder, buffer).await,
+            None => crate::worker::fetch(req_builder, buffer).await,
+        }
+    }
+
     // Method to get cookies as JSON string
     pub fn get_cookies(&self) -> Vec<u8> {
-        let mut buffer: Vec<u8> = Vec::new();
-        let store = self.cookie_store.lock().unwrap();
-        store.save_json(&mut buffer).unwrap();
-        buffer
+        self.cookie_store.export_all()
+    }
+
+    /// Writes every unexpired cookie to a file, e.g. a `cookies.txt` for curl.
+    pub fn save_cookies(
+        &self,
+        path: impl AsRef<std::path::Path>,
+        format: CookieFormat,
+    ) -> std::io::Result<()> {
+        std::fs::write(path, self.cookie_store.export_cookies(format))
+    }
+
+    /// Adds the cookies of a file to the jar, e.g. a session exported from a browser or saved by
+    /// a prior run with `save_cookies`. Returns the number of cookies loaded.
+    pub fn load_cookies(
+        &self,
+        path: impl AsRef<std::path::Path>,
+        format: CookieFormat,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let text = std::fs::read_to_string(path)?;
+        self.cookie_store.import_cookies(&text, format)
+    }
+
+    /// Returns the cookie store, which is partitioned by registrable domain.
+    pub fn cookie_jar(&self) -> Arc<PartitionedCookieStore> {
+        self.cookie_store.clone()
     }
 }
 
-fn new_cookie_store() -> Arc<CookieStoreMutex> {
-    let cookie_store = CookieStoreMutex::new(CookieStore::new(None));
-    Arc::new(cookie_store)
+/// Resolves hosts with the system resolver, keeping or ordering the addresses by the preferred
+/// family, and refusing hosts that resolve to an address the guard blocks. Connections go to the checked addresses, so a host can't resolve
+/// to a public address for the guard and to a private one for the connection (DNS rebinding).
+struct Resolver {
+    preference: IpPreference,
+    guard: Option<Arc<HostGuard>>,
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let preference = self.preference;
+        let guard = self.guard.clone();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if let Some(guard) = guard {
+                for addr in &addrs {
+                    guard.check_ip(name.as_str(), addr.ip())?;
+                }
+            }
+            let addrs = order_by_family(preference, addrs);
+            if addrs.is_empty() {
+                return Err(format!("{} has no address of the preferred family", name).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn order_by_family(preference: IpPreference, mut addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    match preference {
+        IpPreference::Any => {}
+        IpPreference::V4Only => addrs.retain(|a| a.is_ipv4()),
+        IpPreference::V6Only => addrs.retain(|a| a.is_ipv6()),
+        // stable, so the resolver's order is kept within each family
+        IpPreference::Prefer(IpFamily::V4) => addrs.sort_by_key(|a| a.is_ipv6()),
+        IpPreference::Prefer(IpFamily::V6) => addrs.sort_by_key(|a| a.is_ipv4()),
+    }
+    addrs
+}
+
+fn new_cookie_store() -> Arc<PartitionedCookieStore> {
+    Arc::new(PartitionedCookieStore::new())
 }
 
 #[cfg(test)]
 mod tests {
     use crate::request::{MimicBody, MimicForm};
+    use crate::StepError;
     use reqwest::header::HeaderValue;
     use reqwest::Proxy;
+    use std::path::Path;
 
     use super::*;
 
@@ -267,6 +644,333 @@ mod tests {
         }
     }
 
+    #[test]
+    fn it_should_reuse_clients_and_urls_with_the_same_settings() {
+        let mut http = HttpRequester::new();
+        let req = Request::new(Method::GET, "https://example.com/search".to_string());
+        let _ = http.build_reqwest(req.clone()).unwrap();
+        let _ = http.build_reqwest(req.clone()).unwrap();
+        assert_eq!(http.cache.lock().unwrap().clients.len(), 1);
+        assert_eq!(http.cache.lock().unwrap().urls.len(), 1);
+
+        http.settings.set_user_agent(Some("agent".to_string()));
+        let _ = http.build_reqwest(req.clone()).unwrap();
+        assert_eq!(http.cache.lock().unwrap().clients.len(), 2);
+
+        http.settings
+            .set_proxy(Some(Proxy::http("https://secure.example").unwrap()));
+        let _ = http.build_reqwest(req).unwrap();
+        assert_eq!(http.cache.lock().unwrap().clients.len(), 2);
+    }
+
+    #[test]
+    fn it_should_apply_socket_options() {
+        let mut http = HttpRequester::new();
+        http.settings
+            .set_tcp_nodelay(false)
+            .set_tcp_keepalive(Some(Duration::from_secs(15)))
+            .set_connect_timeout(Some(Duration::from_secs(5)));
+
+        let _ = http.build_client().unwrap();
+        assert!(!http.settings.socket_options().nodelay);
+
+        http.settings.set_tcp_nodelay(true);
+        let _ = http.build_client().unwrap();
+        assert_eq!(http.cache.lock().unwrap().clients.len(), 2);
+    }
+
+    #[test]
+    fn it_should_order_addresses_by_family() {
+        let v4: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let v6: SocketAddr = "[::1]:0".parse().unwrap();
+        let addrs = vec![v6, v4];
+
+        assert_eq!(order_by_family(IpPreference::Any, addrs.clone()), addrs);
+        assert_eq!(
+            order_by_family(IpPreference::V4Only, addrs.clone()),
+            vec![v4]
+        );
+        assert_eq!(
+            order_by_family(IpPreference::V6Only, addrs.clone()),
+            vec![v6]
+        );
+        assert_eq!(
+            order_by_family(IpPreference::Prefer(IpFamily::V4), addrs.clone()),
+            vec![v4, v6]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_pin_the_address_family_per_request() {
+        let server =
+            crate::test_server::TestServer::start(|_| crate::test_server::TestResponse::ok("ok"))
+                .await;
+        let url = server.url("/").replace("127.0.0.1", "localhost");
+
+        let mut http = HttpRequester::new();
+        http.settings.set_ip_preference(IpPreference::V6Only);
+        let req = Request::new(Method::GET, url);
+        assert!(http
+            .build_reqwest(req.clone())
+            .unwrap()
+            .send()
+            .await
+            .is_err());
+
+        let req = req.with_ip_preference(IpPreference::V4Only);
+        assert!(http.build_reqwest(req).unwrap().send().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn it_should_override_the_host_and_the_connected_address() {
+        let server = crate::test_server::TestServer::start(|req| {
+            crate::test_server::TestResponse::ok(req.header("host").unwrap_or_default())
+        })
+        .await;
+        let addr: SocketAddr = server
+            .url("")
+            .trim_start_matches("http://")
+            .parse()
+            .unwrap();
+        let http = HttpRequester::new();
+
+        let req = Request::new(Method::GET, server.url("/")).with_host_header("admin.internal");
+        let res = http.build_reqwest(req).unwrap().send().await.unwrap();
+        assert_eq!(res.text().await.unwrap(), "admin.internal");
+
+        let url = format!("http://shop.example:{}/", addr.port());
+        let req = Request::new(Method::GET, url).with_connect_to(addr);
+        let res = http.build_reqwest(req).unwrap().send().await.unwrap();
+        assert_eq!(
+            res.text().await.unwrap(),
+            format!("shop.example:{}", addr.port())
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_send_origin_form_to_the_connected_address() {
+        let server = crate::test_server::TestServer::start(|req| {
+            crate::test_server::TestResponse::ok(&req.path)
+        })
+        .await;
+        let addr: SocketAddr = server
+            .url("")
+            .trim_start_matches("http://")
+            .parse()
+            .unwrap();
+        let mut http = HttpRequester::new();
+        let url = format!("http://shop.example:{}/item?id=1", addr.port());
+
+        let req = Request::new(Method::GET, url.clone()).with_connect_to(addr);
+        let res = http.build_reqwest(req).unwrap().send().await.unwrap();
+        assert_eq!(res.text().await.unwrap(), "/item?id=1");
+
+        http.settings
+            .set_proxy(Some(Proxy::http(server.url("")).unwrap()));
+        let req = Request::new(Method::GET, url.clone());
+        let res = http.build_reqwest(req).unwrap().send().await.unwrap();
+        assert_eq!(res.text().await.unwrap(), url);
+    }
+
+    #[tokio::test]
+    async fn it_should_only_connect_to_addresses_the_guard_allows() {
+        let server =
+            crate::test_server::TestServer::start(|_| crate::test_server::TestResponse::ok(""))
+                .await;
+        let port = server.url("").rsplit(':').next().unwrap().to_string();
+        let url = format!("http://localhost:{}/", port);
+        let mut http = HttpRequester::new();
+        http.settings
+            .set_ip_preference(IpPreference::V4Only)
+            .set_host_guard(Some(Arc::new(HostGuard::new())));
+
+        // `localhost` is resolved to connect, even though the guard didn't check it
+        let builder = http.build_reqwest(Request::new(Method::GET, url.clone()));
+        let err = http
+            .execute(builder.unwrap(), &mut BytesMut::new(), None)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.error, StepError::BlockedHost(_)),
+            "{:?}",
+            err.error
+        );
+        assert_eq!(server.hits(), 0);
+
+        http.settings
+            .set_host_guard(Some(Arc::new(HostGuard::permissive())));
+        let builder = http.build_reqwest(Request::new(Method::GET, url));
+        http.execute(builder.unwrap(), &mut BytesMut::new(), None)
+            .await
+            .unwrap();
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_send_custom_methods_and_probe_options() {
+        let server = crate::test_server::TestServer::start(|req| match req.method.as_str() {
+            "OPTIONS" => crate::test_server::TestResponse::status(204, "")
+                .with_header("Allow", "GET, OPTIONS, PROPFIND, PURGE")
+                .with_header("Access-Control-Allow-Origin", "https://app.example")
+                .with_header("Access-Control-Allow-Methods", "PROPFIND"),
+            method => crate::test_server::TestResponse::ok(method),
+        })
+        .await;
+
+        let http = HttpRequester::new();
+        for method in ["PROPFIND", "PURGE", "X-REINDEX", "TRACE"] {
+            let req = Request::custom(method, server.url("/dav")).unwrap();
+            let res = http.build_reqwest(req).unwrap().send().await.unwrap();
+            assert_eq!(res.text().await.unwrap(), method);
+        }
+        assert!(Request::custom("NOT A METHOD", server.url("/")).is_err());
+
+        let ctx = crate::Context::new();
+        let propfind = Method::from_bytes(b"PROPFIND").unwrap();
+        let info = ctx
+            .probe_options(
+                &server.url("/dav"),
+                Some("https://app.example"),
+                Some(&propfind),
+            )
+            .await
+            .unwrap();
+        assert_eq!(info.status(), 204);
+        assert!(info.allow().contains(&propfind));
+        assert!(info.cors().allows_method(&propfind));
+        assert!(info.cors().allows_origin("https://app.example"));
+        let sent = &server.requests()[4];
+        assert_eq!(sent.header("origin"), Some("https://app.example"));
+        assert_eq!(
+            sent.header("access-control-request-method"),
+            Some("PROPFIND")
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_not_probe_the_options_of_hosts_the_guard_blocks() {
+        let mut ctx = crate::Context::new();
+        ctx.get_client_settings_mut()
+            .set_host_guard(Some(Arc::new(HostGuard::new())));
+        let err = ctx
+            .probe_options("http://169.254.169.254/latest", None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StepError::BlockedHost(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn it_should_set_the_content_type_of_typed_bodies() {
+        let server =
+            crate::test_server::TestServer::start(|_| crate::test_server::TestResponse::ok(""))
+                .await;
+        let http = HttpRequester::new();
+
+        let order = serde_json::json!({"sku": "A-1", "quantity": 2});
+        let requests = [
+            Request::new(Method::POST, server.url("/orders"))
+                .with_json(&order)
+                .unwrap(),
+            Request::new(Method::PUT, server.url("/profile"))
+                .with_form(&[("name", "Jane Doe"), ("city", "São Paulo")]),
+            Request::new(Method::PATCH, server.url("/avatar"))
+                .with_body_bytes(vec![0x89, 0x50], "image/png"),
+        ];
+        for req in requests {
+            http.build_reqwest(req).unwrap().send().await.unwrap();
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, "application/vnd.api+json".parse().unwrap());
+        let req = Request::new(Method::POST, server.url("/orders"))
+            .with_json(&order)
+            .unwrap()
+            .with_headers(headers);
+        http.build_reqwest(req).unwrap().send().await.unwrap();
+
+        let sent = server.requests();
+        assert_eq!(sent[0].header("content-type"), Some("application/json"));
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&sent[0].body).unwrap(),
+            order
+        );
+        assert_eq!(
+            sent[1].header("content-type"),
+            Some("application/x-www-form-urlencoded")
+        );
+        assert_eq!(sent[1].body, b"name=Jane+Doe&city=S%C3%A3o+Paulo");
+        assert_eq!(sent[2].header("content-type"), Some("image/png"));
+        assert_eq!(sent[2].body, vec![0x89, 0x50]);
+        assert_eq!(
+            sent[3].header("content-type"),
+            Some("application/vnd.api+json")
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_upload_multipart_files() {
+        let server =
+            crate::test_server::TestServer::start(|_| crate::test_server::TestResponse::ok("ok"))
+                .await;
+        let path = std::env::temp_dir().join("mimicr-upload-test.csv");
+        std::fs::write(&path, "id,name\n1,jane\n").unwrap();
+
+        let form = MimicForm::default()
+            .with_text("title", "report")
+            .with_file("upload", &path)
+            .unwrap()
+            .with_bytes(
+                "avatar",
+                vec![0x89, b'P', b'N', b'G'],
+                "me.png",
+                "image/png",
+            );
+        assert_eq!(form.names(), vec!["title", "upload", "avatar"]);
+        assert!(MimicForm::default()
+            .with_file("missing", Path::new("/does/not/exist.csv"))
+            .is_err());
+
+        let req = Request::new(Method::POST, server.url("/upload")).with_multipart(form);
+        let http = HttpRequester::new();
+        http.build_reqwest(req).unwrap().send().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let sent = &server.requests()[0];
+        assert!(sent
+            .header("content-type")
+            .unwrap()
+            .starts_with("multipart/form-data; boundary="));
+        let body = String::from_utf8_lossy(&sent.body);
+        assert!(body.contains("name=\"title\"\r\n\r\nreport"));
+        assert!(body.contains(
+            "name=\"upload\"; filename=\"mimicr-upload-test.csv\"\r\nContent-Type: text/csv\r\n\r\nid,name\n1,jane\n"
+        ));
+        assert!(body.contains("filename=\"me.png\"\r\nContent-Type: image/png"));
+    }
+
+    #[tokio::test]
+    async fn it_should_encode_multipart_forms_in_a_dialect() {
+        let server =
+            crate::test_server::TestServer::start(|_| crate::test_server::TestResponse::ok("ok"))
+                .await;
+        let form = MimicForm::default()
+            .with_bytes("photo", vec![1, 2], "cat.jpg", "image/jpeg")
+            .with_text("caption", "hi")
+            .with_dialect(crate::MultipartDialect::Firefox);
+        let req = Request::new(Method::POST, server.url("/upload")).with_multipart(form);
+        let http = HttpRequester::new();
+        http.build_reqwest(req).unwrap().send().await.unwrap();
+
+        let sent = &server.requests()[0];
+        let content_type = sent.header("content-type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/form-data; boundary=----geckoformboundary")
+            .unwrap();
+        let body = String::from_utf8_lossy(&sent.body);
+        assert!(body.starts_with(&format!("------geckoformboundary{}\r\n", boundary)));
+        assert!(body.contains("filename=\"cat.jpg\"\r\nContent-Type: image/jpeg\r\n\r\n"));
+        assert!(body.ends_with(&format!("hi\r\n------geckoformboundary{}--\r\n", boundary)));
+    }
+
     #[test]
     fn it_should_build_a_request_using_new() {
         let http = HttpRequester::new();
@@ -0,0 +1,282 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::Proxy;
+use tokio::time::Instant;
+
+/// A proxy and/or user agent that requests can be sent as.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    id: String,
+    proxy: Option<Proxy>,
+    proxy_provider: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Identity {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            proxy: None,
+            proxy_provider: None,
+            user_agent: None,
+        }
+    }
+
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Names the provider of the identity's proxy, see `ProxyAccounting`.
+    pub fn with_proxy_provider(mut self, provider: &str) -> Self {
+        self.proxy_provider = Some(provider.to_string());
+        self
+    }
+
+    pub fn with_user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = Some(user_agent.to_string());
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn proxy(&self) -> Option<Proxy> {
+        self.proxy.clone()
+    }
+
+    pub fn proxy_provider(&self) -> Option<&str> {
+        self.proxy_provider.as_deref()
+    }
+
+    pub fn user_agent(&self) -> Option<String> {
+        self.user_agent.clone()
+    }
+}
+
+/// A snapshot of an identity's health.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdentityScore {
+    pub id: String,
+    /// The recency weighted success rate, between 0 and 1. Identities without history score 0.5.
+    pub score: f64,
+    pub successes: u64,
+    pub failures: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    identity: Identity,
+    // decayed outcome weights, halved every `half_life`
+    success_weight: f64,
+    failure_weight: f64,
+    successes: u64,
+    failures: u64,
+    updated: Instant,
+}
+
+impl Entry {
+    fn decay(&mut self, now: Instant, half_life: Duration) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let factor = 0.5f64.powf(elapsed / half_life.as_secs_f64().max(f64::EPSILON));
+        self.success_weight *= factor;
+        self.failure_weight *= factor;
+        self.updated = now;
+    }
+
+    fn score(&self) -> f64 {
+        (self.success_weight + 1.0) / (self.success_weight + self.failure_weight + 2.0)
+    }
+}
+
+/// A pool of identities that tracks an exponentially decaying success score for each one and
+/// picks healthy identities more often. Old outcomes lose half their weight every `half_life`,
+/// so a burned proxy recovers slowly instead of being reused at full rate.
+/// Share it between workers with an `Arc`.
+#[derive(Debug)]
+pub struct IdentityPool {
+    half_life: Duration,
+    min_score: f64,
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl IdentityPool {
+    /// Creates a pool with a 10 minute half-life.
+    pub fn new(identities: Vec<Identity>) -> Self {
+        let now = Instant::now();
+        let entries = identities
+            .into_iter()
+            .map(|identity| Entry {
+                identity,
+                success_weight: 0.0,
+                failure_weight: 0.0,
+                successes: 0,
+                failures: 0,
+                updated: now,
+            })
+            .collect();
+
+        Self {
+            half_life: Duration::from_secs(600),
+            min_score: 0.01,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Sets how long it takes for an outcome to lose half of its weight.
+    pub fn with_half_life(mut self, half_life: Duration) -> Self {
+        self.half_life = half_life;
+        self
+    }
+
+    /// Identities scoring below `min_score` are still picked, as if they scored `min_score`,
+    /// so they get a chance to recover.
+    pub fn with_min_score(mut self, min_score: f64) -> Self {
+        self.min_score = min_score.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, id: &str) -> Option<Identity> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .find(|e| e.identity.id == id)
+            .map(|e| e.identity.clone())
+    }
+
+    /// Picks an identity at random, weighted by score.
+    pub fn select(&self) -> Option<Identity> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        entries
+            .iter_mut()
+            .for_each(|entry| entry.decay(now, self.half_life));
+
+        let weights: Vec<f64> = entries
+            .iter()
+            .map(|entry| entry.score().max(self.min_score))
+            .collect();
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return entries.first().map(|entry| entry.identity.clone());
+        }
+
+        let mut pick = rand::thread_rng().gen_range(0.0..total);
+        for (entry, weight) in entries.iter().zip(weights) {
+            if pick < weight {
+                return Some(entry.identity.clone());
+            }
+            pick -= weight;
+        }
+        entries.last().map(|entry| entry.identity.clone())
+    }
+
+    pub fn record_success(&self, id: &str) {
+        self.record(id, true);
+    }
+
+    pub fn record_failure(&self, id: &str) {
+        self.record(id, false);
+    }
+
+    fn record(&self, id: &str, success: bool) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.iter_mut().find(|e| e.identity.id == id) {
+            entry.decay(Instant::now(), self.half_life);
+            if success {
+                entry.success_weight += 1.0;
+                entry.successes += 1;
+            } else {
+                entry.failure_weight += 1.0;
+                entry.failures += 1;
+            }
+        }
+    }
+
+    /// Returns the current score of every identity, in insertion order.
+    pub fn scores(&self) -> Vec<IdentityScore> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        entries
+            .iter_mut()
+            .map(|entry| {
+                entry.decay(now, self.half_life);
+                IdentityScore {
+                    id: entry.identity.id.clone(),
+                    score: entry.score(),
+                    successes: entry.successes,
+                    failures: entry.failures,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool() -> IdentityPool {
+        IdentityPool::new(vec![
+            Identity::new("healthy").with_user_agent("ua-1"),
+            Identity::new("burned").with_proxy(Proxy::all("http://127.0.0.1:8080").unwrap()),
+        ])
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_score_identities_by_outcome() {
+        let pool = pool();
+        assert_eq!(pool.scores()[0].score, 0.5);
+
+        for _ in 0..10 {
+            pool.record_success("healthy");
+            pool.record_failure("burned");
+        }
+
+        let scores = pool.scores();
+        assert!(scores[0].score > 0.9);
+        assert!(scores[1].score < 0.1);
+        assert_eq!(scores[1].failures, 10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_bias_selection_toward_healthy_identities() {
+        let pool = pool();
+        for _ in 0..20 {
+            pool.record_success("healthy");
+            pool.record_failure("burned");
+        }
+
+        let healthy = (0..1000)
+            .filter(|_| pool.select().unwrap().id() == "healthy")
+            .count();
+        assert!(healthy > 900, "healthy picked {} times", healthy);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_forget_old_outcomes() {
+        let pool = pool().with_half_life(Duration::from_secs(60));
+        for _ in 0..10 {
+            pool.record_failure("burned");
+        }
+        let before = pool.scores()[1].score;
+
+        tokio::time::advance(Duration::from_secs(600)).await;
+        let after = pool.scores()[1].score;
+
+        assert!(before < 0.1);
+        assert!(after > 0.49, "score only recovered to {}", after);
+        assert_eq!(pool.scores()[1].failures, 10);
+    }
+}
@@ -0,0 +1,289 @@
+use std::error::Error;
+
+use serde_json::Value;
+
+/// One step of a JSONPath, e.g. `.items`, `[*]` or `..id`.
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    /// Whether it matches at any depth (`..`) instead of the children only.
+    descendants: bool,
+    selector: Selector,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Selector {
+    Wildcard,
+    /// Member names, `.name` or `['a','b']`.
+    Names(Vec<String>),
+    /// Array indices, negative ones counting from the end.
+    Indices(Vec<i64>),
+    /// `[start:end]`, with the same negative indices.
+    Slice(Option<i64>, Option<i64>),
+}
+
+/// Returns the values a JSONPath like `$.data.items[*].id` selects, in document order. Supported
+/// are member names (`.name`, `['name']`), indices and unions (`[0]`, `[-1]`, `[0,2]`),
+/// slices (`[1:3]`), wildcards (`.*`, `[*]`) and recursive descent (`..name`). Filters and
+/// scripts aren't. Paths that select nothing return no values, invalid ones an error.
+pub fn json_path<'a>(
+    value: &'a Value,
+    path: &str,
+) -> Result<Vec<&'a Value>, Box<dyn Error + Send + Sync>> {
+    let mut nodes = vec![value];
+    for segment in parse(path)? {
+        if segment.descendants {
+            nodes = nodes.into_iter().flat_map(descendants).collect();
+        }
+        nodes = nodes
+            .into_iter()
+            .flat_map(|node| select(node, &segment.selector))
+            .collect();
+    }
+    Ok(nodes)
+}
+
+fn parse(path: &str) -> Result<Vec<Segment>, Box<dyn Error + Send + Sync>> {
+    let invalid = |reason: &str| format!("invalid JSONPath `{}`: {}", path, reason);
+    let mut rest = path
+        .trim()
+        .strip_prefix('$')
+        .ok_or_else(|| invalid("it doesn't start with `$`"))?;
+
+    let mut segments = vec![];
+    while !rest.is_empty() {
+        let descendants = rest.starts_with("..");
+        let after_dots = if descendants {
+            &rest[2..]
+        } else {
+            rest.strip_prefix('.').unwrap_or(rest)
+        };
+        let dotted = descendants || rest.starts_with('.');
+
+        let (selector, after) = if let Some(bracket) = after_dots.strip_prefix('[') {
+            let end = closing_bracket(bracket).ok_or_else(|| invalid("unclosed `[`"))?;
+            let selector = parse_bracket(&bracket[..end]).map_err(|reason| invalid(&reason))?;
+            (selector, &bracket[end + 1..])
+        } else if !dotted {
+            return Err(invalid("expected `.` or `[`").into());
+        } else if let Some(after) = after_dots.strip_prefix('*') {
+            (Selector::Wildcard, after)
+        } else {
+            let end = after_dots.find(['.', '[']).unwrap_or(after_dots.len());
+            if end == 0 {
+                return Err(invalid("empty member name").into());
+            }
+            let name = after_dots[..end].to_string();
+            (Selector::Names(vec![name]), &after_dots[end..])
+        };
+        segments.push(Segment {
+            descendants,
+            selector,
+        });
+        rest = after;
+    }
+    Ok(segments)
+}
+
+/// Returns the position of the `]` closing a bracket, skipping quoted names.
+fn closing_bracket(text: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ']') => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_bracket(text: &str) -> Result<Selector, String> {
+    let text = text.trim();
+    if text == "*" {
+        return Ok(Selector::Wildcard);
+    }
+    if let Some((start, end)) = text.split_once(':') {
+        let bound = |bound: &str| match bound.trim() {
+            "" => Ok(None),
+            bound => bound
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("invalid slice bound `{}`", bound)),
+        };
+        return Ok(Selector::Slice(bound(start)?, bound(end)?));
+    }
+
+    let items = split_union(text);
+    if items.iter().all(|item| item.starts_with(['\'', '"'])) {
+        let names = items
+            .iter()
+            .map(|item| unquote(item))
+            .collect::<Result<_, _>>()?;
+        return Ok(Selector::Names(names));
+    }
+    items
+        .iter()
+        .map(|item| {
+            item.parse()
+                .map_err(|_| format!("invalid index `{}`", item))
+        })
+        .collect::<Result<_, _>>()
+        .map(Selector::Indices)
+}
+
+/// Splits `'a', 'b'` or `0, 2` on the commas outside quotes.
+fn split_union(text: &str) -> Vec<&str> {
+    let mut items = vec![];
+    let (mut start, mut quote, mut escaped) = (0, None, false);
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ',') => {
+                items.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(text[start..].trim());
+    items
+}
+
+fn unquote(item: &str) -> Result<String, String> {
+    let quote = item.chars().next().unwrap_or('\'');
+    let inner = item
+        .strip_prefix(quote)
+        .and_then(|item| item.strip_suffix(quote))
+        .ok_or_else(|| format!("unterminated name `{}`", item))?;
+    let mut name = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => name.extend(chars.next()),
+            c => name.push(c),
+        }
+    }
+    Ok(name)
+}
+
+/// Returns the value and everything nested in it, in document order.
+fn descendants(value: &Value) -> Vec<&Value> {
+    let mut nodes = vec![value];
+    let children: Box<dyn Iterator<Item = &Value>> = match value {
+        Value::Array(items) => Box::new(items.iter()),
+        Value::Object(members) => Box::new(members.values()),
+        _ => Box::new(std::iter::empty()),
+    };
+    for child in children {
+        nodes.extend(descendants(child));
+    }
+    nodes
+}
+
+fn select<'a>(value: &'a Value, selector: &Selector) -> Vec<&'a Value> {
+    match (selector, value) {
+        (Selector::Wildcard, Value::Array(items)) => items.iter().collect(),
+        (Selector::Wildcard, Value::Object(members)) => members.values().collect(),
+        (Selector::Names(names), Value::Object(members)) => names
+            .iter()
+            .filter_map(|name| members.get(name.as_str()))
+            .collect(),
+        (Selector::Indices(indices), Value::Array(items)) => indices
+            .iter()
+            .filter_map(|&index| resolve(index, items.len()))
+            .filter_map(|index| items.get(index))
+            .collect(),
+        (Selector::Slice(start, end), Value::Array(items)) => {
+            let len = items.len() as i64;
+            let bound = |bound: i64| {
+                if bound < 0 {
+                    (len + bound).max(0)
+                } else {
+                    bound.min(len)
+                }
+            };
+            let start = start.map_or(0, bound) as usize;
+            let end = end.map_or(len, bound) as usize;
+            items
+                .get(start..end.max(start))
+                .unwrap_or_default()
+                .iter()
+                .collect()
+        }
+        _ => vec![],
+    }
+}
+
+fn resolve(index: i64, len: usize) -> Option<usize> {
+    if index < 0 {
+        len.checked_sub(index.unsigned_abs() as usize)
+    } else {
+        Some(index as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn order() -> Value {
+        json!({
+            "data": {
+                "items": [
+                    {"id": 1, "name": "Lamp", "tags": ["home"]},
+                    {"id": 2, "name": "Desk"},
+                    {"id": 3, "name": "Chair", "variant": {"id": 31}}
+                ],
+                "total.price": 99.5
+            }
+        })
+    }
+
+    #[test]
+    fn it_should_select_values_by_path() {
+        let order = order();
+        let ids = |path| {
+            json_path(&order, path)
+                .unwrap()
+                .into_iter()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            ids("$.data.items[*].id"),
+            vec![json!(1), json!(2), json!(3)]
+        );
+        assert_eq!(ids("$['data']['items'][-1].name"), vec![json!("Chair")]);
+        assert_eq!(ids("$.data.items[0,2].id"), vec![json!(1), json!(3)]);
+        assert_eq!(ids("$.data.items[1:].id"), vec![json!(2), json!(3)]);
+        assert_eq!(ids("$..id"), vec![json!(1), json!(2), json!(3), json!(31)]);
+        assert_eq!(ids("$.data['total.price']"), vec![json!(99.5)]);
+        assert_eq!(ids("$.data.items[0].tags.*"), vec![json!("home")]);
+        assert_eq!(ids("$"), vec![order.clone()]);
+        assert!(ids("$.data.missing[0]").is_empty());
+        assert!(ids("$.data.items[7]").is_empty());
+    }
+
+    #[test]
+    fn it_should_reject_invalid_paths() {
+        for path in [
+            "data.items",
+            "$.data[",
+            "$.data[x]",
+            "$.",
+            "$data",
+            "$[1:x]",
+        ] {
+            assert!(json_path(&order(), path).is_err(), "{}", path);
+        }
+    }
+}
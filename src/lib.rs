@@ -1,15 +1,152 @@
-pub use client_settings::ClientSettings;
-pub use context::Context;
+pub use artifact::{
+    open_artifact, read_artifact, write_artifact, ArtifactWriter, DEFAULT_COMPRESSION_THRESHOLD,
+};
+pub use audit::{
+    read_audit_log, verify_audit_chain, AuditEntry, AuditError, AuditLog, GENESIS_HASH,
+};
+pub use bot_pool::{BotPool, SeedRun, SwarmReport, SEED_KEY};
+pub use cassette::{
+    Cassette, CassetteMode, CassetteRequest, CassetteResponse, Interaction, RequestMatcher,
+};
+pub use charset::detect_encoding;
+pub use checkpoint::{Checkpoint, CheckpointError};
+pub use client_settings::{ClientSettings, IpFamily, IpPreference, SocketOptions};
+pub use coherence::{CoherenceIssue, CoherenceMode, CoherenceValidator};
+pub use consent::{ConsentChoice, ConsentPlatform};
+pub use context::{Context, ContextSnapshot, ResponseInfo};
+pub use cookie_file::CookieFormat;
+pub use cookie_jar::PartitionedCookieStore;
+pub use cors::Cors;
+pub use data_url::{decode_base64, find_data_urls, DataUrl, DataUrlError};
+pub use domain::DomainPattern;
+pub use download::{Download, DownloadProgress, DownloadReport, Preflight, ProgressFn};
+pub use environment::{Environment, EnvironmentOverlays, ENVIRONMENT_ENV};
 pub use errors::StepError;
+pub use explain::Explanation;
+pub use extract::Captures;
+pub use fan_out::{ChildOutcome, ChildResponse, FanOutError};
+#[cfg(feature = "feed")]
+pub use feed::FeedEntry;
+pub use flow::{Flow, RequestStep};
+#[cfg(feature = "html")]
+pub use form::Form;
+pub use frontier::{CrawlScope, Frontier, FrontierEntry};
+pub use har::HarRecorder;
+pub use host_guard::{GuardViolation, HostGuard, IpRange};
+#[cfg(feature = "html")]
+pub use html::{HtmlElement, StructuredData};
 pub use http_requester::HttpRequester;
-pub use request::Request;
-pub use steps::Stepable;
+pub use identity_pool::{Identity, IdentityPool, IdentityScore};
+pub use json_path::json_path;
+#[cfg(feature = "image")]
+pub use media::{ImageHash, ImageInfo};
+pub use metrics::{LatencyHistogram, Metrics, RequestCounts, RequestOutcome, LATENCY_BUCKETS_MS};
+pub use multipart::MultipartDialect;
+pub use ndjson::{NdjsonStream, StreamEnd};
+pub use observability::Observability;
+pub use parser::{parse_headers, parse_raw_request, ParseError, ParseMode};
+#[cfg(feature = "html")]
+pub use primitives::SubmitForm;
+pub use primitives::{
+    DownloadFile, ExtractAndEnqueue, FetchPage, LinkExtractor, PollPredicate, PollUntil,
+};
+pub use profile::{Profile, ProfileRotator};
+pub use proxy_accounting::{ProxyAccounting, ProxyUsage, UNNAMED_PROVIDER};
+pub use rate_limiter::{AdaptiveThrottle, RateLimit, RateLimitBudget, RateLimiter};
+pub use redirect::{RedirectHop, RedirectPolicy, DEFAULT_MAX_REDIRECTS};
+pub use referrer::ReferrerChain;
+pub use request::{MimicBody, MimicForm, Request};
+pub use retry::{Backoff, RetryPolicy, IDEMPOTENCY_KEY_HEADER};
+pub use run_history::{HistoryRun, HistoryStep, RunHistory, StepStats};
+pub use run_report::{DeadLetter, RunReport, RunSummary, StepRecord, StopReason};
+pub use sampling::{BodySample, BodySampling};
+pub use schedule::{Clock, DelayedQueue};
+pub use schema::{validate_schema, SchemaViolation};
+pub use scrubber::{Scrubber, REDACTED};
+pub use session::{SessionAffinity, SessionRotation};
+pub use similarity::{PageClassifier, PageKind, PageMatch, SimHash};
+pub use singleflight::Singleflight;
+pub use sitemap::{parse_sitemap, Sitemap, SitemapEntry, SitemapMonitor, SitemapState};
+pub use snapshot::{assert_matches_snapshot, snapshot_path, Snapshot, UPDATE_SNAPSHOTS_ENV};
+pub use steps::{StepManager, Stepable, VariantStats};
+pub use store::Store;
+pub use timeout::{TimeoutInfo, TimeoutKind};
+pub use tokio_util::sync::CancellationToken;
+pub use transform::{decompress, strip_xssi, strip_xssi_prefix, Transformer, XSSI_PREFIXES};
+pub use validators::{ValidatorStore, Validators};
+pub use warm_up::WarmUp;
 pub use worker::Worker;
 
+mod artifact;
+mod audit;
+mod bot_pool;
+mod cassette;
+mod charset;
+mod checkpoint;
 mod client_settings;
+mod coherence;
+mod consent;
 mod context;
+mod cookie_file;
+mod cookie_jar;
+mod cors;
+mod data_url;
+mod domain;
+mod download;
+mod environment;
 mod errors;
+mod explain;
+mod extract;
+mod fan_out;
+#[cfg(feature = "feed")]
+mod feed;
+mod flow;
+#[cfg(feature = "html")]
+mod form;
+mod frontier;
+mod har;
+mod host_guard;
+#[cfg(feature = "html")]
+mod html;
 mod http_requester;
+mod identity_pool;
+mod json_path;
+#[cfg(feature = "image")]
+mod media;
+mod metrics;
+mod multipart;
+mod ndjson;
+mod observability;
+mod parser;
+#[cfg(feature = "pdf")]
+mod pdf;
+mod primitives;
+mod profile;
+mod proxy_accounting;
+mod rate_limiter;
+mod redirect;
+mod referrer;
 mod request;
+mod retry;
+mod run_history;
+mod run_report;
+mod sampling;
+mod schedule;
+mod schema;
+mod scrubber;
+mod session;
+mod similarity;
+mod singleflight;
+mod sitemap;
+mod snapshot;
 mod steps;
+mod store;
+#[cfg(test)]
+mod test_server;
+mod timeout;
+mod trace;
+mod transform;
+mod uuid;
+mod validators;
+mod warm_up;
 mod worker;
@@ -0,0 +1,153 @@
+use std::error::Error;
+use std::fmt;
+use std::io::Cursor;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+
+/// The basic properties of a downloaded image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    /// The detected format in lowercase, e.g. `png` or `jpeg`.
+    pub format: String,
+}
+
+/// A 64 bit difference hash (dHash). Visually similar images have hashes with a small hamming distance,
+/// regardless of resizing or re-encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageHash(pub u64);
+
+impl ImageHash {
+    /// Returns the number of differing bits, 0 for identical images and up to 64.
+    pub fn distance(&self, other: &ImageHash) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+
+    /// Returns true if the images are likely the same picture. A threshold of 10 works well for dHash.
+    pub fn is_similar(&self, other: &ImageHash, threshold: u32) -> bool {
+        self.distance(other) <= threshold
+    }
+}
+
+impl fmt::Display for ImageHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+fn decode(bytes: &[u8]) -> Result<(DynamicImage, ImageFormat), Box<dyn Error + Send + Sync>> {
+    let format = image::guess_format(bytes)?;
+    let image = image::load_from_memory_with_format(bytes, format)?;
+    Ok((image, format))
+}
+
+/// Reads the dimensions and format of an encoded image.
+pub fn image_info(bytes: &[u8]) -> Result<ImageInfo, Box<dyn Error + Send + Sync>> {
+    let (image, format) = decode(bytes)?;
+    Ok(ImageInfo {
+        width: image.width(),
+        height: image.height(),
+        format: format!("{:?}", format).to_lowercase(),
+    })
+}
+
+/// Computes the difference hash of an encoded image.
+pub fn image_hash(bytes: &[u8]) -> Result<ImageHash, Box<dyn Error + Send + Sync>> {
+    let (image, _) = decode(bytes)?;
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = small.get_pixel(x, y)[0];
+            let right = small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | (left > right) as u64;
+        }
+    }
+
+    Ok(ImageHash(hash))
+}
+
+/// Scales an image down to fit within `max_width` x `max_height`, keeping its aspect ratio,
+/// and returns it encoded as PNG.
+pub fn image_thumbnail(
+    bytes: &[u8],
+    max_width: u32,
+    max_height: u32,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let (image, _) = decode(bytes)?;
+    let thumbnail = image.thumbnail(max_width, max_height);
+
+    let mut buffer = Cursor::new(Vec::new());
+    thumbnail.write_to(&mut buffer, ImageFormat::Png)?;
+    Ok(buffer.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    fn gradient(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let image = RgbImage::from_fn(width, height, |x, y| {
+            let v = (x * 255 / width) as u8;
+            Rgb([v, v, (y * 255 / height) as u8])
+        });
+
+        let mut buffer = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut buffer, format)
+            .unwrap();
+        buffer.into_inner()
+    }
+
+    #[test]
+    fn it_should_read_image_dimensions_and_format() {
+        let info = image_info(&gradient(40, 20, ImageFormat::Png)).unwrap();
+        assert_eq!(
+            info,
+            ImageInfo {
+                width: 40,
+                height: 20,
+                format: "png".to_string()
+            }
+        );
+
+        let info = image_info(&gradient(16, 16, ImageFormat::Jpeg)).unwrap();
+        assert_eq!(info.format, "jpeg");
+    }
+
+    #[test]
+    fn it_should_hash_resized_images_similarly() {
+        let original = image_hash(&gradient(200, 100, ImageFormat::Png)).unwrap();
+        let resized = image_hash(&gradient(100, 50, ImageFormat::Jpeg)).unwrap();
+        let flipped = {
+            let (image, _) = decode(&gradient(200, 100, ImageFormat::Png)).unwrap();
+            let mut buffer = Cursor::new(Vec::new());
+            image
+                .fliph()
+                .write_to(&mut buffer, ImageFormat::Png)
+                .unwrap();
+            image_hash(&buffer.into_inner()).unwrap()
+        };
+
+        assert!(original.is_similar(&resized, 10));
+        assert!(original.distance(&flipped) > 10);
+        assert_eq!(original.to_string().len(), 16);
+    }
+
+    #[test]
+    fn it_should_create_thumbnails_that_fit() {
+        let thumbnail = image_thumbnail(&gradient(400, 200, ImageFormat::Png), 100, 100).unwrap();
+        let info = image_info(&thumbnail).unwrap();
+        assert_eq!((info.width, info.height), (100, 50));
+    }
+
+    #[test]
+    fn it_should_error_on_non_images() {
+        assert!(image_info(b"definitely not an image").is_err());
+    }
+}
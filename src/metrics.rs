@@ -0,0 +1,595 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// Upper bounds of the latency buckets in milliseconds. The last bucket holds everything slower.
+pub const LATENCY_BUCKETS_MS: [u64; 10] = [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// A latency histogram with fixed buckets, plus the number of failed requests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// One count per bucket of `LATENCY_BUCKETS_MS`, plus one for slower requests.
+    counts: Vec<u64>,
+    count: u64,
+    errors: u64,
+    sum_ms: u64,
+    max_ms: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram::new()
+    }
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; LATENCY_BUCKETS_MS.len() + 1],
+            count: 0,
+            errors: 0,
+            sum_ms: 0,
+            max_ms: 0,
+        }
+    }
+
+    pub fn record(&mut self, elapsed_ms: u64, success: bool) {
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| elapsed_ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum_ms += elapsed_ms;
+        self.max_ms = self.max_ms.max(elapsed_ms);
+        if !success {
+            self.errors += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &LatencyHistogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.errors += other.errors;
+        self.sum_ms += other.sum_ms;
+        self.max_ms = self.max_ms.max(other.max_ms);
+    }
+
+    /// Returns `(upper bound in ms, count)` for every bucket. The last bound is `u64::MAX`.
+    pub fn buckets(&self) -> Vec<(u64, u64)> {
+        LATENCY_BUCKETS_MS
+            .iter()
+            .copied()
+            .chain([u64::MAX])
+            .zip(self.counts.iter().copied())
+            .collect()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    pub fn sum_ms(&self) -> u64 {
+        self.sum_ms
+    }
+
+    pub fn max_ms(&self) -> u64 {
+        self.max_ms
+    }
+
+    pub fn mean_ms(&self) -> f64 {
+        match self.count {
+            0 => 0.0,
+            count => self.sum_ms as f64 / count as f64,
+        }
+    }
+
+    /// Returns the share of failed requests, between 0 and 1.
+    pub fn error_rate(&self) -> f64 {
+        match self.count {
+            0 => 0.0,
+            count => self.errors as f64 / count as f64,
+        }
+    }
+
+    /// Estimates a percentile (0 to 100) as the upper bound of the bucket it falls in.
+    /// Requests slower than the last bucket report the slowest request seen.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+
+        let rank = ((percentile.clamp(0.0, 100.0) / 100.0) * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (bound, count) in self.buckets() {
+            seen += count;
+            if seen >= rank.max(1) {
+                return bound.min(self.max_ms);
+            }
+        }
+        self.max_ms
+    }
+}
+
+/// How a request of a step ended, see `Metrics::record_request`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestOutcome {
+    /// The status of the response, `None` if none was received.
+    pub status: Option<u16>,
+    pub success: bool,
+    pub timeout: bool,
+    /// The size of the request on the wire.
+    pub bytes_sent: u64,
+    /// The size of the response body, after decompression.
+    pub bytes_received: u64,
+}
+
+/// What the requests of a step, or of every step, came to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestCounts {
+    pub requests: u64,
+    pub successes: u64,
+    /// The failed requests that didn't time out.
+    pub errors: u64,
+    pub timeouts: u64,
+    /// The number of responses of each status.
+    pub status_codes: BTreeMap<u16, u64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl RequestCounts {
+    fn record(&mut self, outcome: &RequestOutcome) {
+        self.requests += 1;
+        match (outcome.success, outcome.timeout) {
+            (true, _) => self.successes += 1,
+            (false, true) => self.timeouts += 1,
+            (false, false) => self.errors += 1,
+        }
+        if let Some(status) = outcome.status {
+            *self.status_codes.entry(status).or_default() += 1;
+        }
+        self.bytes_sent += outcome.bytes_sent;
+        self.bytes_received += outcome.bytes_received;
+    }
+
+    fn merge(&mut self, other: &RequestCounts) {
+        self.requests += other.requests;
+        self.successes += other.successes;
+        self.errors += other.errors;
+        self.timeouts += other.timeouts;
+        for (status, count) in &other.status_codes {
+            *self.status_codes.entry(*status).or_default() += count;
+        }
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+    }
+}
+
+/// Request latencies and errors, keyed by step and by host, so a flow hitting many domains shows
+/// which target is degrading, and the outcomes, status codes and bytes of each step's requests.
+/// Share it between workers with an `Arc`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    steps: Mutex<HashMap<String, LatencyHistogram>>,
+    hosts: Mutex<HashMap<String, LatencyHistogram>>,
+    tags: Mutex<HashMap<String, LatencyHistogram>>,
+    counts: Mutex<HashMap<String, RequestCounts>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records one request of `step` to `host`.
+    pub fn record(&self, step: &str, host: Option<&str>, elapsed_ms: u64, success: bool) {
+        self.record_tagged(step, host, &BTreeMap::new(), elapsed_ms, success);
+    }
+
+    /// Records one request, also labelling it with each of the request's tags.
+    pub fn record_tagged(
+        &self,
+        step: &str,
+        host: Option<&str>,
+        tags: &BTreeMap<String, String>,
+        elapsed_ms: u64,
+        success: bool,
+    ) {
+        if !tags.is_empty() {
+            let mut histograms = self.tags.lock().unwrap();
+            for (key, value) in tags {
+                histograms
+                    .entry(tag_label(key, value))
+                    .or_default()
+                    .record(elapsed_ms, success);
+            }
+        }
+
+        self.steps
+            .lock()
+            .unwrap()
+            .entry(step.to_string())
+            .or_default()
+            .record(elapsed_ms, success);
+
+        if let Some(host) = host {
+            self.hosts
+                .lock()
+                .unwrap()
+                .entry(host.to_string())
+                .or_default()
+                .record(elapsed_ms, success);
+        }
+    }
+
+    /// Counts one request of `step` by its outcome. The worker records its latency separately,
+    /// with `record` or `record_tagged`.
+    pub fn record_request(&self, step: &str, outcome: &RequestOutcome) {
+        self.counts
+            .lock()
+            .unwrap()
+            .entry(step.to_string())
+            .or_default()
+            .record(outcome);
+    }
+
+    pub fn step_counts(&self, step: &str) -> Option<RequestCounts> {
+        self.counts.lock().unwrap().get(step).cloned()
+    }
+
+    /// Returns the counts of every step added up.
+    pub fn total_counts(&self) -> RequestCounts {
+        let mut total = RequestCounts::default();
+        for counts in self.counts.lock().unwrap().values() {
+            total.merge(counts);
+        }
+        total
+    }
+
+    /// Returns the latencies of every request, of every step.
+    pub fn total_latency(&self) -> LatencyHistogram {
+        let mut total = LatencyHistogram::new();
+        for histogram in self.steps.lock().unwrap().values() {
+            total.merge(histogram);
+        }
+        total
+    }
+
+    /// Forgets everything recorded so far, e.g. at the start of each reporting interval of a
+    /// long running bot.
+    pub fn reset(&self) {
+        self.steps.lock().unwrap().clear();
+        self.hosts.lock().unwrap().clear();
+        self.tags.lock().unwrap().clear();
+        self.counts.lock().unwrap().clear();
+    }
+
+    pub fn step_latency(&self, step: &str) -> Option<LatencyHistogram> {
+        self.steps.lock().unwrap().get(step).cloned()
+    }
+
+    pub fn host_latency(&self, host: &str) -> Option<LatencyHistogram> {
+        self.hosts.lock().unwrap().get(host).cloned()
+    }
+
+    /// Returns the histogram of the requests tagged `key=value`.
+    pub fn tag_latency(&self, key: &str, value: &str) -> Option<LatencyHistogram> {
+        self.tags
+            .lock()
+            .unwrap()
+            .get(&tag_label(key, value))
+            .cloned()
+    }
+
+    /// Returns the histogram of every host, sorted by host.
+    pub fn hosts(&self) -> BTreeMap<String, LatencyHistogram> {
+        self.hosts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// Returns the histogram of every step, sorted by step name.
+    pub fn steps(&self) -> BTreeMap<String, LatencyHistogram> {
+        self.steps
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+}
+
+#[cfg(feature = "metrics-prometheus")]
+impl Metrics {
+    /// Renders every counter and histogram in the Prometheus text format, for a `/metrics`
+    /// endpoint to serve. The constant labels go on every series, e.g. `[("bot", "prices")]` to
+    /// tell the bots of a fleet apart. Latencies are in seconds, as Prometheus expects.
+    pub fn render_prometheus(&self, labels: &[(&str, &str)]) -> String {
+        let mut out = String::new();
+        let counts: BTreeMap<String, RequestCounts> = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        let mut counter = |name: &str, help: &str, series: Vec<(Vec<(&str, String)>, u64)>| {
+            out.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} counter\n",
+                name = name,
+                help = help
+            ));
+            for (series_labels, value) in series {
+                out.push_str(&format!(
+                    "{}{} {}\n",
+                    name,
+                    prometheus_labels(labels, &series_labels),
+                    value
+                ));
+            }
+        };
+        counter(
+            "mimicr_requests_total",
+            "Requests sent, by step and outcome.",
+            counts
+                .iter()
+                .flat_map(|(step, c)| {
+                    [
+                        ("success", c.successes),
+                        ("error", c.errors),
+                        ("timeout", c.timeouts),
+                    ]
+                    .map(|(outcome, value)| {
+                        let series = vec![("step", step.clone()), ("outcome", outcome.into())];
+                        (series, value)
+                    })
+                })
+                .collect(),
+        );
+        counter(
+            "mimicr_responses_total",
+            "Responses received, by step and status.",
+            counts
+                .iter()
+                .flat_map(|(step, c)| {
+                    c.status_codes.iter().map(|(status, value)| {
+                        let series = vec![("step", step.clone()), ("status", status.to_string())];
+                        (series, *value)
+                    })
+                })
+                .collect(),
+        );
+        counter(
+            "mimicr_bytes_sent_total",
+            "Bytes sent on the wire, by step.",
+            counts
+                .iter()
+                .map(|(step, c)| (vec![("step", step.clone())], c.bytes_sent))
+                .collect(),
+        );
+        counter(
+            "mimicr_bytes_received_total",
+            "Response body bytes received, by step.",
+            counts
+                .iter()
+                .map(|(step, c)| (vec![("step", step.clone())], c.bytes_received))
+                .collect(),
+        );
+
+        let histograms = [
+            ("mimicr_step_latency_seconds", "step", self.steps()),
+            ("mimicr_host_latency_seconds", "host", self.hosts()),
+        ];
+        for (name, key, histograms) in histograms {
+            out.push_str(&format!(
+                "# HELP {name} Request latency, by {key}.\n# TYPE {name} histogram\n",
+                name = name,
+                key = key
+            ));
+            for (value, histogram) in histograms {
+                let mut cumulative = 0;
+                for (bound, count) in histogram.buckets() {
+                    cumulative += count;
+                    let le = match bound {
+                        u64::MAX => "+Inf".to_string(),
+                        bound => (bound as f64 / 1000.0).to_string(),
+                    };
+                    let series = [(key, value.clone()), ("le", le)];
+                    out.push_str(&format!(
+                        "{}_bucket{} {}\n",
+                        name,
+                        prometheus_labels(labels, &series),
+                        cumulative
+                    ));
+                }
+                let series = prometheus_labels(labels, &[(key, value)]);
+                out.push_str(&format!(
+                    "{}_sum{} {}\n{}_count{} {}\n",
+                    name,
+                    series,
+                    histogram.sum_ms() as f64 / 1000.0,
+                    name,
+                    series,
+                    histogram.count()
+                ));
+            }
+        }
+        out
+    }
+}
+
+/// Renders `{a="1",b="2"}`, escaping the values.
+#[cfg(feature = "metrics-prometheus")]
+fn prometheus_labels(constant: &[(&str, &str)], series: &[(&str, String)]) -> String {
+    let labels: Vec<String> = constant
+        .iter()
+        .map(|(key, value)| (*key, *value))
+        .chain(series.iter().map(|(key, value)| (*key, value.as_str())))
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+fn tag_label(key: &str, value: &str) -> String {
+    format!("{}={}", key, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_bucket_latencies() {
+        let mut histogram = LatencyHistogram::new();
+        for ms in [5, 40, 40, 90, 20_000] {
+            histogram.record(ms, true);
+        }
+        histogram.record(300, false);
+
+        assert_eq!(histogram.count(), 6);
+        assert_eq!(histogram.buckets()[0], (10, 1));
+        assert_eq!(histogram.buckets()[2], (50, 2));
+        assert_eq!(histogram.buckets().last().unwrap(), &(u64::MAX, 1));
+        assert_eq!(histogram.percentile(50.0), 50);
+        assert_eq!(histogram.percentile(100.0), 20_000);
+        assert!((histogram.error_rate() - 1.0 / 6.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn it_should_key_latencies_by_host_and_step() {
+        let metrics = Metrics::new();
+        metrics.record("Search", Some("a.example.com"), 30, true);
+        metrics.record("Search", Some("b.example.com"), 3000, false);
+        metrics.record("Search", None, 10, true);
+
+        assert_eq!(metrics.step_latency("Search").unwrap().count(), 3);
+        assert_eq!(metrics.host_latency("a.example.com").unwrap().count(), 1);
+
+        let slow = metrics.host_latency("b.example.com").unwrap();
+        assert_eq!(slow.percentile(99.0), 3000);
+        assert_eq!(slow.error_rate(), 1.0);
+        assert_eq!(metrics.hosts().len(), 2);
+    }
+
+    #[test]
+    fn it_should_label_latencies_by_tag() {
+        let metrics = Metrics::new();
+        let tags = BTreeMap::from([("category".to_string(), "checkout".to_string())]);
+        metrics.record_tagged("Pay", Some("shop.com"), &tags, 120, false);
+        metrics.record("Browse", Some("shop.com"), 20, true);
+
+        let checkout = metrics.tag_latency("category", "checkout").unwrap();
+        assert_eq!(checkout.count(), 1);
+        assert_eq!(checkout.errors(), 1);
+        assert!(metrics.tag_latency("category", "browse").is_none());
+        assert_eq!(metrics.host_latency("shop.com").unwrap().count(), 2);
+    }
+
+    #[test]
+    fn it_should_count_outcomes_and_add_them_up() {
+        let metrics = Metrics::new();
+        let ok = RequestOutcome {
+            status: Some(200),
+            success: true,
+            bytes_sent: 100,
+            bytes_received: 2000,
+            ..Default::default()
+        };
+        metrics.record_request("Search", &ok);
+        metrics.record_request("Search", &ok);
+        metrics.record_request(
+            "Search",
+            &RequestOutcome {
+                status: Some(503),
+                ..Default::default()
+            },
+        );
+        metrics.record_request(
+            "Detail",
+            &RequestOutcome {
+                timeout: true,
+                bytes_sent: 80,
+                ..Default::default()
+            },
+        );
+        metrics.record("Search", None, 40, true);
+        metrics.record("Detail", None, 30_000, false);
+
+        let search = metrics.step_counts("Search").unwrap();
+        assert_eq!(
+            (search.requests, search.successes, search.errors),
+            (3, 2, 1)
+        );
+        assert_eq!(search.status_codes[&200], 2);
+        assert_eq!(search.bytes_received, 4000);
+
+        let total = metrics.total_counts();
+        assert_eq!((total.requests, total.timeouts), (4, 1));
+        assert_eq!(total.bytes_sent, 280);
+        assert_eq!(total.status_codes.len(), 2);
+        assert_eq!(metrics.total_latency().count(), 2);
+        assert_eq!(metrics.total_latency().percentile(100.0), 30_000);
+
+        metrics.reset();
+        assert!(metrics.step_counts("Search").is_none());
+        assert_eq!(metrics.total_counts(), RequestCounts::default());
+        assert!(metrics.steps().is_empty());
+    }
+
+    #[cfg(feature = "metrics-prometheus")]
+    #[test]
+    fn it_should_render_prometheus_text() {
+        let metrics = Metrics::new();
+        for (status, ms) in [(200, 40), (200, 300), (503, 20_000)] {
+            let outcome = RequestOutcome {
+                status: Some(status),
+                success: status == 200,
+                bytes_received: 1000,
+                ..Default::default()
+            };
+            metrics.record_request("Search", &outcome);
+            metrics.record("Search", Some("shop.com"), ms, status == 200);
+        }
+
+        let text = metrics.render_prometheus(&[("bot", "prices \"eu\"")]);
+        let lines: Vec<&str> = text.lines().collect();
+        for line in [
+            "# TYPE mimicr_requests_total counter",
+            r#"mimicr_requests_total{bot="prices \"eu\"",step="Search",outcome="success"} 2"#,
+            r#"mimicr_requests_total{bot="prices \"eu\"",step="Search",outcome="error"} 1"#,
+            r#"mimicr_responses_total{bot="prices \"eu\"",step="Search",status="503"} 1"#,
+            r#"mimicr_bytes_received_total{bot="prices \"eu\"",step="Search"} 3000"#,
+            "# TYPE mimicr_step_latency_seconds histogram",
+            r#"mimicr_step_latency_seconds_bucket{bot="prices \"eu\"",step="Search",le="0.05"} 1"#,
+            r#"mimicr_step_latency_seconds_bucket{bot="prices \"eu\"",step="Search",le="10"} 2"#,
+            r#"mimicr_step_latency_seconds_bucket{bot="prices \"eu\"",step="Search",le="+Inf"} 3"#,
+            r#"mimicr_step_latency_seconds_sum{bot="prices \"eu\"",step="Search"} 20.34"#,
+            r#"mimicr_host_latency_seconds_count{bot="prices \"eu\"",host="shop.com"} 3"#,
+        ] {
+            assert!(lines.contains(&line), "missing {}", line);
+        }
+        assert!(Metrics::new()
+            .render_prometheus(&[])
+            .contains("# TYPE mimicr_requests_total counter\n# HELP"));
+    }
+}
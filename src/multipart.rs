@@ -0,0 +1,198 @@
+use rand::Rng;
+use reqwest::multipart::Part as FormPart;
+
+use crate::uuid::uuid_v4;
+
+/// The client whose `multipart/form-data` encoding a `MimicForm` copies, for endpoints that
+/// fingerprint the boundary, the part headers or the part order, see `MimicForm::with_dialect`.
+/// A fresh boundary is drawn for every request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MultipartDialect {
+    /// reqwest's own encoding.
+    #[default]
+    Reqwest,
+    /// `----WebKitFormBoundary` and 16 letters or digits.
+    Chrome,
+    /// Encoded like Chrome, both being WebKit-derived.
+    Safari,
+    /// `----geckoformboundary` and 32 hex digits.
+    Firefox,
+    /// A UUID boundary, and a `Content-Length` on every part.
+    OkHttp,
+    /// 32 hex digits, text fields before files, and no `Content-Type` on untyped files.
+    PythonRequests,
+}
+
+/// A part of a form to encode.
+pub(crate) struct Part<'a> {
+    pub name: &'a str,
+    pub data: &'a [u8],
+    /// The file name and type of file parts.
+    pub file: Option<(&'a str, Option<&'a str>)>,
+}
+
+impl MultipartDialect {
+    pub(crate) fn boundary(&self) -> String {
+        let mut rng = rand::thread_rng();
+        let hex = |rng: &mut rand::rngs::ThreadRng, len: usize| -> String {
+            (0..len)
+                .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+                .collect()
+        };
+        match self {
+            MultipartDialect::Reqwest => {
+                let blocks: Vec<String> = (0..4).map(|_| hex(&mut rng, 16)).collect();
+                blocks.join("-")
+            }
+            MultipartDialect::Chrome | MultipartDialect::Safari => {
+                let suffix: String = (0..16)
+                    .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
+                    .collect();
+                format!("----WebKitFormBoundary{}", suffix)
+            }
+            MultipartDialect::Firefox => format!("----geckoformboundary{}", hex(&mut rng, 32)),
+            MultipartDialect::OkHttp => uuid_v4(),
+            MultipartDialect::PythonRequests => hex(&mut rng, 32),
+        }
+    }
+
+    /// Encodes the parts, returning the `Content-Type` to send with the body.
+    pub(crate) fn encode(&self, parts: &[Part]) -> (String, Vec<u8>) {
+        let boundary = self.boundary();
+        let mut ordered: Vec<&Part> = parts.iter().collect();
+        if *self == MultipartDialect::PythonRequests {
+            // requests encodes `data` before `files`
+            ordered.sort_by_key(|part| part.file.is_some());
+        }
+
+        let mut body = vec![];
+        for part in ordered {
+            body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+            let mut disposition = format!("form-data; name=\"{}\"", self.quote(part.name));
+            let mut content_type = None;
+            if let Some((file_name, mime)) = part.file {
+                disposition.push_str(&format!("; filename=\"{}\"", self.quote(file_name)));
+                // an invalid type, e.g. with a line break, is sent as if it was unset, like
+                // `From<MimicForm> for Form` does
+                let mime = mime.filter(|mime| FormPart::text("").mime_str(mime).is_ok());
+                content_type = match self {
+                    MultipartDialect::PythonRequests | MultipartDialect::OkHttp => mime,
+                    _ => Some(mime.unwrap_or("application/octet-stream")),
+                };
+            }
+            let mut headers = format!("Content-Disposition: {}\r\n", disposition);
+            if let Some(content_type) = content_type {
+                headers.push_str(&format!("Content-Type: {}\r\n", content_type));
+            }
+            if *self == MultipartDialect::OkHttp {
+                headers.push_str(&format!("Content-Length: {}\r\n", part.data.len()));
+            }
+            body.extend_from_slice(headers.as_bytes());
+            body.extend_from_slice(b"\r\n");
+            body.extend_from_slice(part.data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+        (content_type, body)
+    }
+
+    /// Escapes a name or file name inside quotes the way the client does.
+    fn quote(&self, value: &str) -> String {
+        let mut quoted = String::with_capacity(value.len());
+        for c in value.chars() {
+            match (self, c) {
+                (_, '"') => quoted.push_str("%22"),
+                (_, '\r') => quoted.push_str("%0D"),
+                (_, '\n') => quoted.push_str("%0A"),
+                (MultipartDialect::PythonRequests, '\\') => quoted.push_str("\\\\"),
+                (MultipartDialect::PythonRequests, c) if c < ' ' && c != '\x1b' => {
+                    quoted.push_str(&format!("%{:02X}", c as u32))
+                }
+                (_, c) => quoted.push(c),
+            }
+        }
+        quoted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts() -> Vec<Part<'static>> {
+        vec![
+            Part {
+                name: "avatar",
+                data: b"PNG",
+                file: Some(("my \"best\".png", None)),
+            },
+            Part {
+                name: "title",
+                data: b"report",
+                file: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn it_should_draw_boundaries_like_each_client() {
+        let chrome = MultipartDialect::Chrome.boundary();
+        assert_eq!(chrome.len(), 38);
+        assert!(chrome.starts_with("----WebKitFormBoundary"));
+        assert_ne!(chrome, MultipartDialect::Chrome.boundary());
+
+        let firefox = MultipartDialect::Firefox.boundary();
+        assert!(firefox.starts_with("----geckoformboundary") && firefox.len() == 53);
+
+        let uuid = MultipartDialect::OkHttp.boundary();
+        let groups: Vec<usize> = uuid.split('-').map(str::len).collect();
+        assert_eq!(groups, vec![8, 4, 4, 4, 12]);
+        assert_eq!(uuid.as_bytes()[14], b'4');
+        assert!(matches!(uuid.as_bytes()[19], b'8' | b'9' | b'a' | b'b'));
+
+        let python = MultipartDialect::PythonRequests.boundary();
+        assert!(python.len() == 32 && python.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn it_should_encode_parts_like_each_client() {
+        let (content_type, body) = MultipartDialect::Chrome.encode(&parts());
+        let boundary = content_type
+            .strip_prefix("multipart/form-data; boundary=")
+            .unwrap();
+        let expected = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"avatar\"; filename=\"my %22best%22.png\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\nPNG\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nreport\r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(String::from_utf8(body).unwrap(), expected);
+
+        let (_, body) = MultipartDialect::OkHttp.encode(&parts());
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("filename=\"my %22best%22.png\"\r\nContent-Length: 3\r\n\r\nPNG"));
+        assert!(body.contains("name=\"title\"\r\nContent-Length: 6\r\n\r\nreport"));
+
+        let (_, body) = MultipartDialect::PythonRequests.encode(&parts());
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.find("name=\"title\"").unwrap() < body.find("name=\"avatar\"").unwrap());
+        assert!(body.contains("filename=\"my %22best%22.png\"\r\n\r\nPNG"));
+    }
+
+    #[test]
+    fn it_should_not_send_invalid_part_types() {
+        let injected = [Part {
+            name: "avatar",
+            data: b"PNG",
+            file: Some(("a.png", Some("image/png\r\nX-Injected: 1"))),
+        }];
+        let (_, body) = MultipartDialect::Firefox.encode(&injected);
+        let body = String::from_utf8(body).unwrap();
+        assert!(!body.contains("X-Injected"));
+        assert!(body.contains("filename=\"a.png\"\r\nContent-Type: application/octet-stream\r\n"));
+        let (_, body) = MultipartDialect::OkHttp.encode(&injected);
+        assert!(!String::from_utf8(body).unwrap().contains("Content-Type"));
+    }
+}
@@ -0,0 +1,327 @@
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use bytes::{Buf, BytesMut};
+use serde::de::DeserializeOwned;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::{redirect, HttpRequester, Request, ResponseInfo, StepError};
+
+/// How long the body of a stream may take, the request timeout only covers its headers.
+const STREAM_LIFETIME: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Why an `NdjsonStream` stopped yielding records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEnd {
+    /// The server closed the response.
+    Finished,
+    /// The stream's `CancellationToken` was cancelled.
+    Cancelled,
+    /// The stream ran for its `with_max_duration`.
+    MaxDuration,
+}
+
+/// The records of a streaming endpoint sending one JSON value per line, e.g. a firehose, read
+/// while they arrive, see `Context::ndjson_stream`. Blank keep-alive lines are skipped.
+/// Endless streams end when cancelled or after a maximum duration, the connection is dropped
+/// with the stream. Redirects are followed by the request's policy and checked by the host
+/// guard, but streams are neither recorded to nor replayed from a cassette or HAR recorder.
+pub struct NdjsonStream<T> {
+    response: Option<reqwest::Response>,
+    info: ResponseInfo,
+    buffer: BytesMut,
+    records: u64,
+    deadline: Option<Instant>,
+    cancel: Option<CancellationToken>,
+    end: Option<StreamEnd>,
+    _record: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> NdjsonStream<T> {
+    fn new(response: reqwest::Response) -> Self {
+        let info = ResponseInfo::new(
+            response.status().as_u16(),
+            response.headers().clone(),
+            response.url().to_string(),
+        )
+        .with_version(response.version());
+        Self {
+            response: Some(response),
+            info,
+            buffer: BytesMut::new(),
+            records: 0,
+            deadline: None,
+            cancel: None,
+            end: None,
+            _record: PhantomData,
+        }
+    }
+
+    /// Stops the stream this long after it was opened, e.g. to sample a firehose for a minute.
+    pub fn with_max_duration(mut self, max_duration: Duration) -> Self {
+        self.deadline = Some(Instant::now() + max_duration);
+        self
+    }
+
+    /// Stops the stream as soon as the token is cancelled, even while waiting for a record.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Returns the status, headers and url of the response.
+    pub fn info(&self) -> &ResponseInfo {
+        &self.info
+    }
+
+    /// Returns how many records were yielded so far.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Returns why the stream stopped, `None` while it's open.
+    pub fn end(&self) -> Option<StreamEnd> {
+        self.end
+    }
+
+    /// Returns the next record, or `None` once the stream ended. A line that isn't valid JSON
+    /// for `T` returns a `StepError::Parse` and the stream goes on with the next line.
+    pub async fn next(&mut self) -> Option<Result<T, StepError>> {
+        loop {
+            if let Some(line) = self.next_line() {
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                self.records += 1;
+                return Some(
+                    serde_json::from_slice(&line).map_err(|err| StepError::Parse(err.to_string())),
+                );
+            }
+            let (cancel, deadline) = (self.cancel.clone(), self.deadline);
+            let response = self.response.as_mut()?;
+            let cancelled = async {
+                match &cancel {
+                    Some(token) => token.cancelled().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let expired = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let chunk = tokio::select! {
+                biased;
+                _ = cancelled => Err(StreamEnd::Cancelled),
+                _ = expired => Err(StreamEnd::MaxDuration),
+                chunk = response.chunk() => Ok(chunk),
+            };
+            match chunk {
+                Ok(Ok(Some(chunk))) => self.buffer.extend_from_slice(&chunk),
+                Ok(Ok(None)) => self.finish(StreamEnd::Finished),
+                Ok(Err(err)) => {
+                    self.finish(StreamEnd::Finished);
+                    return Some(Err(StepError::from_reqwest(&err)));
+                }
+                Err(end) => {
+                    // a record cut off by the limit isn't complete, so it's dropped
+                    self.buffer.clear();
+                    self.finish(end);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Takes the next complete line off the buffer, or the rest of it once the response ended.
+    fn next_line(&mut self) -> Option<BytesMut> {
+        match self.buffer.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                let line = self.buffer.split_to(end);
+                self.buffer.advance(1);
+                Some(line)
+            }
+            None if self.response.is_none() && !self.buffer.is_empty() => Some(self.buffer.split()),
+            None => None,
+        }
+    }
+
+    fn finish(&mut self, end: StreamEnd) {
+        self.response = None;
+        self.end = Some(end);
+    }
+}
+
+/// Sends the request and returns the stream of its records once the response headers arrived.
+pub(crate) async fn open<T: DeserializeOwned>(
+    requester: &mut HttpRequester,
+    req: Request,
+) -> Result<NdjsonStream<T>, StepError> {
+    crate::fan_out::apply_request_settings(requester, &req);
+    let guard = requester.settings.host_guard().cloned();
+    crate::fan_out::check_guard(guard.as_deref(), &req).await?;
+    let codes = req.status_codes().unwrap_or_default();
+    let timeout = req.timeout().unwrap_or(Duration::from_secs(30));
+    let (client, req) = requester
+        .build_reqwest(req)
+        .map_err(|err| StepError::from_reqwest(&err))?
+        .timeout(STREAM_LIFETIME)
+        .build_split();
+    let mut req = req.map_err(|err| StepError::from_reqwest(&err))?;
+    let policy = requester.settings.redirect_policy();
+
+    // the client doesn't follow redirects, so every hop is checked by the guard
+    let follow = async {
+        let mut hops = vec![];
+        loop {
+            let next = req.try_clone();
+            let response = client
+                .execute(req)
+                .await
+                .map_err(|err| StepError::from_reqwest(&err))?;
+            let info = ResponseInfo::new(
+                response.status().as_u16(),
+                response.headers().clone(),
+                response.url().to_string(),
+            );
+            match redirect::next_request(policy, guard.as_deref(), &mut hops, &info, next).await? {
+                Some(next) => req = next,
+                None => return Ok((response, hops)),
+            }
+        }
+    };
+    let (response, hops) = tokio::time::timeout(timeout, follow)
+        .await
+        .map_err(|_| StepError::Timeout)??;
+    crate::fan_out::check_status(response.status().as_u16(), codes)?;
+    let mut stream = NdjsonStream::new(response);
+    stream.info = stream.info.with_redirects(hops);
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+    use crate::Context;
+    use reqwest::Method;
+    use serde_derive::Deserialize;
+    use tokio::io::AsyncWriteExt;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Event {
+        id: u32,
+    }
+
+    #[tokio::test]
+    async fn it_should_read_records_line_by_line() {
+        let server = TestServer::start(|_| {
+            TestResponse::ok("{\"id\":1}\r\n\r\n{\"id\":2}\nnot json\n{\"id\":3}")
+                .with_header("Content-Type", "application/x-ndjson")
+        })
+        .await;
+
+        let ctx = Context::new();
+        let req = Request::new(Method::GET, server.url("/events"));
+        let mut stream = ctx.ndjson_stream::<Event>(req).await.unwrap();
+        assert_eq!(stream.info().status(), 200);
+        assert_eq!(stream.next().await.unwrap().unwrap(), Event { id: 1 });
+        assert_eq!(stream.next().await.unwrap().unwrap(), Event { id: 2 });
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(StepError::Parse(_)))
+        ));
+        assert_eq!(stream.next().await.unwrap().unwrap(), Event { id: 3 });
+        assert!(stream.next().await.is_none());
+        assert_eq!(stream.end(), Some(StreamEnd::Finished));
+        assert_eq!(stream.records(), 4);
+
+        let req = Request::new(Method::GET, server.url("/events")).with_status_codes(vec![204]);
+        assert!(matches!(
+            ctx.ndjson_stream::<Event>(req).await,
+            Err(StepError::StatusCodeNotFound(200, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_should_stop_endless_streams() {
+        // a firehose sending one record, then nothing but keeping the connection open
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/firehose", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(b"9\r\n{\"id\":1}\n\r\n").await;
+                    let _ = stream.write_all(b"4\r\n{\"id\r\n").await;
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                });
+            }
+        });
+
+        let ctx = Context::new();
+        let req = Request::new(Method::GET, url.clone());
+        let mut stream = ctx
+            .ndjson_stream::<Event>(req)
+            .await
+            .unwrap()
+            .with_max_duration(Duration::from_millis(200));
+        assert_eq!(stream.next().await.unwrap().unwrap(), Event { id: 1 });
+        assert!(stream.next().await.is_none());
+        assert_eq!(stream.end(), Some(StreamEnd::MaxDuration));
+
+        let token = CancellationToken::new();
+        let mut stream = ctx
+            .ndjson_stream::<Event>(Request::new(Method::GET, url))
+            .await
+            .unwrap()
+            .with_cancellation(token.clone());
+        assert_eq!(stream.next().await.unwrap().unwrap(), Event { id: 1 });
+        let cancel = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            token.cancel();
+        });
+        assert!(stream.next().await.is_none());
+        assert_eq!(stream.end(), Some(StreamEnd::Cancelled));
+        cancel.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_should_follow_the_redirects_the_guard_allows() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/events" => TestResponse::status(302, "").with_header("Location", "/v2/events"),
+            "/internal" => TestResponse::status(302, "")
+                .with_header("Location", "http://169.254.169.254/latest"),
+            _ => TestResponse::ok("{\"id\":1}\n"),
+        })
+        .await;
+        let mut ctx = Context::new();
+        let mut stream = ctx
+            .ndjson_stream::<Event>(Request::new(Method::GET, server.url("/events")))
+            .await
+            .unwrap();
+        assert_eq!(stream.info().final_url(), server.url("/v2/events"));
+        assert_eq!(stream.info().redirects().len(), 1);
+        assert_eq!(stream.next().await.unwrap().unwrap(), Event { id: 1 });
+
+        ctx.get_client_settings_mut()
+            .set_host_guard(Some(std::sync::Arc::new(
+                crate::HostGuard::new().without_resolving(),
+            )));
+        // the server is only reached by name, its address being private itself
+        let internal = server.url("/internal").replace("127.0.0.1", "localhost");
+        let err = ctx
+            .ndjson_stream::<Event>(Request::new(Method::GET, internal))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, StepError::BlockedHost(_)), "{}", err);
+        let direct = Request::new(Method::GET, server.url("/v2/events"));
+        let err = ctx.ndjson_stream::<Event>(direct).await.err().unwrap();
+        assert!(matches!(err, StepError::BlockedHost(_)), "{}", err);
+        assert_eq!(server.hits(), 3);
+    }
+}
@@ -0,0 +1,40 @@
+/// What the worker records about each step it runs. Everything is recorded by default;
+/// `Observability::fast()` records nothing but the per step and per host metrics, for hot loops
+/// sending millions of requests. Set it with `Worker::set_observability`, or for a single step
+/// with `Worker::set_step_observability`, e.g. to debug one step of a fast worker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Observability {
+    /// Records successful steps into the worker's snapshot.
+    pub observers: bool,
+    /// Keeps a `StepRecord` of the step in the `RunSummary`. Steps are still counted towards the
+    /// iteration limit and, when rolling back, still recorded.
+    pub history: bool,
+    /// Labels the step's metrics with the context's tags.
+    pub metric_labels: bool,
+}
+
+impl Observability {
+    /// Records everything, the default.
+    pub fn full() -> Self {
+        Self {
+            observers: true,
+            history: true,
+            metric_labels: true,
+        }
+    }
+
+    /// Skips the snapshot, the run history and the metric labels.
+    pub fn fast() -> Self {
+        Self {
+            observers: false,
+            history: false,
+            metric_labels: false,
+        }
+    }
+}
+
+impl Default for Observability {
+    fn default() -> Self {
+        Self::full()
+    }
+}
@@ -0,0 +1,223 @@
+use std::error::Error;
+use std::fmt;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, HOST};
+use reqwest::Method;
+
+use crate::request::MimicBody;
+use crate::Request;
+
+/// How to treat malformed lines in captured headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Every non-empty line must be a valid `Name: value` header.
+    Strict,
+    /// Skips lines that aren't valid headers, HTTP/2 pseudo headers (`:authority`) and request or
+    /// status lines, and joins folded continuation lines, as found in headers copied from
+    /// browser dev tools or intercepting proxies.
+    Lenient,
+}
+
+/// A header or request that couldn't be parsed. `line` is 1-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl ParseError {
+    fn new(line: usize, reason: &str) -> Self {
+        Self {
+            line,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl Error for ParseError {}
+
+/// Parses a blob of `Name: value` lines. This is what `hdr!` uses, in lenient mode.
+pub fn parse_headers(text: &str, mode: ParseMode) -> Result<HeaderMap, ParseError> {
+    let mut headers = HeaderMap::new();
+    let mut last: Option<(HeaderName, String)> = None;
+
+    for (index, raw) in text.lines().enumerate() {
+        let number = index + 1;
+
+        // obs-fold: a line starting with whitespace continues the previous header
+        if mode == ParseMode::Lenient && raw.starts_with([' ', '\t']) && !raw.contains(':') {
+            if let Some((_, value)) = &mut last {
+                value.push(' ');
+                value.push_str(raw.trim());
+                continue;
+            }
+        }
+
+        let line = raw.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some((name, value)) = last.take() {
+            insert(&mut headers, name, &value, number - 1, mode)?;
+        }
+
+        match parse_line(line, number) {
+            Ok(Some(header)) => last = Some(header),
+            Ok(None) => continue,
+            Err(err) if mode == ParseMode::Strict => return Err(err),
+            Err(_) => continue,
+        }
+    }
+
+    if let Some((name, value)) = last {
+        insert(&mut headers, name, &value, text.lines().count(), mode)?;
+    }
+
+    Ok(headers)
+}
+
+/// Returns `None` for lines that are skipped in every mode, like HTTP/2 pseudo headers.
+fn parse_line(line: &str, number: usize) -> Result<Option<(HeaderName, String)>, ParseError> {
+    if line.starts_with(':') {
+        return Ok(None);
+    }
+
+    let Some((name, value)) = line.split_once(':') else {
+        return Err(ParseError::new(number, "missing `:` separator"));
+    };
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ParseError::new(number, "empty header name"));
+    }
+
+    let name = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| ParseError::new(number, "invalid header name"))?;
+    Ok(Some((name, value.trim().to_string())))
+}
+
+fn insert(
+    headers: &mut HeaderMap,
+    name: HeaderName,
+    value: &str,
+    number: usize,
+    mode: ParseMode,
+) -> Result<(), ParseError> {
+    match HeaderValue::from_str(value) {
+        Ok(value) => {
+            headers.insert(name, value);
+            Ok(())
+        }
+        Err(_) if mode == ParseMode::Lenient => Ok(()),
+        Err(_) => Err(ParseError::new(number, "invalid header value")),
+    }
+}
+
+/// Parses a raw HTTP/1.x request, e.g. one copied from an intercepting proxy:
+/// a request line, headers, a blank line and an optional body.
+/// Origin-form targets (`/path`) are resolved with the `Host` header and `scheme`.
+pub fn parse_raw_request(text: &str, scheme: &str, mode: ParseMode) -> Result<Request, ParseError> {
+    let text = text.trim_start_matches(['\r', '\n']);
+    let (head, body) = match text.find("\r\n\r\n") {
+        Some(i) => (&text[..i], &text[i + 4..]),
+        None => match text.find("\n\n") {
+            Some(i) => (&text[..i], &text[i + 2..]),
+            None => (text, ""),
+        },
+    };
+
+    let (request_line, header_text) = head.split_once('\n').unwrap_or((head, ""));
+    let mut parts = request_line.split_whitespace();
+    let method = parts
+        .next()
+        .and_then(|m| Method::from_bytes(m.as_bytes()).ok())
+        .ok_or_else(|| ParseError::new(1, "invalid request method"))?;
+    let target = parts
+        .next()
+        .ok_or_else(|| ParseError::new(1, "missing request target"))?;
+    if mode == ParseMode::Strict && !parts.next().is_some_and(|v| v.starts_with("HTTP/")) {
+        return Err(ParseError::new(1, "missing HTTP version"));
+    }
+
+    let headers = parse_headers(header_text, mode).map_err(|err| ParseError {
+        line: err.line + 1,
+        reason: err.reason,
+    })?;
+
+    let url = if target.starts_with("http://") || target.starts_with("https://") {
+        target.to_string()
+    } else {
+        let host = headers
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .ok_or_else(|| ParseError::new(1, "relative target without a Host header"))?;
+        format!("{}://{}{}", scheme, host, target)
+    };
+
+    let mut request = Request::new(method, url).with_headers(headers);
+    if !body.is_empty() {
+        request = request.with_body(MimicBody::from_text(body.to_string()));
+    }
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_reject_malformed_lines_in_strict_mode() {
+        assert_eq!(
+            parse_headers("Accept: */*\nnot a header", ParseMode::Strict).unwrap_err(),
+            ParseError::new(2, "missing `:` separator")
+        );
+        assert_eq!(
+            parse_headers("Bad Name: x", ParseMode::Strict)
+                .unwrap_err()
+                .reason,
+            "invalid header name"
+        );
+        assert_eq!(
+            parse_headers("X-Test: bad\u{7f}value", ParseMode::Strict)
+                .unwrap_err()
+                .reason,
+            "invalid header value"
+        );
+    }
+
+    #[test]
+    fn it_should_clean_up_captured_headers_in_lenient_mode() {
+        let captured = "GET /search HTTP/2\r\n:authority: example.com\r\n:method: GET\r\nAccept: text/html\r\nX-Long: first\r\n\tsecond\r\nBad Name: x\r\nCookie: a=b";
+
+        let headers = parse_headers(captured, ParseMode::Lenient).unwrap();
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers["accept"], "text/html");
+        assert_eq!(headers["x-long"], "first second");
+        assert_eq!(headers["cookie"], "a=b");
+    }
+
+    #[test]
+    fn it_should_parse_raw_requests() {
+        let raw = "POST /api/login HTTP/1.1\r\nHost: example.com\r\nContent-Type: application/json\r\n\r\n{\"user\": \"ann\"}";
+
+        let request = parse_raw_request(raw, "https", ParseMode::Strict).unwrap();
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.url(), "https://example.com/api/login");
+        assert_eq!(
+            request.headers().unwrap()["content-type"],
+            "application/json"
+        );
+        assert!(request.body().is_some());
+
+        assert!(parse_raw_request("GET /", "https", ParseMode::Lenient).is_err());
+        assert!(
+            parse_raw_request("GET http://example.com/ x", "https", ParseMode::Strict).is_err()
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use std::error::Error;
+use std::panic;
+
+/// Extracts the text of every page of a PDF document.
+/// The extractor panics on some malformed documents, so panics are turned into errors.
+pub fn extract_pdf_pages(body: &[u8]) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    match panic::catch_unwind(|| pdf_extract::extract_text_from_mem_by_pages(body)) {
+        Ok(Ok(pages)) => Ok(pages),
+        Ok(Err(err)) => Err(err.to_string().into()),
+        Err(_) => Err("Unable to extract text from the PDF document.".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a minimal single page PDF, computing the xref offsets so the document is valid.
+    fn pdf_with_text(text: &str) -> Vec<u8> {
+        let content = format!("BT /F1 24 Tf 72 720 Td ({}) Tj ET", text);
+        let objects = [
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            "<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_string(),
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] /Contents 4 0 R /Resources << /Font << /F1 5 0 R >> >> >>".to_string(),
+            format!("<< /Length {} >>\nstream\n{}\nendstream", content.len(), content),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>"
+                .to_string(),
+        ];
+
+        let mut pdf = b"%PDF-1.4\n".to_vec();
+        let mut offsets = vec![];
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).into_bytes());
+        }
+
+        let xref = pdf.len();
+        pdf.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).into_bytes());
+        for offset in offsets {
+            pdf.extend(format!("{:010} 00000 n \n", offset).into_bytes());
+        }
+        pdf.extend(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+                objects.len() + 1,
+                xref
+            )
+            .into_bytes(),
+        );
+        pdf
+    }
+
+    #[test]
+    fn it_should_extract_text_from_a_pdf() {
+        let pages = extract_pdf_pages(&pdf_with_text("Quarterly report")).unwrap();
+        assert_eq!(pages.len(), 1);
+        assert!(pages[0].contains("Quarterly report"));
+    }
+
+    #[test]
+    fn it_should_error_on_documents_that_are_not_pdfs() {
+        assert!(extract_pdf_pages(b"<html>not a pdf</html>").is_err());
+    }
+}
@@ -0,0 +1,553 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::Method;
+
+use crate::{Backoff, Context, Download, Frontier, FrontierEntry, Request, StepError, Stepable};
+
+/// The request tag carrying the depth of the page crawled by `ExtractAndEnqueue`.
+const DEPTH_TAG: &str = "frontier.depth";
+
+/// Decides from a response whether `PollUntil` is done.
+pub type PollPredicate = dyn Fn(&Context) -> bool + Send + Sync;
+
+/// Returns the urls `ExtractAndEnqueue` adds to its frontier, relative ones included.
+pub type LinkExtractor = dyn Fn(&Context) -> Vec<String> + Send + Sync;
+
+/// Fetches a page, optionally keeping its body in the store, then continues with the next step.
+#[derive(Debug, Clone)]
+pub struct FetchPage {
+    name: String,
+    request: Request,
+    store_as: Option<String>,
+    next: Option<String>,
+}
+
+impl FetchPage {
+    pub fn new(name: &str, url: &str) -> Self {
+        Self::with_request(name, Request::new(Method::GET, url.to_string()))
+    }
+
+    /// Fetches with a request of its own, e.g. with headers or a proxy.
+    pub fn with_request(name: &str, request: Request) -> Self {
+        Self {
+            name: name.to_string(),
+            request,
+            store_as: None,
+            next: None,
+        }
+    }
+
+    /// Keeps the body of the page as a `String` under the key of the store.
+    pub fn store_as(mut self, key: &str) -> Self {
+        self.store_as = Some(key.to_string());
+        self
+    }
+
+    pub fn with_next(mut self, next: &str) -> Self {
+        self.next = Some(next.to_string());
+        self
+    }
+}
+
+#[async_trait]
+impl Stepable for FetchPage {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn on_request(&self) -> Request {
+        self.request.clone()
+    }
+
+    async fn on_success(&self, ctx: &mut Context) {
+        if let (Some(key), Ok(body)) = (&self.store_as, ctx.body_text()) {
+            ctx.get_store_mut().set(key, body);
+        }
+        if let Some(next) = &self.next {
+            ctx.set_next_step(next.clone());
+        }
+    }
+
+    async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+    async fn on_timeout(&self, _ctx: &mut Context) {}
+}
+
+/// Fills in and submits a form of the previous step's page, e.g. a login form fetched with
+/// `FetchPage`. Hidden fields, like CSRF tokens, are sent as found. The step fails if the page
+/// has no form matching the selector.
+#[cfg(feature = "html")]
+#[derive(Debug, Clone)]
+pub struct SubmitForm {
+    name: String,
+    selector: String,
+    fields: Vec<(String, String)>,
+    next: Option<String>,
+}
+
+#[cfg(feature = "html")]
+impl SubmitForm {
+    /// Submits the first form matching the CSS selector, e.g. `form#login`.
+    pub fn new(name: &str, selector: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            selector: selector.to_string(),
+            fields: vec![],
+            next: None,
+        }
+    }
+
+    /// Sets a field, replacing its value in the form.
+    pub fn with_field(mut self, name: &str, value: &str) -> Self {
+        self.fields.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_next(mut self, next: &str) -> Self {
+        self.next = Some(next.to_string());
+        self
+    }
+}
+
+#[cfg(feature = "html")]
+#[async_trait]
+impl Stepable for SubmitForm {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Without a page to read the form from, there's nothing to submit.
+    fn on_request(&self) -> Request {
+        Request::new(Method::GET, format!("form not found: {}", self.selector))
+    }
+
+    fn on_request_with(&self, ctx: &Context) -> Request {
+        let Ok(Some(form)) = ctx.form(&self.selector) else {
+            return self.on_request();
+        };
+        self.fields
+            .iter()
+            .fold(form, |form, (name, value)| form.set(name, value))
+            .to_request()
+    }
+
+    async fn on_success(&self, ctx: &mut Context) {
+        if let Some(next) = &self.next {
+            ctx.set_next_step(next.clone());
+        }
+    }
+
+    async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+    async fn on_timeout(&self, _ctx: &mut Context) {}
+}
+
+/// Streams a file to disk, see `Download`, then continues with the next step.
+#[derive(Debug, Clone)]
+pub struct DownloadFile {
+    name: String,
+    request: Request,
+    next: Option<String>,
+}
+
+impl DownloadFile {
+    pub fn new(name: &str, url: &str, download: Download) -> Self {
+        Self::with_request(name, Request::new(Method::GET, url.to_string()), download)
+    }
+
+    pub fn with_request(name: &str, request: Request, download: Download) -> Self {
+        Self {
+            name: name.to_string(),
+            request: request.with_download(download),
+            next: None,
+        }
+    }
+
+    pub fn with_next(mut self, next: &str) -> Self {
+        self.next = Some(next.to_string());
+        self
+    }
+}
+
+#[async_trait]
+impl Stepable for DownloadFile {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn on_request(&self) -> Request {
+        self.request.clone()
+    }
+
+    async fn on_success(&self, ctx: &mut Context) {
+        if let Some(next) = &self.next {
+            ctx.set_next_step(next.clone());
+        }
+    }
+
+    async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+    async fn on_timeout(&self, _ctx: &mut Context) {}
+}
+
+/// Sends a request again until the predicate passes on its response, e.g. until an export is
+/// ready, then continues with the next step, which finds the final response in the context. The
+/// wait between polls follows a `Backoff`, constant unless set with `with_backoff`. Polls are
+/// scheduled with `Context::schedule_step`, so they aren't stopped as loops. After
+/// `max_attempts` failed polls, it continues with the `on_exhausted` step, if any.
+#[derive(Clone)]
+pub struct PollUntil {
+    name: String,
+    request: Request,
+    predicate: Arc<PollPredicate>,
+    backoff: Backoff,
+    max_attempts: u32,
+    next: Option<String>,
+    exhausted: Option<String>,
+}
+
+impl PollUntil {
+    /// Polls every 5 seconds, up to 10 times.
+    pub fn new(
+        name: &str,
+        request: Request,
+        predicate: impl Fn(&Context) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            request,
+            predicate: Arc::new(predicate),
+            backoff: Backoff::constant(Duration::from_secs(5)),
+            max_attempts: 10,
+            next: None,
+            exhausted: None,
+        }
+    }
+
+    /// Waits the same time between every poll.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.backoff = Backoff::constant(interval);
+        self
+    }
+
+    /// Waits longer after every unsuccessful poll, e.g. for jobs that may take seconds or hours.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn with_max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    pub fn with_next(mut self, next: &str) -> Self {
+        self.next = Some(next.to_string());
+        self
+    }
+
+    /// Continues with this step when the predicate never passed.
+    pub fn on_exhausted(mut self, step: &str) -> Self {
+        self.exhausted = Some(step.to_string());
+        self
+    }
+
+    /// The store key counting the polls of the current run.
+    fn attempts_key(&self) -> String {
+        format!("{}.attempts", self.name)
+    }
+}
+
+#[async_trait]
+impl Stepable for PollUntil {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn on_request(&self) -> Request {
+        self.request.clone()
+    }
+
+    async fn on_success(&self, ctx: &mut Context) {
+        let key = self.attempts_key();
+        let attempts = ctx.get_store().get::<u32>(&key).copied().unwrap_or(0) + 1;
+        let done = (self.predicate)(ctx);
+        if done || attempts >= self.max_attempts {
+            ctx.get_store_mut().remove::<u32>(&key);
+            let next = if done { &self.next } else { &self.exhausted };
+            if let Some(next) = next {
+                ctx.set_next_step(next.clone());
+            }
+            return;
+        }
+        ctx.get_store_mut().set(&key, attempts);
+        ctx.schedule_step(self.name.clone(), self.backoff.delay(attempts));
+    }
+
+    async fn on_error(&self, ctx: &mut Context, _err: StepError) {
+        ctx.get_store_mut().remove::<u32>(&self.attempts_key());
+    }
+
+    async fn on_timeout(&self, ctx: &mut Context) {
+        ctx.get_store_mut().remove::<u32>(&self.attempts_key());
+    }
+}
+
+/// Crawls the urls of a frontier one per execution: fetches the next one, adds the links the
+/// extractor finds on it to the frontier, and runs again until the frontier is empty, then
+/// continues with the next step. Pages count towards `Worker::set_max_iterations`. Seed the
+/// frontier before running it; started on an empty frontier, it skips to the next step, or
+/// finishes the run without one.
+#[derive(Clone)]
+pub struct ExtractAndEnqueue {
+    name: String,
+    frontier: Arc<Frontier>,
+    extract: Arc<LinkExtractor>,
+    next: Option<String>,
+}
+
+impl ExtractAndEnqueue {
+    pub fn new(
+        name: &str,
+        frontier: Arc<Frontier>,
+        extract: impl Fn(&Context) -> Vec<String> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            frontier,
+            extract: Arc::new(extract),
+            next: None,
+        }
+    }
+
+    /// Follows the `href` of every link of the pages.
+    #[cfg(feature = "html")]
+    pub fn links(name: &str, frontier: Arc<Frontier>) -> Self {
+        Self::new(name, frontier, |ctx| {
+            ctx.select_all("a[href]")
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|link| link.attr("href").map(str::to_string))
+                .collect()
+        })
+    }
+
+    pub fn with_next(mut self, next: &str) -> Self {
+        self.next = Some(next.to_string());
+        self
+    }
+
+    fn continue_crawl(&self, ctx: &mut Context) {
+        if !self.frontier.is_empty() {
+            ctx.set_next_step(self.name.clone());
+        } else if let Some(next) = &self.next {
+            ctx.set_next_step(next.clone());
+        }
+    }
+}
+
+#[async_trait]
+impl Stepable for ExtractAndEnqueue {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Pops the next url of the frontier. The depth travels in a tag to enqueue its links at.
+    fn on_request(&self) -> Request {
+        match self.frontier.pop() {
+            Some(entry) => {
+                Request::new(Method::GET, entry.url).with_tag(DEPTH_TAG, &entry.depth.to_string())
+            }
+            None => Request::default().skip().skip_to(self.next.clone()),
+        }
+    }
+
+    async fn on_success(&self, ctx: &mut Context) {
+        let parent = FrontierEntry {
+            url: ctx
+                .final_url()
+                .map(str::to_string)
+                .unwrap_or_else(|| ctx.get_url()),
+            depth: ctx
+                .get_tags()
+                .get(DEPTH_TAG)
+                .and_then(|depth| depth.parse().ok())
+                .unwrap_or_default(),
+        };
+        for href in (self.extract)(ctx) {
+            self.frontier.add_link(&parent, &href);
+        }
+        self.continue_crawl(ctx);
+    }
+
+    /// A page that failed is skipped, the crawl goes on.
+    async fn on_error(&self, ctx: &mut Context, _err: StepError) {
+        self.continue_crawl(ctx);
+    }
+
+    async fn on_timeout(&self, ctx: &mut Context) {
+        self.continue_crawl(ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+    use crate::{Clock, CrawlScope, Worker};
+
+    #[tokio::test]
+    async fn it_should_poll_until_the_predicate_passes() {
+        let polls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = {
+            let polls = polls.clone();
+            TestServer::start(move |req| match req.path.as_str() {
+                "/export" => match polls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                    0..=1 => TestResponse::ok("pending"),
+                    _ => TestResponse::ok("done"),
+                },
+                _ => TestResponse::ok("report"),
+            })
+            .await
+        };
+
+        let mut worker = Worker::new();
+        let clock = Clock::virtual_time();
+        worker.ctx.get_delayed_steps_mut().set_clock(clock.clone());
+        let export = Request::new(Method::GET, server.url("/export"));
+        worker.add_step(
+            PollUntil::new("Poll", export.clone(), |ctx| {
+                ctx.body_text().is_ok_and(|body| body == "done")
+            })
+            .with_interval(Duration::from_secs(60))
+            .with_next("Report"),
+        );
+        worker.add_step(FetchPage::new("Report", &server.url("/report")).store_as("report"));
+
+        let summary = worker.run("Poll").await;
+        assert!(summary.is_success());
+        assert_eq!(polls.load(std::sync::atomic::Ordering::SeqCst), 3);
+        assert!(clock.skipped() >= Duration::from_secs(119));
+        assert_eq!(
+            worker.ctx.get_store().get::<String>("report").unwrap(),
+            "report"
+        );
+
+        let mut worker = Worker::new();
+        worker
+            .ctx
+            .get_delayed_steps_mut()
+            .set_clock(Clock::virtual_time());
+        worker.add_step(
+            PollUntil::new("Poll", export, |_| false)
+                .with_max_attempts(2)
+                .on_exhausted("Report"),
+        );
+        worker.add_step(FetchPage::new("Report", &server.url("/report")));
+        let summary = worker.run("Poll").await;
+        let steps: Vec<&str> = summary.steps.iter().map(|s| s.step.as_str()).collect();
+        assert_eq!(steps, vec!["Poll", "Poll", "Report"]);
+    }
+
+    #[tokio::test]
+    async fn it_should_back_off_between_polls() {
+        let server = TestServer::start(|_| TestResponse::ok("pending")).await;
+
+        let mut worker = Worker::new();
+        let clock = Clock::virtual_time();
+        worker.ctx.get_delayed_steps_mut().set_clock(clock.clone());
+        let backoff = Backoff::new(Duration::from_secs(10), 2.0, Duration::from_secs(30));
+        let job = Request::new(Method::GET, server.url("/job"));
+        worker.add_step(
+            PollUntil::new("Poll", job, |_| false)
+                .with_backoff(backoff)
+                .with_max_attempts(5),
+        );
+
+        worker.run("Poll").await;
+        assert_eq!(server.hits(), 5);
+        // 10, 20, 30 and 30 seconds
+        let skipped = clock.skipped();
+        assert!(skipped >= Duration::from_secs(89) && skipped <= Duration::from_secs(90));
+    }
+
+    #[cfg(feature = "html")]
+    #[tokio::test]
+    async fn it_should_submit_the_form_of_the_fetched_page() {
+        let server = TestServer::start(|req| match req.method.as_str() {
+            "GET" => TestResponse::ok(
+                "<form id=\"login\" action=\"/session\" method=\"post\">
+                <input type=\"hidden\" name=\"csrf\" value=\"t0k3n\">
+                <input name=\"user\"></form>",
+            ),
+            _ => TestResponse::ok("welcome"),
+        })
+        .await;
+
+        let mut worker = Worker::new();
+        worker.add_step(FetchPage::new("LoginPage", &server.url("/login")).with_next("Login"));
+        worker.add_step(SubmitForm::new("Login", "form#login").with_field("user", "jane"));
+        assert!(worker.run("LoginPage").await.is_success());
+        let sent = &server.requests()[1];
+        assert_eq!(sent.path, "/session");
+        assert_eq!(String::from_utf8_lossy(&sent.body), "csrf=t0k3n&user=jane");
+
+        // without the form, the step fails
+        let mut worker = Worker::new();
+        worker.add_step(SubmitForm::new("Login", "form#login"));
+        assert!(!worker.run("Login").await.is_success());
+    }
+
+    #[tokio::test]
+    async fn it_should_crawl_the_frontier() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/" => TestResponse::ok("/a /b"),
+            "/a" => TestResponse::ok("/b /c"),
+            _ => TestResponse::ok(""),
+        })
+        .await;
+        let frontier = Arc::new(Frontier::new(CrawlScope::new().with_max_depth(1)));
+        frontier.add_seed(&server.url("/"));
+
+        let mut worker = Worker::new();
+        worker.add_step(
+            ExtractAndEnqueue::new("Crawl", frontier.clone(), |ctx| {
+                let body = ctx.body_text().unwrap_or_default();
+                body.split_whitespace().map(str::to_string).collect()
+            })
+            .with_next("Done"),
+        );
+        worker.add_step(FetchPage::new("Done", &server.url("/done")));
+
+        let summary = worker.run("Crawl").await;
+        assert!(summary.is_success());
+        let paths: Vec<String> = server.requests().iter().map(|r| r.path.clone()).collect();
+        // `/c` is found at depth 2
+        assert_eq!(paths, vec!["/", "/a", "/b", "/done"]);
+        assert!(frontier.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_should_skip_an_empty_frontier() {
+        let server = TestServer::start(|_| TestResponse::ok("")).await;
+        let frontier = Arc::new(Frontier::new(CrawlScope::new()));
+
+        let mut worker = Worker::new();
+        worker.add_step(ExtractAndEnqueue::new(
+            "Crawl",
+            frontier.clone(),
+            |_| vec![],
+        ));
+        let summary = worker.run("Crawl").await;
+        assert!(summary.is_success(), "{:?}", summary.stopped);
+        assert_eq!(server.hits(), 0);
+
+        let mut worker = Worker::new();
+        worker.add_step(ExtractAndEnqueue::new("Crawl", frontier, |_| vec![]).with_next("Done"));
+        worker.add_step(FetchPage::new("Done", &server.url("/done")));
+        assert!(worker.run("Crawl").await.is_success());
+        assert_eq!(server.requests()[0].path, "/done");
+    }
+}
@@ -0,0 +1,232 @@
+use rand::seq::SliceRandom;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT};
+
+use crate::Request;
+
+const ACCEPT_CHROME: &str = "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8,application/signed-exchange;v=b3;q=0.7";
+const ACCEPT_FIREFOX: &str =
+    "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,*/*;q=0.8";
+const ACCEPT_SAFARI: &str = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+
+/// The headers a browser sends with a navigation, in the order it sends them. The user agent,
+/// client hints and `Accept` headers of a profile agree with each other, so requests pass a
+/// `CoherenceValidator`. `Accept-Encoding` is left to the request's compression setting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    name: String,
+    headers: HeaderMap,
+}
+
+impl Profile {
+    /// Creates an empty profile. Add headers in the order the browser sends them.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            headers: HeaderMap::new(),
+        }
+    }
+
+    /// Appends a header, or replaces its value in place if it is already set.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            self.headers.insert(name, value);
+        }
+        self
+    }
+
+    pub fn chrome_120_windows() -> Self {
+        Self::chrome_120(
+            "chrome_120_windows",
+            "Windows NT 10.0; Win64; x64",
+            "Windows",
+        )
+    }
+
+    pub fn chrome_120_mac() -> Self {
+        Self::chrome_120(
+            "chrome_120_mac",
+            "Macintosh; Intel Mac OS X 10_15_7",
+            "macOS",
+        )
+    }
+
+    pub fn firefox_windows() -> Self {
+        Self::firefox_121("firefox_windows", "Windows NT 10.0; Win64; x64")
+    }
+
+    pub fn firefox_mac() -> Self {
+        Self::firefox_121("firefox_mac", "Macintosh; Intel Mac OS X 10.15")
+    }
+
+    pub fn safari_17_mac() -> Self {
+        Self::new("safari_17_mac")
+            .with_header("accept", ACCEPT_SAFARI)
+            .with_header("sec-fetch-site", "none")
+            .with_header("accept-language", "en-US,en;q=0.9")
+            .with_header("sec-fetch-mode", "navigate")
+            .with_header("user-agent", "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.2 Safari/605.1.15")
+            .with_header("sec-fetch-dest", "document")
+    }
+
+    /// Returns every built-in profile.
+    pub fn all() -> Vec<Self> {
+        vec![
+            Self::chrome_120_windows(),
+            Self::chrome_120_mac(),
+            Self::firefox_windows(),
+            Self::firefox_mac(),
+            Self::safari_17_mac(),
+        ]
+    }
+
+    fn chrome_120(name: &str, platform: &str, hint_platform: &str) -> Self {
+        Self::new(name)
+            .with_header(
+                "sec-ch-ua",
+                "\"Not_A Brand\";v=\"8\", \"Chromium\";v=\"120\", \"Google Chrome\";v=\"120\"",
+            )
+            .with_header("sec-ch-ua-mobile", "?0")
+            .with_header("sec-ch-ua-platform", &format!("\"{}\"", hint_platform))
+            .with_header("upgrade-insecure-requests", "1")
+            .with_header(
+                "user-agent",
+                &format!("Mozilla/5.0 ({}) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36", platform),
+            )
+            .with_header("accept", ACCEPT_CHROME)
+            .with_header("sec-fetch-site", "none")
+            .with_header("sec-fetch-mode", "navigate")
+            .with_header("sec-fetch-user", "?1")
+            .with_header("sec-fetch-dest", "document")
+            .with_header("accept-language", "en-US,en;q=0.9")
+    }
+
+    fn firefox_121(name: &str, platform: &str) -> Self {
+        Self::new(name)
+            .with_header(
+                "user-agent",
+                &format!(
+                    "Mozilla/5.0 ({}; rv:121.0) Gecko/20100101 Firefox/121.0",
+                    platform
+                ),
+            )
+            .with_header("accept", ACCEPT_FIREFOX)
+            .with_header("accept-language", "en-US,en;q=0.5")
+            .with_header("upgrade-insecure-requests", "1")
+            .with_header("sec-fetch-dest", "document")
+            .with_header("sec-fetch-mode", "navigate")
+            .with_header("sec-fetch-site", "none")
+            .with_header("sec-fetch-user", "?1")
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn user_agent(&self) -> Option<&str> {
+        self.headers.get(USER_AGENT)?.to_str().ok()
+    }
+
+    /// Sets the profile's headers on a request, in the profile's order. Headers and a user agent
+    /// the request sets itself win, but keep the position the profile gives them.
+    pub fn apply(&self, req: Request) -> Request {
+        let mut headers = self.headers.clone();
+        if let Some(user_agent) = req.user_agent() {
+            if let Ok(value) = HeaderValue::from_str(&user_agent) {
+                headers.insert(USER_AGENT, value);
+            }
+        }
+        if let Some(own) = req.headers() {
+            for (name, value) in own.iter() {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+
+        let user_agent = headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+        let req = req.with_headers(headers);
+        match user_agent {
+            Some(user_agent) => req.with_user_agent(user_agent),
+            None => req,
+        }
+    }
+}
+
+/// Picks a random profile for each new session. Set it with `Worker::set_profile_rotator`;
+/// the worker keeps the picked profile until the session is rotated.
+#[derive(Debug, Clone)]
+pub struct ProfileRotator {
+    profiles: Vec<Profile>,
+}
+
+impl ProfileRotator {
+    pub fn new(profiles: Vec<Profile>) -> Self {
+        Self { profiles }
+    }
+
+    /// Rotates through every built-in profile.
+    pub fn desktop() -> Self {
+        Self::new(Profile::all())
+    }
+
+    pub fn profiles(&self) -> &[Profile] {
+        &self.profiles
+    }
+
+    /// Returns a random profile, or `None` if there are none.
+    pub fn pick(&self) -> Option<Profile> {
+        self.profiles.choose(&mut rand::thread_rng()).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CoherenceMode, CoherenceValidator};
+    use reqwest::Method;
+
+    fn request() -> Request {
+        Request::new(Method::GET, "https://example.com/".to_string())
+    }
+
+    #[test]
+    fn it_should_build_coherent_profiles() {
+        let validator = CoherenceValidator::new(CoherenceMode::Error)
+            .with_expected_languages(vec!["en".to_string()]);
+        for profile in Profile::all() {
+            let req = profile.apply(request());
+            assert_eq!(req.user_agent().as_deref(), profile.user_agent());
+            assert!(
+                validator.validate(&req).is_empty(),
+                "{} is incoherent: {:?}",
+                profile.name(),
+                validator.validate(&req)
+            );
+        }
+    }
+
+    #[test]
+    fn it_should_keep_the_profile_order_and_the_request_headers() {
+        let req =
+            request().with_headers(crate::hdr!("accept: application/json\nx-api-key: secret"));
+        let req = Profile::firefox_mac().apply(req);
+        let headers = req.headers().unwrap();
+        let names: Vec<&str> = headers.keys().map(|name| name.as_str()).collect();
+
+        assert_eq!(&names[..3], &["user-agent", "accept", "accept-language"]);
+        assert_eq!(names.last(), Some(&"x-api-key"));
+        assert_eq!(headers["accept"], "application/json");
+
+        let req = Profile::chrome_120_mac().apply(request().with_user_agent("custom".to_string()));
+        assert_eq!(req.headers().unwrap()["user-agent"], "custom");
+        assert_eq!(req.user_agent().unwrap(), "custom");
+    }
+}
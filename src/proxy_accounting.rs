@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde_derive::Serialize;
+
+use crate::singleflight::SharedResult;
+use crate::Request;
+
+/// The provider proxied requests are accounted to when neither the request nor its identity
+/// names one.
+pub const UNNAMED_PROVIDER: &str = "unnamed";
+
+/// The traffic sent through one proxy provider.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ProxyUsage {
+    /// Every attempt, including retries.
+    pub requests: u64,
+    /// Attempts that failed without a response.
+    pub failures: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl ProxyUsage {
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes_sent + self.bytes_received
+    }
+}
+
+/// Counts the requests and bytes sent through each proxy provider, so invoices of several
+/// providers can be reconciled with what the bot actually sent. Name the provider with
+/// `Request::with_proxy_provider` or `Identity::with_proxy_provider` and share one accounting
+/// between workers with `Worker::set_proxy_accounting`.
+/// Bytes are counted on the wire format: the request line, headers and body, and the status
+/// line, headers and body of the response, using `Content-Length` for compressed bodies.
+/// Proxy handshakes, TLS overhead and streamed multipart bodies aren't visible to the bot, so
+/// invoices can be slightly higher.
+#[derive(Debug, Default)]
+pub struct ProxyAccounting {
+    usage: Mutex<BTreeMap<String, ProxyUsage>>,
+}
+
+impl ProxyAccounting {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the usage of a provider.
+    pub fn usage(&self, provider: &str) -> ProxyUsage {
+        self.usage
+            .lock()
+            .unwrap()
+            .get(provider)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the usage of every provider.
+    pub fn report(&self) -> BTreeMap<String, ProxyUsage> {
+        self.usage.lock().unwrap().clone()
+    }
+
+    /// Returns the usage so far and starts counting from zero, e.g. at the end of a billing period.
+    pub fn take_report(&self) -> BTreeMap<String, ProxyUsage> {
+        std::mem::take(&mut *self.usage.lock().unwrap())
+    }
+
+    /// Returns the report as CSV, one provider per line.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("provider,requests,failures,bytes_sent,bytes_received\n");
+        for (provider, usage) in self.report() {
+            csv.push_str(&format!(
+                "{},{},{},{},{}\n",
+                csv_field(&provider),
+                usage.requests,
+                usage.failures,
+                usage.bytes_sent,
+                usage.bytes_received
+            ));
+        }
+        csv
+    }
+
+    /// Writes the report to a file, as JSON if the path ends with `.json` and as CSV otherwise.
+    pub fn export(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let data = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::to_vec_pretty(&self.report())?
+        } else {
+            self.to_csv().into_bytes()
+        };
+        std::fs::write(path, data)
+    }
+
+    /// Accounts one attempt of a proxied request.
+    pub(crate) fn record(&self, provider: &str, bytes_sent: u64, result: &SharedResult) {
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(provider.to_string()).or_default();
+        usage.requests += 1;
+        usage.bytes_sent += bytes_sent;
+        match result {
+            Ok(res) => usage.bytes_received += response_size(res),
+            Err(err) => {
+                usage.failures += 1;
+                if let Some(partial) = &err.partial {
+                    usage.bytes_received += response_size(partial);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the size of a request on the wire.
+pub(crate) fn request_size(req: &Request) -> u64 {
+    // "GET /path HTTP/1.1\r\n", the host header and the final "\r\n"
+    let mut size = req.method().as_str().len() + req.url().len() + 13;
+    if let Some(headers) = req.headers() {
+        size += headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len() + 4)
+            .sum::<usize>();
+    }
+    if let Some(user_agent) = req.user_agent() {
+        size += user_agent.len() + 14;
+    }
+    let body = req.body().and_then(|body| body.as_bytes().map(|b| b.len()));
+    (size + body.unwrap_or_default()) as u64
+}
+
+fn response_size(res: &crate::singleflight::SharedResponse) -> u64 {
+    // "HTTP/1.1 200 OK\r\n" and the final "\r\n"
+    let headers: usize = 19
+        + res
+            .info
+            .headers()
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len() + 4)
+            .sum::<usize>();
+    let body = res
+        .info
+        .header("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(res.body.len());
+    (headers + body) as u64
+}
+
+/// Quotes a CSV field when it needs to be.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::singleflight::{SharedError, SharedResponse};
+    use crate::{ResponseInfo, StepError};
+
+    #[test]
+    fn it_should_export_the_usage_per_provider() {
+        let accounting = ProxyAccounting::new();
+        let info = ResponseInfo::new(200, crate::hdr!("content-length: 100"), String::new());
+        let ok = Ok(SharedResponse {
+            info,
+            body: bytes::Bytes::from_static(b"short"),
+        });
+        let timeout = Err(SharedError {
+            error: StepError::Timeout,
+            partial: None,
+            connecting: false,
+        });
+        accounting.record("acme, inc", 50, &ok);
+        accounting.record("acme, inc", 50, &timeout);
+        accounting.record("datacenter", 10, &ok);
+
+        let usage = accounting.usage("acme, inc");
+        assert_eq!((usage.requests, usage.failures), (2, 1));
+        // the compressed length, and the headers
+        assert_eq!(
+            usage.bytes_received,
+            19 + ("content-length".len() + 3 + 4) as u64 + 100
+        );
+        assert_eq!(usage.total_bytes(), 240);
+        assert_eq!(
+            accounting.to_csv().lines().collect::<Vec<_>>(),
+            vec![
+                "provider,requests,failures,bytes_sent,bytes_received",
+                "\"acme, inc\",2,1,100,140",
+                "datacenter,1,0,10,140",
+            ]
+        );
+    }
+}
@@ -0,0 +1,803 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use tokio::time::Instant;
+
+use crate::PartitionedCookieStore;
+
+/// A token bucket limit: `burst` tokens that refill at `per_second` tokens per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    burst: f64,
+    per_second: f64,
+    smooth: bool,
+    min_delay: Duration,
+    jitter: Duration,
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, per_second: f64) -> Self {
+        Self {
+            burst: burst.max(1) as f64,
+            per_second,
+            smooth: false,
+            min_delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+        }
+    }
+
+    /// Only spaces requests out by a politeness delay, without a rate limit, see `with_min_delay`.
+    pub fn politeness(min_delay: Duration, jitter: Duration) -> Self {
+        Self::new(1, f64::MAX).with_min_delay(min_delay, jitter)
+    }
+
+    /// Waits at least `min_delay`, plus a random part of `jitter`, between two requests to the
+    /// host, on top of the rate limit, so requests don't arrive at a machine-regular interval.
+    pub fn with_min_delay(mut self, min_delay: Duration, jitter: Duration) -> Self {
+        self.min_delay = min_delay;
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn min_delay(&self) -> Duration {
+        self.min_delay
+    }
+
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// Picks the delay before the next request, between `min_delay` and `min_delay + jitter`.
+    fn next_delay(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.min_delay;
+        }
+        self.min_delay + rand::thread_rng().gen_range(Duration::ZERO..=self.jitter)
+    }
+
+    /// Spreads requests evenly at the refill rate, like a leaky bucket, instead of letting a full
+    /// bucket burst. Some WAFs flag the bursts at the start of each window.
+    pub fn smoothed(mut self) -> Self {
+        self.smooth = true;
+        self
+    }
+
+    pub fn is_smoothed(&self) -> bool {
+        self.smooth
+    }
+
+    /// The tokens a full bucket holds, a single one when smoothed.
+    fn capacity(&self) -> f64 {
+        if self.smooth {
+            1.0
+        } else {
+            self.burst
+        }
+    }
+
+    /// Allows `requests` every second with a burst of the same size.
+    pub fn per_second(requests: u32) -> Self {
+        Self::new(requests, requests as f64)
+    }
+
+    /// Allows `requests` every minute with a burst of one request.
+    pub fn per_minute(requests: u32) -> Self {
+        Self::new(1, requests as f64 / 60.0)
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst as u32
+    }
+
+    pub fn refill_rate(&self) -> f64 {
+        self.per_second
+    }
+}
+
+/// An AIMD controller for `RateLimiter`: every healthy response raises a host's rate by
+/// `increase` requests per second, every failed or slow response multiplies it by `decrease`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveThrottle {
+    min_rate: f64,
+    max_rate: f64,
+    increase: f64,
+    decrease: f64,
+    max_latency: Duration,
+}
+
+impl AdaptiveThrottle {
+    /// Keeps each host's rate between `min_rate` and `max_rate` requests per second.
+    /// Responses slower than 2 seconds count as unhealthy.
+    pub fn new(min_rate: f64, max_rate: f64) -> Self {
+        Self {
+            min_rate: min_rate.min(max_rate),
+            max_rate: max_rate.max(min_rate),
+            increase: max_rate.max(min_rate) / 20.0,
+            decrease: 0.5,
+            max_latency: Duration::from_secs(2),
+        }
+    }
+
+    /// Sets the rate added per healthy response, in requests per second.
+    pub fn with_increase(mut self, increase: f64) -> Self {
+        self.increase = increase.max(0.0);
+        self
+    }
+
+    /// Sets the factor the rate is multiplied by on an unhealthy response, e.g. `0.5`.
+    pub fn with_decrease(mut self, decrease: f64) -> Self {
+        self.decrease = decrease.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Responses slower than this count as unhealthy, even if they succeeded.
+    pub fn with_max_latency(mut self, max_latency: Duration) -> Self {
+        self.max_latency = max_latency;
+        self
+    }
+
+    fn adjust(&self, rate: f64, latency: Duration, success: bool) -> f64 {
+        let rate = if success && latency <= self.max_latency {
+            rate + self.increase
+        } else {
+            rate * self.decrease
+        };
+        rate.clamp(self.min_rate, self.max_rate)
+    }
+}
+
+/// The prefixes of the rate-limit headers servers send: the IETF draft's `RateLimit-Remaining`,
+/// and the `X-RateLimit-Remaining` of GitHub, Twitter and most others.
+const BUDGET_HEADER_PREFIXES: &[&str] = &["ratelimit-", "x-ratelimit-", "x-rate-limit-"];
+
+/// Reset values past this many seconds are Unix timestamps rather than delays.
+const EPOCH_RESET_THRESHOLD: u64 = 1_000_000_000;
+
+/// The request budget a server announces in its rate-limit headers, see
+/// `Context::rate_limit_budget`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitBudget {
+    /// The requests allowed per window, if announced.
+    pub limit: Option<u64>,
+    /// The requests left in the current window.
+    pub remaining: u64,
+    /// How long until the window resets, if announced.
+    pub reset: Option<Duration>,
+}
+
+impl RateLimitBudget {
+    /// Parses the `RateLimit-*`, `X-RateLimit-*` or structured `RateLimit` headers of a
+    /// response. Resets given as Unix timestamps are turned into delays.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let header = |name: &str| headers.get(name)?.to_str().ok();
+        let fields = BUDGET_HEADER_PREFIXES
+            .iter()
+            .find_map(|prefix| {
+                let field = |name: &str| header(&format!("{}{}", prefix, name)).map(str::to_string);
+                Some((field("limit"), field("remaining")?, field("reset")))
+            })
+            .or_else(|| {
+                // the structured form of the later drafts: `limit=100, remaining=50, reset=30`
+                let value = header("ratelimit")?;
+                let param = |name: &str| {
+                    value.split([',', ';']).find_map(|param| {
+                        let (key, value) = param.split_once('=')?;
+                        (key.trim() == name).then(|| value.trim().to_string())
+                    })
+                };
+                Some((param("limit"), param("remaining")?, param("reset")))
+            })?;
+        let (limit, remaining, reset) = fields;
+
+        let reset = reset.as_deref().and_then(first_number).map(|reset| {
+            if reset >= EPOCH_RESET_THRESHOLD {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                Duration::from_secs(reset.saturating_sub(now))
+            } else {
+                Duration::from_secs(reset)
+            }
+        });
+        Some(Self {
+            limit: limit.as_deref().and_then(first_number),
+            remaining: first_number(&remaining)?,
+            reset,
+        })
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+/// Returns the number a header value starts with, e.g. `100` of `100, 100;w=60`.
+fn first_number(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
+/// The budget announced by a host's last response, see `RateLimiter::with_budget_awareness`.
+#[derive(Debug)]
+struct Budget {
+    remaining: u64,
+    reset_at: Instant,
+    /// The time between requests that spreads what's left of the budget until the reset.
+    pace: Duration,
+    next_allowed: Instant,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// The politeness delay after the last request ends at this instant.
+    next_allowed: Instant,
+}
+
+/// A per-host token bucket rate limiter that can be shared between workers with an `Arc`.
+/// Each request consumes its `Request::with_cost` in tokens, so expensive endpoints slow a flow
+/// down more than cheap ones.
+#[derive(Debug)]
+pub struct RateLimiter {
+    default_limit: Option<RateLimit>,
+    host_limits: HashMap<String, RateLimit>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    adaptive: Option<AdaptiveThrottle>,
+    per_domain: bool,
+    rates: Mutex<HashMap<String, f64>>,
+    /// The number of waiting requests per host and priority.
+    waiting: Mutex<HashMap<String, BTreeMap<u8, usize>>>,
+    budget_reserve: Option<u64>,
+    budgets: Mutex<HashMap<String, Budget>>,
+}
+
+/// Registers a waiting request until it is dropped, even if the acquiring future is cancelled.
+struct Waiting<'a> {
+    limiter: &'a RateLimiter,
+    host: String,
+    priority: u8,
+}
+
+impl<'a> Waiting<'a> {
+    fn new(limiter: &'a RateLimiter, host: &str, priority: u8) -> Self {
+        *limiter
+            .waiting
+            .lock()
+            .unwrap()
+            .entry(host.to_string())
+            .or_default()
+            .entry(priority)
+            .or_default() += 1;
+
+        Self {
+            limiter,
+            host: host.to_string(),
+            priority,
+        }
+    }
+
+    fn has_higher_priority(&self) -> bool {
+        if self.priority == u8::MAX {
+            return false;
+        }
+        self.limiter
+            .waiting
+            .lock()
+            .unwrap()
+            .get(&self.host)
+            .is_some_and(|waiting| waiting.range(self.priority + 1..).next().is_some())
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut waiting = self.limiter.waiting.lock().unwrap();
+        if let Some(priorities) = waiting.get_mut(&self.host) {
+            if let Some(count) = priorities.get_mut(&self.priority) {
+                *count -= 1;
+                if *count == 0 {
+                    priorities.remove(&self.priority);
+                }
+            }
+            if priorities.is_empty() {
+                waiting.remove(&self.host);
+            }
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new()
+    }
+}
+
+impl RateLimiter {
+    /// Creates a limiter without any limits. Hosts without a limit are never throttled.
+    pub fn new() -> Self {
+        Self {
+            default_limit: None,
+            host_limits: HashMap::new(),
+            buckets: Mutex::new(HashMap::new()),
+            adaptive: None,
+            per_domain: false,
+            rates: Mutex::new(HashMap::new()),
+            waiting: Mutex::new(HashMap::new()),
+            budget_reserve: None,
+            budgets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the limit used for every host without its own limit.
+    pub fn with_default_limit(mut self, limit: RateLimit) -> Self {
+        self.default_limit = Some(limit);
+        self
+    }
+
+    /// Sets the limit for a single host, e.g. `api.example.com`.
+    pub fn with_host_limit(mut self, host: &str, limit: RateLimit) -> Self {
+        self.host_limits.insert(host.to_lowercase(), limit);
+        self
+    }
+
+    /// Adjusts each host's refill rate from the outcomes passed to `record`. Hosts without a
+    /// limit start at the throttle's maximum rate with a burst of one.
+    pub fn with_adaptive_throttle(mut self, throttle: AdaptiveThrottle) -> Self {
+        self.adaptive = Some(throttle);
+        self
+    }
+
+    /// Shares the buckets of every host of a registrable domain, e.g. `www.example.com` and
+    /// `api.example.com`, since they usually end up on the same servers. Limits set for a
+    /// single host still apply to it, but count towards the domain's bucket.
+    pub fn per_domain(mut self) -> Self {
+        self.per_domain = true;
+        self
+    }
+
+    /// Slows down before the server does: once the rate-limit headers passed to `record_budget`
+    /// announce `reserve` requests or fewer left, the rest are spread evenly until the window
+    /// resets, and an exhausted budget waits for the reset. This applies to hosts without a
+    /// limit too.
+    pub fn with_budget_awareness(mut self, reserve: u64) -> Self {
+        self.budget_reserve = Some(reserve);
+        self
+    }
+
+    /// Feeds the budget a host announced, see `with_budget_awareness`. Budgets without a reset
+    /// are ignored, since there's no telling how long to wait.
+    pub fn record_budget(&self, host: &str, budget: &RateLimitBudget) {
+        let (Some(reserve), Some(reset)) = (self.budget_reserve, budget.reset) else {
+            return;
+        };
+        let mut budgets = self.budgets.lock().unwrap();
+        if budget.remaining > reserve {
+            budgets.remove(&self.key(host));
+            return;
+        }
+
+        let now = Instant::now();
+        let next_allowed = budgets
+            .get(&self.key(host))
+            .map_or(now, |budget| budget.next_allowed);
+        budgets.insert(
+            self.key(host),
+            Budget {
+                remaining: budget.remaining,
+                reset_at: now + reset,
+                pace: reset / (budget.remaining.max(1) as u32),
+                next_allowed,
+            },
+        );
+    }
+
+    /// Reserves the next request of the host's budget, returning how long to wait for it.
+    fn budget_delay(&self, host: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let mut budgets = self.budgets.lock().unwrap();
+        let key = self.key(host);
+        let budget = budgets.get_mut(&key)?;
+        if budget.reset_at <= now {
+            budgets.remove(&key);
+            return None;
+        }
+        if budget.remaining == 0 {
+            return Some(budget.reset_at - now);
+        }
+
+        let slot = budget.next_allowed.max(now);
+        budget.next_allowed = slot + budget.pace;
+        budget.remaining -= 1;
+        Some(slot - now).filter(|wait| !wait.is_zero())
+    }
+
+    /// Returns the key of the bucket a host's requests are counted in.
+    fn key(&self, host: &str) -> String {
+        if self.per_domain {
+            PartitionedCookieStore::partition_key(host)
+        } else {
+            host.to_lowercase()
+        }
+    }
+
+    pub fn limit_for(&self, host: &str) -> Option<RateLimit> {
+        let key = self.key(host);
+        let limit = (self.host_limits.get(&host.to_lowercase()))
+            .or_else(|| self.host_limits.get(&key))
+            .copied()
+            .or(self.default_limit);
+
+        let Some(throttle) = &self.adaptive else {
+            return limit;
+        };
+        let limit = limit.unwrap_or(RateLimit::new(1, throttle.max_rate));
+        let per_second = match self.rates.lock().unwrap().get(&key) {
+            Some(rate) => *rate,
+            None => limit.per_second.clamp(throttle.min_rate, throttle.max_rate),
+        };
+
+        Some(RateLimit {
+            per_second,
+            ..limit
+        })
+    }
+
+    /// Feeds a response's latency and outcome to the adaptive throttle, if there is one.
+    pub fn record(&self, host: &str, latency: Duration, success: bool) {
+        let Some(throttle) = self.adaptive else {
+            return;
+        };
+        let Some(current) = self.limit_for(host).map(|l| l.per_second) else {
+            return;
+        };
+
+        self.rates
+            .lock()
+            .unwrap()
+            .insert(self.key(host), throttle.adjust(current, latency, success));
+    }
+
+    /// Returns a host's current requests per second, including adaptive adjustments.
+    pub fn current_rate(&self, host: &str) -> Option<f64> {
+        self.limit_for(host).map(|l| l.per_second)
+    }
+
+    /// Waits until `cost` tokens are available for the host and consumes them.
+    /// A cost larger than the burst is allowed once the bucket is full, leaving it in debt.
+    pub async fn acquire(&self, host: &str, cost: u32) {
+        self.acquire_with_priority(host, cost, 0).await;
+    }
+
+    /// Like `acquire`, but requests waiting for the same host with a higher priority get their
+    /// tokens first, so urgent flows don't queue behind bulk work.
+    pub async fn acquire_with_priority(&self, host: &str, cost: u32, priority: u8) {
+        if let Some(wait) = self.budget_delay(host) {
+            tokio::time::sleep(wait).await;
+        }
+        if self.limit_for(host).is_none() {
+            return;
+        }
+        let waiting = Waiting::new(self, &self.key(host), priority);
+
+        loop {
+            let Some(limit) = self.limit_for(host) else {
+                return;
+            };
+            let wait = if waiting.has_higher_priority() {
+                // check again once the next token has been refilled
+                Some(
+                    Duration::from_secs_f64(1.0 / limit.per_second.max(1.0))
+                        .max(Duration::from_millis(1)),
+                )
+            } else {
+                self.try_acquire(host, cost, limit)
+            };
+
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => return,
+            }
+        }
+    }
+
+    /// Consumes the tokens if available, otherwise returns how long to wait before trying again.
+    fn try_acquire(&self, host: &str, cost: u32, limit: RateLimit) -> Option<Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(self.key(host)).or_insert(Bucket {
+            tokens: limit.capacity(),
+            updated: now,
+            next_allowed: now,
+        });
+        if bucket.next_allowed > now {
+            return Some(bucket.next_allowed - now);
+        }
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(limit.capacity());
+        bucket.updated = now;
+
+        let required = (cost as f64).min(limit.capacity());
+        if bucket.tokens >= required {
+            bucket.tokens -= cost as f64;
+            bucket.next_allowed = now + limit.next_delay();
+            return None;
+        }
+
+        if limit.per_second <= 0.0 {
+            return Some(Duration::from_secs(1));
+        }
+        let missing = required - bucket.tokens;
+        Some(Duration::from_secs_f64(missing / limit.per_second))
+    }
+
+    /// Returns the tokens currently available for a host, if it has a limit.
+    pub fn available(&self, host: &str) -> Option<f64> {
+        let limit = self.limit_for(host)?;
+        let buckets = self.buckets.lock().unwrap();
+        match buckets.get(&self.key(host)) {
+            Some(bucket) => {
+                let elapsed = Instant::now().duration_since(bucket.updated).as_secs_f64();
+                Some((bucket.tokens + elapsed * limit.per_second).min(limit.capacity()))
+            }
+            None => Some(limit.capacity()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_not_throttle_hosts_without_limits() {
+        let limiter = RateLimiter::new().with_host_limit("slow.com", RateLimit::per_second(1));
+
+        let start = Instant::now();
+        for _ in 0..10 {
+            limiter.acquire("fast.com", 5).await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert!(limiter.available("fast.com").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_wait_for_expensive_requests() {
+        let limiter = RateLimiter::new().with_default_limit(RateLimit::new(5, 1.0));
+
+        let start = Instant::now();
+        limiter.acquire("search.com", 5).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        limiter.acquire("search.com", 5).await;
+        assert!(start.elapsed() >= Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_serve_higher_priorities_first() {
+        let limiter = Arc::new(RateLimiter::new().with_default_limit(RateLimit::new(1, 1.0)));
+        limiter.acquire("shop.com", 1).await;
+
+        let order = Arc::new(Mutex::new(vec![]));
+        let spawn = |label: &'static str, priority: u8| {
+            let limiter = limiter.clone();
+            let order = order.clone();
+            tokio::spawn(async move {
+                limiter.acquire_with_priority("shop.com", 1, priority).await;
+                order.lock().unwrap().push(label);
+            })
+        };
+
+        let bulk = spawn("bulk", 0);
+        tokio::task::yield_now().await;
+        let urgent = spawn("urgent", 9);
+        bulk.await.unwrap();
+        urgent.await.unwrap();
+
+        assert_eq!(*order.lock().unwrap(), vec!["urgent", "bulk"]);
+        assert!(limiter.waiting.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_should_back_off_and_recover_adaptively() {
+        let limiter = RateLimiter::new()
+            .with_host_limit("api.com", RateLimit::per_second(10))
+            .with_adaptive_throttle(AdaptiveThrottle::new(1.0, 10.0).with_increase(1.0));
+        assert_eq!(limiter.current_rate("api.com"), Some(10.0));
+
+        limiter.record("api.com", Duration::from_millis(100), false);
+        assert_eq!(limiter.current_rate("api.com"), Some(5.0));
+        limiter.record("api.com", Duration::from_secs(5), true);
+        assert_eq!(limiter.current_rate("api.com"), Some(2.5));
+        for _ in 0..5 {
+            limiter.record("api.com", Duration::from_millis(100), false);
+        }
+        assert_eq!(limiter.current_rate("api.com"), Some(1.0));
+
+        for _ in 0..20 {
+            limiter.record("api.com", Duration::from_millis(100), true);
+        }
+        assert_eq!(limiter.current_rate("api.com"), Some(10.0));
+        assert_eq!(limiter.current_rate("other.com"), Some(10.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_wait_the_politeness_delay_between_requests() {
+        let limiter = Arc::new(RateLimiter::new().with_default_limit(RateLimit::politeness(
+            Duration::from_secs(2),
+            Duration::from_secs(1),
+        )));
+
+        let start = Instant::now();
+        let bots: Vec<_> = (0..3)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move { limiter.acquire("shop.com", 1).await })
+            })
+            .collect();
+        for bot in bots {
+            bot.await.unwrap();
+        }
+        // three requests, with two delays of 2 to 3 seconds between them
+        assert!(start.elapsed() >= Duration::from_secs(4));
+        assert!(start.elapsed() <= Duration::from_secs(6) + Duration::from_millis(10));
+
+        let start = Instant::now();
+        limiter.acquire("other.com", 1).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_share_the_buckets_of_a_domain() {
+        let limiter = RateLimiter::new()
+            .with_default_limit(RateLimit::per_second(1))
+            .with_host_limit("api.example.com", RateLimit::per_second(10))
+            .per_domain();
+        assert_eq!(limiter.current_rate("api.example.com"), Some(10.0));
+        assert_eq!(limiter.current_rate("www.example.com"), Some(1.0));
+
+        let start = Instant::now();
+        limiter.acquire("www.example.com", 1).await;
+        limiter.acquire("cdn.example.com", 1).await;
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(limiter.available("example.com"), Some(0.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_space_smoothed_requests_evenly() {
+        let limiter = RateLimiter::new()
+            .with_host_limit("waf.com", RateLimit::per_second(4).smoothed())
+            .with_host_limit("burst.com", RateLimit::per_second(4));
+
+        let start = Instant::now();
+        let mut released = vec![];
+        for _ in 0..4 {
+            limiter.acquire("waf.com", 1).await;
+            released.push(start.elapsed());
+        }
+        assert_eq!(
+            released,
+            vec![
+                Duration::ZERO,
+                Duration::from_millis(250),
+                Duration::from_millis(500),
+                Duration::from_millis(750)
+            ]
+        );
+
+        let start = Instant::now();
+        for _ in 0..4 {
+            limiter.acquire("burst.com", 1).await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // an expensive request delays the next one by its cost
+        let start = Instant::now();
+        limiter.acquire("waf.com", 2).await;
+        limiter.acquire("waf.com", 1).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(750));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_weight_cheap_requests_less() {
+        let limiter = RateLimiter::new().with_host_limit("api.com", RateLimit::new(2, 1.0));
+
+        let start = Instant::now();
+        for _ in 0..4 {
+            limiter.acquire("API.com", 1).await;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(2) && elapsed < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn it_should_parse_rate_limit_headers() {
+        let headers = |pairs: &[(&'static str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            headers
+        };
+
+        let budget = RateLimitBudget::from_headers(&headers(&[
+            ("x-ratelimit-limit", "5000"),
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", "30"),
+        ]))
+        .unwrap();
+        assert_eq!(budget.limit, Some(5000));
+        assert!(budget.is_exhausted());
+        assert_eq!(budget.reset, Some(Duration::from_secs(30)));
+
+        let budget = RateLimitBudget::from_headers(&headers(&[
+            ("ratelimit-remaining", "42"),
+            ("ratelimit-limit", "100, 100;w=60"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            (budget.limit, budget.remaining, budget.reset),
+            (Some(100), 42, None)
+        );
+
+        let in_a_minute = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let budget = RateLimitBudget::from_headers(&headers(&[
+            ("x-rate-limit-remaining", "7"),
+            ("x-rate-limit-reset", &in_a_minute.to_string()),
+        ]))
+        .unwrap();
+        assert!(budget.reset.unwrap() > Duration::from_secs(55));
+
+        let budget = RateLimitBudget::from_headers(&headers(&[(
+            "ratelimit",
+            "limit=10, remaining=3, reset=5",
+        )]))
+        .unwrap();
+        assert_eq!(budget.remaining, 3);
+        assert!(RateLimitBudget::from_headers(&HeaderMap::new()).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_spread_a_low_budget_until_the_reset() {
+        let limiter = RateLimiter::new().with_budget_awareness(5);
+        let budget = |remaining, reset| RateLimitBudget {
+            limit: Some(100),
+            remaining,
+            reset: Some(Duration::from_secs(reset)),
+        };
+
+        // plenty left, no waiting
+        limiter.record_budget("api.com", &budget(50, 60));
+        let start = Instant::now();
+        limiter.acquire("api.com", 1).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // 4 left over 8 seconds, one every 2 seconds
+        limiter.record_budget("api.com", &budget(4, 8));
+        for _ in 0..3 {
+            limiter.acquire("api.com", 1).await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(4));
+
+        limiter.record_budget("api.com", &budget(0, 10));
+        let start = Instant::now();
+        limiter.acquire("api.com", 1).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        limiter.acquire("api.com", 1).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+    }
+}
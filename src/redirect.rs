@@ -0,0 +1,195 @@
+use reqwest::header::{
+    HeaderMap, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, HOST, LOCATION,
+    PROXY_AUTHORIZATION, REFERER, WWW_AUTHENTICATE,
+};
+use reqwest::{Method, Url};
+
+use crate::{HostGuard, ResponseInfo, StepError};
+
+/// The number of redirects followed by default, like reqwest and browsers.
+pub const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// Whether the redirects of a request are followed, see `Request::with_redirect_policy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum RedirectPolicy {
+    /// Follows up to `DEFAULT_MAX_REDIRECTS` redirects.
+    #[default]
+    Follow,
+    /// Returns the redirect response itself, e.g. to read the `code` of an OAuth callback from
+    /// its `Location`.
+    None,
+    /// Follows up to this many redirects, failing with `StepError::TooManyRedirects` past them.
+    Limit(usize),
+}
+
+impl RedirectPolicy {
+    /// Returns how many redirects are followed.
+    pub fn max_redirects(&self) -> usize {
+        match self {
+            Self::Follow => DEFAULT_MAX_REDIRECTS,
+            Self::None => 0,
+            Self::Limit(max) => *max,
+        }
+    }
+}
+
+/// A redirect that was followed, see `Context::redirect_chain`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedirectHop {
+    /// The url that answered with the redirect.
+    pub url: String,
+    pub status: u16,
+    /// The absolute url it redirected to.
+    pub location: String,
+    /// The `Set-Cookie` headers of the redirect. The cookies are already in the jar.
+    pub set_cookies: Vec<String>,
+}
+
+impl RedirectHop {
+    pub(crate) fn new(info: &ResponseInfo, location: &Url) -> Self {
+        Self {
+            url: info.final_url().to_string(),
+            status: info.status(),
+            location: location.to_string(),
+            set_cookies: info.set_cookies().into_iter().map(str::to_string).collect(),
+        }
+    }
+}
+
+/// Returns the absolute url a response redirects to, if it's a redirect with a valid
+/// `Location`.
+pub(crate) fn redirect_location(info: &ResponseInfo) -> Option<Url> {
+    if !matches!(info.status(), 301 | 302 | 303 | 307 | 308) {
+        return None;
+    }
+    let location = info.header(LOCATION.as_str())?;
+    Url::parse(info.final_url()).ok()?.join(location).ok()
+}
+
+/// Returns the request following a response's redirect, or `None` to return the response: it
+/// isn't a redirect, the policy doesn't follow redirects, or the request can't be sent again.
+/// Fails past the policy's limit, and when the guard refuses the location. Followed hops are
+/// added to `hops`.
+pub(crate) async fn next_request(
+    policy: RedirectPolicy,
+    guard: Option<&HostGuard>,
+    hops: &mut Vec<RedirectHop>,
+    info: &ResponseInfo,
+    next: Option<reqwest::Request>,
+) -> Result<Option<reqwest::Request>, StepError> {
+    let (Some(location), Some(next)) = (redirect_location(info), next) else {
+        return Ok(None);
+    };
+    if policy == RedirectPolicy::None {
+        return Ok(None);
+    }
+    if hops.len() >= policy.max_redirects() {
+        return Err(StepError::TooManyRedirects(info.final_url().to_string()));
+    }
+    if let Some(guard) = guard {
+        if let Err(violation) = guard.check(location.as_str()).await {
+            return Err(StepError::BlockedHost(violation.to_string()));
+        }
+    }
+    hops.push(RedirectHop::new(info, &location));
+    Ok(Some(follow(next, info.status(), location)))
+}
+
+/// Turns a request into the one following its redirect, the way browsers do: 303s, and 301s
+/// and 302s of a POST, become a GET without a body; credentials aren't sent to another
+/// origin.
+pub(crate) fn follow(mut req: reqwest::Request, status: u16, location: Url) -> reqwest::Request {
+    let previous = req.url().clone();
+    let to_get = match status {
+        303 => req.method() != Method::HEAD,
+        301 | 302 => req.method() == Method::POST,
+        _ => false,
+    };
+    if to_get {
+        *req.method_mut() = Method::GET;
+        *req.body_mut() = None;
+        for name in [CONTENT_TYPE, CONTENT_LENGTH] {
+            req.headers_mut().remove(name);
+        }
+    }
+
+    let headers = req.headers_mut();
+    if previous.origin() != location.origin() {
+        remove_credentials(headers);
+    }
+    headers.remove(REFERER);
+    // no referer from a secure page to an insecure one
+    if !(previous.scheme() == "https" && location.scheme() == "http") {
+        let mut referer = previous;
+        referer.set_fragment(None);
+        let _ = referer.set_username("");
+        let _ = referer.set_password(None);
+        if let Ok(value) = referer.as_str().parse() {
+            headers.insert(REFERER, value);
+        }
+    }
+
+    *req.url_mut() = location;
+    req
+}
+
+fn remove_credentials(headers: &mut HeaderMap) {
+    for name in [
+        AUTHORIZATION,
+        COOKIE,
+        PROXY_AUTHORIZATION,
+        WWW_AUTHENTICATE,
+        HOST,
+    ] {
+        headers.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirect(status: u16, url: &str, location: &str) -> ResponseInfo {
+        let mut headers = HeaderMap::new();
+        headers.insert(LOCATION, location.parse().unwrap());
+        ResponseInfo::new(status, headers, url.to_string())
+    }
+
+    #[test]
+    fn it_should_resolve_redirect_locations() {
+        let info = redirect(302, "https://shop.example/a/b", "../login?next=%2F");
+        assert_eq!(
+            redirect_location(&info).unwrap().as_str(),
+            "https://shop.example/login?next=%2F"
+        );
+        assert!(redirect_location(&redirect(200, "https://shop.example/", "/x")).is_none());
+        assert_eq!(RedirectPolicy::default().max_redirects(), 10);
+        assert_eq!(RedirectPolicy::Limit(2).max_redirects(), 2);
+    }
+
+    #[test]
+    fn it_should_follow_redirects_like_browsers() {
+        let client = reqwest::Client::new();
+        let post = client
+            .post("https://shop.example/login")
+            .header(AUTHORIZATION, "Bearer abc")
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body("user=jane")
+            .build()
+            .unwrap();
+
+        let location = Url::parse("https://shop.example/account").unwrap();
+        let next = follow(post.try_clone().unwrap(), 302, location);
+        assert_eq!(next.method(), Method::GET);
+        assert!(next.body().is_none() && !next.headers().contains_key(CONTENT_TYPE));
+        assert_eq!(next.headers()[AUTHORIZATION], "Bearer abc");
+        assert_eq!(next.headers()[REFERER], "https://shop.example/login");
+
+        let location = Url::parse("http://auth.example/callback").unwrap();
+        let next = follow(post, 307, location);
+        assert_eq!(next.method(), Method::POST);
+        assert!(next.body().is_some());
+        assert!(!next.headers().contains_key(AUTHORIZATION));
+        assert!(!next.headers().contains_key(REFERER));
+    }
+}
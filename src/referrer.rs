@@ -0,0 +1,94 @@
+use reqwest::Url;
+
+/// Keeps a plausible `Referer` chain across a flow, e.g. search engine -> landing page -> target.
+/// Every successful GET becomes the referrer of the next request, trimmed the way browsers do
+/// with the default `strict-origin-when-cross-origin` policy.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReferrerChain {
+    entry: Option<String>,
+    current: Option<String>,
+}
+
+impl ReferrerChain {
+    /// Creates a chain that starts without a referrer, like a typed in or bookmarked url.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts the chain as if the user arrived from `url`, e.g. `https://www.google.com/`.
+    pub fn with_entry(mut self, url: &str) -> Self {
+        self.entry = Some(url.to_string());
+        self.current = Some(url.to_string());
+        self
+    }
+
+    /// Returns the page the next request navigates from.
+    pub fn current(&self) -> Option<&String> {
+        self.current.as_ref()
+    }
+
+    /// Records `url` as the page the next request navigates from.
+    pub fn visit(&mut self, url: &str) {
+        self.current = Some(url.to_string());
+    }
+
+    /// Starts over from the entry page.
+    pub fn reset(&mut self) {
+        self.current = self.entry.clone();
+    }
+
+    /// Returns the `Referer` value a browser would send when navigating from the current page to
+    /// `target`: the full url (without fragment) for same-origin requests, only the origin for
+    /// cross-origin requests and nothing when downgrading from https to http.
+    pub fn referer_for(&self, target: &str) -> Option<String> {
+        let from = Url::parse(self.current.as_ref()?).ok()?;
+        let to = Url::parse(target).ok()?;
+
+        if from.scheme() == "https" && to.scheme() == "http" {
+            return None;
+        }
+
+        if from.origin() == to.origin() {
+            let mut referer = from;
+            referer.set_fragment(None);
+            let _ = referer.set_username("");
+            let _ = referer.set_password(None);
+            Some(referer.to_string())
+        } else {
+            Some(format!("{}/", from.origin().ascii_serialization()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_follow_the_chain() {
+        let mut chain = ReferrerChain::new().with_entry("https://www.google.com/search?q=shoes");
+        assert_eq!(
+            chain.referer_for("https://shop.example.com/").unwrap(),
+            "https://www.google.com/"
+        );
+
+        chain.visit("https://shop.example.com/shoes#reviews");
+        assert_eq!(
+            chain.referer_for("https://shop.example.com/cart").unwrap(),
+            "https://shop.example.com/shoes"
+        );
+        assert!(chain.referer_for("http://shop.example.com/cart").is_none());
+
+        chain.reset();
+        assert_eq!(
+            chain.current().unwrap(),
+            "https://www.google.com/search?q=shoes"
+        );
+    }
+
+    #[test]
+    fn it_should_not_send_a_referer_without_a_previous_page() {
+        let chain = ReferrerChain::new();
+        assert!(chain.referer_for("https://example.com/").is_none());
+    }
+}
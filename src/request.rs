@@ -1,8 +1,17 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
-use reqwest::header::HeaderMap;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use reqwest::multipart::{Form, Part};
 use reqwest::{Body, Method, Proxy};
+use serde_json::Value;
+
+use crate::multipart::Part as EncodedPart;
+use crate::{Download, IpPreference, MultipartDialect, RedirectPolicy, RetryPolicy};
 
 #[derive(Debug, Clone)]
 pub struct Request {
@@ -11,17 +20,30 @@ pub struct Request {
     headers: Option<HeaderMap>,
     timeout: Option<Duration>,
     body: Option<MimicBody>,
+    content_type: Option<String>,
     multipart: Option<MimicForm>,
     status_codes: Option<Vec<u16>>,
     proxy: Option<Proxy>,
+    proxy_provider: Option<String>,
     user_agent: Option<String>,
     gzip: bool,
     skip_to: Option<String>,
+    skipped: bool,
+    cost: u32,
+    priority: Option<u8>,
+    tags: BTreeMap<String, String>,
+    ip_preference: Option<IpPreference>,
+    retry_policy: Option<RetryPolicy>,
+    respect_retry_after: Option<bool>,
+    redirect_policy: Option<RedirectPolicy>,
+    conditional: bool,
+    response_schema: Option<Arc<Value>>,
+    host_header: Option<String>,
+    connect_to: Option<SocketAddr>,
+    download: Option<Download>,
 }
 
 /// A builder for a request.
-/// Note: This needs a skip() method to skip a request.
-/// Also it needs a skip_to() method to skip to a specific step.
 impl Request {
     pub fn new(method: Method, url: String) -> Self {
         Self {
@@ -30,15 +52,36 @@ impl Request {
             headers: None,
             timeout: Some(Duration::new(30, 0)),
             body: None,
+            content_type: None,
             multipart: None,
             status_codes: None,
             proxy: None,
+            proxy_provider: None,
             user_agent: None,
             gzip: true,
             skip_to: None,
+            skipped: false,
+            cost: 1,
+            priority: None,
+            tags: BTreeMap::new(),
+            ip_preference: None,
+            retry_policy: None,
+            respect_retry_after: None,
+            redirect_policy: None,
+            conditional: false,
+            response_schema: None,
+            host_header: None,
+            connect_to: None,
+            download: None,
         }
     }
 
+    /// Creates a request with any method token, e.g. `PROPFIND` or `PURGE`, failing on tokens
+    /// that aren't valid HTTP methods.
+    pub fn custom(method: &str, url: String) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(Self::new(Method::from_bytes(method.as_bytes())?, url))
+    }
+
     pub fn method(&self) -> Method {
         self.method.clone()
     }
@@ -47,6 +90,11 @@ impl Request {
         &self.url
     }
 
+    pub fn with_url(mut self, url: String) -> Self {
+        self.url = url;
+        self
+    }
+
     pub fn with_headers(mut self, headers: HeaderMap) -> Self {
         self.headers = Some(headers);
         self
@@ -74,6 +122,44 @@ impl Request {
         self.body.as_ref().map(|b| Body::from(b.clone()))
     }
 
+    /// Returns the bytes of the body, `None` without one or for multipart bodies.
+    pub(crate) fn body_bytes(&self) -> Option<&[u8]> {
+        self.body.as_ref().map(|body| match body {
+            MimicBody::Bytes(bytes) => bytes.as_slice(),
+            MimicBody::Text(text) => text.as_bytes(),
+        })
+    }
+
+    /// Sets the body to the value serialized as JSON, sent as `application/json`.
+    pub fn with_json(
+        self,
+        value: &impl serde::Serialize,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let json = serde_json::to_vec(value)?;
+        Ok(self.with_body_bytes(json, "application/json"))
+    }
+
+    /// Sets the body to the url encoded fields, sent as `application/x-www-form-urlencoded`.
+    pub fn with_form<K: AsRef<str>, V: AsRef<str>>(self, fields: &[(K, V)]) -> Self {
+        let mut url = reqwest::Url::parse("http://form/").unwrap();
+        url.query_pairs_mut().extend_pairs(fields);
+        let encoded = url.query().unwrap_or_default().as_bytes().to_vec();
+        self.with_body_bytes(encoded, "application/x-www-form-urlencoded")
+    }
+
+    /// Sets the body to raw bytes of a content type, e.g. `application/octet-stream`.
+    pub fn with_body_bytes(mut self, bytes: impl Into<Vec<u8>>, content_type: &str) -> Self {
+        self.body = Some(MimicBody::from_bytes(bytes.into()));
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+
+    /// Returns the `Content-Type` of a body set by `with_json`, `with_form` or
+    /// `with_body_bytes`. It's sent unless the headers already have one.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
     pub fn with_multipart(mut self, multipart: MimicForm) -> Self {
         self.multipart = Some(multipart);
         self
@@ -83,6 +169,10 @@ impl Request {
         self.multipart.as_ref().map(|m| Form::from(m.clone()))
     }
 
+    pub(crate) fn multipart_form(&self) -> Option<&MimicForm> {
+        self.multipart.as_ref()
+    }
+
     pub fn with_status_codes(mut self, status_codes: Vec<u16>) -> Self {
         self.status_codes = Some(status_codes);
         self
@@ -101,6 +191,16 @@ impl Request {
         self.proxy.clone()
     }
 
+    /// Names the provider of the request's proxy, see `ProxyAccounting`.
+    pub fn with_proxy_provider(mut self, provider: &str) -> Self {
+        self.proxy_provider = Some(provider.to_string());
+        self
+    }
+
+    pub fn proxy_provider(&self) -> Option<&str> {
+        self.proxy_provider.as_deref()
+    }
+
     pub fn with_user_agent(mut self, user_agent: String) -> Self {
         self.user_agent = Some(user_agent);
         self
@@ -129,14 +229,233 @@ impl Request {
         self
     }
 
+    /// Skips the request without a step to continue with, so the run finishes unless a step
+    /// is scheduled.
+    pub fn skip(mut self) -> Self {
+        self.skipped = true;
+        self
+    }
+
     pub fn is_skipped(&self) -> bool {
-        self.skip_to.is_some()
+        self.skipped || self.skip_to.is_some()
     }
 
     pub fn get_skip_to_step(&self) -> Option<String> {
         self.skip_to.clone()
     }
 
+    /// Sets how many rate limiter tokens the request consumes. Defaults to 1.
+    pub fn with_cost(mut self, cost: u32) -> Self {
+        self.cost = cost;
+        self
+    }
+
+    pub fn cost(&self) -> u32 {
+        self.cost
+    }
+
+    /// Sets the scheduling priority of this request only. Higher values are served first.
+    /// Requests without a priority inherit the flow's priority from `Context::set_priority`.
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    pub fn priority(&self) -> Option<u8> {
+        self.priority
+    }
+
+    /// Attaches a key/value tag, e.g. `with_tag("category", "checkout")`. Tags are kept in the
+    /// context, labelled in the metrics and copied to the run report's dead letters.
+    pub fn with_tag(mut self, key: &str, value: &str) -> Self {
+        self.tags.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        &self.tags
+    }
+
+    /// Overrides the session's address family for this request only.
+    pub fn with_ip_preference(mut self, preference: IpPreference) -> Self {
+        self.ip_preference = Some(preference);
+        self
+    }
+
+    pub fn ip_preference(&self) -> Option<IpPreference> {
+        self.ip_preference
+    }
+
+    /// Retries the request on timeouts and retryable statuses before the step's `on_error`.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    pub fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry_policy.as_ref()
+    }
+
+    /// Sleeps for the `Retry-After` of a 429 or 503 response and retries, up to 3 attempts,
+    /// instead of failing the step. With a retry policy, overrides its `respect_retry_after`.
+    pub fn with_respect_retry_after(mut self, respect: bool) -> Self {
+        self.respect_retry_after = Some(respect);
+        self
+    }
+
+    pub fn respect_retry_after(&self) -> Option<bool> {
+        self.respect_retry_after
+    }
+
+    /// Follows redirects, or not, or only so many. Every followed hop is kept, see
+    /// `Context::redirect_chain`. Requests without one follow up to 10 redirects.
+    pub fn with_redirect_policy(mut self, policy: RedirectPolicy) -> Self {
+        self.redirect_policy = Some(policy);
+        self
+    }
+
+    pub fn redirect_policy(&self) -> Option<RedirectPolicy> {
+        self.redirect_policy
+    }
+
+    /// Sends a GET with the `If-None-Match` and `If-Modified-Since` of the url's last response,
+    /// kept by the worker, and accepts a `304`, see `Context::not_modified`.
+    pub fn with_conditional(mut self, conditional: bool) -> Self {
+        self.conditional = conditional;
+        self
+    }
+
+    pub fn is_conditional(&self) -> bool {
+        self.conditional
+    }
+
+    /// Validates the JSON body of the response against a JSON Schema, failing the step with a
+    /// `StepError::SchemaMismatch` naming every field that drifted, see `validate_schema`.
+    pub fn with_response_schema(mut self, schema: Value) -> Self {
+        self.response_schema = Some(Arc::new(schema));
+        self
+    }
+
+    pub fn response_schema(&self) -> Option<&Value> {
+        self.response_schema.as_deref()
+    }
+
+    /// Returns the retry policy the request is sent with: its own, or the `Retry-After` one of
+    /// `with_respect_retry_after`.
+    pub(crate) fn effective_retry_policy(&self) -> Option<RetryPolicy> {
+        match (&self.retry_policy, self.respect_retry_after) {
+            (Some(policy), Some(respect)) => Some(policy.clone().respect_retry_after(respect)),
+            (Some(policy), None) => Some(policy.clone()),
+            (None, Some(true)) => Some(RetryPolicy::retry_after_only(3)),
+            (None, _) => None,
+        }
+    }
+
+    /// Sends this `Host` header instead of the url's host, e.g. to probe the virtual hosts of a
+    /// server. TLS still sends the url's host as SNI.
+    pub fn with_host_header(mut self, host: &str) -> Self {
+        self.host_header = Some(host.to_string());
+        self
+    }
+
+    pub fn host_header(&self) -> Option<&str> {
+        self.host_header.as_deref()
+    }
+
+    /// Connects to this address instead of resolving the url's host, keeping the url's host in
+    /// the `Host` header and SNI. The port is the url's, the address's port is ignored.
+    ///
+    /// There is no switch between the request-target forms: requests sent `with_proxy` always use
+    /// absolute-form for http urls and a CONNECT tunnel for https urls, as reqwest does. To send
+    /// origin-form to a forward proxy or gateway, connect to it with this instead.
+    pub fn with_connect_to(mut self, addr: SocketAddr) -> Self {
+        self.connect_to = Some(addr);
+        self
+    }
+
+    pub fn connect_to(&self) -> Option<SocketAddr> {
+        self.connect_to
+    }
+
+    /// Streams a successful response to a file instead of reading it into the context, see
+    /// `Context::download_report`.
+    pub fn with_download(mut self, download: Download) -> Self {
+        self.download = Some(download);
+        self
+    }
+
+    pub fn download(&self) -> Option<&Download> {
+        self.download.as_ref()
+    }
+
+    /// Converts a request recorded by a browser, an entry of a HAR file's `log.entries`, e.g.
+    /// to bootstrap a flow, see `Flow::from_har`. HTTP/2 pseudo headers, `Content-Length` and
+    /// `Cookie` are left out; the cookie jar sends the session's own cookies. Form posts recorded
+    /// as `params` are urlencoded.
+    pub fn from_har_entry(entry: &serde_json::Value) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let request = entry.get("request").unwrap_or(entry);
+        let field = |name: &str| {
+            request
+                .get(name)
+                .and_then(|value| value.as_str())
+                .ok_or_else(|| std::io::Error::other(format!("HAR request without a {}", name)))
+        };
+        let method = Method::from_bytes(field("method")?.as_bytes())?;
+        let url = field("url")?.to_string();
+
+        let mut headers = HeaderMap::new();
+        let recorded = request.get("headers").and_then(|h| h.as_array());
+        for header in recorded.into_iter().flatten() {
+            let (Some(name), Some(value)) = (
+                header.get("name").and_then(|n| n.as_str()),
+                header.get("value").and_then(|v| v.as_str()),
+            ) else {
+                continue;
+            };
+            if name.starts_with(':')
+                || name.eq_ignore_ascii_case("content-length")
+                || name.eq_ignore_ascii_case("cookie")
+            {
+                continue;
+            }
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+
+        let mut req = Self::new(method, url);
+        if !headers.is_empty() {
+            req = req.with_headers(headers);
+        }
+        let Some(post_data) = request.get("postData") else {
+            return Ok(req);
+        };
+        let body = match post_data.get("text").and_then(|t| t.as_str()) {
+            Some(text) => text.to_string(),
+            None => {
+                let params = post_data.get("params").and_then(|p| p.as_array());
+                let pairs = params.into_iter().flatten().filter_map(|param| {
+                    let name = param.get("name")?.as_str()?;
+                    Some((name, param.get("value")?.as_str().unwrap_or_default()))
+                });
+                let mut url = reqwest::Url::parse("http://form/").unwrap();
+                url.query_pairs_mut().extend_pairs(pairs);
+                url.query().unwrap_or_default().to_string()
+            }
+        };
+        Ok(req.with_body(MimicBody::from_text(body)))
+    }
+
+    /// Returns the host of the request url, if it can be parsed.
+    pub fn host(&self) -> Option<String> {
+        reqwest::Url::parse(&self.url)
+            .ok()
+            .and_then(|url| url.host_str().map(|h| h.to_string()))
+    }
+
     pub fn build(self) -> Self {
         self
     }
@@ -150,12 +469,27 @@ impl Default for Request {
             headers: None,
             timeout: Some(Duration::new(30, 0)),
             body: None,
+            content_type: None,
             multipart: None,
             status_codes: None,
             proxy: None,
+            proxy_provider: None,
             user_agent: None,
             gzip: true,
             skip_to: None,
+            skipped: false,
+            cost: 1,
+            priority: None,
+            tags: BTreeMap::new(),
+            ip_preference: None,
+            retry_policy: None,
+            respect_retry_after: None,
+            redirect_policy: None,
+            conditional: false,
+            response_schema: None,
+            host_header: None,
+            connect_to: None,
+            download: None,
         }
     }
 }
@@ -185,30 +519,165 @@ impl From<MimicBody> for Body {
     }
 }
 
-#[derive(Debug, Clone)]
+/// A `multipart/form-data` body of text fields and files, sent in the order they were added.
+#[derive(Debug, Clone, Default)]
 pub struct MimicForm {
-    texts: Vec<(String, String)>,
-    bytes: Vec<(String, Vec<u8>)>,
+    parts: Vec<(String, MimicPart)>,
+    dialect: MultipartDialect,
+}
+
+#[derive(Debug, Clone)]
+enum MimicPart {
+    Text(String),
+    Bytes {
+        data: Vec<u8>,
+        file_name: Option<String>,
+        mime: Option<String>,
+    },
 }
 
 impl MimicForm {
+    /// Creates a form with the text fields followed by the byte fields.
     pub fn new(texts: Vec<(String, String)>, bytes: Vec<(String, Vec<u8>)>) -> Self {
-        Self { texts, bytes }
+        let texts = texts
+            .into_iter()
+            .map(|(name, value)| (name, MimicPart::Text(value)));
+        let bytes = bytes.into_iter().map(|(name, data)| {
+            let part = MimicPart::Bytes {
+                data,
+                file_name: None,
+                mime: None,
+            };
+            (name, part)
+        });
+        Self {
+            parts: texts.chain(bytes).collect(),
+            dialect: MultipartDialect::default(),
+        }
+    }
+
+    pub fn with_text(mut self, name: &str, value: &str) -> Self {
+        self.parts
+            .push((name.to_string(), MimicPart::Text(value.to_string())));
+        self
+    }
+
+    /// Adds a file part from memory, e.g. `with_bytes("avatar", png, "me.png", "image/png")`.
+    pub fn with_bytes(mut self, name: &str, data: Vec<u8>, file_name: &str, mime: &str) -> Self {
+        let part = MimicPart::Bytes {
+            data,
+            file_name: Some(file_name.to_string()),
+            mime: Some(mime.to_string()),
+        };
+        self.parts.push((name.to_string(), part));
+        self
+    }
+
+    /// Adds a file part read from disk, named after the file, with a content type guessed from
+    /// its extension. The file is read now, so the request can be sent again on retries.
+    pub fn with_file(self, name: &str, path: &Path) -> std::io::Result<Self> {
+        let data = std::fs::read(path)?;
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mime = guess_mime(path);
+        Ok(self.with_bytes(name, data, &file_name, mime))
+    }
+
+    /// Returns the names of the parts, in order.
+    pub fn names(&self) -> Vec<&str> {
+        self.parts.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// Encodes the form the way a client does, e.g. `MultipartDialect::Chrome` for an upload a
+    /// browser would make.
+    pub fn with_dialect(mut self, dialect: MultipartDialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    pub fn dialect(&self) -> MultipartDialect {
+        self.dialect
+    }
+
+    /// Returns the `Content-Type` and body of a form with a dialect, `None` for reqwest's.
+    pub(crate) fn encode(&self) -> Option<(String, Vec<u8>)> {
+        if self.dialect == MultipartDialect::Reqwest {
+            return None;
+        }
+        let parts: Vec<EncodedPart> = self
+            .parts
+            .iter()
+            .map(|(name, part)| match part {
+                MimicPart::Text(value) => EncodedPart {
+                    name,
+                    data: value.as_bytes(),
+                    file: None,
+                },
+                MimicPart::Bytes {
+                    data,
+                    file_name,
+                    mime,
+                } => EncodedPart {
+                    name,
+                    data,
+                    file: file_name
+                        .as_deref()
+                        .map(|file_name| (file_name, mime.as_deref())),
+                },
+            })
+            .collect();
+        Some(self.dialect.encode(&parts))
+    }
+}
+
+/// Guesses a file's content type from the extensions commonly uploaded.
+fn guess_mime(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "txt" => "text/plain",
+        "csv" => "text/csv",
+        "html" | "htm" => "text/html",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp4" => "video/mp4",
+        _ => "application/octet-stream",
     }
 }
 
 impl From<MimicForm> for Form {
     fn from(body: MimicForm) -> Form {
-        let form = Form::new();
-        // add all body.texts to form.text without losing ownership of form afterwards
-        let form = body
-            .texts
+        body.parts
             .into_iter()
-            .fold(form, |form, (key, value)| form.text(key, value));
-
-        body.bytes.into_iter().fold(form, |form, (key, value)| {
-            form.part(key, Part::bytes(value))
-        })
+            .fold(Form::new(), |form, (name, part)| match part {
+                MimicPart::Text(value) => form.text(name, value),
+                MimicPart::Bytes {
+                    data,
+                    file_name,
+                    mime,
+                } => {
+                    let mut part = Part::bytes(data);
+                    if let Some(file_name) = file_name {
+                        part = part.file_name(file_name);
+                    }
+                    // an invalid type is sent without a content type rather than failing
+                    if let Some(mime) = mime.filter(|m| Part::text("").mime_str(m).is_ok()) {
+                        part = part.mime_str(&mime).unwrap();
+                    }
+                    form.part(name, part)
+                }
+            })
     }
 }
 
@@ -216,19 +685,8 @@ impl From<MimicForm> for Form {
 #[macro_export]
 macro_rules! hdr {
     ($text:expr) => {{
-        let mut headers = HeaderMap::new();
-        for line in $text.lines() {
-            if line.is_empty() || !line.contains(":") {
-                continue;
-            }
-            // split at the first occurrence of ":" and only take the first two parts
-            // this should split most headers correctly
-            let mut parts = line.splitn(2, ":");
-            let key = parts.next().unwrap().trim();
-            let value = parts.next().unwrap().trim();
-            headers.insert(key, value.parse().unwrap());
-        }
-        headers
+        // lenient parsing never fails, malformed lines are skipped
+        $crate::parse_headers(&$text, $crate::ParseMode::Lenient).unwrap_or_default()
     }};
     () => {{
         let headers = HeaderMap::new();
@@ -278,6 +736,9 @@ mod tests {
             .skip_to(Some("RobotsTxt".to_string()))
             .build();
         assert_eq!(req.get_skip_to_step().unwrap(), "RobotsTxt");
+
+        let req = Request::new(Method::GET, "https://google.com".to_string()).skip();
+        assert!(req.is_skipped() && req.get_skip_to_step().is_none());
     }
 
     #[test]
@@ -308,4 +769,15 @@ mod tests {
         assert_eq!(req.user_agent().unwrap(), "reqwest");
         assert!(!req.is_compressed());
     }
+
+    #[test]
+    fn it_should_default_to_a_cost_of_one() {
+        let req = Request::new(Method::GET, "https://google.com/search".to_string());
+        assert_eq!(req.cost(), 1);
+        assert_eq!(req.host().unwrap(), "google.com");
+
+        let req = req.with_cost(5);
+        assert_eq!(req.cost(), 5);
+        assert!(Request::default().host().is_none());
+    }
 }
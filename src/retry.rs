@@ -0,0 +1,313 @@
+use std::time::Duration;
+
+use reqwest::header::{HeaderName, HeaderValue, RETRY_AFTER};
+use reqwest::Method;
+
+use crate::singleflight::SharedResult;
+use crate::uuid::uuid_v4;
+use crate::{Request, ResponseInfo};
+
+/// The header idempotency keys are sent in unless the policy names another one.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Exponentially growing waits between attempts: `initial`, then `initial * multiplier`, and so
+/// on, capped at `max`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    initial: Duration,
+    multiplier: f64,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, multiplier: f64, max: Duration) -> Self {
+        Self {
+            initial,
+            multiplier: multiplier.max(1.0),
+            max,
+        }
+    }
+
+    /// Waits the same time between every attempt.
+    pub fn constant(delay: Duration) -> Self {
+        Self::new(delay, 1.0, delay)
+    }
+
+    /// Returns the wait after the given failed attempt, starting at 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let secs = self.initial.as_secs_f64() * self.multiplier.powi(exponent);
+        // past `Duration::MAX`, or infinite
+        Duration::try_from_secs_f64(secs).map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+impl Default for Backoff {
+    /// 500 ms, doubling up to 30 seconds.
+    fn default() -> Self {
+        Self::new(Duration::from_millis(500), 2.0, Duration::from_secs(30))
+    }
+}
+
+/// Retries a request before the step's error handler is called. Retryable errors, e.g. timeouts
+/// and dropped connections (see `StepError::is_retryable`), and the `retry_on_status` statuses
+/// are retried. Attach it with `Request::with_retry_policy`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Backoff,
+    retry_on_status: Vec<u16>,
+    respect_retry_after: bool,
+    max_retry_after: Duration,
+    require_retry_after: bool,
+    idempotency_header: Option<String>,
+}
+
+impl RetryPolicy {
+    /// Tries a request up to `max_attempts` times, including the first one.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            backoff: Backoff::default(),
+            retry_on_status: vec![408, 429, 500, 502, 503, 504],
+            respect_retry_after: true,
+            max_retry_after: Duration::from_secs(300),
+            require_retry_after: false,
+            idempotency_header: None,
+        }
+    }
+
+    /// Only retries 429 and 503 responses with a `Retry-After`, after waiting for it. It's the
+    /// policy of requests without one that set `Request::with_respect_retry_after`.
+    pub fn retry_after_only(max_attempts: u32) -> Self {
+        Self {
+            retry_on_status: vec![429, 503],
+            require_retry_after: true,
+            ..Self::new(max_attempts)
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Replaces the retried statuses, 408, 429, 500, 502, 503 and 504 by default.
+    pub fn with_retry_on_status(mut self, statuses: Vec<u16>) -> Self {
+        self.retry_on_status = statuses;
+        self
+    }
+
+    /// Waits for the `Retry-After` seconds of a retried status instead of the backoff. Enabled by
+    /// default. Responses asking for longer than `max_retry_after` aren't retried.
+    pub fn respect_retry_after(mut self, respect: bool) -> Self {
+        self.respect_retry_after = respect;
+        self
+    }
+
+    pub fn with_max_retry_after(mut self, max: Duration) -> Self {
+        self.max_retry_after = max;
+        self
+    }
+
+    /// Sends POST and PATCH requests with a random idempotency key in the `Idempotency-Key`
+    /// header, the same one on every attempt, so a retried payment isn't submitted twice.
+    /// Requests that set the header themselves keep their own key.
+    pub fn with_idempotency_key(self) -> Self {
+        self.with_idempotency_header(IDEMPOTENCY_KEY_HEADER)
+    }
+
+    /// Like `with_idempotency_key`, sending the key in another header, e.g. `X-Request-Id`.
+    pub fn with_idempotency_header(mut self, header: &str) -> Self {
+        self.idempotency_header = Some(header.to_string());
+        self
+    }
+
+    pub fn idempotency_header(&self) -> Option<&str> {
+        self.idempotency_header.as_deref()
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub fn backoff(&self) -> &Backoff {
+        &self.backoff
+    }
+
+    /// Returns how long to wait before retrying after the given attempt, or `None` if the result
+    /// shouldn't be retried.
+    pub(crate) fn retry_delay(&self, attempt: u32, result: &SharedResult) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+
+        match result {
+            Err(err) if err.error.is_retryable() => Some(self.backoff.delay(attempt)),
+            Err(_) => None,
+            Ok(res) if self.retry_on_status.contains(&res.info.status()) => {
+                match retry_after(&res.info).filter(|_| self.respect_retry_after) {
+                    Some(wait) if wait > self.max_retry_after => None,
+                    Some(wait) => Some(wait),
+                    None if self.require_retry_after => None,
+                    None => Some(self.backoff.delay(attempt)),
+                }
+            }
+            Ok(_) => None,
+        }
+    }
+
+    /// Adds an idempotency key to unsafe requests when the policy asks for one.
+    pub(crate) fn apply_idempotency_key(&self, req: Request) -> Request {
+        let Some(Ok(name)) = self
+            .idempotency_header
+            .as_ref()
+            .map(|header| HeaderName::from_bytes(header.as_bytes()))
+        else {
+            return req;
+        };
+        if !matches!(req.method(), Method::POST | Method::PATCH) {
+            return req;
+        }
+
+        let mut headers = req.headers().unwrap_or_default();
+        if headers.contains_key(&name) {
+            return req;
+        }
+        headers.insert(name, HeaderValue::from_str(&uuid_v4()).unwrap());
+        req.with_headers(headers)
+    }
+}
+
+/// Returns the `Retry-After` header in seconds. HTTP dates aren't supported.
+fn retry_after(info: &ResponseInfo) -> Option<Duration> {
+    let seconds = info.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    seconds.trim().parse().ok().map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::singleflight::{SharedError, SharedResponse};
+    use crate::StepError;
+    use reqwest::header::HeaderMap;
+
+    fn response(status: u16, retry_after: Option<&str>) -> SharedResponse {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = retry_after {
+            headers.insert(RETRY_AFTER, value.parse().unwrap());
+        }
+        SharedResponse {
+            info: ResponseInfo::new(status, headers, "https://example.com/".to_string()),
+            body: bytes::Bytes::new(),
+        }
+    }
+
+    #[test]
+    fn it_should_back_off_exponentially() {
+        let backoff = Backoff::new(Duration::from_millis(100), 2.0, Duration::from_secs(1));
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(3), Duration::from_millis(400));
+        assert_eq!(backoff.delay(10), Duration::from_secs(1));
+        assert_eq!(backoff.delay(80), Duration::from_secs(1));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(1));
+        assert_eq!(
+            Backoff::constant(Duration::from_secs(2)).delay(5),
+            Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn it_should_only_retry_retryable_results() {
+        let policy = RetryPolicy::new(3).with_backoff(Backoff::constant(Duration::from_secs(1)));
+        let timeout = Err(SharedError {
+            error: StepError::Timeout,
+            partial: None,
+            connecting: false,
+        });
+        let not_found = Err(SharedError {
+            error: StepError::StepNotFound("Login".to_string()),
+            partial: None,
+            connecting: false,
+        });
+
+        assert_eq!(
+            policy.retry_delay(1, &timeout),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(policy.retry_delay(3, &timeout), None);
+        assert_eq!(policy.retry_delay(1, &not_found), None);
+        assert_eq!(policy.retry_delay(1, &Ok(response(200, None))), None);
+        assert_eq!(policy.retry_delay(1, &Ok(response(404, None))), None);
+        assert_eq!(
+            policy.retry_delay(1, &Ok(response(503, None))),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn it_should_add_one_idempotency_key_to_unsafe_requests() {
+        let policy = RetryPolicy::new(3).with_idempotency_key();
+        let post = Request::new(Method::POST, "https://example.com/pay".to_string());
+
+        let req = policy.apply_idempotency_key(post.clone());
+        let key = req.headers().unwrap()["idempotency-key"].clone();
+        assert_eq!(key.len(), 36);
+        assert_eq!(&key.to_str().unwrap()[14..15], "4");
+
+        let again = policy.apply_idempotency_key(req);
+        assert_eq!(again.headers().unwrap()["idempotency-key"], key);
+        assert_ne!(
+            policy
+                .apply_idempotency_key(post.clone())
+                .headers()
+                .unwrap()["idempotency-key"],
+            key
+        );
+
+        let get = Request::new(Method::GET, "https://example.com/".to_string());
+        assert!(policy.apply_idempotency_key(get).headers().is_none());
+        assert!(RetryPolicy::new(3)
+            .apply_idempotency_key(post.clone())
+            .headers()
+            .is_none());
+
+        let custom = RetryPolicy::new(3).with_idempotency_header("X-Request-Id");
+        assert!(custom
+            .apply_idempotency_key(post)
+            .headers()
+            .unwrap()
+            .contains_key("x-request-id"));
+    }
+
+    #[test]
+    fn it_should_respect_retry_after() {
+        let policy = RetryPolicy::new(3);
+        assert_eq!(
+            policy.retry_delay(1, &Ok(response(429, Some("7")))),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(
+            policy.retry_delay(1, &Ok(response(429, Some("3600")))),
+            None
+        );
+        assert_eq!(
+            policy.retry_delay(1, &Ok(response(429, Some("Wed, 21 Oct 2015 07:28:00 GMT")))),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            policy
+                .respect_retry_after(false)
+                .retry_delay(1, &Ok(response(429, Some("7")))),
+            Some(Duration::from_millis(500))
+        );
+
+        let policy = RetryPolicy::retry_after_only(3);
+        assert_eq!(
+            policy.retry_delay(1, &Ok(response(503, Some("2")))),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(policy.retry_delay(1, &Ok(response(503, None))), None);
+        assert_eq!(policy.retry_delay(1, &Ok(response(502, Some("2")))), None);
+    }
+}
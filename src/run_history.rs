@@ -0,0 +1,303 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{RunReport, RunSummary, StopReason};
+
+/// A step of a run kept by `RunHistory`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryStep {
+    pub step: String,
+    pub elapsed_ms: u64,
+    pub error: Option<String>,
+}
+
+/// A run kept by `RunHistory`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryRun {
+    pub flow: String,
+    /// When the run was recorded, in seconds since the Unix epoch.
+    pub recorded_at: u64,
+    pub success: bool,
+    /// Why the run stopped, e.g. `finished` or the error of the failed step.
+    pub stopped: String,
+    pub steps: Vec<HistoryStep>,
+}
+
+impl HistoryRun {
+    pub fn recorded_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.recorded_at)
+    }
+
+    pub fn elapsed_ms(&self) -> u64 {
+        self.steps.iter().map(|step| step.elapsed_ms).sum()
+    }
+}
+
+/// How often a step ran and failed, see `RunHistory::step_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StepStats {
+    pub runs: u64,
+    pub failures: u64,
+    /// The total time spent in the step, to average it over its runs.
+    pub elapsed_ms: u64,
+}
+
+impl StepStats {
+    pub fn failure_rate(&self) -> f64 {
+        match self.runs {
+            0 => 0.0,
+            runs => self.failures as f64 / runs as f64,
+        }
+    }
+
+    pub fn average_ms(&self) -> u64 {
+        self.elapsed_ms.checked_div(self.runs).unwrap_or_default()
+    }
+}
+
+/// Keeps the runs of a long-running deployment in a JSON lines file, one run per line, and
+/// answers questions about them, e.g. the failure rate of each step over the last week, so bots
+/// can report trends without a metrics stack. Runs are appended as they're recorded; the whole
+/// file is read when it's opened, so prune old runs with `prune_before` now and then.
+#[derive(Debug)]
+pub struct RunHistory {
+    path: Option<PathBuf>,
+    runs: Mutex<Vec<HistoryRun>>,
+}
+
+impl RunHistory {
+    /// Opens the history kept in a file, creating it on the first recorded run. Lines that
+    /// can't be parsed, e.g. the last line of a crashed process, are skipped.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let runs = match std::fs::read_to_string(&path) {
+            Ok(text) => text
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            runs: Mutex::new(runs),
+        })
+    }
+
+    /// Keeps the history in memory only.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            runs: Mutex::new(vec![]),
+        }
+    }
+
+    /// Records a run of `Worker::run` under the name of its flow.
+    pub fn record(&self, flow: &str, summary: &RunSummary) -> io::Result<()> {
+        self.record_at(flow, summary, SystemTime::now())
+    }
+
+    /// Like `record`, for a run that happened at another time, e.g. when importing old runs.
+    pub fn record_at(&self, flow: &str, summary: &RunSummary, at: SystemTime) -> io::Result<()> {
+        let steps = summary
+            .steps
+            .iter()
+            .map(|record| HistoryStep {
+                step: record.step.clone(),
+                elapsed_ms: record.elapsed_ms,
+                error: record.error.clone(),
+            })
+            .collect();
+        let stopped = match &summary.stopped {
+            StopReason::Finished => "finished".to_string(),
+            StopReason::Failed(error) => error.clone(),
+            StopReason::MaxIterations => "max iterations".to_string(),
+            StopReason::LoopDetected(url) => format!("loop detected at {}", url),
+            StopReason::Cancelled => "cancelled".to_string(),
+        };
+        self.push(HistoryRun {
+            flow: flow.to_string(),
+            recorded_at: unix_secs(at),
+            success: summary.is_success(),
+            stopped,
+            steps,
+        })
+    }
+
+    /// Records a batch of `Worker::run_batch` as a single run.
+    pub fn record_report(&self, flow: &str, report: &RunReport) -> io::Result<()> {
+        let completed = report.completed.iter().map(|step| HistoryStep {
+            step: step.clone(),
+            elapsed_ms: 0,
+            error: None,
+        });
+        let failed = report.dead_letters.iter().map(|letter| HistoryStep {
+            step: letter.step.clone(),
+            elapsed_ms: 0,
+            error: Some(letter.error.clone()),
+        });
+        let stopped = match report.dead_letters.len() {
+            0 => "finished".to_string(),
+            failed => format!("{} steps failed", failed),
+        };
+        self.push(HistoryRun {
+            flow: flow.to_string(),
+            recorded_at: unix_secs(SystemTime::now()),
+            success: report.is_success(),
+            stopped,
+            steps: completed.chain(failed).collect(),
+        })
+    }
+
+    fn push(&self, run: HistoryRun) -> io::Result<()> {
+        let mut runs = self.runs.lock().unwrap();
+        if let Some(path) = &self.path {
+            let mut line = serde_json::to_vec(&run)?;
+            line.push(b'\n');
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            file.write_all(&line)?;
+        }
+        runs.push(run);
+        Ok(())
+    }
+
+    /// Returns the names of the flows with recorded runs.
+    pub fn flows(&self) -> Vec<String> {
+        let runs = self.runs.lock().unwrap();
+        let mut flows: Vec<String> = runs.iter().map(|run| run.flow.clone()).collect();
+        flows.sort();
+        flows.dedup();
+        flows
+    }
+
+    /// Returns the runs of a flow recorded within the period, e.g. the last week, in the order
+    /// they were recorded.
+    pub fn runs(&self, flow: &str, period: Duration) -> Vec<HistoryRun> {
+        let since = unix_secs(SystemTime::now().checked_sub(period).unwrap_or(UNIX_EPOCH));
+        let runs = self.runs.lock().unwrap();
+        runs.iter()
+            .filter(|run| run.flow == flow && run.recorded_at >= since)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the share of successful runs of a flow within the period, if it ran.
+    pub fn success_rate(&self, flow: &str, period: Duration) -> Option<f64> {
+        let runs = self.runs(flow, period);
+        let succeeded = runs.iter().filter(|run| run.success).count();
+        (!runs.is_empty()).then(|| succeeded as f64 / runs.len() as f64)
+    }
+
+    /// Returns how often each step of a flow ran and failed within the period.
+    pub fn step_stats(&self, flow: &str, period: Duration) -> BTreeMap<String, StepStats> {
+        let mut stats: BTreeMap<String, StepStats> = BTreeMap::new();
+        for step in self.runs(flow, period).iter().flat_map(|run| &run.steps) {
+            let stats = stats.entry(step.step.clone()).or_default();
+            stats.runs += 1;
+            stats.failures += u64::from(step.error.is_some());
+            stats.elapsed_ms += step.elapsed_ms;
+        }
+        stats
+    }
+
+    /// Forgets the runs recorded before a time, rewriting the file. Returns how many were
+    /// removed.
+    pub fn prune_before(&self, time: SystemTime) -> io::Result<usize> {
+        let before = unix_secs(time);
+        let mut runs = self.runs.lock().unwrap();
+        let count = runs.len();
+        runs.retain(|run| run.recorded_at >= before);
+
+        if let Some(path) = &self.path {
+            let mut data = vec![];
+            for run in runs.iter() {
+                data.extend(serde_json::to_vec(run)?);
+                data.push(b'\n');
+            }
+            std::fs::write(path, data)?;
+        }
+        Ok(count - runs.len())
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StepRecord;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn summary(failed_step: Option<&str>) -> RunSummary {
+        let record = |step: &str, error: Option<&str>| StepRecord {
+            step: step.to_string(),
+            variant: None,
+            url: "https://shop.example/".to_string(),
+            elapsed_ms: 100,
+            error: error.map(str::to_string),
+            body: None,
+            download: None,
+        };
+        let mut steps = vec![record("Login", None)];
+        let stopped = match failed_step {
+            Some(step) => {
+                steps.push(record(step, Some("timeout")));
+                StopReason::Failed("timeout".to_string())
+            }
+            None => {
+                steps.push(record("Checkout", None));
+                StopReason::Finished
+            }
+        };
+        RunSummary {
+            steps,
+            stopped,
+            rolled_back: vec![],
+        }
+    }
+
+    #[test]
+    fn it_should_query_the_runs_of_the_last_week() {
+        let path = std::env::temp_dir().join("mimicr-run-history-test.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let history = RunHistory::open(&path).unwrap();
+        let month_ago = SystemTime::now() - 30 * DAY;
+        history
+            .record_at("checkout", &summary(None), month_ago)
+            .unwrap();
+        history.record("checkout", &summary(None)).unwrap();
+        history
+            .record("checkout", &summary(Some("Checkout")))
+            .unwrap();
+        history.record("search", &summary(None)).unwrap();
+
+        // reopened from the file
+        let history = RunHistory::open(&path).unwrap();
+        assert_eq!(history.flows(), vec!["checkout", "search"]);
+        assert_eq!(history.runs("checkout", 7 * DAY).len(), 2);
+        assert_eq!(history.runs("checkout", 365 * DAY).len(), 3);
+        assert_eq!(history.success_rate("checkout", 7 * DAY), Some(0.5));
+        assert_eq!(history.success_rate("missing", 7 * DAY), None);
+
+        let stats = history.step_stats("checkout", 7 * DAY);
+        assert_eq!(stats["Login"].failure_rate(), 0.0);
+        assert_eq!(stats["Checkout"].failure_rate(), 0.5);
+        assert_eq!(stats["Checkout"].average_ms(), 100);
+
+        assert_eq!(history.prune_before(SystemTime::now() - DAY).unwrap(), 1);
+        let history = RunHistory::open(&path).unwrap();
+        assert_eq!(history.runs("checkout", 365 * DAY).len(), 2);
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+
+use crate::DownloadReport;
+
+/// A step that still failed after every attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub step: String,
+    /// The error of the last attempt.
+    pub error: String,
+    pub attempts: u32,
+    /// The tags of the last attempt's request.
+    pub tags: BTreeMap<String, String>,
+    /// The last attempt's response body, as kept by the worker's body sampling.
+    pub body: Option<String>,
+}
+
+/// The outcome of a batch of steps run by `Worker::run_batch`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunReport {
+    /// The steps that succeeded, in the order they ran.
+    pub completed: Vec<String>,
+    /// The steps that failed after exhausting their attempts. Pass the report to
+    /// `Worker::retry_failures` to run only these again.
+    pub dead_letters: Vec<DeadLetter>,
+}
+
+impl RunReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.dead_letters.is_empty()
+    }
+
+    /// Returns the dead letters whose request had the tag `key=value`.
+    pub fn dead_letters_tagged(&self, key: &str, value: &str) -> Vec<&DeadLetter> {
+        self.dead_letters
+            .iter()
+            .filter(|d| d.tags.get(key).is_some_and(|v| v == value))
+            .collect()
+    }
+
+    /// Returns the names of the failed steps, in the order they ran.
+    pub fn failed_steps(&self) -> Vec<String> {
+        self.dead_letters.iter().map(|d| d.step.clone()).collect()
+    }
+}
+
+/// A step executed by `Worker::run`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepRecord {
+    pub step: String,
+    /// The variant of the step that ran, if it has variants.
+    pub variant: Option<String>,
+    pub url: String,
+    pub elapsed_ms: u64,
+    /// The error, if the step failed.
+    pub error: Option<String>,
+    /// The response body, as kept by the worker's body sampling.
+    pub body: Option<String>,
+    /// The size and speed of the step's download, if it streamed its body to a file.
+    pub download: Option<DownloadReport>,
+}
+
+/// Why `Worker::run` stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The last step didn't set a next step.
+    Finished,
+    /// A step failed and its error handler didn't set a next step.
+    Failed(String),
+    /// The run reached the max iterations.
+    MaxIterations,
+    /// The step requested a url it already requested too many times in this run.
+    LoopDetected(String),
+    /// The run was cancelled, see `Worker::shutdown`.
+    Cancelled,
+}
+
+/// Every step executed by `Worker::run`, in order, and why the run stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    pub steps: Vec<StepRecord>,
+    pub stopped: StopReason,
+    /// The compensating requests sent when the failed run was rolled back, most recent step first.
+    pub rolled_back: Vec<StepRecord>,
+}
+
+impl RunSummary {
+    pub fn is_success(&self) -> bool {
+        self.stopped == StopReason::Finished
+    }
+
+    /// Returns the names of the executed steps, in order.
+    pub fn step_names(&self) -> Vec<&str> {
+        self.steps.iter().map(|s| s.step.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_list_failed_steps() {
+        let report = RunReport {
+            completed: vec!["A".to_string()],
+            dead_letters: vec![DeadLetter {
+                step: "B".to_string(),
+                error: "timeout".to_string(),
+                attempts: 3,
+                tags: BTreeMap::from([("category".to_string(), "checkout".to_string())]),
+                body: None,
+            }],
+        };
+
+        assert!(!report.is_success());
+        assert_eq!(report.failed_steps(), vec!["B"]);
+        assert_eq!(report.dead_letters_tagged("category", "checkout").len(), 1);
+        assert!(report.dead_letters_tagged("category", "search").is_empty());
+        assert!(RunReport::new().is_success());
+    }
+}
@@ -0,0 +1,126 @@
+use serde_json::{Map, Value};
+
+/// What is kept of a response body.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum BodySample {
+    /// Nothing.
+    #[default]
+    Nothing,
+    /// The whole body.
+    Full,
+    /// The first bytes of the body, followed by the full length if it was cut.
+    Prefix(usize),
+    /// An object with the values at these JSON pointers, e.g. `/data/items/0/id`.
+    /// Bodies that aren't JSON are kept as their first 256 bytes.
+    Pointers(Vec<String>),
+}
+
+/// Limits the response bodies kept in snapshots, run summaries and dead letters, so
+/// high-throughput workers don't log every body in full. See `Worker::set_body_sampling`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BodySampling {
+    sample: BodySample,
+    full_on_error: bool,
+}
+
+impl BodySampling {
+    /// Samples successful responses with `sample` and keeps the bodies of failures in full.
+    pub fn new(sample: BodySample) -> Self {
+        Self {
+            sample,
+            full_on_error: true,
+        }
+    }
+
+    /// Samples failures like successful responses instead of keeping them in full.
+    pub fn sample_errors(mut self) -> Self {
+        self.full_on_error = false;
+        self
+    }
+
+    /// Returns what is kept of a body, or `None` if nothing is.
+    pub fn sample(&self, body: &[u8], failed: bool) -> Option<String> {
+        if failed && self.full_on_error {
+            return Some(String::from_utf8_lossy(body).into_owned());
+        }
+
+        match &self.sample {
+            BodySample::Nothing => None,
+            BodySample::Full => Some(String::from_utf8_lossy(body).into_owned()),
+            BodySample::Prefix(len) => Some(prefix(body, *len)),
+            BodySample::Pointers(pointers) => match serde_json::from_slice::<Value>(body) {
+                Ok(json) => {
+                    let projection: Map<String, Value> = pointers
+                        .iter()
+                        .map(|p| (p.clone(), json.pointer(p).cloned().unwrap_or(Value::Null)))
+                        .collect();
+                    Some(Value::Object(projection).to_string())
+                }
+                Err(_) => Some(prefix(body, 256)),
+            },
+        }
+    }
+}
+
+fn prefix(body: &[u8], len: usize) -> String {
+    if body.len() <= len {
+        return String::from_utf8_lossy(body).into_owned();
+    }
+    // a character cut in half is replaced rather than kept as invalid utf-8
+    let text = String::from_utf8_lossy(&body[..len]);
+    format!(
+        "{}... ({} bytes)",
+        text.trim_end_matches('\u{FFFD}'),
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_keep_prefixes_and_full_errors() {
+        let sampling = BodySampling::new(BodySample::Prefix(5));
+        assert_eq!(
+            sampling.sample(b"hello world", false).unwrap(),
+            "hello... (11 bytes)"
+        );
+        assert_eq!(sampling.sample(b"hi", false).unwrap(), "hi");
+        assert_eq!(
+            sampling.sample(b"hello world", true).unwrap(),
+            "hello world"
+        );
+        assert_eq!(
+            sampling
+                .sample_errors()
+                .sample(b"hello world", true)
+                .unwrap(),
+            "hello... (11 bytes)"
+        );
+        assert_eq!(
+            BodySampling::new(BodySample::Prefix(2))
+                .sample("é!".as_bytes(), false)
+                .unwrap(),
+            "é... (3 bytes)"
+        );
+        assert!(BodySampling::new(BodySample::Nothing)
+            .sample(b"x", false)
+            .is_none());
+    }
+
+    #[test]
+    fn it_should_project_json_bodies() {
+        let sampling = BodySampling::new(BodySample::Pointers(vec![
+            "/id".to_string(),
+            "/items/1".to_string(),
+            "/missing".to_string(),
+        ]));
+
+        let sample = sampling
+            .sample(br#"{"id": 7, "items": ["a", "b"], "html": "<div>"}"#, false)
+            .unwrap();
+        assert_eq!(sample, r#"{"/id":7,"/items/1":"b","/missing":null}"#);
+        assert_eq!(sampling.sample(b"<html>", false).unwrap(), "<html>");
+    }
+}
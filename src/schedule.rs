@@ -0,0 +1,161 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// The time source of a `DelayedQueue`. The default follows tokio's clock. A virtual clock
+/// jumps straight to the time a step is due instead of waiting for it, so tests of "recheck in
+/// 6 hours" logic finish in milliseconds while requests still run on real time.
+/// Clones of a virtual clock share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct Clock {
+    /// How far a virtual clock is ahead of tokio's clock.
+    skipped: Option<Arc<Mutex<Duration>>>,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn virtual_time() -> Self {
+        Self {
+            skipped: Some(Arc::new(Mutex::new(Duration::ZERO))),
+        }
+    }
+
+    pub fn is_virtual(&self) -> bool {
+        self.skipped.is_some()
+    }
+
+    pub fn now(&self) -> Instant {
+        Instant::now() + self.skipped()
+    }
+
+    /// Returns how much time a virtual clock skipped so far.
+    pub fn skipped(&self) -> Duration {
+        self.skipped
+            .as_ref()
+            .map_or(Duration::ZERO, |skipped| *skipped.lock().unwrap())
+    }
+
+    /// Moves a virtual clock ahead. Does nothing on tokio's clock.
+    pub fn advance(&self, duration: Duration) {
+        if let Some(skipped) = &self.skipped {
+            *skipped.lock().unwrap() += duration;
+        }
+    }
+
+    pub async fn sleep_until(&self, due: Instant) {
+        match &self.skipped {
+            Some(_) => self.advance(due.saturating_duration_since(self.now())),
+            None => tokio::time::sleep_until(due).await,
+        }
+    }
+}
+
+/// Steps waiting to run at a later time, e.g. "recheck in 6 hours", earliest first.
+/// Times are measured on the queue's `Clock`.
+#[derive(Debug, Default)]
+pub struct DelayedQueue {
+    /// The due time, an insertion counter keeping steps due at once in order, and the step.
+    entries: BinaryHeap<Reverse<(Instant, u64, String)>>,
+    pushed: u64,
+    clock: Clock,
+}
+
+impl DelayedQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the clock, e.g. with `Clock::virtual_time()` in tests.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Schedules a step to run once `delay` has passed.
+    pub fn push(&mut self, step: &str, delay: Duration) {
+        self.push_at(step, self.clock.now() + delay);
+    }
+
+    pub fn push_at(&mut self, step: &str, due: Instant) {
+        self.pushed += 1;
+        self.entries
+            .push(Reverse((due, self.pushed, step.to_string())));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns when the earliest step is due.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.entries.peek().map(|Reverse((due, _, _))| *due)
+    }
+
+    /// Removes and returns the earliest step if it is already due.
+    pub fn pop_due(&mut self) -> Option<String> {
+        if self.next_due()? > self.clock.now() {
+            return None;
+        }
+        self.entries.pop().map(|Reverse((_, _, step))| step)
+    }
+
+    /// Waits until the earliest step is due, then removes and returns it.
+    pub async fn pop(&mut self) -> Option<String> {
+        self.clock.sleep_until(self.next_due()?).await;
+        self.entries.pop().map(|Reverse((_, _, step))| step)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_release_steps_in_due_order_on_virtual_time() {
+        let mut queue = DelayedQueue::new();
+        let start = Instant::now();
+        queue.push("Recheck", Duration::from_secs(6 * 3600));
+        queue.push("Ping", Duration::from_secs(60));
+        queue.push("Ping again", Duration::from_secs(60));
+
+        assert_eq!(queue.len(), 3);
+        assert!(queue.pop_due().is_none());
+
+        assert_eq!(queue.pop().await.unwrap(), "Ping");
+        assert_eq!(queue.pop_due().unwrap(), "Ping again");
+        assert_eq!(queue.pop().await.unwrap(), "Recheck");
+        assert_eq!(start.elapsed(), Duration::from_secs(6 * 3600));
+        assert!(queue.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn it_should_skip_waits_on_a_virtual_clock() {
+        let clock = Clock::virtual_time();
+        let mut queue = DelayedQueue::new();
+        queue.set_clock(clock.clone());
+        queue.push("Recheck", Duration::from_secs(6 * 3600));
+
+        let start = std::time::Instant::now();
+        assert_eq!(queue.pop().await.unwrap(), "Recheck");
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(clock.skipped() >= Duration::from_secs(6 * 3600 - 1));
+        assert!(!Clock::new().is_virtual());
+    }
+}
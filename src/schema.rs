@@ -0,0 +1,362 @@
+use std::fmt;
+
+use serde_json::{Map, Value};
+
+/// A field of a response that doesn't match its schema, see `Request::with_response_schema`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaViolation {
+    /// The JSONPath of the field, e.g. `$.items[0].price`.
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Validates a value against a JSON Schema, returning every field that doesn't match. Supported
+/// are `type`, `enum`, `const`, `properties`, `required`, `additionalProperties`, `items`,
+/// `minItems`, `maxItems`, `minLength`, `maxLength`, `pattern`, `minimum`, `maximum`, their
+/// `exclusive` variants, `allOf`, `anyOf`, `oneOf`, `not` and boolean schemas. Other keywords,
+/// `$ref` and `format` included, are ignored.
+pub fn validate_schema(schema: &Value, value: &Value) -> Vec<SchemaViolation> {
+    let mut violations = vec![];
+    validate(schema, value, "$", &mut violations);
+    violations
+}
+
+fn validate(schema: &Value, value: &Value, path: &str, violations: &mut Vec<SchemaViolation>) {
+    let mut violation = |message: String| {
+        violations.push(SchemaViolation {
+            path: path.to_string(),
+            message,
+        })
+    };
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => return violation("no value is allowed".to_string()),
+        Value::Object(schema) => schema,
+        _ => return,
+    };
+
+    if let Some(types) = schema.get("type") {
+        let types: Vec<&str> = match types {
+            Value::String(name) => vec![name],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        if !types.is_empty() && !types.iter().any(|name| is_type(value, name)) {
+            return violation(format!(
+                "expected {}, found {}",
+                types.join(" or "),
+                type_name(value)
+            ));
+        }
+    }
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            violation(format!(
+                "{} isn't one of {}",
+                value,
+                Value::from(allowed.clone())
+            ));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            violation(format!("expected {}, found {}", expected, value));
+        }
+    }
+
+    match value {
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            let bound = |keyword| schema.get(keyword).and_then(Value::as_f64);
+            let checks = [
+                ("minimum", bound("minimum").filter(|min| number < *min)),
+                ("maximum", bound("maximum").filter(|max| number > *max)),
+                (
+                    "exclusiveMinimum",
+                    bound("exclusiveMinimum").filter(|min| number <= *min),
+                ),
+                (
+                    "exclusiveMaximum",
+                    bound("exclusiveMaximum").filter(|max| number >= *max),
+                ),
+            ];
+            for (keyword, bound) in checks {
+                if let Some(bound) = bound {
+                    violation(format!(
+                        "{} is outside the {} of {}",
+                        number, keyword, bound
+                    ));
+                }
+            }
+        }
+        Value::String(text) => {
+            let len = text.chars().count() as u64;
+            if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+                if len < min {
+                    violation(format!("{} characters is shorter than {}", len, min));
+                }
+            }
+            if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+                if len > max {
+                    violation(format!("{} characters is longer than {}", len, max));
+                }
+            }
+            if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                match regex::Regex::new(pattern) {
+                    Ok(regex) if !regex.is_match(text) => {
+                        violation(format!("{:?} doesn't match `{}`", text, pattern))
+                    }
+                    Ok(_) => {}
+                    Err(err) => violation(format!("invalid pattern `{}`: {}", pattern, err)),
+                }
+            }
+        }
+        Value::Array(items) => {
+            let len = items.len() as u64;
+            if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+                if len < min {
+                    violation(format!("{} items is fewer than {}", len, min));
+                }
+            }
+            if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+                if len > max {
+                    violation(format!("{} items is more than {}", len, max));
+                }
+            }
+        }
+        _ => {}
+    }
+
+    if let Value::Object(members) = value {
+        validate_object(schema, members, path, violations);
+    }
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (index, item) in items.iter().enumerate() {
+            validate(
+                item_schema,
+                item,
+                &format!("{}[{}]", path, index),
+                violations,
+            );
+        }
+    }
+    validate_combinators(schema, value, path, violations);
+}
+
+fn validate_object(
+    schema: &Map<String, Value>,
+    members: &Map<String, Value>,
+    path: &str,
+    violations: &mut Vec<SchemaViolation>,
+) {
+    let properties = schema.get("properties").and_then(Value::as_object);
+    if let Some(Value::Array(required)) = schema.get("required") {
+        for name in required.iter().filter_map(Value::as_str) {
+            if !members.contains_key(name) {
+                violations.push(SchemaViolation {
+                    path: member_path(path, name),
+                    message: "required field is missing".to_string(),
+                });
+            }
+        }
+    }
+    for (name, member) in members {
+        let member_schema = properties
+            .and_then(|properties| properties.get(name))
+            .or_else(|| schema.get("additionalProperties"));
+        if let Some(member_schema) = member_schema {
+            let path = member_path(path, name);
+            match member_schema {
+                Value::Bool(false) => violations.push(SchemaViolation {
+                    path,
+                    message: "unexpected field".to_string(),
+                }),
+                member_schema => validate(member_schema, member, &path, violations),
+            }
+        }
+    }
+}
+
+fn validate_combinators(
+    schema: &Map<String, Value>,
+    value: &Value,
+    path: &str,
+    violations: &mut Vec<SchemaViolation>,
+) {
+    let schemas = |keyword| {
+        schema
+            .get(keyword)
+            .and_then(Value::as_array)
+            .map(|schemas| schemas.as_slice())
+            .unwrap_or_default()
+    };
+    for sub in schemas("allOf") {
+        validate(sub, value, path, violations);
+    }
+
+    let matching = |schemas: &[Value]| {
+        schemas
+            .iter()
+            .filter(|sub| validate_schema(sub, value).is_empty())
+            .count()
+    };
+    let mut violation = |message: &str| {
+        violations.push(SchemaViolation {
+            path: path.to_string(),
+            message: message.to_string(),
+        })
+    };
+    let any_of = schemas("anyOf");
+    if !any_of.is_empty() && matching(any_of) == 0 {
+        violation("matches none of `anyOf`");
+    }
+    let one_of = schemas("oneOf");
+    if !one_of.is_empty() {
+        match matching(one_of) {
+            1 => {}
+            0 => violation("matches none of `oneOf`"),
+            _ => violation("matches more than one of `oneOf`"),
+        }
+    }
+    if let Some(not) = schema.get("not") {
+        if validate_schema(not, value).is_empty() {
+            violation("matches `not`");
+        }
+    }
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match (name, value) {
+        ("null", Value::Null)
+        | ("boolean", Value::Bool(_))
+        | ("number", Value::Number(_))
+        | ("string", Value::String(_))
+        | ("array", Value::Array(_))
+        | ("object", Value::Object(_)) => true,
+        ("integer", Value::Number(number)) => {
+            number.is_i64() || number.is_u64() || number.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        _ => false,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Appends a member to a path, as `.name`, or `['na-me']` for names that aren't identifiers.
+fn member_path(path: &str, name: &str) -> String {
+    let identifier = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if identifier {
+        format!("{}.{}", path, name)
+    } else {
+        format!(
+            "{}['{}']",
+            path,
+            name.replace('\\', "\\\\").replace('\'', "\\'")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn product_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["id", "items"],
+            "properties": {
+                "id": {"type": "integer"},
+                "status": {"enum": ["open", "closed"]},
+                "items": {
+                    "type": "array",
+                    "minItems": 1,
+                    "items": {
+                        "type": "object",
+                        "required": ["sku", "price"],
+                        "properties": {
+                            "sku": {"type": "string", "pattern": "^[A-Z]-\\d+$"},
+                            "price": {"type": "number", "minimum": 0},
+                            "note": {"type": ["string", "null"]}
+                        },
+                        "additionalProperties": false
+                    }
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn it_should_accept_matching_values() {
+        let order = json!({
+            "id": 7,
+            "status": "open",
+            "items": [{"sku": "A-1", "price": 9.5, "note": null}],
+            "extra": true
+        });
+        assert!(validate_schema(&product_schema(), &order).is_empty());
+        assert!(validate_schema(&json!(true), &order).is_empty());
+    }
+
+    #[test]
+    fn it_should_report_every_field_that_drifted() {
+        let order = json!({
+            "id": "7",
+            "status": "pending",
+            "items": [
+                {"sku": "A-1", "price": 9.5},
+                {"sku": "a1", "price": -1, "discount-code": "X"},
+                {"price": 3}
+            ]
+        });
+        let mut violations: Vec<String> = validate_schema(&product_schema(), &order)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        violations.sort();
+        assert_eq!(
+            violations,
+            vec![
+                "$.id: expected integer, found string",
+                "$.items[1].price: -1 is outside the minimum of 0",
+                r#"$.items[1].sku: "a1" doesn't match `^[A-Z]-\d+$`"#,
+                "$.items[1]['discount-code']: unexpected field",
+                "$.items[2].sku: required field is missing",
+                r#"$.status: "pending" isn't one of ["open","closed"]"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_combine_schemas() {
+        let id = json!({"oneOf": [{"type": "integer"}, {"type": "string", "minLength": 3}]});
+        assert!(validate_schema(&id, &json!(12)).is_empty());
+        assert!(validate_schema(&id, &json!("abc")).is_empty());
+        assert_eq!(
+            validate_schema(&id, &json!("ab"))[0].message,
+            "matches none of `oneOf`"
+        );
+        let not_null = json!({"not": {"type": "null"}, "anyOf": [{"const": 1}, {"const": 2}]});
+        assert_eq!(validate_schema(&not_null, &json!(null)).len(), 2);
+        assert_eq!(
+            validate_schema(&json!(false), &json!(1))[0].path,
+            "$".to_string()
+        );
+    }
+}
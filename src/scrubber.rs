@@ -0,0 +1,178 @@
+use reqwest::Url;
+use serde_json::Value;
+
+/// The value sensitive fields are replaced with.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Replaces sensitive headers, JSON fields and query parameters with a placeholder, so recorded
+/// traffic (cassettes, HAR files) can be committed safely. Apply it when a recording is written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scrubber {
+    headers: Vec<String>,
+    json_fields: Vec<String>,
+    query_params: Vec<String>,
+    placeholder: String,
+}
+
+impl Default for Scrubber {
+    fn default() -> Self {
+        Scrubber::new()
+    }
+}
+
+impl Scrubber {
+    /// Scrubs the `Authorization`, `Proxy-Authorization`, `Cookie` and `Set-Cookie` headers.
+    pub fn new() -> Self {
+        Self {
+            headers: vec![
+                "authorization".to_string(),
+                "proxy-authorization".to_string(),
+                "cookie".to_string(),
+                "set-cookie".to_string(),
+            ],
+            json_fields: vec![],
+            query_params: vec![],
+            placeholder: REDACTED.to_string(),
+        }
+    }
+
+    /// Also scrubs this header. Names are case insensitive.
+    pub fn with_header(mut self, name: &str) -> Self {
+        self.headers.push(name.to_lowercase());
+        self
+    }
+
+    /// Scrubs every JSON field with this name, at any depth, e.g. `password` or `access_token`.
+    pub fn with_json_field(mut self, name: &str) -> Self {
+        self.json_fields.push(name.to_string());
+        self
+    }
+
+    /// Scrubs this query parameter of urls, e.g. `api_key`.
+    pub fn with_query_param(mut self, name: &str) -> Self {
+        self.query_params.push(name.to_string());
+        self
+    }
+
+    pub fn with_placeholder(mut self, placeholder: &str) -> Self {
+        self.placeholder = placeholder.to_string();
+        self
+    }
+
+    pub fn is_sensitive_header(&self, name: &str) -> bool {
+        self.headers.iter().any(|h| h.eq_ignore_ascii_case(name))
+    }
+
+    /// Replaces the values of sensitive headers.
+    pub fn scrub_headers(&self, headers: &mut [(String, String)]) {
+        for (name, value) in headers.iter_mut() {
+            if self.is_sensitive_header(name) {
+                *value = self.placeholder.clone();
+            }
+        }
+    }
+
+    /// Replaces the values of sensitive fields in a JSON document.
+    pub fn scrub_json(&self, value: &mut Value) {
+        match value {
+            Value::Object(object) => {
+                for (key, field) in object.iter_mut() {
+                    if self.json_fields.iter().any(|f| f == key) {
+                        *field = Value::String(self.placeholder.clone());
+                    } else {
+                        self.scrub_json(field);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.scrub_json(item)),
+            _ => {}
+        }
+    }
+
+    /// Scrubs a body if it is JSON. Other bodies are returned unchanged.
+    pub fn scrub_body(&self, body: &[u8]) -> Vec<u8> {
+        if self.json_fields.is_empty() {
+            return body.to_vec();
+        }
+        match serde_json::from_slice::<Value>(body) {
+            Ok(mut value) => {
+                self.scrub_json(&mut value);
+                serde_json::to_vec(&value).unwrap_or_else(|_| body.to_vec())
+            }
+            Err(_) => body.to_vec(),
+        }
+    }
+
+    /// Replaces the values of sensitive query parameters.
+    pub fn scrub_url(&self, url: &str) -> String {
+        let Ok(mut parsed) = Url::parse(url) else {
+            return url.to_string();
+        };
+        if self.query_params.is_empty() || parsed.query().is_none() {
+            return url.to_string();
+        }
+
+        let pairs: Vec<(String, String)> = parsed
+            .query_pairs()
+            .map(|(k, v)| {
+                if self.query_params.iter().any(|p| *p == k) {
+                    (k.to_string(), self.placeholder.clone())
+                } else {
+                    (k.to_string(), v.to_string())
+                }
+            })
+            .collect();
+        parsed.query_pairs_mut().clear().extend_pairs(pairs);
+        parsed.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn it_should_scrub_sensitive_headers() {
+        let scrubber = Scrubber::new().with_header("X-Api-Key");
+        let mut headers = vec![
+            ("Authorization".to_string(), "Bearer secret".to_string()),
+            ("x-api-key".to_string(), "secret".to_string()),
+            ("Accept".to_string(), "*/*".to_string()),
+        ];
+        scrubber.scrub_headers(&mut headers);
+
+        assert_eq!(headers[0].1, REDACTED);
+        assert_eq!(headers[1].1, REDACTED);
+        assert_eq!(headers[2].1, "*/*");
+    }
+
+    #[test]
+    fn it_should_scrub_nested_json_fields() {
+        let scrubber = Scrubber::new()
+            .with_json_field("password")
+            .with_placeholder("***");
+        let body = json!({"user": {"name": "ann", "password": "hunter2"}, "logins": [{"password": {"old": 1}}]});
+
+        let scrubbed: Value =
+            serde_json::from_slice(&scrubber.scrub_body(body.to_string().as_bytes())).unwrap();
+        assert_eq!(scrubbed["user"]["password"], "***");
+        assert_eq!(scrubbed["user"]["name"], "ann");
+        assert_eq!(scrubbed["logins"][0]["password"], "***");
+        assert_eq!(scrubber.scrub_body(b"not json"), b"not json");
+    }
+
+    #[test]
+    fn it_should_scrub_query_params() {
+        let scrubber = Scrubber::new().with_query_param("api_key");
+        assert_eq!(
+            scrubber.scrub_url("https://example.com/search?q=shoes&api_key=secret"),
+            "https://example.com/search?q=shoes&api_key=%5BREDACTED%5D"
+        );
+        assert_eq!(
+            scrubber.scrub_url("https://example.com/"),
+            "https://example.com/"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::http_requester::HttpRequester;
+use crate::{Identity, IdentityPool, Request};
+
+/// Retires a worker's session after a number of requests or an amount of time, regardless of how
+/// well it is doing. A rotated session starts with an empty cookie jar.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionRotation {
+    max_requests: Option<u64>,
+    max_age: Option<Duration>,
+    login_step: Option<String>,
+}
+
+impl SessionRotation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rotates after the session has sent `requests` requests.
+    pub fn after_requests(mut self, requests: u64) -> Self {
+        self.max_requests = Some(requests.max(1));
+        self
+    }
+
+    /// Rotates once the session is older than `age`.
+    pub fn after(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// Runs this step first in every new session, e.g. to log in again.
+    pub fn with_login_step(mut self, step: &str) -> Self {
+        self.login_step = Some(step.to_string());
+        self
+    }
+
+    pub fn login_step(&self) -> Option<&String> {
+        self.login_step.as_ref()
+    }
+
+    /// Returns true if a session with this many requests and this age should be retired.
+    pub fn is_due(&self, requests: u64, age: Duration) -> bool {
+        self.max_requests.is_some_and(|max| requests >= max)
+            || self.max_age.is_some_and(|max| age >= max)
+    }
+}
+
+/// Routes every request carrying the same value for a tag, e.g. `with_tag("account", "ann")`,
+/// to the same identity and cookie jar, whichever worker sends it. Requests without the tag use
+/// the worker's own session. Share it between workers with an `Arc`, see
+/// `Worker::set_session_affinity`.
+pub struct SessionAffinity {
+    tag: String,
+    sessions: Mutex<HashMap<String, AffinitySession>>,
+}
+
+struct AffinitySession {
+    identity: Option<String>,
+    requester: HttpRequester,
+}
+
+impl SessionAffinity {
+    pub fn new(tag: &str) -> Self {
+        Self {
+            tag: tag.to_string(),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Returns the session key of a request, the value of its affinity tag.
+    pub fn key_for(&self, req: &Request) -> Option<String> {
+        req.tags().get(&self.tag).cloned()
+    }
+
+    /// Returns the keys of every session created so far.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.sessions.lock().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    /// Returns the identity bound to a session, binding one from the pool on first use.
+    /// If the bound identity was removed from the pool, a new one is bound. Returns `None` for
+    /// sessions no worker has entered yet.
+    pub fn identity(&self, key: &str, pool: Option<&IdentityPool>) -> Option<Identity> {
+        let pool = pool?;
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(key)?;
+        if let Some(identity) = session.identity.as_deref().and_then(|id| pool.get(id)) {
+            return Some(identity);
+        }
+
+        let identity = pool.select()?;
+        session.identity = Some(identity.id().to_string());
+        Some(identity)
+    }
+
+    /// Returns the requester of a session. New sessions get an empty cookie jar and the client
+    /// settings of `template`.
+    pub(crate) fn requester(&self, key: &str, template: &HttpRequester) -> HttpRequester {
+        self.sessions
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_insert_with(|| AffinitySession {
+                identity: None,
+                requester: {
+                    let mut requester = HttpRequester::with_settings((*template.settings).clone());
+                    requester.set_cassette(template.cassette());
+                    requester.set_har_recorder(template.har_recorder());
+                    requester
+                },
+            })
+            .requester
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_be_due_after_either_limit() {
+        let rotation = SessionRotation::new()
+            .after_requests(10)
+            .after(Duration::from_secs(600));
+
+        assert!(!rotation.is_due(9, Duration::from_secs(599)));
+        assert!(rotation.is_due(10, Duration::ZERO));
+        assert!(rotation.is_due(0, Duration::from_secs(600)));
+        assert!(!SessionRotation::new().is_due(u64::MAX, Duration::MAX));
+    }
+
+    #[test]
+    fn it_should_keep_the_identity_of_a_session() {
+        let pool = IdentityPool::new((0..8).map(|i| Identity::new(&i.to_string())).collect());
+        let affinity = SessionAffinity::new("account");
+        let template = HttpRequester::new();
+        affinity.requester("ann", &template);
+        affinity.requester("bob", &template);
+
+        let ann = affinity.identity("ann", Some(&pool)).unwrap();
+        for _ in 0..20 {
+            assert_eq!(
+                affinity.identity("ann", Some(&pool)).unwrap().id(),
+                ann.id()
+            );
+        }
+        assert!(affinity.identity("bob", Some(&pool)).is_some());
+        assert!(affinity.identity("carl", Some(&pool)).is_none());
+        assert!(affinity.identity("ann", None).is_none());
+        assert_eq!(affinity.keys(), vec!["ann", "bob"]);
+
+        let req = Request::default().with_tag("account", "ann");
+        assert_eq!(affinity.key_for(&req).unwrap(), "ann");
+        assert!(affinity.key_for(&Request::default()).is_none());
+    }
+}
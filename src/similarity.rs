@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Words per shingle. Three words keep the order of the text without making every small
+/// change, like a request id, flip most shingles.
+const SHINGLE_WORDS: usize = 3;
+
+/// A 64 bit SimHash of a page's text: similar pages get hashes differing in few bits, so a
+/// page can be compared with a template without keeping the template's body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SimHash(pub u64);
+
+impl SimHash {
+    /// Hashes the words of a page in shingles, ignoring its markup, scripts and styles, case and
+    /// punctuation.
+    pub fn of(body: &str) -> Self {
+        let text = visible_text(body).to_lowercase();
+        let words: Vec<&str> = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+
+        let mut weights = [0i64; 64];
+        let shingles = words.windows(SHINGLE_WORDS.min(words.len().max(1)));
+        for shingle in shingles {
+            let hash = fnv1a(shingle);
+            for (bit, weight) in weights.iter_mut().enumerate() {
+                match hash >> bit & 1 {
+                    1 => *weight += 1,
+                    _ => *weight -= 1,
+                }
+            }
+        }
+
+        let hash = weights
+            .iter()
+            .enumerate()
+            .filter(|(_, weight)| **weight > 0)
+            .fold(0u64, |hash, (bit, _)| hash | 1 << bit);
+        Self(hash)
+    }
+
+    /// Returns the number of bits the hashes differ in, 0 for identical pages.
+    pub fn distance(&self, other: &SimHash) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+
+    /// Returns how similar the pages are, from 0.0 to 1.0 for identical pages.
+    pub fn similarity(&self, other: &SimHash) -> f64 {
+        1.0 - self.distance(other) as f64 / 64.0
+    }
+}
+
+/// The kind and hash of each template of a step.
+type Templates = Vec<(PageKind, SimHash)>;
+
+/// What a template page is an example of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PageKind {
+    /// The page the step expects, e.g. a product page.
+    Good,
+    /// A block, captcha or challenge page served instead, often with a 200.
+    Block,
+}
+
+/// The template a response is closest to, see `PageClassifier::classify`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageMatch {
+    pub kind: PageKind,
+    pub similarity: f64,
+}
+
+/// Tells block pages from real ones by comparing a response with known-good and known-block
+/// templates of its step, beyond what the status code says. Templates are reduced to their
+/// `SimHash`, so keeping many of them is cheap. Share it between workers with an `Arc`.
+#[derive(Debug)]
+pub struct PageClassifier {
+    /// The templates of each step, and of every step under `None`.
+    templates: Mutex<HashMap<Option<String>, Templates>>,
+    threshold: f64,
+}
+
+impl Default for PageClassifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PageClassifier {
+    /// Matches responses at least 85% similar to a template.
+    pub fn new() -> Self {
+        Self {
+            templates: Mutex::new(HashMap::new()),
+            threshold: 0.85,
+        }
+    }
+
+    /// Sets how similar, from 0.0 to 1.0, a response must be to match a template.
+    pub fn with_threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Adds a template for a step, e.g. a body saved when the step got blocked.
+    pub fn add_template(&self, step: &str, kind: PageKind, body: &str) {
+        self.insert(Some(step.to_string()), kind, SimHash::of(body));
+    }
+
+    /// Adds a template for every step, e.g. the challenge page of the target's WAF.
+    pub fn add_shared_template(&self, kind: PageKind, body: &str) {
+        self.insert(None, kind, SimHash::of(body));
+    }
+
+    fn insert(&self, step: Option<String>, kind: PageKind, hash: SimHash) {
+        let mut templates = self.templates.lock().unwrap();
+        templates.entry(step).or_default().push((kind, hash));
+    }
+
+    /// Returns the most similar template of the step, or shared template, if the response is
+    /// similar enough to one.
+    pub fn classify(&self, step: &str, body: &str) -> Option<PageMatch> {
+        let hash = SimHash::of(body);
+        let templates = self.templates.lock().unwrap();
+        let candidates = [templates.get(&Some(step.to_string())), templates.get(&None)];
+        candidates
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|(kind, template)| PageMatch {
+                kind: *kind,
+                similarity: hash.similarity(template),
+            })
+            .filter(|found| found.similarity >= self.threshold)
+            .max_by(|a, b| a.similarity.total_cmp(&b.similarity))
+    }
+
+    /// Returns true if the response is closest to a block template.
+    pub fn is_block(&self, step: &str, body: &str) -> bool {
+        self.classify(step, body)
+            .is_some_and(|found| found.kind == PageKind::Block)
+    }
+}
+
+/// Strips tags, and the contents of scripts and styles, from a page.
+fn visible_text(body: &str) -> String {
+    let lower = body.to_ascii_lowercase();
+    let mut text = String::with_capacity(body.len());
+    let mut i = 0;
+    while let Some(start) = body[i..].find('<').map(|start| i + start) {
+        text.push_str(&body[i..start]);
+        text.push(' ');
+        let skipped = ["script", "style"].into_iter().find_map(|tag| {
+            let is_open = lower[start + 1..].starts_with(tag);
+            let close = format!("</{}", tag);
+            is_open.then(|| {
+                lower[start..]
+                    .find(&close)
+                    .map(|end| start + end + close.len())
+            })
+        });
+        let after = skipped.flatten().unwrap_or(start + 1);
+        i = match body[after..].find('>') {
+            Some(end) => after + end + 1,
+            None => body.len(),
+        };
+    }
+    text.push_str(&body[i..]);
+    text
+}
+
+/// Hashes a shingle with the 64 bit FNV-1a hash, stable across platforms and releases.
+fn fnv1a(words: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in words.join(" ").bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRODUCT: &str = "<html><head><style>body { color: red }</style></head><body>
+        <h1>Espresso machine</h1><p>A compact espresso machine with a steel boiler, a steam wand
+        and a removable water tank. Brews two cups at once and heats up in thirty seconds.</p>
+        <p>Price: 199 EUR. In stock, ships within two days.</p>
+        <script>window.requestId = 'a1b2c3';</script></body></html>";
+
+    const BLOCK: &str = "<html><body><h1>Access denied</h1><p>We detected unusual activity from
+        your network. Please complete the security check to continue browsing. If you believe
+        this is an error, contact support with the reference below.</p>
+        <p>Reference: 18.2f4d1c2</p></body></html>";
+
+    #[test]
+    fn it_should_hash_similar_pages_closely() {
+        let product = SimHash::of(PRODUCT);
+        let restocked = SimHash::of(&PRODUCT.replace("a1b2c3", "zz99").replace("199", "189"));
+        assert_eq!(product.distance(&product), 0);
+        assert!(product.similarity(&restocked) > 0.85);
+        assert!(product.similarity(&SimHash::of(BLOCK)) < 0.85);
+        // markup and scripts don't count
+        assert_eq!(
+            SimHash::of("<b>Hello</b> world"),
+            SimHash::of("hello WORLD")
+        );
+        assert_eq!(visible_text("a<script>x < y</script>b"), "a b");
+    }
+
+    #[test]
+    fn it_should_classify_responses_by_their_templates() {
+        let classifier = PageClassifier::new();
+        classifier.add_template("Product", PageKind::Good, PRODUCT);
+        classifier.add_shared_template(PageKind::Block, BLOCK);
+
+        let blocked = BLOCK.replace("18.2f4d1c2", "18.99aa0");
+        assert!(classifier.is_block("Product", &blocked));
+        assert!(classifier.is_block("Search", &blocked));
+
+        let found = classifier
+            .classify("Product", &PRODUCT.replace("199", "149"))
+            .unwrap();
+        assert_eq!(found.kind, PageKind::Good);
+        assert!(classifier.classify("Search", PRODUCT).is_none());
+        assert!(!classifier.is_block("Product", "<h1>Something else entirely</h1>"));
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+
+use crate::{ResponseInfo, StepError};
+
+/// A fully read response that can be handed to several workers at once.
+#[derive(Debug, Clone)]
+pub struct SharedResponse {
+    pub info: ResponseInfo,
+    pub body: bytes::Bytes,
+}
+
+/// A failed fetch, in a form that can be cloned to every waiting worker.
+#[derive(Debug, Clone)]
+pub struct SharedError {
+    pub error: StepError,
+    /// The status and the part of the body read before the error, if the headers were received.
+    pub partial: Option<SharedResponse>,
+    /// Whether the request failed while connecting.
+    pub connecting: bool,
+}
+
+impl SharedError {
+    pub fn from_reqwest(err: &reqwest::Error) -> Self {
+        Self {
+            error: StepError::from_reqwest(err),
+            partial: None,
+            connecting: err.is_connect(),
+        }
+    }
+
+    pub fn with_partial(mut self, info: ResponseInfo, body: bytes::Bytes) -> Self {
+        self.partial = Some(SharedResponse { info, body });
+        self
+    }
+}
+
+pub type SharedResult = Result<SharedResponse, SharedError>;
+
+type Flight = Arc<OnceCell<SharedResult>>;
+
+/// Coalesces identical in-flight requests so only one of them hits the network.
+/// Share one `Arc<Singleflight>` between workers; every worker waiting on the same key
+/// receives a clone of the leader's response.
+#[derive(Default)]
+pub struct Singleflight {
+    flights: Mutex<HashMap<String, Flight>>,
+}
+
+impl Singleflight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `fetch` for `key` unless an identical request is already in flight, in which case
+    /// its result is awaited instead. Returns the result and whether it was shared from another caller.
+    pub async fn run<F, Fut>(&self, key: &str, fetch: F) -> (SharedResult, bool)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = SharedResult>,
+    {
+        let flight = {
+            let mut flights = self.flights.lock().unwrap();
+            flights
+                .entry(key.to_string())
+                .or_insert_with(|| Arc::new(OnceCell::new()))
+                .clone()
+        };
+
+        let mut leader = false;
+        let result = flight
+            .get_or_init(|| {
+                leader = true;
+                fetch()
+            })
+            .await
+            .clone();
+
+        if leader {
+            let mut flights = self.flights.lock().unwrap();
+            if flights
+                .get(key)
+                .map(|f| Arc::ptr_eq(f, &flight))
+                .unwrap_or(false)
+            {
+                flights.remove(key);
+            }
+        }
+
+        (result, !leader)
+    }
+
+    /// Returns the number of distinct requests currently in flight.
+    pub fn in_flight(&self) -> usize {
+        self.flights.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn it_should_coalesce_concurrent_calls() {
+        let group = Arc::new(Singleflight::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..5)
+            .map(|_| {
+                let group = group.clone();
+                let calls = calls.clone();
+                tokio::spawn(async move {
+                    group
+                        .run("GET https://config.example", || async move {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            Ok(SharedResponse {
+                                info: ResponseInfo::new(
+                                    200,
+                                    Default::default(),
+                                    "https://config.example/".to_string(),
+                                ),
+                                body: bytes::Bytes::from_static(b"config"),
+                            })
+                        })
+                        .await
+                })
+            })
+            .collect();
+
+        let mut shared = 0;
+        for handle in handles {
+            let (result, was_shared) = handle.await.unwrap();
+            assert_eq!(result.unwrap().body, "config");
+            if was_shared {
+                shared += 1;
+            }
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(shared, 4);
+        assert_eq!(group.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn it_should_not_cache_completed_calls() {
+        let group = Singleflight::new();
+        for _ in 0..2 {
+            let (result, shared) = group
+                .run("key", || async {
+                    Err(SharedError {
+                        error: StepError::ReqwestError("boom".to_string()),
+                        partial: None,
+                        connecting: false,
+                    })
+                })
+                .await;
+            assert_eq!(result.unwrap_err().error.to_string(), "Reqwest error: boom");
+            assert!(!shared);
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use reqwest::Method;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{Context, Request, StepError, Stepable};
+
+/// A `<url>` or `<sitemap>` entry of a sitemap.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SitemapEntry {
+    pub loc: String,
+    pub lastmod: Option<String>,
+}
+
+/// A parsed sitemap. A sitemap index only lists other sitemaps.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sitemap {
+    pub urls: Vec<SitemapEntry>,
+    pub sitemaps: Vec<SitemapEntry>,
+}
+
+/// Parses a `urlset` sitemap or a `sitemapindex`.
+pub fn parse_sitemap(xml: &str) -> Sitemap {
+    Sitemap {
+        urls: parse_entries(xml, "url"),
+        sitemaps: parse_entries(xml, "sitemap"),
+    }
+}
+
+fn parse_entries(xml: &str, tag: &str) -> Vec<SitemapEntry> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+
+    xml.split(&open)
+        .skip(1)
+        .filter_map(|block| {
+            let block = block.split(&close).next()?;
+            let loc = tag_text(block, "loc")?;
+            Some(SitemapEntry {
+                loc,
+                lastmod: tag_text(block, "lastmod"),
+            })
+        })
+        .collect()
+}
+
+fn tag_text(block: &str, tag: &str) -> Option<String> {
+    let start = block.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = block[start..].find(&format!("</{}>", tag))? + start;
+    let text = block[start..end].trim();
+    let text = text
+        .strip_prefix("<![CDATA[")
+        .and_then(|t| t.strip_suffix("]]>"))
+        .unwrap_or(text);
+
+    Some(
+        text.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&"),
+    )
+}
+
+/// The `lastmod` of every page seen by previous runs, persisted as JSON.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SitemapState {
+    pages: HashMap<String, Option<String>>,
+}
+
+impl SitemapState {
+    /// Loads the state from a file, starting empty if the file doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let data = crate::read_artifact(path)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Large states are gzipped, see `write_artifact`.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let data = serde_json::to_vec_pretty(self)?;
+        crate::write_artifact(path, &data, crate::DEFAULT_COMPRESSION_THRESHOLD)?;
+        Ok(())
+    }
+
+    /// Returns the entries that are new, or whose `lastmod` differs from the stored one.
+    pub fn changes(&self, entries: &[SitemapEntry]) -> Vec<SitemapEntry> {
+        entries
+            .iter()
+            .filter(|entry| match self.pages.get(&entry.loc) {
+                Some(lastmod) => entry.lastmod.is_some() && lastmod != &entry.lastmod,
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn mark_seen(&mut self, entry: &SitemapEntry) {
+        self.pages.insert(entry.loc.clone(), entry.lastmod.clone());
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+}
+
+type PageHandler = dyn Fn(&mut Context, &SitemapEntry) + Send + Sync;
+
+struct MonitorState {
+    sitemap_url: String,
+    state_path: PathBuf,
+    state: SitemapState,
+    sitemaps: VecDeque<String>,
+    pages: VecDeque<SitemapEntry>,
+    changed: Vec<SitemapEntry>,
+}
+
+impl MonitorState {
+    fn save(&self) {
+        // the next run re-fetches anything that couldn't be recorded, so this is best effort
+        let _ = self.state.save(&self.state_path);
+    }
+
+    fn next_step(&self) -> Option<String> {
+        if !self.sitemaps.is_empty() {
+            Some(SitemapMonitor::SITEMAP_STEP.to_string())
+        } else if !self.pages.is_empty() {
+            Some(SitemapMonitor::PAGE_STEP.to_string())
+        } else {
+            None
+        }
+    }
+}
+
+/// A prebuilt flow that watches a site for new or changed pages.
+/// It fetches the sitemap (following sitemap indexes), compares every `lastmod` with the state
+/// stored by the previous run and only fetches the pages that changed.
+///
+/// ```no_run
+/// # async fn run() {
+/// use mimicr::{SitemapMonitor, Worker};
+///
+/// let monitor = SitemapMonitor::new("https://example.com/sitemap.xml", "sitemap-state.json")
+///     .on_changed_page(|ctx, entry| println!("{} changed ({} ms)", entry.loc, ctx.get_time_elapsed()));
+///
+/// let mut worker = Worker::new();
+/// worker.add_many_steps(monitor.steps());
+///
+/// let mut step = Some(monitor.start_step());
+/// while let Some(name) = step {
+///     let _ = worker.try_step(&name).await;
+///     step = worker.ctx.get_next_step();
+/// }
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct SitemapMonitor {
+    shared: Arc<Mutex<MonitorState>>,
+    on_page: Arc<PageHandler>,
+}
+
+impl SitemapMonitor {
+    pub const SITEMAP_STEP: &'static str = "SitemapMonitor::Sitemap";
+    pub const PAGE_STEP: &'static str = "SitemapMonitor::Page";
+
+    /// Creates a monitor for `sitemap_url` that stores what it has seen at `state_path`.
+    pub fn new(sitemap_url: &str, state_path: impl Into<PathBuf>) -> Self {
+        let shared = MonitorState {
+            sitemap_url: sitemap_url.to_string(),
+            state_path: state_path.into(),
+            state: SitemapState::default(),
+            sitemaps: VecDeque::new(),
+            pages: VecDeque::new(),
+            changed: vec![],
+        };
+
+        Self {
+            shared: Arc::new(Mutex::new(shared)),
+            on_page: Arc::new(|_, _| {}),
+        }
+    }
+
+    /// Sets the callback that receives every changed page after it has been fetched.
+    pub fn on_changed_page(
+        mut self,
+        handler: impl Fn(&mut Context, &SitemapEntry) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_page = Arc::new(handler);
+        self
+    }
+
+    /// Returns the steps of the flow, ready for `Worker::add_many_steps`.
+    pub fn steps(&self) -> Vec<Arc<dyn Stepable>> {
+        vec![
+            Arc::new(SitemapFetchStep {
+                monitor: self.clone(),
+            }),
+            Arc::new(PageFetchStep {
+                monitor: self.clone(),
+            }),
+        ]
+    }
+
+    /// Loads the stored state, queues the root sitemap and returns the first step to run.
+    pub fn start_step(&self) -> String {
+        let mut shared = self.shared.lock().unwrap();
+        shared.state = SitemapState::load(&shared.state_path).unwrap_or_default();
+        shared.sitemaps = VecDeque::from([shared.sitemap_url.clone()]);
+        shared.pages.clear();
+        shared.changed.clear();
+        Self::SITEMAP_STEP.to_string()
+    }
+
+    /// Returns the pages found to have changed during the current run.
+    pub fn changed_pages(&self) -> Vec<SitemapEntry> {
+        self.shared.lock().unwrap().changed.clone()
+    }
+}
+
+struct SitemapFetchStep {
+    monitor: SitemapMonitor,
+}
+
+#[async_trait]
+impl Stepable for SitemapFetchStep {
+    fn name(&self) -> String {
+        SitemapMonitor::SITEMAP_STEP.to_string()
+    }
+
+    fn on_request(&self) -> Request {
+        let shared = self.monitor.shared.lock().unwrap();
+        let url = shared
+            .sitemaps
+            .front()
+            .cloned()
+            .unwrap_or(shared.sitemap_url.clone());
+        Request::new(Method::GET, url)
+    }
+
+    async fn on_success(&self, ctx: &mut Context) {
+        let mut shared = self.monitor.shared.lock().unwrap();
+        shared.sitemaps.pop_front();
+
+        let sitemap = parse_sitemap(&ctx.body_text().unwrap_or_default());
+        shared
+            .sitemaps
+            .extend(sitemap.sitemaps.into_iter().map(|s| s.loc));
+
+        let changes = shared.state.changes(&sitemap.urls);
+        shared.pages.extend(changes);
+
+        match shared.next_step() {
+            Some(step) => ctx.set_next_step(step),
+            None => shared.save(),
+        }
+    }
+
+    async fn on_error(&self, ctx: &mut Context, _err: StepError) {
+        let mut shared = self.monitor.shared.lock().unwrap();
+        shared.sitemaps.pop_front();
+        match shared.next_step() {
+            Some(step) => ctx.set_next_step(step),
+            None => ctx.clear_next_step(),
+        }
+    }
+
+    async fn on_timeout(&self, ctx: &mut Context) {
+        self.on_error(ctx, StepError::ReqwestError("timeout".to_string()))
+            .await;
+    }
+}
+
+struct PageFetchStep {
+    monitor: SitemapMonitor,
+}
+
+#[async_trait]
+impl Stepable for PageFetchStep {
+    fn name(&self) -> String {
+        SitemapMonitor::PAGE_STEP.to_string()
+    }
+
+    fn on_request(&self) -> Request {
+        let shared = self.monitor.shared.lock().unwrap();
+        match shared.pages.front() {
+            Some(page) => Request::new(Method::GET, page.loc.clone()),
+            None => Request::default().skip_to(shared.next_step()),
+        }
+    }
+
+    async fn on_success(&self, ctx: &mut Context) {
+        let page = {
+            let mut shared = self.monitor.shared.lock().unwrap();
+            let Some(page) = shared.pages.pop_front() else {
+                return;
+            };
+            shared.state.mark_seen(&page);
+            shared.changed.push(page.clone());
+            shared.save();
+            page
+        };
+
+        (self.monitor.on_page)(ctx, &page);
+
+        let shared = self.monitor.shared.lock().unwrap();
+        match shared.next_step() {
+            Some(step) => ctx.set_next_step(step),
+            None => ctx.clear_next_step(),
+        }
+    }
+
+    async fn on_error(&self, ctx: &mut Context, _err: StepError) {
+        // the page isn't marked as seen, so the next run tries it again
+        let mut shared = self.monitor.shared.lock().unwrap();
+        shared.pages.pop_front();
+        match shared.next_step() {
+            Some(step) => ctx.set_next_step(step),
+            None => {
+                shared.save();
+                ctx.clear_next_step()
+            }
+        }
+    }
+
+    async fn on_timeout(&self, ctx: &mut Context) {
+        self.on_error(ctx, StepError::ReqwestError("timeout".to_string()))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+    use crate::Worker;
+
+    const SITEMAP: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+            <url><loc>https://example.com/a?x=1&amp;y=2</loc><lastmod>2023-10-01</lastmod></url>
+            <url>
+                <loc><![CDATA[https://example.com/b]]></loc>
+            </url>
+        </urlset>"#;
+
+    #[test]
+    fn it_should_parse_urlsets_and_indexes() {
+        let sitemap = parse_sitemap(SITEMAP);
+        assert_eq!(sitemap.urls.len(), 2);
+        assert_eq!(sitemap.urls[0].loc, "https://example.com/a?x=1&y=2");
+        assert_eq!(sitemap.urls[0].lastmod.as_deref(), Some("2023-10-01"));
+        assert_eq!(sitemap.urls[1].loc, "https://example.com/b");
+        assert!(sitemap.urls[1].lastmod.is_none());
+
+        let index = parse_sitemap(
+            "<sitemapindex><sitemap><loc>https://example.com/s1.xml</loc></sitemap></sitemapindex>",
+        );
+        assert!(index.urls.is_empty());
+        assert_eq!(index.sitemaps[0].loc, "https://example.com/s1.xml");
+    }
+
+    #[test]
+    fn it_should_detect_new_and_changed_pages() {
+        let mut state = SitemapState::default();
+        let entries = parse_sitemap(SITEMAP).urls;
+        assert_eq!(state.changes(&entries).len(), 2);
+
+        entries.iter().for_each(|e| state.mark_seen(e));
+        assert!(state.changes(&entries).is_empty());
+
+        let updated = vec![SitemapEntry {
+            loc: "https://example.com/a?x=1&y=2".to_string(),
+            lastmod: Some("2023-10-02".to_string()),
+        }];
+        assert_eq!(state.changes(&updated), updated);
+    }
+
+    async fn run(worker: &mut Worker, monitor: &SitemapMonitor) {
+        let mut step = Some(monitor.start_step());
+        while let Some(name) = step {
+            let _ = worker.try_step(&name).await;
+            step = worker.ctx.get_next_step();
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_only_fetch_changed_pages_between_runs() {
+        let lastmod = Arc::new(Mutex::new("2023-10-01".to_string()));
+        let base = Arc::new(Mutex::new(String::new()));
+
+        let server = {
+            let lastmod = lastmod.clone();
+            let base = base.clone();
+            TestServer::start(move |req| match req.path.as_str() {
+                "/index.xml" => TestResponse::ok(&format!(
+                    "<sitemapindex><sitemap><loc>{}/sitemap.xml</loc></sitemap></sitemapindex>",
+                    base.lock().unwrap()
+                )),
+                "/sitemap.xml" => TestResponse::ok(&format!(
+                    "<urlset><url><loc>{0}/a</loc><lastmod>{1}</lastmod></url><url><loc>{0}/b</loc></url></urlset>",
+                    base.lock().unwrap(),
+                    lastmod.lock().unwrap()
+                )),
+                _ => TestResponse::ok("page"),
+            })
+            .await
+        };
+        *base.lock().unwrap() = server.url("");
+
+        let state_path = std::env::temp_dir().join(format!(
+            "mimicr-sitemap-{}-{}.json",
+            std::process::id(),
+            server.url("").len()
+        ));
+        let _ = std::fs::remove_file(&state_path);
+
+        let fetched = Arc::new(AtomicUsize::new(0));
+        let counter = fetched.clone();
+        let monitor = SitemapMonitor::new(&server.url("/index.xml"), &state_path).on_changed_page(
+            move |ctx, _| {
+                assert_eq!(ctx.body_text().unwrap(), "page");
+                counter.fetch_add(1, Ordering::SeqCst);
+            },
+        );
+
+        let mut worker = Worker::new();
+        worker.add_many_steps(monitor.steps());
+
+        run(&mut worker, &monitor).await;
+        assert_eq!(fetched.load(Ordering::SeqCst), 2);
+        assert_eq!(SitemapState::load(&state_path).unwrap().len(), 2);
+
+        run(&mut worker, &monitor).await;
+        assert_eq!(fetched.load(Ordering::SeqCst), 2);
+        assert!(monitor.changed_pages().is_empty());
+
+        *lastmod.lock().unwrap() = "2023-10-05".to_string();
+        run(&mut worker, &monitor).await;
+        assert_eq!(fetched.load(Ordering::SeqCst), 3);
+        assert!(monitor.changed_pages()[0].loc.ends_with("/a"));
+
+        let _ = std::fs::remove_file(&state_path);
+    }
+}
@@ -0,0 +1,119 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// Set this environment variable to rewrite every snapshot instead of comparing against it.
+pub const UPDATE_SNAPSHOTS_ENV: &str = "MIMICR_UPDATE_SNAPSHOTS";
+
+/// Collects the outputs of a flow run, e.g. every step's response and the records a step emits,
+/// so they can be compared against a golden file with `assert_matches_snapshot`.
+/// Share it with a worker through `Worker::set_snapshot` to record every successful step.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    entries: Mutex<Vec<Value>>,
+}
+
+impl Snapshot {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a labelled value, e.g. `record("product", &product)`.
+    pub fn record(&self, label: &str, value: &impl Serialize) {
+        let value = serde_json::to_value(value).unwrap_or(Value::Null);
+        self.entries
+            .lock()
+            .unwrap()
+            .push(serde_json::json!({ "label": label, "value": value }));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::Array(self.entries.lock().unwrap().clone())
+    }
+
+    /// See `assert_matches_snapshot`.
+    pub fn assert_matches(&self, path: impl AsRef<Path>) {
+        assert_matches_snapshot(path, &self.to_json());
+    }
+}
+
+/// Returns `snapshots/<name>.json` in the crate being tested.
+pub fn snapshot_path(name: &str) -> PathBuf {
+    let root = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".to_string());
+    Path::new(&root)
+        .join("snapshots")
+        .join(format!("{}.json", name))
+}
+
+/// Compares a value with the golden file at `path`, panicking with both versions if they differ.
+/// The file is written when it doesn't exist yet or when `MIMICR_UPDATE_SNAPSHOTS` is set.
+pub fn assert_matches_snapshot(path: impl AsRef<Path>, value: &impl Serialize) {
+    let path = path.as_ref();
+    let actual = serde_json::to_string_pretty(value).expect("snapshot value is not serializable");
+
+    if std::env::var_os(UPDATE_SNAPSHOTS_ENV).is_some() || !path.exists() {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).expect("unable to create the snapshot directory");
+        }
+        std::fs::write(path, format!("{}\n", actual)).expect("unable to write the snapshot");
+        return;
+    }
+
+    let expected = std::fs::read_to_string(path).expect("unable to read the snapshot");
+    if expected.trim_end() != actual {
+        panic!(
+            "snapshot {} does not match, set {} to update it\n--- expected\n{}\n+++ actual\n{}",
+            path.display(),
+            UPDATE_SNAPSHOTS_ENV,
+            expected.trim_end(),
+            actual
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_snapshot(name: &str) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("mimicr-snapshots-{}", std::process::id()))
+            .join(format!("{}.json", name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn it_should_write_and_then_compare_snapshots() {
+        let path = temp_snapshot("compare");
+        let snapshot = Snapshot::new();
+        snapshot.record(
+            "product",
+            &serde_json::json!({"name": "Blue Shoes", "price": 59.99}),
+        );
+
+        snapshot.assert_matches(&path);
+        assert!(path.exists());
+        snapshot.assert_matches(&path);
+
+        snapshot.record("extra", &1);
+        let changed = std::panic::catch_unwind(|| snapshot.assert_matches(&path));
+        assert!(changed.is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn it_should_place_snapshots_in_the_crate() {
+        assert!(snapshot_path("flow").ends_with("snapshots/flow.json"));
+    }
+}
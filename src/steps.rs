@@ -1,24 +1,113 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use rand::Rng;
+use tokio::sync::Semaphore;
 
 use crate::context::Context;
 use crate::{Request, StepError};
 
+/// A step of a flow. The callbacks are async, so a step can await follow-up I/O, e.g. a database
+/// write or a delay, without blocking the runtime.
 #[async_trait]
-pub trait Stepable {
+pub trait Stepable: Send + Sync {
     fn name(&self) -> String;
     fn on_request(&self) -> Request;
-    fn on_success(&self, ctx: &mut Context);
-    fn on_error(&self, ctx: &mut Context, err: StepError);
-    fn on_timeout(&self, ctx: &mut Context);
+
+    /// Builds the request with access to the context, e.g. to send a token stored by a previous
+    /// step with `Context::get_store_mut`. Defaults to `on_request`.
+    fn on_request_with(&self, _ctx: &Context) -> Request {
+        self.on_request()
+    }
+
+    async fn on_success(&self, ctx: &mut Context);
+    async fn on_error(&self, ctx: &mut Context, err: StepError);
+    /// Called when the request timed out, see `Context::timeout_info` for which timeout fired.
+    async fn on_timeout(&self, ctx: &mut Context);
+
+    /// Called before the worker's `RetryPolicy` sends the request again, with the failed attempt,
+    /// starting at 1, and its error. A retried status is a `StepError::StatusCodeNotFound`. The
+    /// next attempt is built from the context's request, so a step can change it, e.g.
+    /// `ctx.update_from_request(ctx.get_request().clone().with_proxy(next))` to swap proxies.
+    async fn on_retry(&self, _ctx: &mut Context, _attempt: u32, _err: StepError) {}
+
+    /// Called when the run is cancelled, see `Worker::shutdown`, on the step whose request was
+    /// aborted or that was about to run, e.g. to persist what the run got to before it exits.
+    async fn on_cancelled(&self, _ctx: &mut Context) {}
+
+    /// Undoes the step after a later step of the run failed, when the worker rolls back failed
+    /// runs with `Worker::set_rollback_on_failure`. The returned request is sent in the same
+    /// session, e.g. to delete the booking the step created. Keep what it needs, like the booking
+    /// id, in the context's store.
+    async fn on_rollback(&self, _ctx: &mut Context) -> Option<Request> {
+        None
+    }
+
+    /// The most executions of this step allowed at once across every worker sharing the
+    /// `StepManager`, e.g. 1 for a login step. `None` means unlimited.
+    fn max_concurrency(&self) -> Option<usize> {
+        None
+    }
     // async fn execute(&self, res: StepperResponse) -> Result<StepperResponse, Error>;
 }
 
+/// One weighted implementation of a logical step.
+#[derive(Clone)]
+struct StepVariant {
+    label: String,
+    weight: u32,
+    step: Arc<dyn Stepable>,
+    metrics: Arc<VariantMetrics>,
+}
+
+/// Counters for a single step variant. These are shared between clones of the `StepManager`.
+#[derive(Default, Debug)]
+pub struct VariantMetrics {
+    selected: AtomicU64,
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl VariantMetrics {
+    pub fn record_success(&self) {
+        self.succeeded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_failure(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_selected(&self) {
+        self.selected.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A point in time copy of a variant's metrics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VariantStats {
+    pub label: String,
+    pub weight: u32,
+    pub selected: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+/// The step chosen for an execution, along with the variant it came from (if any).
+#[derive(Clone)]
+pub struct SelectedStep {
+    pub step: Arc<dyn Stepable>,
+    pub variant: Option<String>,
+    pub metrics: Option<Arc<VariantMetrics>>,
+}
+
 #[derive(Clone)]
 pub struct StepManager {
     handlers: HashMap<String, Arc<dyn Stepable>>,
+    variants: HashMap<String, Vec<StepVariant>>,
+    /// Shared between clones, so the limits apply to every worker using the same steps.
+    limits: HashMap<String, Arc<Semaphore>>,
 }
 
 impl Default for StepManager {
@@ -31,15 +120,20 @@ impl Default for StepManager {
 impl StepManager {
     pub fn new() -> Self {
         let handlers = HashMap::new();
-        StepManager { handlers }
+        let variants = HashMap::new();
+        StepManager {
+            handlers,
+            variants,
+            limits: HashMap::new(),
+        }
     }
 
     pub fn insert(&mut self, step: impl Stepable + 'static) {
-        self.handlers
-            .insert(step.name().parse().unwrap(), Arc::new(step));
+        self.insert_arc(Arc::new(step));
     }
 
     pub fn insert_arc(&mut self, step: Arc<dyn Stepable>) {
+        self.register_limit(step.as_ref());
         self.handlers.insert(step.name().parse().unwrap(), step);
     }
     pub fn insert_many(&mut self, steps: Vec<Arc<dyn Stepable>>) {
@@ -49,20 +143,128 @@ impl StepManager {
     }
 
     pub fn get(&self, step: &str) -> Option<&Arc<dyn Stepable>> {
-        let step = self.handlers.get(step).unwrap();
-        Some(step)
+        self.handlers.get(step)
+    }
+
+    /// Returns the variant of a step, or its plain step without a variant label.
+    pub fn get_variant(&self, name: &str, variant: Option<&str>) -> Option<&Arc<dyn Stepable>> {
+        match variant {
+            Some(label) => self
+                .variants
+                .get(name)?
+                .iter()
+                .find(|v| v.label == label)
+                .map(|v| &v.step),
+            None => self.get(name),
+        }
+    }
+
+    /// Returns the plain step of a name, or else its first variant, without counting a selection.
+    pub fn get_any(&self, name: &str) -> Option<&Arc<dyn Stepable>> {
+        self.get(name)
+            .or_else(|| self.variants.get(name)?.first().map(|v| &v.step))
+    }
+
+    /// Registers a weighted variant for the logical step returned by `step.name()`.
+    /// When a step has variants, each execution picks one of them proportionally to its weight.
+    pub fn insert_variant(&mut self, label: &str, weight: u32, step: impl Stepable + 'static) {
+        self.insert_variant_arc(label, weight, Arc::new(step));
+    }
+
+    pub fn insert_variant_arc(&mut self, label: &str, weight: u32, step: Arc<dyn Stepable>) {
+        self.register_limit(step.as_ref());
+        let variants = self.variants.entry(step.name()).or_default();
+        variants.retain(|v| v.label != label);
+        variants.push(StepVariant {
+            label: label.to_string(),
+            weight,
+            step,
+            metrics: Arc::new(VariantMetrics::default()),
+        });
+    }
+
+    /// Limits how many executions of a step can run at once, overriding `Stepable::max_concurrency`.
+    pub fn set_max_concurrency(&mut self, name: &str, max: usize) {
+        self.limits
+            .insert(name.to_string(), Arc::new(Semaphore::new(max.max(1))));
+    }
+
+    /// Returns the semaphore limiting a step's concurrency, if it has a limit.
+    pub fn concurrency_limit(&self, name: &str) -> Option<Arc<Semaphore>> {
+        self.limits.get(name).cloned()
+    }
+
+    fn register_limit(&mut self, step: &dyn Stepable) {
+        if let Some(max) = step.max_concurrency() {
+            self.set_max_concurrency(&step.name(), max);
+        }
+    }
+
+    /// Picks the step to execute for `name`. Variants take precedence over a plain step with the same name.
+    pub fn select(&self, name: &str) -> Option<SelectedStep> {
+        if let Some(variants) = self.variants.get(name) {
+            let total: u64 = variants.iter().map(|v| v.weight as u64).sum();
+            if total > 0 {
+                let mut roll = rand::thread_rng().gen_range(0..total);
+                for variant in variants {
+                    if roll < variant.weight as u64 {
+                        variant.metrics.record_selected();
+                        return Some(SelectedStep {
+                            step: variant.step.clone(),
+                            variant: Some(variant.label.clone()),
+                            metrics: Some(variant.metrics.clone()),
+                        });
+                    }
+                    roll -= variant.weight as u64;
+                }
+            }
+        }
+
+        self.handlers.get(name).map(|step| SelectedStep {
+            step: step.clone(),
+            variant: None,
+            metrics: None,
+        })
+    }
+
+    /// Returns the metrics for every variant of the given step, in registration order.
+    pub fn variant_stats(&self, name: &str) -> Vec<VariantStats> {
+        self.variants
+            .get(name)
+            .map(|variants| {
+                variants
+                    .iter()
+                    .map(|v| VariantStats {
+                        label: v.label.clone(),
+                        weight: v.weight,
+                        selected: v.metrics.selected.load(Ordering::Relaxed),
+                        succeeded: v.metrics.succeeded.load(Ordering::Relaxed),
+                        failed: v.metrics.failed.load(Ordering::Relaxed),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
     }
 
     pub fn len(&mut self) -> usize {
         self.handlers.len()
+            + self
+                .variants
+                .keys()
+                .filter(|k| !self.handlers.contains_key(*k))
+                .count()
+    }
+
+    pub fn is_empty(&mut self) -> bool {
+        self.handlers.is_empty() && self.variants.is_empty()
     }
 
     pub fn contains_name(&mut self, step: &String) -> bool {
-        self.handlers.contains_key(step)
+        self.handlers.contains_key(step) || self.variants.contains_key(step)
     }
 
     pub fn contains_step(&mut self, step: impl Stepable) -> bool {
-        self.handlers.contains_key(step.name().as_str())
+        self.contains_name(&step.name())
     }
 }
 
@@ -70,7 +272,6 @@ impl StepManager {
 mod tests {
     use std::time::Duration;
 
-    use reqwest::header::HeaderMap;
     use reqwest::Method;
 
     use crate::{hdr, Request};
@@ -98,21 +299,95 @@ mod tests {
                 .with_status_codes(vec![200])
         }
 
-        fn on_success(&self, ctx: &mut Context) {
+        async fn on_success(&self, ctx: &mut Context) {
             // sleep for 100 ms
-            std::thread::sleep(Duration::from_millis(100));
+            tokio::time::sleep(Duration::from_millis(100)).await;
             ctx.set_next_step("RobotsTxt".to_string());
         }
 
-        fn on_error(&self, _ctx: &mut Context, _err: StepError) {
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {
             todo!()
         }
 
-        fn on_timeout(&self, _ctx: &mut Context) {
+        async fn on_timeout(&self, _ctx: &mut Context) {
             todo!()
         }
     }
 
+    #[derive(Clone, Copy)]
+    struct ExperimentalRobotsTxt;
+
+    #[async_trait]
+    impl Stepable for ExperimentalRobotsTxt {
+        fn name(&self) -> String {
+            "RobotsTxt".parse().unwrap()
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, "https://test.com/robots.txt".to_string())
+        }
+
+        async fn on_success(&self, _ctx: &mut Context) {}
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+
+        fn max_concurrency(&self) -> Option<usize> {
+            Some(2)
+        }
+    }
+
+    #[test]
+    fn insert_should_register_declared_concurrency_limits() {
+        let mut steps = StepManager::new();
+        steps.insert(RobotsTxt);
+        assert!(steps.concurrency_limit("RobotsTxt").is_none());
+
+        steps.insert_variant("experiment", 1, ExperimentalRobotsTxt);
+        let limit = steps.concurrency_limit("RobotsTxt").unwrap();
+        assert_eq!(limit.available_permits(), 2);
+
+        // clones share the limit
+        let clone = steps.clone();
+        let _permit = limit.try_acquire().unwrap();
+        assert_eq!(
+            clone
+                .concurrency_limit("RobotsTxt")
+                .unwrap()
+                .available_permits(),
+            1
+        );
+    }
+
+    #[test]
+    fn select_should_respect_variant_weights() {
+        let mut steps = StepManager::new();
+        steps.insert_variant("control", 1, RobotsTxt);
+        steps.insert_variant("experiment", 0, ExperimentalRobotsTxt);
+
+        for _ in 0..20 {
+            let selected = steps.select("RobotsTxt").unwrap();
+            assert_eq!(selected.variant.unwrap(), "control");
+        }
+
+        let stats = steps.variant_stats("RobotsTxt");
+        assert_eq!(stats[0].selected, 20);
+        assert_eq!(stats[1].selected, 0);
+        assert_eq!(steps.len(), 1);
+    }
+
+    #[test]
+    fn select_should_fall_back_to_plain_step() {
+        let mut steps = StepManager::new();
+        steps.insert(RobotsTxt);
+
+        let selected = steps.select("RobotsTxt").unwrap();
+        assert!(selected.variant.is_none());
+        assert!(steps.select("Missing").is_none());
+        assert!(steps.variant_stats("RobotsTxt").is_empty());
+    }
+
     #[tokio::test]
     async fn step_should_call_on_request_as_expected() {
         let step = RobotsTxt {};
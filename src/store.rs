@@ -0,0 +1,96 @@
+use std::any::Any;
+use std::collections::HashMap;
+
+/// Typed key/value state passed between the steps of a worker, e.g. a token read by a login step
+/// and sent by every later step. A value is only returned when asked for with its own type.
+#[derive(Default)]
+pub struct Store {
+    values: HashMap<String, Box<dyn Any + Send + Sync>>,
+}
+
+impl Store {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a value, replacing any previous value of the key whatever its type.
+    pub fn set<T: Any + Send + Sync>(&mut self, key: &str, value: T) {
+        self.values.insert(key.to_string(), Box::new(value));
+    }
+
+    /// Returns the value of a key, or `None` if it isn't set or holds another type.
+    pub fn get<T: Any + Send + Sync>(&self, key: &str) -> Option<&T> {
+        self.values.get(key)?.downcast_ref()
+    }
+
+    pub fn get_mut<T: Any + Send + Sync>(&mut self, key: &str) -> Option<&mut T> {
+        self.values.get_mut(key)?.downcast_mut()
+    }
+
+    /// Removes and returns a value. A value of another type is left in place.
+    pub fn remove<T: Any + Send + Sync>(&mut self, key: &str) -> Option<T> {
+        if !self.values.get(key)?.is::<T>() {
+            return None;
+        }
+        self.values
+            .remove(key)
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+
+    /// Returns the keys of every value, sorted.
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys: Vec<&str> = self.values.keys().map(String::as_str).collect();
+        keys.sort();
+        keys
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+}
+
+impl std::fmt::Debug for Store {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut keys: Vec<&String> = self.values.keys().collect();
+        keys.sort();
+        f.debug_struct("Store").field("keys", &keys).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_only_return_values_of_the_right_type() {
+        let mut store = Store::new();
+        store.set("csrf", "abc123".to_string());
+        store.set("attempts", 1u32);
+
+        assert_eq!(store.get::<String>("csrf").unwrap(), "abc123");
+        assert!(store.get::<u32>("csrf").is_none());
+        assert!(store.get::<String>("missing").is_none());
+
+        *store.get_mut::<u32>("attempts").unwrap() += 1;
+        assert_eq!(store.get::<u32>("attempts"), Some(&2));
+
+        assert!(store.remove::<u64>("attempts").is_none());
+        assert_eq!(store.remove::<u32>("attempts"), Some(2));
+        assert!(!store.contains("attempts"));
+        assert_eq!(store.len(), 1);
+        assert_eq!(format!("{:?}", store), "Store { keys: [\"csrf\"] }");
+    }
+}
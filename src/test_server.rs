@@ -0,0 +1,241 @@
+//! A tiny HTTP/1.1 server for tests that need a real socket without touching the internet.
+
+#![allow(dead_code)]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Debug, Clone)]
+pub(crate) struct TestRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub delay: Duration,
+    /// Written as is instead of the status, headers and body, to simulate broken servers.
+    pub raw: Option<Vec<u8>>,
+}
+
+impl TestResponse {
+    pub fn raw(response: &[u8]) -> Self {
+        Self {
+            raw: Some(response.to_vec()),
+            ..Self::ok("")
+        }
+    }
+
+    pub fn ok(body: &str) -> Self {
+        Self::status(200, body)
+    }
+
+    pub fn status(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: body.as_bytes().to_vec(),
+            delay: Duration::ZERO,
+            raw: None,
+        }
+    }
+
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+type Handler = dyn Fn(&TestRequest) -> TestResponse + Send + Sync;
+
+pub(crate) struct TestServer {
+    addr: std::net::SocketAddr,
+    hits: Arc<AtomicUsize>,
+    connections: Arc<AtomicUsize>,
+    requests: Arc<Mutex<Vec<TestRequest>>>,
+}
+
+impl TestServer {
+    /// Starts a server closing the connection after every response.
+    pub async fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&TestRequest) -> TestResponse + Send + Sync + 'static,
+    {
+        Self::serve(Arc::new(handler), false).await
+    }
+
+    /// Starts a server keeping connections open between requests, to test connection reuse.
+    pub async fn start_keep_alive<F>(handler: F) -> Self
+    where
+        F: Fn(&TestRequest) -> TestResponse + Send + Sync + 'static,
+    {
+        Self::serve(Arc::new(handler), true).await
+    }
+
+    async fn serve(handler: Arc<Handler>, keep_alive: bool) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let server_hits = hits.clone();
+        let server_connections = connections.clone();
+        let server_requests = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                server_connections.fetch_add(1, Ordering::SeqCst);
+                let handler = handler.clone();
+                let hits = server_hits.clone();
+                let requests = server_requests.clone();
+                tokio::spawn(async move {
+                    while handle(&mut stream, &handler, &hits, &requests, keep_alive).await {}
+                });
+            }
+        });
+
+        Self {
+            addr,
+            hits,
+            connections,
+            requests,
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    /// Returns how many connections were accepted.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    pub fn requests(&self) -> Vec<TestRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Answers a request, returning whether the connection is kept open for the next one.
+async fn handle(
+    stream: &mut TcpStream,
+    handler: &Arc<Handler>,
+    hits: &AtomicUsize,
+    requests: &Mutex<Vec<TestRequest>>,
+    keep_alive: bool,
+) -> bool {
+    let mut buffer = vec![];
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        let Ok(n) = stream.read(&mut chunk).await else {
+            return false;
+        };
+        if n == 0 {
+            return false;
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    let content_length = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = buffer[header_end..].to_vec();
+    while body.len() < content_length {
+        let Ok(n) = stream.read(&mut chunk).await else {
+            return false;
+        };
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+
+    let request = TestRequest {
+        method,
+        path,
+        headers,
+        body,
+    };
+    hits.fetch_add(1, Ordering::SeqCst);
+    requests.lock().unwrap().push(request.clone());
+
+    let response = handler(&request);
+    if !response.delay.is_zero() {
+        tokio::time::sleep(response.delay).await;
+    }
+
+    if let Some(raw) = &response.raw {
+        let _ = stream.write_all(raw).await;
+        let _ = stream.shutdown().await;
+        return false;
+    }
+
+    let mut head = format!("HTTP/1.1 {} OK\r\n", response.status);
+    for (key, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    if !keep_alive {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+
+    let _ = stream.write_all(head.as_bytes()).await;
+    if request.method != "HEAD" {
+        let _ = stream.write_all(&response.body).await;
+    }
+    if !keep_alive {
+        let _ = stream.shutdown().await;
+    }
+    keep_alive
+}
@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+/// Which timeout ended a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    /// The connection wasn't established within the client's connect timeout.
+    Connect,
+    /// The whole request, from connecting to reading the body, took longer than its timeout.
+    Request,
+}
+
+/// Describes the timeout a step's `on_timeout` is called for, see `Context::timeout_info`.
+/// Handlers can use it to retry the step with a longer timeout, e.g. by storing one for the
+/// step's `on_request_with` and calling `set_next_step`, or to give up once the retries are
+/// exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutInfo {
+    pub kind: TimeoutKind,
+    /// How long the last attempt ran before it timed out.
+    pub elapsed: Duration,
+    /// The total time allowed for the request, see `Request::with_timeout`.
+    pub request_timeout: Option<Duration>,
+    /// The time allowed for connecting, see `ClientSettings::set_connect_timeout`.
+    pub connect_timeout: Option<Duration>,
+    /// The attempt that timed out, starting at 1.
+    pub attempt: u32,
+    /// The attempts the request's retry policy allows, 1 without one.
+    pub max_attempts: u32,
+}
+
+impl TimeoutInfo {
+    /// Returns the timeout that fired.
+    pub fn configured(&self) -> Option<Duration> {
+        match self.kind {
+            TimeoutKind::Connect => self.connect_timeout,
+            TimeoutKind::Request => self.request_timeout,
+        }
+    }
+
+    /// Returns true if the retry policy has no attempts left.
+    pub fn is_last_attempt(&self) -> bool {
+        self.attempt >= self.max_attempts
+    }
+}
@@ -0,0 +1,229 @@
+//! The spans of the step lifecycle, emitted with the `tracing` feature for any subscriber to
+//! observe, and compiled to nothing without it:
+//!
+//! - `step` (`step`, `url`, `status`, `elapsed_ms`, `attempts`) around every `Worker::try_step`
+//! - `build_request` (`step`) around `Stepable::on_request`
+//! - `send` (`step`, `method`, `url`, `attempt`, `status`, `elapsed_ms`) around every attempt
+//! - `callback` (`step`, `callback`) around `on_success`, `on_error`, `on_timeout`, `on_retry`
+//!   and `on_cancelled`
+use std::future::Future;
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        f()
+    }
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn step_span(step: &str) -> Span {
+    use tracing::field::Empty;
+    tracing::info_span!(
+        "step",
+        step,
+        url = Empty,
+        status = Empty,
+        elapsed_ms = Empty,
+        attempts = Empty
+    )
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn step_span(_step: &str) -> Span {
+    Span
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn build_span(step: &str) -> Span {
+    tracing::debug_span!("build_request", step)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn build_span(_step: &str) -> Span {
+    Span
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn send_span(step: &str, method: &str, url: &str, attempt: u32) -> Span {
+    use tracing::field::Empty;
+    tracing::debug_span!(
+        "send",
+        step,
+        method,
+        url,
+        attempt,
+        status = Empty,
+        elapsed_ms = Empty
+    )
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn send_span(_step: &str, _method: &str, _url: &str, _attempt: u32) -> Span {
+    Span
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn callback_span(step: &str, callback: &'static str) -> Span {
+    tracing::debug_span!("callback", step, callback)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn callback_span(_step: &str, _callback: &'static str) -> Span {
+    Span
+}
+
+/// Records the response of a `step` or `send` span, with the url and attempts of a step.
+#[cfg(feature = "tracing")]
+pub(crate) fn record_response(
+    span: &Span,
+    url: Option<&str>,
+    status: Option<u16>,
+    elapsed_ms: u64,
+    attempts: Option<u32>,
+) {
+    if let Some(url) = url {
+        span.record("url", url);
+    }
+    if let Some(status) = status {
+        span.record("status", status);
+    }
+    span.record("elapsed_ms", elapsed_ms);
+    if let Some(attempts) = attempts {
+        span.record("attempts", attempts);
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn record_response(
+    _span: &Span,
+    _url: Option<&str>,
+    _status: Option<u16>,
+    _elapsed_ms: u64,
+    _attempts: Option<u32>,
+) {
+}
+
+/// Runs a future in a span, entered every time it's polled.
+pub(crate) async fn in_span<F: Future>(span: &Span, future: F) -> F::Output {
+    #[cfg(feature = "tracing")]
+    return tracing::Instrument::instrument(future, span.clone()).await;
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = span;
+        future.await
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::test_server::{TestResponse, TestServer};
+    use crate::{Context, Request, StepError, Stepable, Worker};
+    use async_trait::async_trait;
+    use reqwest::Method;
+    use std::fmt::Debug;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// A span's name and fields.
+    type Fields = (String, Vec<String>);
+
+    /// Keeps the name and fields of every span, in the order they were opened.
+    #[derive(Clone, Default)]
+    struct Recorder {
+        spans: Arc<Mutex<Vec<Fields>>>,
+    }
+
+    struct Visitor<'a>(&'a mut Vec<String>);
+
+    impl Visit for Visitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.push(format!("{}={:?}", field.name(), value));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.push(format!("{}={}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Recorder {
+        // hyper traces too
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target().starts_with("mimicr")
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut fields = vec![];
+            span.record(&mut Visitor(&mut fields));
+            let mut spans = self.spans.lock().unwrap();
+            spans.push((span.metadata().name().to_string(), fields));
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            let (_, fields) = &mut spans[span.into_u64() as usize - 1];
+            values.record(&mut Visitor(fields));
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &Event<'_>) {}
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    struct PingStep {
+        url: String,
+    }
+
+    #[async_trait]
+    impl Stepable for PingStep {
+        fn name(&self) -> String {
+            String::from("PingStep")
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, self.url.clone())
+        }
+
+        async fn on_success(&self, _ctx: &mut Context) {}
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+    }
+
+    #[tokio::test]
+    async fn it_should_trace_the_step_lifecycle() {
+        let server = TestServer::start(|_req| TestResponse::ok("pong")).await;
+        let recorder = Recorder::default();
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+
+        let mut worker = Worker::new();
+        let url = server.url("/ping");
+        worker.add_step(PingStep { url: url.clone() });
+        worker.try_step("PingStep").await.unwrap();
+
+        let spans = recorder.spans.lock().unwrap();
+        let names: Vec<&str> = spans.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["step", "build_request", "send", "callback"]);
+        let fields = |index: usize| spans[index].1.join(" ");
+        assert!(fields(0).starts_with("step=PingStep"), "{}", fields(0));
+        assert!(fields(0).contains(&format!("url={} status=200", url)));
+        assert!(fields(0).contains("attempts=1"));
+        assert!(fields(2).contains("method=GET"));
+        assert!(fields(2).contains("attempt=1 status=200 elapsed_ms="));
+        assert_eq!(fields(3), "step=PingStep callback=on_success");
+    }
+}
@@ -0,0 +1,110 @@
+use std::error::Error;
+use std::io::Read;
+
+use bytes::Bytes;
+use flate2::read::{GzDecoder, ZlibDecoder};
+
+use crate::ResponseInfo;
+
+/// Rewrites the body of a successful response before the step's `on_success` sees it, e.g. to
+/// unwrap a payload. Register one with `Worker::add_transformer`.
+pub type Transformer =
+    dyn Fn(&ResponseInfo, Bytes) -> Result<Bytes, Box<dyn Error + Send + Sync>> + Send + Sync;
+
+/// Anti JSON hijacking prefixes prepended to JSON responses, longest first.
+pub const XSSI_PREFIXES: &[&str] = &[
+    ")]}',\n",
+    ")]}'\n",
+    ")]}',",
+    ")]}'",
+    "while(1);",
+    "for(;;);",
+    ")]}",
+];
+
+/// Returns the body without a leading XSSI prefix like `)]}'` or `while(1);`.
+/// Whitespace before the prefix is skipped.
+pub fn strip_xssi_prefix(body: &[u8]) -> &[u8] {
+    let start = body
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(body.len());
+    let trimmed = &body[start..];
+
+    match XSSI_PREFIXES
+        .iter()
+        .find(|prefix| trimmed.starts_with(prefix.as_bytes()))
+    {
+        Some(prefix) => &trimmed[prefix.len()..],
+        None => body,
+    }
+}
+
+/// A transformer stripping XSSI prefixes, see `strip_xssi_prefix`.
+pub fn strip_xssi(
+    _info: &ResponseInfo,
+    body: Bytes,
+) -> Result<Bytes, Box<dyn Error + Send + Sync>> {
+    let stripped = strip_xssi_prefix(&body).len();
+    Ok(body.slice(body.len() - stripped..))
+}
+
+/// A transformer inflating bodies that are still gzip or zlib compressed, e.g. when a server
+/// compresses without a `Content-Encoding` header. Other bodies are left as they are.
+pub fn decompress(
+    _info: &ResponseInfo,
+    body: Bytes,
+) -> Result<Bytes, Box<dyn Error + Send + Sync>> {
+    let mut inflated = vec![];
+    match body.as_ref() {
+        [0x1f, 0x8b, ..] => GzDecoder::new(body.as_ref()).read_to_end(&mut inflated)?,
+        // zlib headers are a multiple of 31, with deflate as the method
+        [cmf, flg, ..] if cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0 => {
+            ZlibDecoder::new(body.as_ref()).read_to_end(&mut inflated)?
+        }
+        _ => return Ok(body),
+    };
+    Ok(Bytes::from(inflated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn info() -> ResponseInfo {
+        ResponseInfo::new(200, Default::default(), "https://example.com/".to_string())
+    }
+
+    #[test]
+    fn it_should_strip_xssi_prefixes() {
+        assert_eq!(strip_xssi_prefix(b")]}'\n{\"a\": 1}"), b"{\"a\": 1}");
+        assert_eq!(strip_xssi_prefix(b"  while(1);[1]"), b"[1]");
+        assert_eq!(strip_xssi_prefix(b"for(;;);{}"), b"{}");
+        assert_eq!(strip_xssi_prefix(b"{\"a\": 1}"), b"{\"a\": 1}");
+
+        let body = strip_xssi(&info(), Bytes::from_static(b")]}',\n[]")).unwrap();
+        assert_eq!(body, "[]");
+    }
+
+    #[test]
+    fn it_should_decompress_compressed_bodies() {
+        let mut gzip = GzEncoder::new(vec![], Compression::default());
+        gzip.write_all(b"hello").unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert_eq!(decompress(&info(), Bytes::from(gzip)).unwrap(), "hello");
+
+        let mut zlib = ZlibEncoder::new(vec![], Compression::default());
+        zlib.write_all(b"hello").unwrap();
+        let zlib = zlib.finish().unwrap();
+        assert_eq!(decompress(&info(), Bytes::from(zlib)).unwrap(), "hello");
+
+        assert_eq!(
+            decompress(&info(), Bytes::from_static(b"plain")).unwrap(),
+            "plain"
+        );
+        assert!(decompress(&info(), Bytes::from_static(&[0x1f, 0x8b, 0, 0])).is_err());
+    }
+}
@@ -0,0 +1,33 @@
+use rand::Rng;
+
+/// Returns a random version 4 UUID, e.g. `0b9e4d57-5a6f-4c2e-9d41-7f3a2c8e1b06`.
+pub(crate) fn uuid_v4() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    // the version, then the RFC 4122 variant
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_draw_version_4_uuids() {
+        let uuid = uuid_v4();
+        let groups: Vec<usize> = uuid.split('-').map(str::len).collect();
+        assert_eq!(groups, vec![8, 4, 4, 4, 12]);
+        assert_eq!(uuid.as_bytes()[14], b'4');
+        assert!(matches!(uuid.as_bytes()[19], b'8' | b'9' | b'a' | b'b'));
+        assert_ne!(uuid, uuid_v4());
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use reqwest::header::{HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+
+use crate::{Request, ResponseInfo};
+
+/// The `ETag` and `Last-Modified` of the last full response of a url.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Returns the validators of a response, `None` if it has neither.
+    pub fn from_response(info: &ResponseInfo) -> Option<Self> {
+        let header = |name| info.headers().get(name)?.to_str().ok().map(str::to_string);
+        let validators = Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+        (validators != Self::default()).then_some(validators)
+    }
+}
+
+/// The validators of the urls fetched with `Request::with_conditional`, kept by the worker so
+/// a monitoring step re-fetching a url sends `If-None-Match` and `If-Modified-Since`, and
+/// unchanged pages come back as a bodyless `304`, see `Context::not_modified`. Share one between
+/// workers with `Worker::set_validators`.
+#[derive(Debug, Default)]
+pub struct ValidatorStore {
+    urls: Mutex<HashMap<String, Validators>>,
+}
+
+impl ValidatorStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, url: &str) -> Option<Validators> {
+        self.urls.lock().unwrap().get(url).cloned()
+    }
+
+    pub fn set(&self, url: &str, validators: Validators) {
+        self.urls
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), validators);
+    }
+
+    /// Forgets a url, so it's fetched in full next time.
+    pub fn remove(&self, url: &str) -> Option<Validators> {
+        self.urls.lock().unwrap().remove(url)
+    }
+
+    pub fn len(&self) -> usize {
+        self.urls.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds the conditional headers for the url of the request, unless it already has them.
+    pub(crate) fn apply(&self, req: Request) -> Request {
+        let Some(validators) = self.get(req.url()) else {
+            return req;
+        };
+        let mut headers = req.headers().unwrap_or_default();
+        let mut insert = |name, value: Option<String>| {
+            let value = value.and_then(|v| HeaderValue::from_str(&v).ok());
+            if let (Some(value), false) = (value, headers.contains_key(&name)) {
+                headers.insert(name, value);
+            }
+        };
+        insert(IF_NONE_MATCH, validators.etag);
+        insert(IF_MODIFIED_SINCE, validators.last_modified);
+        req.with_headers(headers)
+    }
+
+    /// Keeps the validators of a full response. A `304` keeps the stored ones, updated by any
+    /// it sent.
+    pub(crate) fn record(&self, url: &str, info: &ResponseInfo) {
+        let Some(validators) = Validators::from_response(info) else {
+            return;
+        };
+        let mut urls = self.urls.lock().unwrap();
+        match (info.status(), urls.get_mut(url)) {
+            (304, Some(stored)) => {
+                stored.etag = validators.etag.or(stored.etag.take());
+                stored.last_modified = validators.last_modified.or(stored.last_modified.take());
+            }
+            (304, None) => {}
+            _ => {
+                urls.insert(url.to_string(), validators);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderMap;
+    use reqwest::Method;
+
+    fn response(status: u16, etag: Option<&str>) -> ResponseInfo {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = etag {
+            headers.insert(ETAG, etag.parse().unwrap());
+        }
+        headers.insert(
+            LAST_MODIFIED,
+            "Wed, 01 May 2024 12:00:00 GMT".parse().unwrap(),
+        );
+        ResponseInfo::new(status, headers, "https://shop.example/stock".to_string())
+    }
+
+    #[test]
+    fn it_should_send_the_validators_of_the_last_response() {
+        let store = ValidatorStore::new();
+        let url = "https://shop.example/stock";
+        let req = store.apply(Request::new(Method::GET, url.to_string()));
+        assert!(req.headers().is_none());
+
+        store.record(url, &response(200, Some("\"v1\"")));
+        let headers = store
+            .apply(Request::new(Method::GET, url.to_string()))
+            .headers()
+            .unwrap();
+        assert_eq!(headers[IF_NONE_MATCH], "\"v1\"");
+        assert_eq!(headers[IF_MODIFIED_SINCE], "Wed, 01 May 2024 12:00:00 GMT");
+
+        // a 304 without an etag keeps the stored one
+        store.record(url, &response(304, None));
+        assert_eq!(store.get(url).unwrap().etag.as_deref(), Some("\"v1\""));
+        store.record(url, &response(304, Some("\"v2\"")));
+        assert_eq!(store.get(url).unwrap().etag.as_deref(), Some("\"v2\""));
+
+        let mut own = HeaderMap::new();
+        own.insert(IF_NONE_MATCH, "\"mine\"".parse().unwrap());
+        let req = Request::new(Method::GET, url.to_string()).with_headers(own);
+        assert_eq!(
+            store.apply(req).headers().unwrap()[IF_NONE_MATCH],
+            "\"mine\""
+        );
+        assert_eq!(
+            store.remove(url).map(|v| v.etag),
+            Some(Some("\"v2\"".to_string()))
+        );
+        assert!(store.is_empty());
+    }
+}
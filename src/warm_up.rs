@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// A list of benign pages visited with human-like pauses before a fresh session or identity
+/// sends its first real request, so a new cookie jar doesn't go straight to the target.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WarmUp {
+    pages: Vec<String>,
+    min_delay: Duration,
+    max_delay: Duration,
+}
+
+impl WarmUp {
+    /// Visits `pages` in order, pausing 2 to 6 seconds after each one.
+    pub fn new(pages: Vec<String>) -> Self {
+        Self {
+            pages,
+            min_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(6),
+        }
+    }
+
+    /// Sets the range of the random pause after each page.
+    pub fn with_delay(mut self, min: Duration, max: Duration) -> Self {
+        self.min_delay = min.min(max);
+        self.max_delay = max.max(min);
+        self
+    }
+
+    pub fn pages(&self) -> &Vec<String> {
+        &self.pages
+    }
+
+    /// Picks a random pause from the delay range.
+    pub fn next_delay(&self) -> Duration {
+        if self.min_delay == self.max_delay {
+            return self.min_delay;
+        }
+        rand::thread_rng().gen_range(self.min_delay..=self.max_delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_pick_delays_within_the_range() {
+        let warm_up = WarmUp::new(vec!["https://example.com/".to_string()])
+            .with_delay(Duration::from_millis(500), Duration::from_millis(100));
+
+        for _ in 0..100 {
+            let delay = warm_up.next_delay();
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(500));
+        }
+    }
+}
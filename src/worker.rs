@@ -1,14 +1,100 @@
 #![allow(dead_code)]
 
+use crate::audit::AuditRecord;
 use crate::context::Context;
-use crate::steps::StepManager;
-use crate::{StepError, Stepable};
-use std::io::Error;
+use crate::domain::most_specific;
+use crate::proxy_accounting::request_size;
+use crate::run_report::DeadLetter;
+use crate::singleflight::{SharedError, SharedResponse, SharedResult};
+use crate::steps::{StepManager, VariantStats};
+use crate::trace;
+use crate::{
+    AuditLog, BodySampling, CancellationToken, Checkpoint, CheckpointError, CoherenceMode,
+    CoherenceValidator, DomainPattern, Download, Environment, Explanation, HarRecorder, HostGuard,
+    HttpRequester, Identity, IdentityPool, Metrics, Observability, Profile, ProfileRotator,
+    ProxyAccounting, RateLimiter, ReferrerChain, Request, RequestOutcome, ResponseInfo, RunReport,
+    RunSummary, SessionAffinity, SessionRotation, Singleflight, Snapshot, StepError, StepRecord,
+    Stepable, StopReason, Store, TimeoutInfo, TimeoutKind, Transformer, ValidatorStore, WarmUp,
+    UNNAMED_PROVIDER,
+};
+use bytes::BytesMut;
+use reqwest::header::{HeaderValue, REFERER};
+use reqwest::{Method, RequestBuilder};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 
+type SessionHook = dyn Fn(&mut Context) + Send + Sync;
+type RequestHook = dyn Fn(&Context, Request) -> Request + Send + Sync;
+type StepHook = dyn Fn(&mut Context, &str) + Send + Sync;
+type StepEndHook =
+    dyn Fn(&mut Context, &str, Option<&(dyn std::error::Error + Send + Sync)>) + Send + Sync;
+type MigrationHook =
+    dyn Fn(&mut Checkpoint) -> Result<(), Box<dyn std::error::Error + Send + Sync>> + Send + Sync;
+
+/// Bodies larger than this grow the read buffer as they arrive instead of up front.
+const MAX_PREALLOCATION: usize = 8 * 1024 * 1024;
+
+/// Runs steps against a single `Context`.
+/// The context owns one `HttpRequester` for the whole session, so cookies set by a step, e.g.
+/// a login, are sent by every following step until `rotate_session` or `Context::reset_session`.
+/// A `Worker` is `Send + Sync`, so it can be moved into a `tokio::spawn` task, and the
+/// `StepManager` can be shared between workers with `Worker::with_steps` since steps are
+/// stored behind `Arc`.
 pub struct Worker {
     steps: StepManager,
     pub ctx: Context,
+    coherence: Option<CoherenceValidator>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    singleflight: Option<Arc<Singleflight>>,
+    identities: Option<Arc<IdentityPool>>,
+    affinity: Option<Arc<SessionAffinity>>,
+    /// The session key of the requester in the context, `None` for the worker's own session.
+    active_session: Option<String>,
+    /// The worker's own requester, set aside while an affinity session is active.
+    own_requester: Option<HttpRequester>,
+    profile_rotator: Option<Arc<ProfileRotator>>,
+    /// The profile picked for each session, as keyed in `warmed`.
+    profiles: HashMap<String, Profile>,
+    /// The profiles replacing the session's for the hosts of a pattern.
+    domain_profiles: Vec<(DomainPattern, Profile)>,
+    warm_up: Option<WarmUp>,
+    /// The identities (or `""` for the worker's own session) that have already been warmed up.
+    warmed: HashSet<String>,
+    referrer: Option<ReferrerChain>,
+    host_guard: Option<Arc<HostGuard>>,
+    metrics: Option<Arc<Metrics>>,
+    proxy_accounting: Option<Arc<ProxyAccounting>>,
+    audit: Option<Arc<AuditLog>>,
+    /// The validators of the urls requested with `Request::with_conditional`.
+    validators: Arc<ValidatorStore>,
+    cancel: CancellationToken,
+    rotation: Option<SessionRotation>,
+    on_session_rotate: Option<Arc<SessionHook>>,
+    session_requests: u64,
+    session_started: Instant,
+    max_attempts: u32,
+    max_iterations: usize,
+    rollback: bool,
+    http2_fallback: bool,
+    /// The hosts that are sent HTTP/1.1 requests only, after HTTP/2 errors.
+    downgraded: HashSet<String>,
+    max_visits: Option<usize>,
+    snapshot: Option<Arc<Snapshot>>,
+    sampling: Option<BodySampling>,
+    transformers: Vec<Arc<Transformer>>,
+    observability: Observability,
+    step_observability: HashMap<String, Observability>,
+    /// Responses are read into this buffer, see `fetch`.
+    read_buffer: BytesMut,
+    flow_version: u32,
+    on_migrate: Option<Arc<MigrationHook>>,
+    /// The hooks of every host, `None`, or of the hosts of a pattern.
+    before_request: Vec<(Option<DomainPattern>, Arc<RequestHook>)>,
+    after_response: Vec<Arc<SessionHook>>,
+    on_step_start: Vec<Arc<StepHook>>,
+    on_step_end: Vec<Arc<StepEndHook>>,
 }
 
 impl Default for Worker {
@@ -19,9 +105,57 @@ impl Default for Worker {
 
 impl Worker {
     pub fn new() -> Self {
-        let steps = StepManager::new();
+        Worker::with_steps(StepManager::new())
+    }
+
+    /// Creates a worker with an existing set of steps, e.g. a `StepManager` cloned from another worker.
+    pub fn with_steps(steps: StepManager) -> Self {
         let ctx = Context::new();
-        Worker { steps, ctx }
+        Worker {
+            steps,
+            ctx,
+            coherence: None,
+            rate_limiter: None,
+            singleflight: None,
+            identities: None,
+            affinity: None,
+            active_session: None,
+            own_requester: None,
+            profile_rotator: None,
+            profiles: HashMap::new(),
+            domain_profiles: vec![],
+            warm_up: None,
+            warmed: HashSet::new(),
+            referrer: None,
+            host_guard: None,
+            metrics: None,
+            proxy_accounting: None,
+            audit: None,
+            validators: Arc::new(ValidatorStore::new()),
+            cancel: CancellationToken::new(),
+            rotation: None,
+            on_session_rotate: None,
+            session_requests: 0,
+            session_started: Instant::now(),
+            max_attempts: 1,
+            max_iterations: 1000,
+            rollback: false,
+            http2_fallback: false,
+            downgraded: HashSet::new(),
+            max_visits: Some(2),
+            snapshot: None,
+            sampling: None,
+            transformers: vec![],
+            observability: Observability::full(),
+            step_observability: HashMap::new(),
+            read_buffer: BytesMut::new(),
+            flow_version: 1,
+            on_migrate: None,
+            before_request: vec![],
+            after_response: vec![],
+            on_step_start: vec![],
+            on_step_end: vec![],
+        }
     }
 
     pub fn add_step(&mut self, step: impl Stepable + 'static) {
@@ -36,286 +170,3900 @@ impl Worker {
         self.steps.insert_arc(step);
     }
 
-    pub fn steps(self) -> StepManager {
-        self.steps
+    /// Registers a weighted variant of a logical step. See `StepManager::insert_variant`.
+    pub fn add_step_variant(&mut self, label: &str, weight: u32, step: impl Stepable + 'static) {
+        self.steps.insert_variant(label, weight, step);
     }
 
-    // get the step by name
-    fn get_step(&self, name: &str) -> Option<Arc<dyn Stepable>> {
-        match self.steps.get(name) {
-            Some(step) => Some(step.clone()),
-            None => None,
-        }
+    /// Returns the selection and outcome counters for each variant of a step.
+    pub fn variant_stats(&self, name: &str) -> Vec<VariantStats> {
+        self.steps.variant_stats(name)
     }
 
-    // start the instant timer to run the step
-    // run send() on the request_builder
-    // stop the instant timer
-    pub async fn try_step(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let step = self.get_step(name).unwrap();
-        let req = step.on_request();
+    /// Validates every request's headers for fingerprint coherence before it is sent.
+    pub fn set_coherence_validator(&mut self, validator: Option<CoherenceValidator>) {
+        self.coherence = validator;
+    }
 
-        if req.get_skip_to_step().is_some() {
-            self.ctx
-                .set_next_step(req.get_skip_to_step().unwrap().clone());
-            return Ok(());
+    /// Sets a per-host rate limiter. Share the same `Arc` between workers to limit them together.
+    /// Every attempt waits for the limiter before it's sent, including its politeness delay, so
+    /// steps don't need to sleep in `on_success`.
+    pub fn set_rate_limiter(&mut self, rate_limiter: Option<Arc<RateLimiter>>) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    /// Runs the flow against an environment: request urls starting with `/` are resolved against
+    /// its base url, and its rate limit applies unless a rate limiter was already set.
+    pub fn set_environment(&mut self, environment: Option<Environment>) {
+        if self.rate_limiter.is_none() {
+            self.rate_limiter = environment
+                .as_ref()
+                .and_then(|env| env.rate_limiter())
+                .map(Arc::new);
         }
+        self.ctx.set_environment(environment);
+    }
 
-        self.ctx.update_from_request(req)?;
-        self.ctx.set_current_step(name.to_string());
+    /// Coalesces identical in-flight GET requests with every other worker sharing the same group.
+    /// Coalesced responses are shared regardless of each worker's cookies, so only use this for
+    /// resources that are the same for every session.
+    pub fn set_singleflight(&mut self, group: Option<Arc<Singleflight>>) {
+        self.singleflight = group;
+    }
 
-        let req_builder = self.ctx.get_request_builder().unwrap();
+    /// Returns the store the steps share, e.g. to seed it before a run.
+    pub fn store_mut(&mut self) -> &mut Store {
+        self.ctx.get_store_mut()
+    }
 
-        // Start processing the request and time it.
-        let stop_watch = std::time::Instant::now();
-        let res = match req_builder.send().await {
-            Ok(res) => res,
-            Err(err) => {
-                if err.is_timeout() {
-                    step.on_timeout(&mut self.ctx);
-                    return Err(Self::timeout_error());
-                }
+    /// Sets the version of the flow the worker runs, 1 by default, stamped on its checkpoints.
+    /// Bump it whenever step names or what the steps keep in the store change.
+    pub fn set_flow_version(&mut self, version: u32) {
+        self.flow_version = version;
+    }
 
-                step.on_error(&mut self.ctx, StepError::ReqwestError(err.to_string()));
-                return Err(Box::new(err));
+    pub fn flow_version(&self) -> u32 {
+        self.flow_version
+    }
+
+    /// Called by `resume` with a checkpoint saved by an older flow version, to bring it up to
+    /// date, e.g. with `Checkpoint::rename_step`. The checkpoint's `flow_version` tells which
+    /// version saved it. Without a hook, older checkpoints aren't resumed.
+    pub fn on_migrate(
+        &mut self,
+        hook: impl Fn(&mut Checkpoint) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+            + Send
+            + Sync
+            + 'static,
+    ) {
+        self.on_migrate = Some(Arc::new(hook));
+    }
+
+    /// Saves the session, its store and cookies, to resume at `next_step` later.
+    pub fn checkpoint(&self, next_step: Option<&str>) -> Checkpoint {
+        Checkpoint::capture(
+            self.flow_version,
+            next_step,
+            self.ctx.get_store(),
+            self.ctx.get_http_requester().cookie_jar().export_session(),
+        )
+    }
+
+    /// Restores a checkpoint into the session and returns the step to resume at, to pass to
+    /// `run`. Checkpoints of older flow versions go through the `on_migrate` hook first; those
+    /// of newer versions, or that still name a step the worker doesn't have, aren't resumed.
+    pub fn resume(
+        &mut self,
+        mut checkpoint: Checkpoint,
+    ) -> Result<Option<String>, CheckpointError> {
+        if checkpoint.flow_version > self.flow_version {
+            return Err(CheckpointError::new(format!(
+                "saved by flow version {}, newer than {}",
+                checkpoint.flow_version, self.flow_version
+            )));
+        }
+        if checkpoint.flow_version < self.flow_version {
+            let Some(migrate) = self.on_migrate.clone() else {
+                return Err(CheckpointError::new(format!(
+                    "saved by flow version {} and there is no migration to {}",
+                    checkpoint.flow_version, self.flow_version
+                )));
+            };
+            migrate(&mut checkpoint).map_err(|err| {
+                CheckpointError::new(format!(
+                    "migration from flow version {} failed: {}",
+                    checkpoint.flow_version, err
+                ))
+            })?;
+            checkpoint.flow_version = self.flow_version;
+        }
+        if let Some(step) = checkpoint.next_step.as_deref() {
+            if !self.steps.contains_name(&step.to_string()) {
+                return Err(CheckpointError::new(format!("unknown step {}", step)));
             }
-        };
+        }
+
         self.ctx
-            .set_time_elapsed(stop_watch.elapsed().as_millis() as u64);
+            .get_http_requester()
+            .cookie_jar()
+            .import_session(checkpoint.cookies.as_bytes())
+            .map_err(|err| CheckpointError::new(format!("invalid cookies: {}", err)))?;
+        checkpoint.restore_store(self.ctx.get_store_mut());
+        Ok(checkpoint.next_step)
+    }
 
-        if !self.check_status_code(res.status().as_u16()) {
-            let error = StepError::StatusCodeNotFound(
-                res.status().as_u16() as i32,
-                self.ctx.get_status_codes().unwrap_or_default(),
-            );
+    /// Sends each request as an identity picked from the pool, unless the request sets its own
+    /// proxy or user agent, and records the outcome against that identity's score.
+    pub fn set_identity_pool(&mut self, pool: Option<Arc<IdentityPool>>) {
+        self.identities = pool;
+    }
 
-            step.on_error(&mut self.ctx, error.clone());
-            return Err(Box::new(error));
-        }
+    /// Sends every request with the headers of a browser profile picked for its session. The
+    /// profile is kept until the session is rotated, so a session never changes browsers, and
+    /// its user agent replaces the identity's.
+    pub fn set_profile_rotator(&mut self, rotator: Option<Arc<ProfileRotator>>) {
+        self.profile_rotator = rotator;
+        self.profiles.clear();
+    }
 
-        let body = match res.bytes().await {
-            Ok(body) => body,
-            Err(err) => {
-                step.on_error(&mut self.ctx, StepError::ReqwestError(err.to_string()));
-                return Err(Box::new(err));
-            }
-        };
+    /// Sends the requests to the hosts of a pattern with this profile, instead of the one picked
+    /// for the session, e.g. a mobile app's fingerprint for its API. The most specific pattern
+    /// matching a host wins.
+    pub fn set_domain_profile(&mut self, pattern: impl Into<DomainPattern>, profile: Profile) {
+        let pattern = pattern.into();
+        self.domain_profiles
+            .retain(|(scoped, _)| *scoped != pattern);
+        self.domain_profiles.push((pattern, profile));
+    }
 
-        self.ctx.set_response_body(body);
+    /// Returns the profile of the worker's current session, if one was picked.
+    pub fn profile(&self) -> Option<&Profile> {
+        let session = self
+            .active_session
+            .clone()
+            .or_else(|| self.ctx.get_current_identity())
+            .unwrap_or_default();
+        self.profiles.get(&session)
+    }
 
-        // clear the next step since the context is being reused, this fixes the infinite loop bug
-        self.ctx.clear_next_step();
-        step.on_success(&mut self.ctx);
+    /// Visits the warm-up pages before the first real request of the worker's session and of
+    /// every identity picked from the identity pool.
+    pub fn set_warm_up(&mut self, warm_up: Option<WarmUp>) {
+        self.warm_up = warm_up;
+    }
 
-        Ok(())
+    /// Forgets which sessions have been warmed up, e.g. after the cookies were cleared.
+    pub fn reset_warm_up(&mut self) {
+        self.warmed.clear();
     }
 
-    fn timeout_error() -> Box<Error> {
-        Box::new(std::io::Error::new(
-            std::io::ErrorKind::TimedOut,
-            "Request timed out",
-        ))
+    /// Sets the `Referer` of every request that doesn't set its own from the chain, and moves
+    /// the chain forward after every successful GET.
+    pub fn set_referrer_chain(&mut self, chain: Option<ReferrerChain>) {
+        self.referrer = chain;
     }
 
-    fn check_status_code(&self, status_code: u16) -> bool {
-        match &self.ctx.get_status_codes() {
-            Some(codes) => {
-                if codes.is_empty() {
-                    return (200..300).contains(&status_code);
+    pub fn referrer_chain(&self) -> Option<&ReferrerChain> {
+        self.referrer.as_ref()
+    }
+
+    /// Refuses requests the guard doesn't allow before anything is sent, e.g. urls taken
+    /// from untrusted input that point at internal services.
+    pub fn set_host_guard(&mut self, guard: Option<HostGuard>) {
+        self.host_guard = guard.map(Arc::new);
+        self.ctx
+            .get_client_settings_mut()
+            .set_host_guard(self.host_guard.clone());
+    }
+
+    /// Establishes connections to the origins before the first latency-sensitive step,
+    /// e.g. `worker.prewarm(&["https://api.example.com"])`. Origins are checked by the host guard.
+    pub async fn prewarm(
+        &mut self,
+        origins: &[&str],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(guard) = &self.host_guard {
+            for origin in origins {
+                if let Err(violation) = guard.check(origin).await {
+                    return Err(Box::new(StepError::BlockedHost(violation.to_string())));
                 }
-                codes.contains(&status_code)
             }
-            None => (200..300).contains(&status_code),
         }
+
+        self.ctx.get_http_requester().prewarm(origins).await?;
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::worker::Worker;
-    use crate::{Context, Request, StepError, Stepable};
-    use async_trait::async_trait;
-    use reqwest::Method;
-    use std::sync::Arc;
+    /// Records every request the worker sends, including warm-ups and rollbacks, for a HAR
+    /// export, see `HarRecorder`.
+    pub fn set_har_recorder(&mut self, recorder: Option<Arc<HarRecorder>>) {
+        if let Some(own) = &mut self.own_requester {
+            own.set_har_recorder(recorder.clone());
+        }
+        self.ctx.set_har_recorder(recorder);
+    }
 
-    #[derive(Clone, Copy)]
-    struct RobotsTxt;
+    /// Counts the requests and bytes sent through each proxy provider.
+    pub fn set_proxy_accounting(&mut self, accounting: Option<Arc<ProxyAccounting>>) {
+        self.proxy_accounting = accounting;
+    }
 
-    const ROBOTS_TXT: &str = "RobotsTxt";
-    const SKIPPABLE_STEP: &str = "SkippableStep";
+    /// Records the requests of steps to a tamper-evident log: every attempt, the HTTP/1.1 resend
+    /// of a failed HTTP/2 request, warm-up visits and rollback compensations. An entry is written
+    /// once its response or error arrives, with its status. A step whose request can't be
+    /// recorded fails with the write error, without calling its callbacks. Requests sent from
+    /// callbacks, e.g. with `Context::fan_out`, `preflight_head` or `ndjson_stream`, aren't
+    /// recorded.
+    pub fn set_audit_log(&mut self, audit: Option<Arc<AuditLog>>) {
+        self.audit = audit;
+    }
 
-    #[async_trait]
-    impl Stepable for RobotsTxt {
-        fn name(&self) -> String {
-            String::from(ROBOTS_TXT)
-        }
+    /// Returns the `ETag` and `Last-Modified` validators kept for conditional requests.
+    pub fn validators(&self) -> &Arc<ValidatorStore> {
+        &self.validators
+    }
 
-        fn on_request(&self) -> Request {
-            Request::new(Method::GET, "https://google.com".to_string())
-        }
+    /// Shares the validators of conditional requests with other workers, e.g. the workers of a
+    /// monitoring pool polling the same urls.
+    pub fn set_validators(&mut self, validators: Arc<ValidatorStore>) {
+        self.validators = validators;
+    }
 
-        fn on_success(&self, ctx: &mut Context) {
-            eprintln!(
-                "Successfully fetched: {} in {}\n\nURL: {}\nBody: {:?}",
-                ctx.get_current_step().unwrap(),
-                ctx.get_time_elapsed_as_string(),
-                ctx.get_url(),
-                ctx.body_text().unwrap(),
-            );
-        }
+    /// Makes the token stop the worker like `shutdown`, e.g. one token cancelled on `SIGINT` for
+    /// every worker of a process.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancel = token;
+    }
 
-        fn on_error(&self, _ctx: &mut Context, _err: StepError) {
-            todo!()
-        }
+    /// Returns the token `shutdown` cancels, to stop the worker from another task.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
 
-        fn on_timeout(&self, _ctx: &mut Context) {
-            todo!()
-        }
+    /// Stops the worker: a request in flight is aborted, `run` returns after the current step
+    /// with `StopReason::Cancelled`, and the step gets `Stepable::on_cancelled`. Cancelling is
+    /// permanent, set a new token to run the worker again.
+    pub fn shutdown(&self) {
+        self.cancel.cancel();
     }
 
-    #[derive(Clone, Copy)]
-    struct SkippableStep;
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
 
-    #[async_trait]
-    impl Stepable for SkippableStep {
-        fn name(&self) -> String {
-            String::from(SKIPPABLE_STEP)
+    /// Records the latency and outcome of every request by step and by host.
+    pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    /// Returns the metrics set with `set_metrics`.
+    pub fn metrics(&self) -> Option<&Arc<Metrics>> {
+        self.metrics.as_ref()
+    }
+
+    /// Sends requests tagged with the affinity's tag under the identity and cookie jar of their
+    /// session, so the steps of one account can run on any worker sharing the affinity.
+    pub fn set_session_affinity(&mut self, affinity: Option<Arc<SessionAffinity>>) {
+        self.enter_session(None);
+        self.affinity = affinity;
+    }
+
+    /// Returns the session key of the last request, if it ran in an affinity session.
+    pub fn active_session(&self) -> Option<&String> {
+        self.active_session.as_ref()
+    }
+
+    /// Puts the requester of a session in the context, or the worker's own with `None`.
+    fn enter_session(&mut self, key: Option<&str>) {
+        if self.active_session.as_deref() == key {
+            return;
         }
+        let requester = match (key, &self.affinity) {
+            (Some(key), Some(affinity)) => affinity.requester(key, self.ctx.get_http_requester()),
+            _ => self.own_requester.take().unwrap_or_default(),
+        };
 
-        fn on_request(&self) -> Request {
-            Request::new(Method::GET, "https://google.com".to_string())
-                .skip_to(Some(ROBOTS_TXT.to_string()))
+        let previous = self.ctx.replace_http_requester(requester);
+        if self.active_session.is_none() {
+            self.own_requester = Some(previous);
         }
+        self.active_session = key.map(|k| k.to_string());
+    }
 
-        fn on_success(&self, _ctx: &mut Context) {
-            todo!("This step should never be called")
+    /// Retires the session once the policy is due, before the next step runs.
+    pub fn set_session_rotation(&mut self, rotation: Option<SessionRotation>) {
+        self.rotation = rotation;
+    }
+
+    /// Called with the fresh context every time the session is rotated, before the login step.
+    pub fn on_session_rotate(&mut self, hook: impl Fn(&mut Context) + Send + Sync + 'static) {
+        self.on_session_rotate = Some(Arc::new(hook));
+    }
+
+    /// Returns the number of requests sent by the current session.
+    pub fn session_requests(&self) -> u64 {
+        self.session_requests
+    }
+
+    /// Replaces the session with a fresh one: new cookies, warm-up and referrer chain.
+    /// Runs the `on_session_rotate` hook and then the rotation's login step, if any.
+    pub async fn rotate_session(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.enter_session(None);
+        self.ctx.reset_session();
+        self.warmed.clear();
+        self.profiles.clear();
+        if let Some(chain) = &mut self.referrer {
+            chain.reset();
         }
+        self.session_requests = 0;
+        self.session_started = Instant::now();
 
-        fn on_error(&self, _ctx: &mut Context, _err: StepError) {
-            todo!("This step should never be called")
+        if let Some(hook) = self.on_session_rotate.clone() {
+            hook(&mut self.ctx);
         }
 
-        fn on_timeout(&self, _ctx: &mut Context) {
-            todo!("This step should never be called")
+        let login = self.rotation.as_ref().and_then(|r| r.login_step().cloned());
+        match login {
+            Some(login) => Box::pin(self.try_step(&login)).await,
+            None => Ok(()),
         }
     }
 
-    #[test]
-    fn it_should_add_step() {
-        let mut worker = Worker::new();
-        worker.add_step(RobotsTxt);
+    /// Limits how many executions of a step run at once across every worker sharing these steps.
+    pub fn set_step_concurrency(&mut self, name: &str, max: usize) {
+        self.steps.set_max_concurrency(name, max);
+    }
 
-        assert_eq!(worker.steps().len(), 1);
+    /// Records the url and body of every successful step into the snapshot, labelled with the
+    /// step name. JSON bodies are stored as JSON, anything else as text.
+    pub fn set_snapshot(&mut self, snapshot: Option<Arc<Snapshot>>) {
+        self.snapshot = snapshot;
     }
 
-    #[test]
-    fn it_should_add_step_arc() {
-        let mut worker = Worker::new();
-        let step = Arc::new(RobotsTxt);
-        worker.add_step_arc(step);
+    /// Keeps only a sample of each response body in the snapshot, the run summaries and the dead
+    /// letters. Without a sampling, snapshots keep full bodies and summaries keep none.
+    pub fn set_body_sampling(&mut self, sampling: Option<BodySampling>) {
+        self.sampling = sampling;
+    }
 
-        assert_eq!(worker.steps().len(), 1);
+    /// Sets what is recorded about every step, see `Observability::fast`.
+    pub fn set_observability(&mut self, observability: Observability) {
+        self.observability = observability;
     }
 
-    #[test]
-    fn it_should_get_step() {
-        let mut worker = Worker::new();
-        worker.add_step(RobotsTxt);
+    /// Sets what is recorded about one step, overriding `set_observability`. `None` removes the
+    /// override.
+    pub fn set_step_observability(&mut self, step: &str, observability: Option<Observability>) {
+        match observability {
+            Some(observability) => self
+                .step_observability
+                .insert(step.to_string(), observability),
+            None => self.step_observability.remove(step),
+        };
+    }
 
-        let step = worker.get_step(ROBOTS_TXT).unwrap();
-        assert_eq!(step.name(), ROBOTS_TXT);
+    /// Returns what is recorded about a step.
+    pub fn observability(&self, step: &str) -> Observability {
+        self.step_observability
+            .get(step)
+            .copied()
+            .unwrap_or(self.observability)
     }
 
-    #[test]
-    fn it_should_call_on_request() {
-        let mut worker = Worker::new();
-        worker.add_step(RobotsTxt);
+    /// Rewrites the body of every successful response before `on_success`, e.g. with
+    /// `strip_xssi` or `decompress`. Transformers run in the order they were added, and one
+    /// failing fails the step with `StepError::MalformedResponse`.
+    pub fn add_transformer(
+        &mut self,
+        transformer: impl Fn(
+                &ResponseInfo,
+                bytes::Bytes,
+            ) -> Result<bytes::Bytes, Box<dyn std::error::Error + Send + Sync>>
+            + Send
+            + Sync
+            + 'static,
+    ) {
+        self.transformers.push(Arc::new(transformer));
+    }
 
-        let step = worker.get_step(ROBOTS_TXT).unwrap();
-        let req = step.on_request();
-        assert_eq!(req.method(), "GET");
+    /// Rewrites the request of every step before it's sent, e.g. to add an auth header, after
+    /// the profile, identity and referer were applied. Hooks run in the order they were added.
+    pub fn before_request(
+        &mut self,
+        hook: impl Fn(&Context, Request) -> Request + Send + Sync + 'static,
+    ) {
+        self.before_request.push((None, Arc::new(hook)));
     }
 
-    #[test]
-    fn it_should_update_context_from_request() {
-        let mut worker = Worker::new();
-        worker.add_step(RobotsTxt);
+    /// Like `before_request`, for the requests to the hosts of a pattern only, e.g. to sign the
+    /// requests of `*.api.example.com` or log in to one site of a multi-site flow. Hooks of every
+    /// host and of patterns run together, in the order they were added.
+    pub fn before_request_for(
+        &mut self,
+        pattern: impl Into<DomainPattern>,
+        hook: impl Fn(&Context, Request) -> Request + Send + Sync + 'static,
+    ) {
+        self.before_request
+            .push((Some(pattern.into()), Arc::new(hook)));
+    }
 
-        let step = worker.get_step(ROBOTS_TXT).unwrap();
-        let req = step.on_request();
+    /// Called with the context of every response received, whatever its status, before the
+    /// status is checked and the step's callbacks run, e.g. to log it.
+    pub fn after_response(&mut self, hook: impl Fn(&mut Context) + Send + Sync + 'static) {
+        self.after_response.push(Arc::new(hook));
+    }
 
-        match worker.ctx.update_from_request(req) {
-            Ok(_) => {
-                assert_eq!(worker.ctx.get_method(), "GET");
-                assert_eq!(worker.ctx.get_url(), "https://google.com");
-            }
-            Err(e) => {
-                println!("Error: {}", e);
-                unreachable!()
-            }
+    /// Called with the step's name before every step `try_step` runs.
+    pub fn on_step_start(&mut self, hook: impl Fn(&mut Context, &str) + Send + Sync + 'static) {
+        self.on_step_start.push(Arc::new(hook));
+    }
+
+    /// Called with the step's name after every step `try_step` runs, and its error if it failed,
+    /// see `StepError::downcast`.
+    pub fn on_step_end(
+        &mut self,
+        hook: impl Fn(&mut Context, &str, Option<&(dyn std::error::Error + Send + Sync)>)
+            + Send
+            + Sync
+            + 'static,
+    ) {
+        self.on_step_end.push(Arc::new(hook));
+    }
+
+    /// Runs the transformers on the response body. The body is left untouched on errors.
+    fn transform_body(&mut self) -> Result<(), StepError> {
+        let Some(info) = self.ctx.response_info() else {
+            return Ok(());
+        };
+        let mut body = self.ctx.body_bytes().unwrap_or_default();
+        for transformer in &self.transformers {
+            body =
+                transformer(info, body).map_err(|e| StepError::MalformedResponse(e.to_string()))?;
         }
+        self.ctx.set_response_body(body);
+        Ok(())
     }
 
-    #[test]
-    fn it_should_update_current_step() {
-        let mut worker = Worker::new();
-        worker.ctx.set_current_step(ROBOTS_TXT.to_string());
-        assert_eq!(worker.ctx.get_current_step().unwrap(), ROBOTS_TXT);
+    /// Returns what the body sampling keeps of the current response body.
+    fn sample_body(&self, failed: bool) -> Option<String> {
+        let sampling = self.sampling.as_ref()?;
+        sampling.sample(self.ctx.body_slice().ok()?, failed)
     }
 
-    /// This actually goes to https://google.com and fetches the page.
-    /// It's ignored because it can break if the internet is down.
-    /// It's here for testing purposes only.
-    #[tokio::test]
-    #[ignore]
-    async fn it_should_try_step() {
-        let mut worker = Worker::new();
-        worker.add_step(RobotsTxt);
+    /// Sets how many times `run_batch` tries a step before moving it to the dead letters.
+    pub fn set_max_attempts(&mut self, attempts: u32) {
+        self.max_attempts = attempts.max(1);
+    }
 
-        match worker.try_step(ROBOTS_TXT).await {
-            Ok(_) => {
-                assert_eq!(worker.ctx.get_method(), "GET");
-                assert_eq!(worker.ctx.get_url(), "https://google.com");
+    /// Runs each step in order, retrying failures up to the max attempts. Steps that still fail,
+    /// or fail with an error that isn't retryable (see `StepError::is_retryable`), are collected
+    /// into the report's dead letters instead of stopping the batch.
+    pub async fn run_batch(&mut self, steps: Vec<String>) -> RunReport {
+        let mut report = RunReport::new();
+
+        for name in steps {
+            if !self.steps.contains_name(&name) {
+                report.dead_letters.push(DeadLetter {
+                    error: StepError::StepNotFound(name.clone()).to_string(),
+                    step: name,
+                    attempts: 0,
+                    tags: Default::default(),
+                    body: None,
+                });
+                continue;
             }
-            Err(e) => {
-                println!("Error: {}", e);
-                assert!(false);
+
+            let mut attempts = 0;
+            loop {
+                attempts += 1;
+                match self.try_step(&name).await {
+                    Ok(()) => {
+                        report.completed.push(name);
+                        break;
+                    }
+                    Err(err)
+                        if attempts >= self.max_attempts
+                            || StepError::downcast(err.as_ref())
+                                .is_some_and(|e| !e.is_retryable()) =>
+                    {
+                        report.dead_letters.push(DeadLetter {
+                            step: name,
+                            error: err.to_string(),
+                            attempts,
+                            tags: self.ctx.get_tags().clone(),
+                            body: self.sample_body(true),
+                        });
+                        break;
+                    }
+                    Err(_) => continue,
+                }
             }
         }
+
+        report
     }
 
-    #[test]
-    fn check_status_codes_should_return_true_if_status_code_matches() {
-        let mut worker = Worker::new();
-        worker.ctx.set_status_codes(vec![200]);
+    /// Retries requests that fail with an HTTP/2 error (GOAWAY, stream reset, protocol error) once
+    /// over HTTP/1.1. The host then stays on HTTP/1.1 for the rest of the session.
+    pub fn set_http2_fallback(&mut self, enabled: bool) {
+        self.http2_fallback = enabled;
+    }
 
-        assert!(worker.check_status_code(200));
+    /// Returns the hosts that were downgraded to HTTP/1.1.
+    pub fn downgraded_hosts(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = self.downgraded.iter().cloned().collect();
+        hosts.sort();
+        hosts
     }
 
-    #[test]
-    fn check_status_codes_should_return_false_if_status_code_does_not_match() {
-        let mut worker = Worker::new();
-        worker.ctx.set_status_codes(vec![200]);
+    /// Makes `run` roll back a failed run: every step that succeeded gets its `on_rollback` called,
+    /// most recent first, e.g. to remove the items a flow added to a cart.
+    pub fn set_rollback_on_failure(&mut self, enabled: bool) {
+        self.rollback = enabled;
+    }
 
-        assert!(!worker.check_status_code(404));
+    /// Sets how many steps `run` executes at most, 1000 by default.
+    pub fn set_max_iterations(&mut self, iterations: usize) {
+        self.max_iterations = iterations;
     }
 
-    #[test]
-    fn check_status_codes_should_use_default_status_codes_if_200_to_300_if_status_cares_are_empty()
-    {
-        let mut worker = Worker::new();
-        worker.ctx.set_status_codes(vec![]);
+    /// Sets how many times `run` lets a step request the same url before stopping it as a loop,
+    /// 2 by default. `None` disables loop detection, e.g. for polling flows.
+    pub fn set_max_visits(&mut self, visits: Option<usize>) {
+        self.max_visits = visits;
+    }
 
-        assert!(worker.check_status_code(200));
+    /// Runs `start_step`, then follows the next step set by each step until one doesn't set it.
+    /// When a step fails, the run continues only if its error handler set a next step.
+    /// Once there is no next step, the run waits for the steps scheduled with
+    /// `Context::schedule_step`, earliest first, and repeating them isn't detected as a loop.
+    pub async fn run(&mut self, start_step: &str) -> RunSummary {
+        let (steps, stopped) = self.run_steps(start_step).await;
+        let rolled_back = match (&stopped, self.rollback) {
+            (StopReason::Failed(_), true) => self.roll_back(&steps).await,
+            _ => vec![],
+        };
+
+        RunSummary {
+            steps,
+            stopped,
+            rolled_back,
+        }
     }
 
-    #[test]
-    fn check_status_codes_should_use_default_status_codes_if_200_to_300_if_no_status_codes_are_set()
-    {
-        let worker = Worker::new();
+    async fn run_steps(&mut self, start_step: &str) -> (Vec<StepRecord>, StopReason) {
+        let mut steps = vec![];
+        let mut iterations = 0;
+        let mut visits: HashMap<(String, String), usize> = HashMap::new();
+        let mut next = Some(start_step.to_string());
+        let mut delayed = false;
 
-        assert!(worker.check_status_code(200));
-        assert!(!worker.check_status_code(404));
+        while let Some(name) = next.take() {
+            if iterations >= self.max_iterations {
+                return (steps, StopReason::MaxIterations);
+            }
+            if !self.steps.contains_name(&name) {
+                return (
+                    steps,
+                    StopReason::Failed(StepError::StepNotFound(name).to_string()),
+                );
+            }
+
+            if self.cancel.is_cancelled() {
+                // looked up without selecting a variant, the step doesn't run
+                if let Some(step) = self.steps.get_any(&name).cloned() {
+                    self.cancel_step(&name, &step).await.ok();
+                }
+                return (steps, StopReason::Cancelled);
+            }
+
+            self.ctx.clear_next_step();
+            let result = self.try_step(&name).await;
+            iterations += 1;
+            let url = self.ctx.get_url();
+            // rolling back walks the history, so it is kept anyway
+            if self.rollback || self.observability(&name).history {
+                steps.push(StepRecord {
+                    step: name.clone(),
+                    variant: self.ctx.get_current_variant(),
+                    url: url.clone(),
+                    elapsed_ms: self.ctx.get_time_elapsed(),
+                    error: result.as_ref().err().map(|err| err.to_string()),
+                    body: self.sample_body(result.is_err()),
+                    download: self.ctx.download_report().cloned(),
+                });
+            }
+
+            next = self.ctx.get_next_step();
+            if let Err(err) = result {
+                if StepError::downcast(err.as_ref()) == Some(&StepError::Cancelled) {
+                    return (steps, StopReason::Cancelled);
+                }
+                if next.is_none() && self.ctx.get_delayed_steps().is_empty() {
+                    return (steps, StopReason::Failed(err.to_string()));
+                }
+            }
+
+            // scheduled steps are meant to repeat, e.g. polling, so they aren't loops
+            if let (Some(max), false) = (self.max_visits, delayed) {
+                let count = visits.entry((name.clone(), url)).or_default();
+                *count += 1;
+                if *count > max {
+                    return (steps, StopReason::LoopDetected(name));
+                }
+            }
+
+            delayed = next.is_none();
+            if delayed {
+                let cancel = self.cancel.clone();
+                next = tokio::select! {
+                    biased;
+                    _ = cancel.cancelled() => return (steps, StopReason::Cancelled),
+                    next = self.ctx.get_delayed_steps_mut().pop() => next,
+                };
+            }
+        }
+
+        (steps, StopReason::Finished)
+    }
+
+    /// Calls `on_rollback` on the steps that succeeded, in reverse order, and sends the
+    /// compensating requests they return.
+    async fn roll_back(&mut self, steps: &[StepRecord]) -> Vec<StepRecord> {
+        let mut records = vec![];
+        for record in steps.iter().rev().filter(|record| record.error.is_none()) {
+            let variant = record.variant.as_deref();
+            let Some(step) = self.steps.get_variant(&record.step, variant).cloned() else {
+                continue;
+            };
+            if let Some(req) = step.on_rollback(&mut self.ctx).await {
+                records.push(self.send_compensation(&record.step, req).await);
+            }
+        }
+        records
+    }
+
+    async fn send_compensation(&mut self, name: &str, req: Request) -> StepRecord {
+        let url = req.url().clone();
+        let stop_watch = std::time::Instant::now();
+        let error = match self.ctx.update_from_request(req) {
+            Err(err) => Some(err.to_string()),
+            Ok(()) => match self.ctx.get_request_builder() {
+                Some(builder) => {
+                    let result = self
+                        .ctx
+                        .get_http_requester()
+                        .execute(builder, &mut self.read_buffer, self.host_guard.as_deref())
+                        .await;
+                    let status = result.as_ref().ok().map(|res| res.info.status());
+                    let identity = self.ctx.get_current_identity();
+                    match (
+                        self.audit_request(name, identity.as_deref(), status),
+                        result,
+                    ) {
+                        (Err(err), _) => Some(err.to_string()),
+                        (Ok(()), Ok(res)) if self.check_status_code(res.info.status()) => None,
+                        (Ok(()), Ok(res)) => Some(
+                            StepError::StatusCodeNotFound(
+                                res.info.status() as i32,
+                                self.ctx.get_status_codes().unwrap_or_default(),
+                            )
+                            .to_string(),
+                        ),
+                        (Ok(()), Err(err)) => Some(err.error.to_string()),
+                    }
+                }
+                None => None,
+            },
+        };
+
+        StepRecord {
+            step: name.to_string(),
+            variant: None,
+            url,
+            elapsed_ms: stop_watch.elapsed().as_millis() as u64,
+            error,
+            body: None,
+            download: None,
+        }
+    }
+
+    /// Runs only the dead letters of a previous report again.
+    pub async fn retry_failures(&mut self, report: &RunReport) -> RunReport {
+        self.run_batch(report.failed_steps()).await
+    }
+
+    pub fn steps(self) -> StepManager {
+        self.steps
+    }
+
+    // get the step by name
+    fn get_step(&self, name: &str) -> Option<Arc<dyn Stepable>> {
+        match self.steps.get(name) {
+            Some(step) => Some(step.clone()),
+            None => None,
+        }
+    }
+
+    pub async fn try_step(
+        &mut self,
+        name: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        for hook in self.on_step_start.clone() {
+            hook(&mut self.ctx, name);
+        }
+        let span = trace::step_span(name);
+        let result = trace::in_span(&span, self.execute_step(name, &span)).await;
+        for hook in self.on_step_end.clone() {
+            hook(
+                &mut self.ctx,
+                name,
+                result.as_ref().err().map(|err| err.as_ref()),
+            );
+        }
+        result
+    }
+
+    // start the instant timer to run the step
+    // run send() on the request_builder
+    // stop the instant timer
+    async fn execute_step(
+        &mut self,
+        name: &str,
+        span: &trace::Span,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let rotate = self.rotation.as_ref().is_some_and(|rotation| {
+            rotation.is_due(self.session_requests, self.session_started.elapsed())
+        });
+        if rotate {
+            self.rotate_session().await?;
+        }
+
+        // e.g. a step whose variants all have a weight of 0
+        let Some(selected) = self.steps.select(name) else {
+            return Err(Box::new(StepError::StepNotFound(name.to_string())));
+        };
+        let observability = self.observability(name);
+        let labels = observability.metric_labels;
+        let step = selected.step;
+        let variant_metrics = selected.metrics;
+        self.ctx.set_current_variant(selected.variant);
+        let mut req = trace::build_span(name).in_scope(|| step.on_request_with(&self.ctx));
+        if let Some(environment) = self.ctx.get_environment() {
+            req = environment.resolve(req);
+        }
+
+        if req.is_skipped() {
+            if let Some(next) = req.get_skip_to_step() {
+                self.ctx.set_next_step(next);
+            }
+            return Ok(());
+        }
+
+        let session_key = self.affinity.as_ref().and_then(|a| a.key_for(&req));
+        self.enter_session(session_key.as_deref());
+        let identity = match (&self.affinity, &session_key) {
+            (Some(affinity), Some(key)) => affinity.identity(key, self.identities.as_deref()),
+            _ => self.identities.as_ref().and_then(|pool| pool.select()),
+        };
+        let session = session_key
+            .clone()
+            .or_else(|| identity.as_ref().map(|i| i.id().to_string()))
+            .unwrap_or_default();
+        if let Some(rotator) = &self.profile_rotator {
+            if !self.profiles.contains_key(&session) {
+                if let Some(profile) = rotator.pick() {
+                    self.profiles.insert(session.clone(), profile);
+                }
+            }
+        }
+        // the profile's user agent goes before the identity's, keeping the client hints coherent
+        let host = req.host();
+        let domain_profile = host
+            .as_deref()
+            .and_then(|host| most_specific(&self.domain_profiles, host));
+        if let Some(profile) = domain_profile.or_else(|| self.profiles.get(&session)) {
+            req = profile.apply(req);
+        }
+        if let Some(identity) = &identity {
+            req = Self::apply_identity(req, identity);
+        }
+        let identity = identity.map(|i| i.id().to_string());
+        self.ctx.set_current_identity(identity.clone());
+
+        if self.warm_up.is_some() && !self.warmed.contains(&session) {
+            self.run_warm_up(name, &req).await;
+            self.warmed.insert(session);
+        }
+
+        req = self.apply_referer(req);
+        for (pattern, hook) in &self.before_request {
+            let applies = match (pattern, &host) {
+                (None, _) => true,
+                (Some(pattern), Some(host)) => pattern.matches(host),
+                (Some(_), None) => false,
+            };
+            if applies {
+                req = hook(&self.ctx, req);
+            }
+        }
+        let conditional = req.is_conditional() && req.method() == Method::GET;
+        if conditional {
+            req = self.validators.apply(req);
+        }
+
+        let url = req.url().clone();
+        let method = req.method().to_string();
+        let is_get = req.method() == Method::GET;
+        let host = req.host();
+        let cost = req.cost();
+        let priority = req.priority().unwrap_or(self.ctx.get_priority());
+        let flight_key = Self::singleflight_key(&req);
+        let retry = req.effective_retry_policy();
+        let download = req.download().cloned();
+        let req_timeout = req.timeout();
+        let proxied = req.proxy().map(|_| {
+            let provider = req.proxy_provider().unwrap_or(UNNAMED_PROVIDER).to_string();
+            (provider, request_size(&req))
+        });
+        // set once here, so every attempt below sends the same key
+        if let Some(policy) = &retry {
+            req = policy.apply_idempotency_key(req);
+        }
+
+        let issues = match &self.coherence {
+            Some(validator) => validator.validate(&req),
+            None => vec![],
+        };
+
+        let downgraded = host.as_ref().is_some_and(|h| self.downgraded.contains(h));
+        // the session's requester may have been swapped since the guard was set
+        self.ctx
+            .get_client_settings_mut()
+            .set_http1_only(downgraded)
+            .set_host_guard(self.host_guard.clone());
+        self.ctx.set_downgraded(false);
+        self.ctx.update_from_request(req)?;
+        self.ctx.set_current_step(name.to_string());
+        self.ctx.set_coherence_issues(issues.clone());
+
+        if !issues.is_empty()
+            && self.coherence.as_ref().map(|v| v.mode()) == Some(CoherenceMode::Error)
+        {
+            let error =
+                StepError::IncoherentRequest(issues.iter().map(|i| i.to_string()).collect());
+            if let Some(metrics) = &variant_metrics {
+                metrics.record_failure();
+            }
+            trace::in_span(
+                &trace::callback_span(name, "on_error"),
+                step.on_error(&mut self.ctx, error.clone()),
+            )
+            .await;
+            return Err(Box::new(error));
+        }
+
+        let guard = self.host_guard.as_deref();
+        if let Err(error) = crate::fan_out::check_guard(guard, self.ctx.get_request()).await {
+            if let Some(metrics) = &variant_metrics {
+                metrics.record_failure();
+            }
+            trace::in_span(
+                &trace::callback_span(name, "on_error"),
+                step.on_error(&mut self.ctx, error.clone()),
+            )
+            .await;
+            return Err(Box::new(error));
+        }
+
+        // held until the step is done, including its callbacks
+        let _permit = match self.steps.concurrency_limit(name) {
+            Some(limit) => limit.acquire_owned().await.ok(),
+            None => None,
+        };
+
+        let mut attempt = 1;
+        let result = loop {
+            if attempt > 1 {
+                self.ctx.rebuild_request()?;
+            }
+            let req_builder = self.ctx.get_request_builder().unwrap();
+
+            if let (Some(limiter), Some(host)) = (&self.rate_limiter, &host) {
+                limiter.acquire_with_priority(host, cost, priority).await;
+            }
+
+            self.session_requests += 1;
+            // drop the previous body so its allocation can be reused for this response
+            drop(self.ctx.take_response_body());
+            self.ctx.set_response_info(None);
+            self.ctx.set_download_report(None);
+            self.ctx.set_timeout_info(None);
+
+            // Start processing the request and time it.
+            let stop_watch = std::time::Instant::now();
+            let send = trace::send_span(name, &method, &url, attempt);
+            let cancel = self.cancel.clone();
+            let sending = trace::in_span(&send, async {
+                match (&self.singleflight, &flight_key) {
+                    _ if download.is_some() => {
+                        self.ctx.set_coalesced(false);
+                        self.download(req_builder, download.as_ref()).await
+                    }
+                    (Some(group), Some(key)) => {
+                        let (requester, buffer) =
+                            (self.ctx.get_http_requester(), &mut self.read_buffer);
+                        let guard = self.host_guard.as_deref();
+                        let (result, coalesced) = group
+                            .run(key, || requester.execute(req_builder, buffer, guard))
+                            .await;
+                        self.ctx.set_coalesced(coalesced);
+                        result
+                    }
+                    _ => {
+                        self.ctx.set_coalesced(false);
+                        let requester = self.ctx.get_http_requester();
+                        let guard = self.host_guard.as_deref();
+                        requester
+                            .execute(req_builder, &mut self.read_buffer, guard)
+                            .await
+                    }
+                }
+            });
+            // dropping the request aborts it
+            let result = tokio::select! {
+                biased;
+                _ = cancel.cancelled() => None,
+                result = sending => Some(result),
+            };
+            let Some(result) = result else {
+                return self.cancel_step(name, &step).await;
+            };
+            self.ctx
+                .set_time_elapsed(stop_watch.elapsed().as_millis() as u64);
+            let status = result.as_ref().ok().map(|res| res.info.status());
+            trace::record_response(&send, None, status, self.ctx.get_time_elapsed(), None);
+            if let (Some(accounting), Some((provider, size))) = (&self.proxy_accounting, &proxied) {
+                accounting.record(provider, *size, &result);
+            }
+            let status = result.as_ref().ok().map(|res| res.info.status());
+            self.audit_request(name, identity.as_deref(), status)?;
+
+            let http2_failed = result
+                .as_ref()
+                .is_err_and(|err| matches!(err.error, StepError::Http2(_)));
+            if http2_failed && self.http2_fallback && !self.ctx.is_downgraded() && !downgraded {
+                // sent again over HTTP/1.1, through the same limits and cancellation
+                self.downgrade(&host);
+                continue;
+            }
+
+            match retry.as_ref().and_then(|p| p.retry_delay(attempt, &result)) {
+                Some(delay) => {
+                    let error = match &result {
+                        Err(err) => err.error.clone(),
+                        Ok(res) => StepError::StatusCodeNotFound(
+                            res.info.status() as i32,
+                            self.ctx.get_status_codes().unwrap_or_default(),
+                        ),
+                    };
+                    trace::in_span(
+                        &trace::callback_span(name, "on_retry"),
+                        step.on_retry(&mut self.ctx, attempt, error),
+                    )
+                    .await;
+                    tokio::select! {
+                        biased;
+                        _ = cancel.cancelled() => return self.cancel_step(name, &step).await,
+                        _ = tokio::time::sleep(delay) => {}
+                    }
+                    attempt += 1;
+                }
+                None => break result,
+            }
+        };
+        let elapsed = Duration::from_millis(self.ctx.get_time_elapsed());
+        let status = result.as_ref().ok().map(|res| res.info.status());
+        trace::record_response(
+            span,
+            Some(&url),
+            status,
+            elapsed.as_millis() as u64,
+            Some(attempt),
+        );
+
+        let res = match result {
+            Ok(res) => res,
+            Err(err) => {
+                if let Some(metrics) = &variant_metrics {
+                    metrics.record_failure();
+                }
+                self.record_identity(&identity, false);
+                if err.error.is_timeout() {
+                    let info = TimeoutInfo {
+                        kind: if err.connecting {
+                            TimeoutKind::Connect
+                        } else {
+                            TimeoutKind::Request
+                        },
+                        elapsed,
+                        request_timeout: req_timeout,
+                        connect_timeout: self
+                            .ctx
+                            .get_client_settings()
+                            .socket_options()
+                            .connect_timeout,
+                        attempt,
+                        max_attempts: retry.as_ref().map_or(1, |p| p.max_attempts()),
+                    };
+                    self.ctx.set_timeout_info(Some(info));
+                    self.record_outcome(name, &host, labels, false);
+                    trace::in_span(
+                        &trace::callback_span(name, "on_timeout"),
+                        step.on_timeout(&mut self.ctx),
+                    )
+                    .await;
+                    return Err(Box::new(StepError::Timeout));
+                }
+                self.record_outcome(name, &host, labels, false);
+
+                // keep what was received of a broken response for the error handler
+                if let Some(partial) = err.partial {
+                    self.ctx.set_response_body(partial.body);
+                    self.ctx.set_response_info(Some(partial.info));
+                    self.ctx.set_partial_body(true);
+                }
+                trace::in_span(
+                    &trace::callback_span(name, "on_error"),
+                    step.on_error(&mut self.ctx, err.error.clone()),
+                )
+                .await;
+                return Err(Box::new(err.error));
+            }
+        };
+
+        // kept for the error handler too, e.g. to read a block page
+        let status = res.info.status();
+        if conditional {
+            self.validators.record(&url, &res.info);
+        }
+        self.ctx.set_response_body(res.body);
+        self.ctx.set_response_info(Some(res.info));
+        self.ctx.set_partial_body(false);
+        for hook in self.after_response.clone() {
+            hook(&mut self.ctx);
+        }
+
+        let not_modified = conditional && status == 304;
+        if !not_modified && !self.check_status_code(status) {
+            let error = StepError::StatusCodeNotFound(
+                status as i32,
+                self.ctx.get_status_codes().unwrap_or_default(),
+            );
+
+            if let Some(metrics) = &variant_metrics {
+                metrics.record_failure();
+            }
+            self.record_identity(&identity, false);
+            self.record_outcome(name, &host, labels, false);
+            trace::in_span(
+                &trace::callback_span(name, "on_error"),
+                step.on_error(&mut self.ctx, error.clone()),
+            )
+            .await;
+            return Err(Box::new(error));
+        }
+
+        // a 304 has no body to transform or validate
+        let checked = if not_modified {
+            Ok(())
+        } else if self.transformers.is_empty() {
+            self.ctx.check_response_schema()
+        } else {
+            self.transform_body()
+                .and_then(|_| self.ctx.check_response_schema())
+        };
+        if let Err(error) = checked {
+            if let Some(metrics) = &variant_metrics {
+                metrics.record_failure();
+            }
+            self.record_identity(&identity, false);
+            self.record_outcome(name, &host, labels, false);
+            trace::in_span(
+                &trace::callback_span(name, "on_error"),
+                step.on_error(&mut self.ctx, error.clone()),
+            )
+            .await;
+            return Err(Box::new(error));
+        }
+
+        // clear the next step since the context is being reused, this fixes the infinite loop bug
+        self.ctx.clear_next_step();
+        if let Some(metrics) = &variant_metrics {
+            metrics.record_success();
+        }
+        self.record_identity(&identity, true);
+        self.record_outcome(name, &host, labels, true);
+        if let (Some(snapshot), true) = (&self.snapshot, observability.observers) {
+            let text = match &self.sampling {
+                Some(_) => self.sample_body(false).unwrap_or_default(),
+                None => self.ctx.body_text().unwrap_or_default(),
+            };
+            let body = serde_json::from_str::<serde_json::Value>(&text)
+                .unwrap_or(serde_json::Value::String(text));
+            snapshot.record(name, &serde_json::json!({ "url": url, "body": body }));
+        }
+        if let (Some(chain), true) = (&mut self.referrer, is_get) {
+            chain.visit(&url);
+        }
+        trace::in_span(
+            &trace::callback_span(name, "on_success"),
+            step.on_success(&mut self.ctx),
+        )
+        .await;
+
+        Ok(())
+    }
+
+    /// Tells the step the run was cancelled, failing it with `StepError::Cancelled`.
+    async fn cancel_step(
+        &mut self,
+        name: &str,
+        step: &Arc<dyn Stepable>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        trace::in_span(
+            &trace::callback_span(name, "on_cancelled"),
+            step.on_cancelled(&mut self.ctx),
+        )
+        .await;
+        Err(Box::new(StepError::Cancelled))
+    }
+
+    /// Visits every warm-up page as the request's proxy and user agent. Failures are ignored since
+    /// the pages only exist to build up a history. Cancelling the worker stops the visits.
+    async fn run_warm_up(&mut self, name: &str, req: &Request) {
+        let Some(warm_up) = self.warm_up.clone() else {
+            return;
+        };
+        let cancel = self.cancel.clone();
+
+        for page in warm_up.pages() {
+            let mut visit = Request::new(Method::GET, page.clone());
+            if let Some(proxy) = req.proxy() {
+                visit = visit.with_proxy(proxy);
+            }
+            if let Some(user_agent) = req.user_agent() {
+                visit = visit.with_user_agent(user_agent);
+            }
+
+            if self.ctx.update_from_request(visit).is_ok() {
+                if let Some(builder) = self.ctx.get_request_builder() {
+                    let requester = self.ctx.get_http_requester();
+                    let guard = self.host_guard.as_deref();
+                    let sending = requester.execute(builder, &mut self.read_buffer, guard);
+                    let result = tokio::select! {
+                        biased;
+                        _ = cancel.cancelled() => return,
+                        result = sending => result,
+                    };
+                    let status = result.ok().map(|res| res.info.status());
+                    let identity = self.ctx.get_current_identity();
+                    let _ = self.audit_request(name, identity.as_deref(), status);
+                }
+            }
+            tokio::select! {
+                biased;
+                _ = cancel.cancelled() => return,
+                _ = tokio::time::sleep(warm_up.next_delay()) => {}
+            }
+        }
+    }
+
+    /// Records the context's request to the audit log, if there is one.
+    fn audit_request(
+        &self,
+        step: &str,
+        identity: Option<&str>,
+        status: Option<u16>,
+    ) -> std::io::Result<()> {
+        let Some(audit) = &self.audit else {
+            return Ok(());
+        };
+        let sent = self.ctx.get_request();
+        audit.record(AuditRecord {
+            step,
+            method: sent.method().as_str(),
+            url: sent.url(),
+            body: sent.body_bytes(),
+            identity,
+            status,
+        })?;
+        Ok(())
+    }
+
+    /// Switches the host to HTTP/1.1 and rebuilds the current request for it.
+    fn downgrade(&mut self, host: &Option<String>) {
+        if let Some(host) = host {
+            self.downgraded.insert(host.clone());
+        }
+        self.ctx.get_client_settings_mut().set_http1_only(true);
+        self.ctx.set_downgraded(true);
+        let _ = self.ctx.rebuild_request();
+    }
+
+    /// Sends the request with the identity's proxy and user agent, unless the request sets its own.
+    fn apply_identity(mut req: Request, identity: &Identity) -> Request {
+        if req.proxy().is_none() {
+            if let Some(proxy) = identity.proxy() {
+                req = req.with_proxy(proxy);
+                if let (None, Some(provider)) = (req.proxy_provider(), identity.proxy_provider()) {
+                    req = req.with_proxy_provider(provider);
+                }
+            }
+        }
+        if req.user_agent().is_none() {
+            if let Some(user_agent) = identity.user_agent() {
+                req = req.with_user_agent(user_agent);
+            }
+        }
+        req
+    }
+
+    /// Streams the response to the download's file and keeps its report, or fetches it into the
+    /// read buffer without a download.
+    async fn download(
+        &mut self,
+        req_builder: RequestBuilder,
+        download: Option<&Download>,
+    ) -> SharedResult {
+        let Some(download) = download else {
+            let requester = self.ctx.get_http_requester();
+            let guard = self.host_guard.as_deref();
+            return requester
+                .execute(req_builder, &mut self.read_buffer, guard)
+                .await;
+        };
+        let policy = self.ctx.get_client_settings().redirect_policy();
+        let guard = self.host_guard.as_deref();
+        let (result, report) =
+            crate::download::download(req_builder, download, policy, guard).await;
+        self.ctx.set_download_report(report);
+        result
+    }
+
+    /// Adds the referrer chain's `Referer` header, unless the request sets its own.
+    fn apply_referer(&self, req: Request) -> Request {
+        let Some(chain) = &self.referrer else {
+            return req;
+        };
+        let mut headers = req.headers().unwrap_or_default();
+        if headers.contains_key(REFERER) {
+            return req;
+        }
+        match chain
+            .referer_for(req.url())
+            .and_then(|referer| HeaderValue::from_str(&referer).ok())
+        {
+            Some(value) => {
+                headers.insert(REFERER, value);
+                req.with_headers(headers)
+            }
+            None => req,
+        }
+    }
+
+    /// Describes what would be sent for the request, see `Request::explain`.
+    pub(crate) fn explain(&self, req: &Request) -> Explanation {
+        let mut notes = vec![];
+        let mut req = req.clone();
+        if let Some(environment) = self.ctx.get_environment() {
+            req = environment.resolve(req);
+        }
+
+        if let Some(pool) = &self.identities {
+            notes.push(format!(
+                "an identity is picked from a pool of {} for each request, its proxy and user agent apply when the request sets none",
+                pool.len()
+            ));
+            if let Some(id) = self.ctx.get_current_identity() {
+                if let Some(identity) = pool.get(&id) {
+                    notes.push(format!("shown as the current identity {:?}", id));
+                    req = Self::apply_identity(req, &identity);
+                }
+            }
+        }
+        if self.warm_up.is_some() {
+            let session = self.ctx.get_current_identity().unwrap_or_default();
+            if !self.warmed.contains(&session) {
+                notes.push("the warm-up pages are visited first, the session is fresh".to_string());
+            }
+        }
+        req = self.apply_referer(req);
+
+        if let Some(guard) = &self.host_guard {
+            if let Err(violation) = guard.check_url(req.url()) {
+                notes.push(format!("blocked by the host guard: {}", violation));
+            }
+        }
+        if let Some(validator) = &self.coherence {
+            for issue in validator.validate(&req) {
+                notes.push(format!("coherence issue: {:?}", issue));
+            }
+        }
+        if let Some(policy) = req.retry_policy() {
+            notes.push(format!(
+                "retried up to {} attempts on timeouts and retryable statuses",
+                policy.max_attempts()
+            ));
+        } else if req.respect_retry_after() == Some(true) {
+            notes.push("retried after the Retry-After of 429 and 503 responses".to_string());
+        }
+        if let Some(next) = req.get_skip_to_step() {
+            notes.push(format!("skipped, the flow continues at {:?}", next));
+        } else if req.is_skipped() {
+            notes.push("skipped, the run finishes here".to_string());
+        }
+
+        let cookies = reqwest::Url::parse(req.url()).ok().and_then(|url| {
+            let jar = self.ctx.get_http_requester().cookie_jar();
+            reqwest::cookie::CookieStore::cookies(jar.as_ref(), &url)
+        });
+        Explanation::new(&req, cookies, self.ctx.get_priority(), notes)
+    }
+
+    /// Feeds the response's latency and outcome to the metrics and the adaptive throttle.
+    fn record_outcome(&self, step: &str, host: &Option<String>, labels: bool, success: bool) {
+        let elapsed = self.ctx.get_time_elapsed();
+        if let Some(metrics) = &self.metrics {
+            let outcome = RequestOutcome {
+                status: self.ctx.status(),
+                success,
+                timeout: self.ctx.timeout_info().is_some(),
+                bytes_sent: request_size(self.ctx.get_request()),
+                bytes_received: self.ctx.body_slice().map_or(0, |body| body.len() as u64),
+            };
+            metrics.record_request(step, &outcome);
+        }
+        match (&self.metrics, labels) {
+            (Some(metrics), true) => {
+                metrics.record_tagged(step, host.as_deref(), self.ctx.get_tags(), elapsed, success)
+            }
+            (Some(metrics), false) => metrics.record(step, host.as_deref(), elapsed, success),
+            (None, _) => {}
+        }
+        if let (Some(limiter), Some(host)) = (&self.rate_limiter, host) {
+            limiter.record(host, std::time::Duration::from_millis(elapsed), success);
+            if let Some(budget) = self.ctx.rate_limit_budget() {
+                limiter.record_budget(host, &budget);
+            }
+        }
+    }
+
+    fn record_identity(&self, identity: &Option<String>, success: bool) {
+        if let (Some(pool), Some(id)) = (&self.identities, identity) {
+            if success {
+                pool.record_success(id);
+            } else {
+                pool.record_failure(id);
+            }
+        }
+    }
+
+    /// Only GET requests are coalesced. The key covers everything that can change the response.
+    fn singleflight_key(req: &Request) -> Option<String> {
+        if req.method() != Method::GET || req.download().is_some() {
+            return None;
+        }
+
+        let mut headers: Vec<String> = req
+            .headers()
+            .unwrap_or_default()
+            .iter()
+            .map(|(k, v)| format!("{}={:?}", k, v))
+            .collect();
+        headers.sort();
+
+        Some(format!(
+            "GET {} {:?} {}",
+            req.url(),
+            req.user_agent(),
+            headers.join("&")
+        ))
+    }
+
+    fn check_status_code(&self, status_code: u16) -> bool {
+        match &self.ctx.get_status_codes() {
+            Some(codes) => {
+                if codes.is_empty() {
+                    return (200..300).contains(&status_code);
+                }
+                codes.contains(&status_code)
+            }
+            None => (200..300).contains(&status_code),
+        }
+    }
+}
+
+/// Sends the request and reads the whole body.
+/// The body is read into `buffer`, whose allocation is reused once the previous body is dropped.
+pub(crate) async fn fetch(req_builder: RequestBuilder, buffer: &mut BytesMut) -> SharedResult {
+    let mut res = req_builder
+        .send()
+        .await
+        .map_err(|err| SharedError::from_reqwest(&err))?;
+    let info = ResponseInfo::new(
+        res.status().as_u16(),
+        res.headers().clone(),
+        res.url().to_string(),
+    )
+    .with_version(res.version());
+    if let Some(length) = res.content_length() {
+        buffer.reserve((length as usize).min(MAX_PREALLOCATION));
+    }
+    loop {
+        match res.chunk().await {
+            Ok(Some(chunk)) => buffer.extend_from_slice(&chunk),
+            Ok(None) => break,
+            Err(err) => {
+                return Err(
+                    SharedError::from_reqwest(&err).with_partial(info, buffer.split().freeze())
+                )
+            }
+        }
+    }
+
+    Ok(SharedResponse {
+        info,
+        body: buffer.split().freeze(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_server::{TestResponse, TestServer};
+    use crate::worker::Worker;
+    use crate::{strip_xssi, StepManager};
+    use crate::{
+        Backoff, BodySample, BodySampling, Checkpoint, Clock, CoherenceMode, CoherenceValidator,
+        Context, Download, Environment, EnvironmentOverlays, FetchPage, HostGuard, Identity,
+        IdentityPool, Metrics, MimicBody, Observability, Profile, ProfileRotator, ProxyAccounting,
+        RateLimit, RedirectPolicy, ReferrerChain, Request, RetryPolicy, SessionAffinity,
+        SessionRotation, Singleflight, Snapshot, StepError, Stepable, StopReason, TimeoutInfo,
+        TimeoutKind, WarmUp,
+    };
+    use async_trait::async_trait;
+    use reqwest::Method;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[derive(Clone, Copy)]
+    struct RobotsTxt;
+
+    const ROBOTS_TXT: &str = "RobotsTxt";
+    const SKIPPABLE_STEP: &str = "SkippableStep";
+
+    #[async_trait]
+    impl Stepable for RobotsTxt {
+        fn name(&self) -> String {
+            String::from(ROBOTS_TXT)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, "https://google.com".to_string())
+        }
+
+        async fn on_success(&self, ctx: &mut Context) {
+            eprintln!(
+                "Successfully fetched: {} in {}\n\nURL: {}\nBody: {:?}",
+                ctx.get_current_step().unwrap(),
+                ctx.get_time_elapsed_as_string(),
+                ctx.get_url(),
+                ctx.body_text().unwrap(),
+            );
+        }
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {
+            todo!()
+        }
+
+        async fn on_timeout(&self, _ctx: &mut Context) {
+            todo!()
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    struct SkippableStep;
+
+    #[async_trait]
+    impl Stepable for SkippableStep {
+        fn name(&self) -> String {
+            String::from(SKIPPABLE_STEP)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, "https://google.com".to_string())
+                .skip_to(Some(ROBOTS_TXT.to_string()))
+        }
+
+        async fn on_success(&self, _ctx: &mut Context) {
+            todo!("This step should never be called")
+        }
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {
+            todo!("This step should never be called")
+        }
+
+        async fn on_timeout(&self, _ctx: &mut Context) {
+            todo!("This step should never be called")
+        }
+    }
+
+    const INCOHERENT_STEP: &str = "IncoherentStep";
+
+    #[derive(Clone, Copy)]
+    struct IncoherentStep;
+
+    #[async_trait]
+    impl Stepable for IncoherentStep {
+        fn name(&self) -> String {
+            String::from(INCOHERENT_STEP)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, "http://127.0.0.1:9".to_string()).with_user_agent(
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) Chrome/116.0.0.0 Safari/537.36"
+                    .to_string(),
+            )
+        }
+
+        async fn on_success(&self, _ctx: &mut Context) {
+            todo!("This step should never succeed")
+        }
+
+        async fn on_error(&self, ctx: &mut Context, _err: StepError) {
+            ctx.set_next_step(ROBOTS_TXT.to_string());
+        }
+
+        async fn on_timeout(&self, _ctx: &mut Context) {
+            todo!("This step should never time out")
+        }
+    }
+
+    const URL_STEP: &str = "UrlStep";
+
+    /// A step that fetches a url from the local test server.
+    #[derive(Clone)]
+    struct UrlStep {
+        url: String,
+    }
+
+    #[async_trait]
+    impl Stepable for UrlStep {
+        fn name(&self) -> String {
+            String::from(URL_STEP)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, self.url.clone())
+        }
+
+        async fn on_success(&self, _ctx: &mut Context) {}
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+    }
+
+    /// A step that continues with `next` after succeeding.
+    struct ChainStep {
+        name: &'static str,
+        url: String,
+        next: Option<&'static str>,
+    }
+
+    #[async_trait]
+    impl Stepable for ChainStep {
+        fn name(&self) -> String {
+            String::from(self.name)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, self.url.clone())
+        }
+
+        async fn on_success(&self, ctx: &mut Context) {
+            if let Some(next) = self.next {
+                ctx.set_next_step(next.to_string());
+            }
+        }
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+    }
+
+    /// A step adding an item to a cart, removed again when the run is rolled back.
+    struct CartStep {
+        url: String,
+    }
+
+    #[async_trait]
+    impl Stepable for CartStep {
+        fn name(&self) -> String {
+            String::from("AddToCart")
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::POST, format!("{}/cart", self.url))
+        }
+
+        async fn on_success(&self, ctx: &mut Context) {
+            let item = ctx.body_text().unwrap();
+            ctx.get_store_mut().set("cart_item", item);
+            ctx.set_next_step("Checkout".to_string());
+        }
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+
+        async fn on_rollback(&self, ctx: &mut Context) -> Option<Request> {
+            let item = ctx.get_store_mut().remove::<String>("cart_item")?;
+            Some(Request::new(
+                Method::DELETE,
+                format!("{}/cart/{}", self.url, item),
+            ))
+        }
+    }
+
+    const LOGIN_STEP: &str = "LoginStep";
+
+    /// A step that logs in against the local test server.
+    struct LoginStep {
+        url: String,
+    }
+
+    #[async_trait]
+    impl Stepable for LoginStep {
+        fn name(&self) -> String {
+            String::from(LOGIN_STEP)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, self.url.clone())
+        }
+
+        async fn on_success(&self, _ctx: &mut Context) {}
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+    }
+
+    /// Sends a request tagged with the account it belongs to.
+    struct AccountStep {
+        name: &'static str,
+        url: String,
+        account: &'static str,
+    }
+
+    #[async_trait]
+    impl Stepable for AccountStep {
+        fn name(&self) -> String {
+            String::from(self.name)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, self.url.clone()).with_tag("account", self.account)
+        }
+
+        async fn on_success(&self, _ctx: &mut Context) {}
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+    }
+
+    #[tokio::test]
+    async fn try_step_should_run_the_hooks_of_every_step() {
+        let server = TestServer::start(|req| match req.header("authorization") {
+            Some("Bearer secret") => TestResponse::ok("hi"),
+            _ => TestResponse::status(401, "who are you"),
+        })
+        .await;
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: server.url("/"),
+        });
+        worker.before_request(|_ctx, req| {
+            let mut headers = req.headers().unwrap_or_default();
+            headers.insert("authorization", "Bearer secret".parse().unwrap());
+            req.with_headers(headers)
+        });
+        let log = events.clone();
+        worker.on_step_start(move |_ctx, step| log.lock().unwrap().push(format!("start {}", step)));
+        let log = events.clone();
+        worker.after_response(move |ctx| {
+            let status = ctx.response_info().unwrap().status();
+            log.lock().unwrap().push(format!("status {}", status));
+        });
+        let log = events.clone();
+        worker.on_step_end(move |_ctx, step, err| {
+            log.lock()
+                .unwrap()
+                .push(format!("end {} {}", step, err.is_none()));
+        });
+
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["start UrlStep", "status 200", "end UrlStep true"]
+        );
+    }
+
+    #[tokio::test]
+    async fn try_step_should_scope_hooks_and_profiles_to_domains() {
+        let server = TestServer::start(|req| {
+            TestResponse::ok(&format!(
+                "{} {}",
+                req.header("authorization").unwrap_or("none"),
+                req.header("user-agent").unwrap_or("none")
+            ))
+        })
+        .await;
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: server.url("/"),
+        });
+        let sign = |token: &'static str| {
+            move |_ctx: &Context, req: Request| {
+                let mut headers = req.headers().unwrap_or_default();
+                headers.insert("authorization", token.parse().unwrap());
+                req.with_headers(headers)
+            }
+        };
+        worker.before_request_for("*.example.com", sign("example"));
+        worker.before_request_for("127.0.0.1", sign("local"));
+        worker.set_domain_profile("*", Profile::new("any").with_header("user-agent", "any"));
+        worker.set_domain_profile(
+            "127.0.0.1",
+            Profile::new("app").with_header("user-agent", "app"),
+        );
+
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(worker.ctx.body_text().unwrap(), "local app");
+    }
+
+    #[tokio::test]
+    async fn run_should_fail_a_step_whose_variants_all_weigh_nothing() {
+        let mut worker = Worker::new();
+        worker.add_step_variant(
+            "off",
+            0,
+            UrlStep {
+                url: "http://127.0.0.1:1/".to_string(),
+            },
+        );
+        let err = worker.try_step(URL_STEP).await.unwrap_err();
+        assert_eq!(
+            StepError::downcast(err.as_ref()),
+            Some(&StepError::StepNotFound(URL_STEP.to_string()))
+        );
+        let summary = worker.run(URL_STEP).await;
+        assert_eq!(
+            summary.stopped,
+            StopReason::Failed("Step not found: UrlStep".to_string())
+        );
+    }
+
+    #[test]
+    fn resume_should_reject_checkpoints_of_unknown_steps() {
+        let mut worker = Worker::new();
+        worker.add_step_variant(
+            "b",
+            1,
+            AccountStep {
+                name: "Orders",
+                url: "http://127.0.0.1:1/orders".to_string(),
+                account: "ann",
+            },
+        );
+        let checkpoint = worker.checkpoint(Some("Deleted"));
+        let err = worker.resume(checkpoint).unwrap_err();
+        assert_eq!(err.reason, "unknown step Deleted");
+
+        // a step registered only as a variant is known
+        let checkpoint = worker.checkpoint(Some("Orders"));
+        assert_eq!(
+            worker.resume(checkpoint).unwrap().as_deref(),
+            Some("Orders")
+        );
+    }
+
+    #[tokio::test]
+    async fn resume_should_migrate_checkpoints_of_older_flow_versions() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/login" => TestResponse::ok("welcome").with_header("set-cookie", "sid=abc"),
+            _ => TestResponse::ok(req.header("cookie").unwrap_or("none")),
+        })
+        .await;
+        let mut worker = Worker::new();
+        worker.add_step(LoginStep {
+            url: server.url("/login"),
+        });
+        worker.try_step(LOGIN_STEP).await.unwrap();
+        worker.store_mut().set("user", "ann".to_string());
+        let checkpoint = worker.checkpoint(Some("Orders"));
+        assert_eq!(checkpoint.flow_version, 1);
+
+        // version 2 renamed the step and the store key
+        let mut worker = Worker::new();
+        worker.set_flow_version(2);
+        worker.add_step(AccountStep {
+            name: "History",
+            url: server.url("/history"),
+            account: "ann",
+        });
+        let err = worker.resume(checkpoint.clone()).unwrap_err();
+        assert!(err.reason.contains("no migration"));
+
+        worker.on_migrate(|checkpoint| {
+            assert_eq!(checkpoint.flow_version, 1);
+            checkpoint.rename_step("Orders", "History");
+            checkpoint.rename_key("user", "account");
+            Ok(())
+        });
+        let next = worker.resume(checkpoint.clone()).unwrap();
+        assert_eq!(next.as_deref(), Some("History"));
+        assert_eq!(worker.store_mut().get::<String>("account").unwrap(), "ann");
+        worker.try_step("History").await.unwrap();
+        assert_eq!(worker.ctx.body_text().unwrap(), "sid=abc");
+
+        let mut older = Worker::new();
+        older.add_step(AccountStep {
+            name: "Orders",
+            url: server.url("/orders"),
+            account: "ann",
+        });
+        let newer = Checkpoint {
+            flow_version: 3,
+            ..checkpoint
+        };
+        assert!(older.resume(newer).unwrap_err().reason.contains("newer"));
+    }
+
+    #[test]
+    fn it_should_add_step() {
+        let mut worker = Worker::new();
+        worker.add_step(RobotsTxt);
+
+        assert_eq!(worker.steps().len(), 1);
+    }
+
+    #[test]
+    fn it_should_add_step_arc() {
+        let mut worker = Worker::new();
+        let step = Arc::new(RobotsTxt);
+        worker.add_step_arc(step);
+
+        assert_eq!(worker.steps().len(), 1);
+    }
+
+    #[test]
+    fn it_should_get_step() {
+        let mut worker = Worker::new();
+        worker.add_step(RobotsTxt);
+
+        let step = worker.get_step(ROBOTS_TXT).unwrap();
+        assert_eq!(step.name(), ROBOTS_TXT);
+    }
+
+    #[test]
+    fn it_should_call_on_request() {
+        let mut worker = Worker::new();
+        worker.add_step(RobotsTxt);
+
+        let step = worker.get_step(ROBOTS_TXT).unwrap();
+        let req = step.on_request();
+        assert_eq!(req.method(), "GET");
+    }
+
+    #[test]
+    fn it_should_update_context_from_request() {
+        let mut worker = Worker::new();
+        worker.add_step(RobotsTxt);
+
+        let step = worker.get_step(ROBOTS_TXT).unwrap();
+        let req = step.on_request();
+
+        match worker.ctx.update_from_request(req) {
+            Ok(_) => {
+                assert_eq!(worker.ctx.get_method(), "GET");
+                assert_eq!(worker.ctx.get_url(), "https://google.com");
+            }
+            Err(e) => {
+                println!("Error: {}", e);
+                unreachable!()
+            }
+        }
+    }
+
+    #[test]
+    fn it_should_update_current_step() {
+        let mut worker = Worker::new();
+        worker.ctx.set_current_step(ROBOTS_TXT.to_string());
+        assert_eq!(worker.ctx.get_current_step().unwrap(), ROBOTS_TXT);
+    }
+
+    /// This actually goes to https://google.com and fetches the page.
+    /// It's ignored because it can break if the internet is down.
+    /// It's here for testing purposes only.
+    #[tokio::test]
+    #[ignore]
+    async fn it_should_try_step() {
+        let mut worker = Worker::new();
+        worker.add_step(RobotsTxt);
+
+        match worker.try_step(ROBOTS_TXT).await {
+            Ok(_) => {
+                assert_eq!(worker.ctx.get_method(), "GET");
+                assert_eq!(worker.ctx.get_url(), "https://google.com");
+            }
+            Err(e) => {
+                println!("Error: {}", e);
+                panic!("try_step failed");
+            }
+        }
+    }
+
+    #[test]
+    fn check_status_codes_should_return_true_if_status_code_matches() {
+        let mut worker = Worker::new();
+        worker.ctx.set_status_codes(vec![200]);
+
+        assert!(worker.check_status_code(200));
+    }
+
+    #[test]
+    fn check_status_codes_should_return_false_if_status_code_does_not_match() {
+        let mut worker = Worker::new();
+        worker.ctx.set_status_codes(vec![200]);
+
+        assert!(!worker.check_status_code(404));
+    }
+
+    #[test]
+    fn check_status_codes_should_use_default_status_codes_if_200_to_300_if_status_cares_are_empty()
+    {
+        let mut worker = Worker::new();
+        worker.ctx.set_status_codes(vec![]);
+
+        assert!(worker.check_status_code(200));
+    }
+
+    #[test]
+    fn check_status_codes_should_use_default_status_codes_if_200_to_300_if_no_status_codes_are_set()
+    {
+        let worker = Worker::new();
+
+        assert!(worker.check_status_code(200));
+        assert!(!worker.check_status_code(404));
+    }
+
+    #[test]
+    fn worker_and_context_should_be_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Worker>();
+        assert_send_sync::<Context>();
+        assert_send_sync::<crate::steps::StepManager>();
+    }
+
+    #[tokio::test]
+    async fn it_should_share_steps_across_tokio_tasks() {
+        let mut worker = Worker::new();
+        worker.add_step(RobotsTxt);
+        let steps = worker.steps();
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let steps = steps.clone();
+                tokio::spawn(async move {
+                    let mut worker = Worker::with_steps(steps);
+                    worker.add_step(SkippableStep);
+                    worker.try_step(SKIPPABLE_STEP).await.unwrap();
+                    worker.ctx.get_next_step()
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.await.unwrap().unwrap(), ROBOTS_TXT);
+        }
+    }
+
+    #[tokio::test]
+    async fn try_step_should_select_a_variant() {
+        let mut worker = Worker::new();
+        worker.add_step_variant("normal", 9, SkippableStep);
+        worker.add_step_variant("experimental", 1, SkippableStep);
+        worker.add_step_variant("normal", 1, SkippableStep);
+
+        worker.try_step(SKIPPABLE_STEP).await.unwrap();
+
+        let stats = worker.variant_stats(SKIPPABLE_STEP);
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats.iter().map(|s| s.selected).sum::<u64>(), 1);
+        let label = worker.ctx.get_current_variant().unwrap();
+        assert!(label == "normal" || label == "experimental");
+    }
+
+    #[tokio::test]
+    async fn try_step_should_refuse_incoherent_requests_in_error_mode() {
+        let mut worker = Worker::new();
+        worker.add_step(IncoherentStep);
+        worker.set_coherence_validator(Some(CoherenceValidator::new(CoherenceMode::Error)));
+
+        let err = worker.try_step(INCOHERENT_STEP).await.unwrap_err();
+        assert!(err.to_string().starts_with("Incoherent request headers"));
+        assert_eq!(worker.ctx.get_coherence_issues().len(), 1);
+        assert_eq!(worker.ctx.get_next_step().unwrap(), ROBOTS_TXT);
+    }
+
+    #[tokio::test]
+    async fn try_step_should_coalesce_identical_gets_across_workers() {
+        let server = TestServer::start(|_| {
+            TestResponse::ok("config").with_delay(Duration::from_millis(200))
+        })
+        .await;
+        let group = Arc::new(Singleflight::new());
+
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let group = group.clone();
+                let url = server.url("/config");
+                tokio::spawn(async move {
+                    let mut worker = Worker::new();
+                    worker.add_step(UrlStep { url });
+                    worker.set_singleflight(Some(group));
+                    worker.try_step(URL_STEP).await.unwrap();
+                    (worker.ctx.body_text().unwrap(), worker.ctx.is_coalesced())
+                })
+            })
+            .collect();
+
+        let mut coalesced = 0;
+        for handle in handles {
+            let (body, was_coalesced) = handle.await.unwrap();
+            assert_eq!(body, "config");
+            coalesced += was_coalesced as usize;
+        }
+
+        assert_eq!(server.hits(), 1);
+        assert_eq!(coalesced, 2);
+    }
+
+    #[tokio::test]
+    async fn try_step_should_reuse_the_read_buffer_across_responses() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/long" => TestResponse::ok(&"a".repeat(4096)),
+            _ => TestResponse::ok("short"),
+        })
+        .await;
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: server.url("/long"),
+        });
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(worker.ctx.body_slice().unwrap().len(), 4096);
+
+        worker.add_step(UrlStep {
+            url: server.url("/short"),
+        });
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(worker.ctx.body_str().unwrap(), "short");
+        assert!(worker.read_buffer.capacity() >= 4096 - "short".len());
+    }
+
+    /// The identity, user agent and whether a proxy was used, as seen by `on_error`.
+    type SeenIdentity = (Option<String>, Option<String>, bool);
+
+    struct BurnedIdentityStep {
+        url: String,
+        seen: Arc<std::sync::Mutex<Vec<SeenIdentity>>>,
+    }
+
+    #[async_trait]
+    impl Stepable for BurnedIdentityStep {
+        fn name(&self) -> String {
+            String::from(URL_STEP)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, self.url.clone())
+        }
+
+        async fn on_success(&self, _ctx: &mut Context) {}
+
+        async fn on_error(&self, ctx: &mut Context, _err: StepError) {
+            self.seen.lock().unwrap().push((
+                ctx.get_current_identity(),
+                ctx.get_current_user_agent(),
+                ctx.get_current_proxy().is_some(),
+            ));
+        }
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+    }
+
+    #[tokio::test]
+    async fn on_error_should_see_the_identity_that_was_used() {
+        let server = TestServer::start(|_| TestResponse::status(403, "blocked")).await;
+        let pool = Arc::new(IdentityPool::new(vec![
+            Identity::new("residential-1").with_user_agent("agent/1.0")
+        ]));
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+
+        let mut worker = Worker::new();
+        worker.add_step(BurnedIdentityStep {
+            url: server.url("/"),
+            seen: seen.clone(),
+        });
+        worker.set_identity_pool(Some(pool));
+
+        assert!(worker.try_step(URL_STEP).await.is_err());
+        assert_eq!(
+            seen.lock().unwrap()[0],
+            (
+                Some("residential-1".to_string()),
+                Some("agent/1.0".to_string()),
+                false
+            )
+        );
+    }
+
+    /// An error and the partial body, if any, as seen by `on_error`.
+    type SeenError = (StepError, Option<String>);
+
+    struct BrokenResponseStep {
+        url: String,
+        errors: Arc<std::sync::Mutex<Vec<SeenError>>>,
+    }
+
+    #[async_trait]
+    impl Stepable for BrokenResponseStep {
+        fn name(&self) -> String {
+            String::from(URL_STEP)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, self.url.clone())
+        }
+
+        async fn on_success(&self, _ctx: &mut Context) {}
+
+        async fn on_error(&self, ctx: &mut Context, err: StepError) {
+            let partial = if ctx.is_partial_body() {
+                ctx.body_text().ok()
+            } else {
+                None
+            };
+            self.errors.lock().unwrap().push((err, partial));
+        }
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+    }
+
+    /// Records the response info seen by the callbacks as `status final_url x-served-by`.
+    struct ResponseInfoStep {
+        url: String,
+        seen: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl ResponseInfoStep {
+        fn record(&self, ctx: &Context) {
+            let served_by = ctx
+                .headers()
+                .and_then(|h| h.get("x-served-by"))
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("-");
+            self.seen.lock().unwrap().push(format!(
+                "{} {} {}",
+                ctx.status().unwrap_or_default(),
+                ctx.final_url().unwrap_or("-"),
+                served_by
+            ));
+        }
+    }
+
+    #[async_trait]
+    impl Stepable for ResponseInfoStep {
+        fn name(&self) -> String {
+            String::from(URL_STEP)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, self.url.clone())
+        }
+
+        async fn on_success(&self, ctx: &mut Context) {
+            self.record(ctx);
+        }
+
+        async fn on_error(&self, ctx: &mut Context, _err: StepError) {
+            self.record(ctx);
+        }
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+    }
+
+    /// Stores the token of the login response for `TokenStep`.
+    struct StoreTokenStep {
+        url: String,
+    }
+
+    #[async_trait]
+    impl Stepable for StoreTokenStep {
+        fn name(&self) -> String {
+            String::from(LOGIN_STEP)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::POST, self.url.clone())
+        }
+
+        async fn on_success(&self, ctx: &mut Context) {
+            let token = ctx.body_text().unwrap();
+            ctx.get_store_mut().set("token", token);
+        }
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+    }
+
+    struct TokenStep {
+        url: String,
+    }
+
+    #[async_trait]
+    impl Stepable for TokenStep {
+        fn name(&self) -> String {
+            String::from(URL_STEP)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, self.url.clone())
+        }
+
+        fn on_request_with(&self, ctx: &Context) -> Request {
+            let token = ctx.get_store().get::<String>("token").unwrap();
+            self.on_request()
+                .with_headers(crate::hdr!(format!("Authorization: Bearer {}", token)))
+        }
+
+        async fn on_success(&self, _ctx: &mut Context) {}
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+    }
+
+    /// Retries with a short backoff and counts the calls of `on_error`.
+    struct RetryStep {
+        url: String,
+        errors: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Stepable for RetryStep {
+        fn name(&self) -> String {
+            String::from(URL_STEP)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, self.url.clone()).with_retry_policy(
+                RetryPolicy::new(3).with_backoff(Backoff::constant(Duration::from_millis(5))),
+            )
+        }
+
+        async fn on_success(&self, _ctx: &mut Context) {}
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {
+            self.errors
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+    }
+
+    #[tokio::test]
+    async fn try_step_should_retry_with_the_request_retry_policy() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/flaky" => TestResponse::status(503, "busy").with_header("Retry-After", "0"),
+            _ => TestResponse::status(429, "slow down"),
+        })
+        .await;
+        let errors = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let mut worker = Worker::new();
+        worker.add_step(RetryStep {
+            url: server.url("/flaky"),
+            errors: errors.clone(),
+        });
+        assert!(worker.try_step(URL_STEP).await.is_err());
+        assert_eq!(server.hits(), 3);
+        assert_eq!(errors.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(worker.session_requests(), 3);
+
+        let healthy = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = {
+            let healthy = healthy.clone();
+            TestServer::start(move |_| {
+                match healthy.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                    0 => TestResponse::status(502, "bad gateway"),
+                    _ => TestResponse::ok("ok"),
+                }
+            })
+            .await
+        };
+        let mut worker = Worker::new();
+        worker.add_step(RetryStep {
+            url: server.url("/flaky"),
+            errors: errors.clone(),
+        });
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(worker.ctx.body_text().unwrap(), "ok");
+        assert_eq!(errors.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    /// A step respecting `Retry-After` or not, without a retry policy.
+    fn retry_after_step(url: String, respect: bool) -> FetchPage {
+        let request = Request::new(Method::GET, url).with_respect_retry_after(respect);
+        FetchPage::with_request(URL_STEP, request)
+    }
+
+    #[tokio::test]
+    async fn try_step_should_wait_for_retry_after_when_asked_to() {
+        let hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = {
+            let hits = hits.clone();
+            TestServer::start(move |req| match req.path.as_str() {
+                "/overloaded" => TestResponse::status(503, "busy"),
+                _ => match hits.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                    0 => TestResponse::status(429, "slow down").with_header("Retry-After", "1"),
+                    _ => TestResponse::ok("ok"),
+                },
+            })
+            .await
+        };
+
+        let mut worker = Worker::new();
+        worker.add_step(retry_after_step(server.url("/limited"), true));
+        let started = std::time::Instant::now();
+        worker.try_step(URL_STEP).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(worker.ctx.body_text().unwrap(), "ok");
+        assert_eq!(server.hits(), 2);
+
+        // without a Retry-After, or without the flag, the step fails at once
+        let mut worker = Worker::new();
+        worker.add_step(retry_after_step(server.url("/overloaded"), true));
+        assert!(worker.try_step(URL_STEP).await.is_err());
+        assert_eq!(server.hits(), 3);
+
+        hits.store(0, std::sync::atomic::Ordering::SeqCst);
+        let mut worker = Worker::new();
+        worker.add_step(retry_after_step(server.url("/limited"), false));
+        assert!(worker.try_step(URL_STEP).await.is_err());
+        assert_eq!(server.hits(), 4);
+    }
+
+    /// A step posting to a url with a redirect policy.
+    fn redirect_step(url: String, policy: RedirectPolicy) -> FetchPage {
+        let request = Request::new(Method::POST, url)
+            .with_body(MimicBody::from_text("user=jane".to_string()))
+            .with_redirect_policy(policy)
+            .with_status_codes(vec![200, 302]);
+        FetchPage::with_request(URL_STEP, request)
+    }
+
+    #[tokio::test]
+    async fn try_step_should_keep_the_redirect_chain() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/login" => TestResponse::status(302, "")
+                .with_header("Set-Cookie", "sid=s1; Path=/")
+                .with_header("Location", "/callback?code=abc"),
+            "/callback?code=abc" => TestResponse::status(303, "").with_header("Location", "/home"),
+            "/loop" => TestResponse::status(302, "").with_header("Location", "/loop"),
+            _ => TestResponse::ok(&format!(
+                "{} {}",
+                req.method,
+                req.header("cookie").unwrap_or_default()
+            )),
+        })
+        .await;
+
+        let mut worker = Worker::new();
+        worker.add_step(redirect_step(server.url("/login"), RedirectPolicy::Follow));
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(worker.ctx.body_text().unwrap(), "GET sid=s1");
+        assert_eq!(worker.ctx.final_url(), Some(server.url("/home").as_str()));
+        let chain = worker.ctx.redirect_chain();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[0].url, server.url("/login"));
+        assert_eq!(chain[0].status, 302);
+        assert_eq!(chain[0].location, server.url("/callback?code=abc"));
+        assert_eq!(chain[0].set_cookies, vec!["sid=s1; Path=/"]);
+        assert_eq!(chain[1].status, 303);
+        // the 302 of the POST is followed with a GET, like browsers do
+        assert_eq!(server.requests()[0].method, "POST");
+        assert_eq!(server.requests()[1].method, "GET");
+
+        worker.add_step(redirect_step(server.url("/login"), RedirectPolicy::None));
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(worker.ctx.status(), Some(302));
+        assert!(worker.ctx.redirect_chain().is_empty());
+
+        worker.add_step(redirect_step(server.url("/loop"), RedirectPolicy::Limit(3)));
+        let err = worker.try_step(URL_STEP).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Too many redirects at {}", server.url("/loop"))
+        );
+    }
+
+    /// A step requesting a url conditionally.
+    fn conditional_step(url: String) -> FetchPage {
+        FetchPage::with_request(
+            URL_STEP,
+            Request::new(Method::GET, url).with_conditional(true),
+        )
+    }
+
+    #[tokio::test]
+    async fn try_step_should_send_the_validators_of_conditional_requests() {
+        let server = TestServer::start(|req| match req.header("if-none-match") {
+            Some("\"v1\"") => TestResponse::status(304, "").with_header("ETag", "\"v1\""),
+            _ => TestResponse::ok("in stock").with_header("ETag", "\"v1\""),
+        })
+        .await;
+
+        let mut worker = Worker::new();
+        worker.add_step(conditional_step(server.url("/stock")));
+        worker.try_step(URL_STEP).await.unwrap();
+        assert!(!worker.ctx.not_modified());
+        assert_eq!(worker.ctx.body_text().unwrap(), "in stock");
+        let etag = worker.validators().get(&server.url("/stock")).unwrap().etag;
+        assert_eq!(etag.as_deref(), Some("\"v1\""));
+
+        worker.try_step(URL_STEP).await.unwrap();
+        assert!(worker.ctx.not_modified());
+        assert_eq!(server.requests()[1].header("if-none-match"), Some("\"v1\""));
+
+        worker.validators().remove(&server.url("/stock"));
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(worker.ctx.status(), Some(200));
+    }
+
+    /// A step fetching a JSON api whose shape is pinned by a schema.
+    fn schema_step(url: String) -> FetchPage {
+        let request = Request::new(Method::GET, url).with_response_schema(serde_json::json!({
+            "type": "object",
+            "required": ["price"],
+            "properties": {"price": {"type": "number"}}
+        }));
+        FetchPage::with_request(URL_STEP, request)
+    }
+
+    #[tokio::test]
+    async fn try_step_should_validate_responses_against_their_schema() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/v1" => TestResponse::ok(r#"{"price": 9.5}"#),
+            "/v2" => TestResponse::ok(r#"{"price": "9.50 EUR"}"#),
+            _ => TestResponse::ok("<html>"),
+        })
+        .await;
+
+        let mut worker = Worker::new();
+        worker.add_step(schema_step(server.url("/v1")));
+        worker.try_step(URL_STEP).await.unwrap();
+
+        for (path, expected) in [
+            (
+                "/v2",
+                StepError::SchemaMismatch(vec![crate::SchemaViolation {
+                    path: "$.price".to_string(),
+                    message: "expected number, found string".to_string(),
+                }]),
+            ),
+            (
+                "/html",
+                StepError::Parse("expected value at line 1 column 1".to_string()),
+            ),
+        ] {
+            worker.add_step(schema_step(server.url(path)));
+            let err = worker.try_step(URL_STEP).await.unwrap_err();
+            assert_eq!(StepError::downcast(err.as_ref()), Some(&expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn try_step_should_record_requests_to_the_audit_log() {
+        let server = TestServer::start(|_| TestResponse::status(302, "")).await;
+
+        let mut worker = Worker::new();
+        let audit = Arc::new(crate::AuditLog::new());
+        worker.set_audit_log(Some(audit.clone()));
+        worker.add_step(redirect_step(server.url("/login"), RedirectPolicy::None));
+        worker.try_step(URL_STEP).await.unwrap();
+        worker.add_step(redirect_step(
+            "http://127.0.0.1:1/login".to_string(),
+            RedirectPolicy::None,
+        ));
+        assert!(worker.try_step(URL_STEP).await.is_err());
+
+        let entries = audit.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].step, URL_STEP);
+        assert_eq!(entries[0].method, "POST");
+        assert_eq!(entries[0].url, server.url("/login"));
+        assert_eq!(entries[0].status, Some(302));
+        assert_eq!(
+            entries[0].body_sha256,
+            Some(crate::audit::sha256_hex(b"user=jane"))
+        );
+        assert_eq!(entries[1].status, None);
+        assert!(crate::verify_audit_chain(&entries).is_ok());
+    }
+
+    #[tokio::test]
+    async fn try_step_should_record_warm_up_visits_to_the_audit_log() {
+        let server = TestServer::start(|_| TestResponse::ok("")).await;
+        let warm_up = WarmUp::new(vec![server.url("/")])
+            .with_delay(Duration::from_millis(1), Duration::from_millis(1));
+
+        let mut worker = Worker::new();
+        let audit = Arc::new(crate::AuditLog::new());
+        worker.set_audit_log(Some(audit.clone()));
+        worker.set_warm_up(Some(warm_up));
+        worker.add_step(UrlStep {
+            url: server.url("/checkout"),
+        });
+        worker.try_step(URL_STEP).await.unwrap();
+
+        let urls: Vec<String> = audit.entries().into_iter().map(|e| e.url).collect();
+        assert_eq!(urls, vec![server.url("/"), server.url("/checkout")]);
+    }
+
+    #[tokio::test]
+    async fn try_step_should_stop_warming_up_on_shutdown() {
+        let server = TestServer::start(|_| TestResponse::ok("")).await;
+        let warm_up = WarmUp::new(vec![server.url("/"), server.url("/about")])
+            .with_delay(Duration::from_secs(10), Duration::from_secs(10));
+
+        let mut worker = Worker::new();
+        worker.set_warm_up(Some(warm_up));
+        worker.add_step(UrlStep {
+            url: server.url("/checkout"),
+        });
+        let token = worker.cancellation_token();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            token.cancel();
+        });
+        let started = std::time::Instant::now();
+        let err = worker.try_step(URL_STEP).await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            StepError::downcast(err.as_ref()),
+            Some(&StepError::Cancelled)
+        );
+        assert_eq!(server.hits(), 1);
+    }
+
+    /// Sends its first attempt through a dead proxy and swaps to the next one on retries.
+    struct ProxySwapStep {
+        proxies: Vec<String>,
+        retries: Arc<std::sync::Mutex<Vec<(u32, bool)>>>,
+    }
+
+    #[async_trait]
+    impl Stepable for ProxySwapStep {
+        fn name(&self) -> String {
+            String::from(URL_STEP)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, "http://shop.invalid/product".to_string())
+                .with_proxy(reqwest::Proxy::http(&self.proxies[0]).unwrap())
+                .with_retry_policy(
+                    RetryPolicy::new(3).with_backoff(Backoff::constant(Duration::from_millis(5))),
+                )
+        }
+
+        async fn on_success(&self, _ctx: &mut Context) {}
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+
+        async fn on_retry(&self, ctx: &mut Context, attempt: u32, err: StepError) {
+            self.retries
+                .lock()
+                .unwrap()
+                .push((attempt, err.is_connect_error()));
+            let next = reqwest::Proxy::http(&self.proxies[attempt as usize]).unwrap();
+            let req = ctx.get_request().clone().with_proxy(next);
+            ctx.update_from_request(req).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn try_step_should_let_steps_swap_proxies_between_attempts() {
+        let proxy = TestServer::start(|req| match req.path.as_str() {
+            "http://shop.invalid/product" => TestResponse::ok("through the proxy"),
+            _ => TestResponse::status(400, "not proxied"),
+        })
+        .await;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let retries = Arc::new(std::sync::Mutex::new(vec![]));
+
+        let mut worker = Worker::new();
+        worker.add_step(ProxySwapStep {
+            proxies: vec![dead.clone(), dead, proxy.url("")],
+            retries: retries.clone(),
+        });
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(worker.ctx.body_text().unwrap(), "through the proxy");
+        assert_eq!(*retries.lock().unwrap(), vec![(1, true), (2, true)]);
+        assert_eq!(proxy.hits(), 1);
+    }
+
+    /// A payment submitted with an idempotency key.
+    fn payment_step(url: String) -> FetchPage {
+        let request = Request::new(Method::POST, url).with_retry_policy(
+            RetryPolicy::new(3)
+                .with_backoff(Backoff::constant(Duration::from_millis(5)))
+                .with_idempotency_key(),
+        );
+        FetchPage::with_request("Pay", request)
+    }
+
+    #[tokio::test]
+    async fn try_step_should_reuse_the_idempotency_key_across_retries() {
+        let server = TestServer::start(|_| TestResponse::status(503, "busy")).await;
+        let mut worker = Worker::new();
+        worker.add_step(payment_step(server.url("/pay")));
+
+        assert!(worker.try_step("Pay").await.is_err());
+        assert!(worker.try_step("Pay").await.is_err());
+        let keys: Vec<String> = server
+            .requests()
+            .iter()
+            .map(|req| req.header("idempotency-key").unwrap().to_string())
+            .collect();
+        assert_eq!(keys.len(), 6);
+        assert!(keys[..3].iter().all(|key| key == &keys[0]));
+        assert!(keys[3..].iter().all(|key| key == &keys[3]));
+        assert_ne!(keys[0], keys[3]);
+    }
+
+    /// A step keeping the names of the steps told the run was cancelled.
+    struct CancellableStep {
+        name: &'static str,
+        url: String,
+        next: Option<&'static str>,
+        cancelled: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Stepable for CancellableStep {
+        fn name(&self) -> String {
+            String::from(self.name)
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, self.url.clone())
+        }
+
+        async fn on_success(&self, ctx: &mut Context) {
+            if let Some(next) = self.next {
+                ctx.set_next_step(next.to_string());
+            }
+        }
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+
+        async fn on_cancelled(&self, _ctx: &mut Context) {
+            self.cancelled.lock().unwrap().push(self.name.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn run_should_abort_the_request_in_flight_on_shutdown() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/slow" => TestResponse::ok("late").with_delay(Duration::from_secs(10)),
+            _ => TestResponse::ok("ok"),
+        })
+        .await;
+        let cancelled = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut worker = Worker::new();
+        worker.add_step(CancellableStep {
+            name: "Fast",
+            url: server.url("/fast"),
+            next: Some("Slow"),
+            cancelled: cancelled.clone(),
+        });
+        worker.add_step(CancellableStep {
+            name: "Slow",
+            url: server.url("/slow"),
+            next: None,
+            cancelled: cancelled.clone(),
+        });
+
+        let token = worker.cancellation_token();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            token.cancel();
+        });
+        let started = std::time::Instant::now();
+        let summary = worker.run("Fast").await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(summary.stopped, StopReason::Cancelled);
+        assert_eq!(summary.steps.len(), 2);
+        assert_eq!(summary.steps[1].error.as_deref(), Some("Request cancelled"));
+        assert_eq!(*cancelled.lock().unwrap(), vec!["Slow"]);
+
+        // a cancelled worker doesn't start the next step
+        let summary = worker.run("Fast").await;
+        assert_eq!(summary.stopped, StopReason::Cancelled);
+        assert!(summary.steps.is_empty());
+        assert_eq!(server.hits(), 2);
+        assert_eq!(*cancelled.lock().unwrap(), vec!["Slow", "Fast"]);
+    }
+
+    #[tokio::test]
+    async fn run_should_not_select_a_variant_once_cancelled() {
+        let cancelled = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut worker = Worker::new();
+        worker.add_step_variant(
+            "control",
+            1,
+            CancellableStep {
+                name: "Checkout",
+                url: "http://127.0.0.1:1/".to_string(),
+                next: None,
+                cancelled: cancelled.clone(),
+            },
+        );
+        worker.shutdown();
+
+        let summary = worker.run("Checkout").await;
+        assert_eq!(summary.stopped, StopReason::Cancelled);
+        assert_eq!(*cancelled.lock().unwrap(), vec!["Checkout"]);
+        assert_eq!(worker.variant_stats("Checkout")[0].selected, 0);
+    }
+
+    /// A step timing out, keeping what its `on_timeout` was told.
+    struct SlowStep {
+        url: String,
+        timeouts: Arc<std::sync::Mutex<Vec<TimeoutInfo>>>,
+    }
+
+    #[async_trait]
+    impl Stepable for SlowStep {
+        fn name(&self) -> String {
+            String::from("Slow")
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, self.url.clone())
+                .with_timeout(Duration::from_millis(100))
+                .with_retry_policy(
+                    RetryPolicy::new(2).with_backoff(Backoff::constant(Duration::from_millis(5))),
+                )
+        }
+
+        async fn on_success(&self, _ctx: &mut Context) {}
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+        async fn on_timeout(&self, ctx: &mut Context) {
+            let info = *ctx.timeout_info().unwrap();
+            self.timeouts.lock().unwrap().push(info);
+        }
+    }
+
+    #[tokio::test]
+    async fn try_step_should_describe_the_timeout_to_on_timeout() {
+        let server =
+            TestServer::start(|_| TestResponse::ok("late").with_delay(Duration::from_secs(2)))
+                .await;
+        let timeouts = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut worker = Worker::new();
+        worker
+            .ctx
+            .get_client_settings_mut()
+            .set_connect_timeout(Some(Duration::from_secs(5)));
+        worker.add_step(SlowStep {
+            url: server.url("/slow"),
+            timeouts: timeouts.clone(),
+        });
+
+        let err = worker.try_step("Slow").await.unwrap_err();
+        assert!(StepError::downcast(err.as_ref()).unwrap().is_timeout());
+        assert_eq!(server.hits(), 2);
+
+        let info = timeouts.lock().unwrap()[0];
+        assert_eq!(info.kind, TimeoutKind::Request);
+        assert_eq!(info.configured(), Some(Duration::from_millis(100)));
+        assert_eq!(info.connect_timeout, Some(Duration::from_secs(5)));
+        assert!(info.elapsed >= Duration::from_millis(100));
+        assert_eq!((info.attempt, info.max_attempts), (2, 2));
+        assert!(info.is_last_attempt());
+    }
+
+    /// A step downloading a file.
+    fn download_step(download: Download, url: String) -> FetchPage {
+        FetchPage::with_request(
+            "Download",
+            Request::new(Method::GET, url).with_download(download),
+        )
+    }
+
+    #[tokio::test]
+    async fn try_step_should_stream_downloads_to_disk_and_resume_them() {
+        const FILE: &str = "0123456789abcdefghij";
+        let server = TestServer::start(|req| match req.header("range") {
+            Some(range) => {
+                let start: usize = range[6..range.len() - 1].parse().unwrap();
+                TestResponse::status(206, &FILE[start..])
+            }
+            None if req.path == "/missing" => TestResponse::status(404, "not found"),
+            None => TestResponse::ok(FILE),
+        })
+        .await;
+        let path = std::env::temp_dir().join("mimicr-download-test.bin");
+        let _ = std::fs::remove_file(&path);
+        let progress = Arc::new(std::sync::Mutex::new(vec![]));
+
+        let download = {
+            let progress = progress.clone();
+            Download::new(&path)
+                .resumable()
+                .with_progress(move |p| progress.lock().unwrap().push(p.downloaded))
+        };
+        let mut worker = Worker::new();
+        worker.add_step(download_step(download.clone(), server.url("/file")));
+        let summary = worker.run("Download").await;
+        assert!(summary.is_success());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), FILE);
+        assert!(worker.ctx.body_slice().map_or(true, |body| body.is_empty()));
+        let report = summary.steps[0].download.clone().unwrap();
+        assert_eq!((report.bytes, report.resumed_from), (20, 0));
+        assert_eq!(progress.lock().unwrap().last(), Some(&20));
+
+        // a broken download left the first half on disk
+        std::fs::write(&path, &FILE[..10]).unwrap();
+        worker.try_step("Download").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), FILE);
+        assert_eq!(server.requests()[1].header("range"), Some("bytes=10-"));
+        let report = worker.ctx.download_report().unwrap();
+        assert_eq!((report.bytes, report.resumed_from), (10, 10));
+
+        let mut worker = Worker::new();
+        worker.add_step(download_step(download, server.url("/missing")));
+        std::fs::remove_file(&path).unwrap();
+        assert!(worker.try_step("Download").await.is_err());
+        assert_eq!(worker.ctx.body_text().unwrap(), "not found");
+        assert!(worker.ctx.download_report().is_none());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn try_step_should_resume_downloads_of_the_same_file_only() {
+        const FILE: &str = "0123456789";
+        let server = TestServer::start(|req| match (req.header("range"), req.header("if-range")) {
+            (Some("bytes=10-"), _) => TestResponse::status(416, "")
+                .with_header("Content-Range", &format!("bytes */{}", FILE.len())),
+            (Some(range), Some("\"v1\"")) => {
+                let start: usize = range[6..range.len() - 1].parse().unwrap();
+                TestResponse::status(206, &FILE[start..])
+            }
+            _ => TestResponse::ok(FILE).with_header("ETag", "\"v1\""),
+        })
+        .await;
+        let path = std::env::temp_dir().join("mimicr-download-if-range-test.bin");
+        let _ = std::fs::remove_file(&path);
+
+        let download = Download::new(&path).resumable();
+        let mut worker = Worker::new();
+        worker.add_step(download_step(download.clone(), server.url("/file")));
+        worker.try_step("Download").await.unwrap();
+        assert_eq!(download.validator().as_deref(), Some("\"v1\""));
+
+        // an already complete file isn't downloaded again
+        worker.try_step("Download").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), FILE);
+        let report = worker.ctx.download_report().unwrap();
+        assert_eq!((report.bytes, report.resumed_from), (0, 10));
+
+        std::fs::write(&path, &FILE[..4]).unwrap();
+        worker.try_step("Download").await.unwrap();
+        assert_eq!(server.requests()[2].header("if-range"), Some("\"v1\""));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), FILE);
+
+        // a partial file of another version starts over
+        std::fs::write(&path, &FILE[..4]).unwrap();
+        let other = Download::new(&path).resumable().with_validator("\"v0\"");
+        worker.add_step(download_step(other, server.url("/file")));
+        worker.try_step("Download").await.unwrap();
+        let report = worker.ctx.download_report().unwrap();
+        assert_eq!((report.bytes, report.resumed_from), (10, 0));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), FILE);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn try_step_should_follow_the_redirects_of_downloads() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/latest" => TestResponse::status(302, "").with_header("Location", "/v2.bin"),
+            "/internal" => TestResponse::status(302, "")
+                .with_header("Location", "http://127.0.0.1:8080/v2.bin"),
+            _ => TestResponse::ok("v2"),
+        })
+        .await;
+        let path = std::env::temp_dir().join("mimicr-download-redirect-test.bin");
+        let _ = std::fs::remove_file(&path);
+
+        let mut worker = Worker::new();
+        worker.add_step(download_step(Download::new(&path), server.url("/latest")));
+        worker.try_step("Download").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "v2");
+        let chain = worker.ctx.redirect_chain();
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain[0].location, server.url("/v2.bin"));
+        std::fs::remove_file(&path).unwrap();
+
+        let port = server.url("").rsplit(':').next().unwrap().to_string();
+        worker.add_step(download_step(
+            Download::new(&path),
+            format!("http://localhost:{}/internal", port),
+        ));
+        worker.set_host_guard(Some(HostGuard::new().without_resolving()));
+        let err = worker.try_step("Download").await.unwrap_err();
+        assert!(err.to_string().starts_with("Blocked request"), "{}", err);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn try_step_should_transform_bodies_before_on_success() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/api" => TestResponse::ok(")]}'\n{\"name\": \"mimicr\"}"),
+            _ => TestResponse::ok("not encrypted"),
+        })
+        .await;
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: server.url("/api"),
+        });
+        worker.add_transformer(strip_xssi);
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(worker.ctx.body_text().unwrap(), "{\"name\": \"mimicr\"}");
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: server.url("/plain"),
+        });
+        worker.add_transformer(|info, body| match info.header("x-encrypted") {
+            Some(_) => Ok(body),
+            None => Err("missing the encryption header".into()),
+        });
+        let err = worker.try_step(URL_STEP).await.unwrap_err();
+        assert!(err.to_string().contains("missing the encryption header"));
+        assert_eq!(worker.ctx.body_text().unwrap(), "not encrypted");
+    }
+
+    #[tokio::test]
+    async fn try_step_should_pass_stored_values_to_later_steps() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/login" => TestResponse::ok("t0k3n"),
+            _ => match req.header("authorization") {
+                Some("Bearer t0k3n") => TestResponse::ok("orders"),
+                _ => TestResponse::status(401, "unauthorized"),
+            },
+        })
+        .await;
+
+        let mut worker = Worker::new();
+        worker.add_step(StoreTokenStep {
+            url: server.url("/login"),
+        });
+        worker.add_step(TokenStep {
+            url: server.url("/orders"),
+        });
+
+        worker.try_step(LOGIN_STEP).await.unwrap();
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(worker.ctx.body_text().unwrap(), "orders");
+        assert!(worker.ctx.get_store().contains("token"));
+    }
+
+    #[tokio::test]
+    async fn try_step_should_resolve_paths_against_the_environment() {
+        let server = TestServer::start(|req| TestResponse::ok(&req.path)).await;
+        let overlays = EnvironmentOverlays::new(Environment::new().with_base_url("http://0.0.0.0"))
+            .with_environment(
+                "local",
+                Environment::new()
+                    .with_base_url(&server.url(""))
+                    .with_rate_limit(RateLimit::per_second(50)),
+            );
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: "/account".to_string(),
+        });
+        worker.set_environment(Some(overlays.select("local").unwrap()));
+        worker.try_step(URL_STEP).await.unwrap();
+
+        assert_eq!(worker.ctx.body_text().unwrap(), "/account");
+        assert_eq!(worker.ctx.get_url(), server.url("/account"));
+        assert!(worker.rate_limiter.is_some());
+    }
+
+    #[tokio::test]
+    async fn try_step_should_expose_the_response_info_to_callbacks() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/old" => TestResponse::status(301, "").with_header("Location", "/new"),
+            "/new" => TestResponse::ok("moved").with_header("X-Served-By", "edge-1"),
+            _ => TestResponse::status(404, "not found"),
+        })
+        .await;
+
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut worker = Worker::new();
+        worker.add_step(ResponseInfoStep {
+            url: server.url("/old"),
+            seen: seen.clone(),
+        });
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(
+            worker.ctx.response_info().unwrap().header("x-served-by"),
+            Some("edge-1")
+        );
+
+        let mut worker = Worker::new();
+        worker.add_step(ResponseInfoStep {
+            url: server.url("/gone"),
+            seen: seen.clone(),
+        });
+        assert!(worker.try_step(URL_STEP).await.is_err());
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                format!("200 {} edge-1", server.url("/new")),
+                format!("404 {} -", server.url("/gone")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn try_step_should_surface_protocol_errors_with_partial_bodies() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/truncated" => {
+                TestResponse::raw(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\npartial")
+            }
+            "/chunked" => TestResponse::raw(
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\nzz\r\n",
+            ),
+            _ => TestResponse::ok("").with_header("X-Huge", &"a".repeat(512 * 1024)),
+        })
+        .await;
+        let errors = Arc::new(std::sync::Mutex::new(vec![]));
+
+        let mut worker = Worker::new();
+        for path in ["/truncated", "/chunked", "/huge"] {
+            worker.add_step(BrokenResponseStep {
+                url: server.url(path),
+                errors: errors.clone(),
+            });
+            let err = worker.try_step(URL_STEP).await.unwrap_err();
+            assert!(StepError::downcast(err.as_ref())
+                .unwrap()
+                .is_protocol_error());
+        }
+
+        let errors = errors.lock().unwrap();
+        assert!(matches!(errors[0].0, StepError::PrematureClose(_)));
+        assert_eq!(errors[0].1.as_deref(), Some("partial"));
+        assert!(errors[0].0.is_retryable());
+        assert!(matches!(errors[1].0, StepError::InvalidChunkedEncoding(_)));
+        assert_eq!(errors[1].1.as_deref(), Some("hello"));
+        assert!(matches!(errors[2].0, StepError::OversizedHeaders));
+        assert_eq!(errors[2].1, None);
+    }
+
+    #[tokio::test]
+    async fn run_should_follow_next_steps_until_done() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/broken" => TestResponse::status(503, "unavailable"),
+            _ => TestResponse::ok("ok"),
+        })
+        .await;
+
+        let mut worker = Worker::new();
+        worker.add_step(ChainStep {
+            name: "Login",
+            url: server.url("/login"),
+            next: Some("Account"),
+        });
+        worker.add_step(ChainStep {
+            name: "Account",
+            url: server.url("/account"),
+            next: None,
+        });
+        worker.add_step(ChainStep {
+            name: "Broken",
+            url: server.url("/broken"),
+            next: Some("Login"),
+        });
+        worker.ctx.set_next_step("Broken".to_string());
+
+        let summary = worker.run("Login").await;
+        assert!(summary.is_success());
+        assert_eq!(summary.step_names(), vec!["Login", "Account"]);
+        assert_eq!(summary.steps[1].url, server.url("/account"));
+
+        let summary = worker.run("Broken").await;
+        assert_eq!(summary.step_names(), vec!["Broken"]);
+        assert!(matches!(summary.stopped, StopReason::Failed(_)));
+        assert!(summary.steps[0].error.is_some());
+
+        let summary = worker.run("Missing").await;
+        assert!(summary.steps.is_empty());
+        assert!(matches!(summary.stopped, StopReason::Failed(_)));
+    }
+
+    #[tokio::test]
+    async fn run_should_skip_the_history_in_fast_mode() {
+        let server = TestServer::start(|_| TestResponse::ok("ok")).await;
+        let metrics = Arc::new(Metrics::new());
+        let mut worker = Worker::new();
+        worker.set_metrics(Some(metrics.clone()));
+        worker.set_observability(Observability::fast());
+        worker.add_step(ChainStep {
+            name: "Login",
+            url: server.url("/login"),
+            next: Some("Account"),
+        });
+        worker.add_step(ChainStep {
+            name: "Account",
+            url: server.url("/account"),
+            next: None,
+        });
+
+        let summary = worker.run("Login").await;
+        assert!(summary.is_success());
+        assert!(summary.steps.is_empty());
+        assert_eq!(metrics.step_latency("Account").unwrap().count(), 1);
+
+        worker.set_step_observability("Account", Some(Observability::full()));
+        assert_eq!(worker.observability("Account"), Observability::full());
+        let summary = worker.run("Login").await;
+        assert_eq!(summary.step_names(), vec!["Account"]);
+
+        worker.set_step_observability("Account", None);
+        assert!(worker.run("Login").await.steps.is_empty());
+        assert_eq!(metrics.step_latency("Login").unwrap().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn run_should_roll_back_succeeded_steps_when_a_later_step_fails() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/cart" => TestResponse::ok("item-1"),
+            "/checkout" => TestResponse::status(500, "out of stock"),
+            _ => TestResponse::ok("removed"),
+        })
+        .await;
+
+        let mut worker = Worker::new();
+        worker.add_step(CartStep {
+            url: server.url(""),
+        });
+        worker.add_step(ChainStep {
+            name: "Checkout",
+            url: server.url("/checkout"),
+            next: None,
+        });
+
+        let summary = worker.run("AddToCart").await;
+        assert!(matches!(summary.stopped, StopReason::Failed(_)));
+        assert!(summary.rolled_back.is_empty());
+
+        worker.set_rollback_on_failure(true);
+        let summary = worker.run("AddToCart").await;
+        assert_eq!(summary.step_names(), vec!["AddToCart", "Checkout"]);
+        assert_eq!(summary.rolled_back.len(), 1);
+        assert_eq!(summary.rolled_back[0].step, "AddToCart");
+        assert_eq!(summary.rolled_back[0].url, server.url("/cart/item-1"));
+        assert!(summary.rolled_back[0].error.is_none());
+
+        let deletes: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|req| req.method == "DELETE")
+            .map(|req| req.path)
+            .collect();
+        assert_eq!(deletes, vec!["/cart/item-1"]);
+    }
+
+    #[tokio::test]
+    async fn run_should_roll_back_the_variant_that_ran() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/v2/cart" => TestResponse::ok("item-1"),
+            "/checkout" => TestResponse::status(500, "out of stock"),
+            _ => TestResponse::ok("removed"),
+        })
+        .await;
+
+        let mut worker = Worker::new();
+        worker.add_step(CartStep {
+            url: server.url(""),
+        });
+        worker.add_step_variant(
+            "v2",
+            1,
+            CartStep {
+                url: server.url("/v2"),
+            },
+        );
+        worker.add_step(ChainStep {
+            name: "Checkout",
+            url: server.url("/checkout"),
+            next: None,
+        });
+        worker.set_rollback_on_failure(true);
+
+        let summary = worker.run("AddToCart").await;
+        assert_eq!(summary.steps[0].variant.as_deref(), Some("v2"));
+        assert_eq!(summary.rolled_back[0].url, server.url("/v2/cart/item-1"));
+    }
+
+    #[tokio::test]
+    async fn try_step_should_keep_the_profile_of_a_session_until_it_rotates() {
+        let server = TestServer::start(|_| TestResponse::ok("ok")).await;
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: server.url("/"),
+        });
+        let profiles = vec![Profile::chrome_120_windows(), Profile::firefox_mac()];
+        worker.set_profile_rotator(Some(Arc::new(ProfileRotator::new(profiles))));
+
+        worker.try_step(URL_STEP).await.unwrap();
+        let first = worker.profile().unwrap().clone();
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(worker.profile(), Some(&first));
+
+        let requests = server.requests();
+        for req in &requests {
+            assert_eq!(req.header("user-agent"), first.user_agent());
+            assert_eq!(
+                req.header("accept-language"),
+                first.headers()["accept-language"].to_str().ok()
+            );
+        }
+
+        worker.rotate_session().await.unwrap();
+        assert!(worker.profile().is_none());
+        worker.try_step(URL_STEP).await.unwrap();
+        assert!(worker.profile().is_some());
+    }
+
+    #[tokio::test]
+    async fn run_should_keep_the_session_cookies_across_steps() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/login" => TestResponse::ok("welcome").with_header("Set-Cookie", "sid=abc; Path=/"),
+            _ => match req.header("cookie") {
+                Some("sid=abc") => TestResponse::ok("account"),
+                _ => TestResponse::status(401, "login first"),
+            },
+        })
+        .await;
+
+        let mut worker = Worker::new();
+        worker.add_step(ChainStep {
+            name: "Login",
+            url: server.url("/login"),
+            next: Some("Account"),
+        });
+        worker.add_step(ChainStep {
+            name: "Account",
+            url: server.url("/account"),
+            next: None,
+        });
+
+        assert!(worker.run("Login").await.is_success());
+        assert_eq!(worker.ctx.body_text().unwrap(), "account");
+    }
+
+    #[tokio::test]
+    async fn try_step_should_route_sessions_to_the_same_identity_and_cookies() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/login/ann" => {
+                TestResponse::ok("welcome").with_header("Set-Cookie", "sid=ann; Path=/")
+            }
+            _ => match req.header("cookie") {
+                Some(cookie) => TestResponse::ok(cookie),
+                None => TestResponse::status(401, "login first"),
+            },
+        })
+        .await;
+
+        let pool = Arc::new(IdentityPool::new(
+            (0..8)
+                .map(|i| Identity::new(&i.to_string()).with_user_agent(&format!("agent-{}", i)))
+                .collect(),
+        ));
+        let affinity = Arc::new(SessionAffinity::new("account"));
+        let mut workers: Vec<Worker> = (0..2)
+            .map(|_| {
+                let mut worker = Worker::new();
+                worker.set_identity_pool(Some(pool.clone()));
+                worker.set_session_affinity(Some(affinity.clone()));
+                worker.add_step(AccountStep {
+                    name: "Login",
+                    url: server.url("/login/ann"),
+                    account: "ann",
+                });
+                worker.add_step(AccountStep {
+                    name: "AnnOrders",
+                    url: server.url("/orders"),
+                    account: "ann",
+                });
+                worker.add_step(AccountStep {
+                    name: "BobOrders",
+                    url: server.url("/orders"),
+                    account: "bob",
+                });
+                worker.add_step(UrlStep {
+                    url: server.url("/public"),
+                });
+                worker
+            })
+            .collect();
+
+        workers[0].try_step("Login").await.unwrap();
+        let ann = workers[0].ctx.get_current_identity().unwrap();
+
+        workers[1].try_step("AnnOrders").await.unwrap();
+        assert_eq!(workers[1].ctx.body_text().unwrap(), "sid=ann");
+        assert_eq!(workers[1].ctx.get_current_identity().unwrap(), ann);
+        assert_eq!(workers[1].active_session().unwrap(), "ann");
+
+        assert!(workers[1].try_step("BobOrders").await.is_err());
+        assert!(workers[1].try_step(URL_STEP).await.is_err());
+        assert!(workers[1].active_session().is_none());
+
+        workers[1].try_step("AnnOrders").await.unwrap();
+        assert_eq!(workers[1].ctx.get_current_identity().unwrap(), ann);
+        assert_eq!(affinity.keys(), vec!["ann", "bob"]);
+
+        let agents: Vec<String> = server
+            .requests()
+            .iter()
+            .filter(|r| r.path == "/login/ann" || r.header("cookie") == Some("sid=ann"))
+            .filter_map(|r| r.header("user-agent").map(|ua| ua.to_string()))
+            .collect();
+        assert_eq!(agents.len(), 3);
+        assert!(agents.iter().all(|ua| ua == &agents[0]));
+    }
+
+    #[tokio::test]
+    async fn run_should_sample_bodies_and_keep_errors_in_full() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/home" => TestResponse::ok("hello world"),
+            _ => TestResponse::status(500, "server exploded"),
+        })
+        .await;
+
+        let mut worker = Worker::new();
+        worker.add_step(ChainStep {
+            name: "Home",
+            url: server.url("/home"),
+            next: Some("Broken"),
+        });
+        worker.add_step(ChainStep {
+            name: "Broken",
+            url: server.url("/broken"),
+            next: None,
+        });
+
+        let summary = worker.run("Home").await;
+        assert!(summary.steps.iter().all(|s| s.body.is_none()));
+
+        worker.set_body_sampling(Some(BodySampling::new(BodySample::Prefix(4))));
+        let summary = worker.run("Home").await;
+        assert_eq!(summary.steps[0].body.as_deref(), Some("hell... (11 bytes)"));
+        assert_eq!(summary.steps[1].body.as_deref(), Some("server exploded"));
+    }
+
+    /// Checks again in 6 hours until the response is no longer pending.
+    struct RecheckStep {
+        url: String,
+    }
+
+    #[async_trait]
+    impl Stepable for RecheckStep {
+        fn name(&self) -> String {
+            String::from("Recheck")
+        }
+
+        fn on_request(&self) -> Request {
+            Request::new(Method::GET, self.url.clone())
+        }
+
+        async fn on_success(&self, ctx: &mut Context) {
+            if ctx.body_text().unwrap() == "pending" {
+                ctx.schedule_step(self.name(), Duration::from_secs(6 * 3600));
+            }
+        }
+
+        async fn on_error(&self, _ctx: &mut Context, _err: StepError) {}
+
+        async fn on_timeout(&self, _ctx: &mut Context) {}
+    }
+
+    #[tokio::test]
+    async fn run_should_wait_for_scheduled_steps_on_virtual_time() {
+        let checks = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = {
+            let checks = checks.clone();
+            TestServer::start(move |_| {
+                match checks.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                    0..=2 => TestResponse::ok("pending"),
+                    _ => TestResponse::ok("ready"),
+                }
+            })
+            .await
+        };
+
+        let mut worker = Worker::new();
+        worker.add_step(RecheckStep {
+            url: server.url("/export"),
+        });
+        let clock = Clock::virtual_time();
+        worker.ctx.get_delayed_steps_mut().set_clock(clock.clone());
+
+        let summary = worker.run("Recheck").await;
+        assert!(summary.is_success());
+        assert_eq!(summary.steps.len(), 4);
+        assert_eq!(worker.ctx.body_text().unwrap(), "ready");
+        assert!(clock.skipped() >= Duration::from_secs(18 * 3600 - 1));
+        assert!(worker.ctx.get_delayed_steps().is_empty());
+    }
+
+    #[tokio::test]
+    async fn run_should_stop_loops_and_long_runs() {
+        let server = TestServer::start(|_| TestResponse::ok("ok")).await;
+
+        let mut worker = Worker::new();
+        worker.add_step(ChainStep {
+            name: "Poll",
+            url: server.url("/status"),
+            next: Some("Poll"),
+        });
+
+        let summary = worker.run("Poll").await;
+        assert_eq!(summary.steps.len(), 3);
+        assert_eq!(
+            summary.stopped,
+            StopReason::LoopDetected("Poll".to_string())
+        );
+
+        worker.set_max_visits(None);
+        worker.set_max_iterations(5);
+        let summary = worker.run("Poll").await;
+        assert_eq!(summary.steps.len(), 5);
+        assert_eq!(summary.stopped, StopReason::MaxIterations);
+    }
+
+    #[tokio::test]
+    async fn try_step_should_keep_downgraded_hosts_on_http1() {
+        let server = TestServer::start(|_| TestResponse::ok("ok")).await;
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: server.url("/"),
+        });
+        worker.set_http2_fallback(true);
+        worker.try_step(URL_STEP).await.unwrap();
+        assert!(!worker.ctx.get_client_settings_mut().is_http1_only());
+
+        worker.downgrade(&Some("127.0.0.1".to_string()));
+        assert!(worker.ctx.is_downgraded());
+        assert_eq!(worker.downgraded_hosts(), vec!["127.0.0.1"]);
+
+        worker.try_step(URL_STEP).await.unwrap();
+        assert!(worker.ctx.get_client_settings_mut().is_http1_only());
+        assert!(!worker.ctx.is_downgraded());
+    }
+
+    #[tokio::test]
+    async fn prewarm_should_connect_to_each_origin() {
+        let server = TestServer::start_keep_alive(|_| TestResponse::ok("")).await;
+
+        let mut worker = Worker::new();
+        worker.prewarm(&[&server.url("/")]).await.unwrap();
+        assert_eq!(server.requests()[0].method, "HEAD");
+        // the next request reuses the warm connection
+        worker.add_step(UrlStep {
+            url: server.url("/"),
+        });
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!((server.hits(), server.connections()), (2, 1));
+
+        worker.set_host_guard(Some(HostGuard::new()));
+        assert!(worker.prewarm(&[&server.url("/")]).await.is_err());
+        assert_eq!(server.hits(), 2);
+        assert!(Worker::new().prewarm(&["not a url"]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn try_step_should_send_requests_as_pool_identities() {
+        let server = TestServer::start(|req| match req.header("user-agent") {
+            Some("blocked-agent") => TestResponse::status(403, "blocked"),
+            _ => TestResponse::ok("ok"),
+        })
+        .await;
+        let pool = Arc::new(IdentityPool::new(vec![
+            Identity::new("blocked").with_user_agent("blocked-agent")
+        ]));
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: server.url("/"),
+        });
+        worker.set_identity_pool(Some(pool.clone()));
+
+        assert!(worker.try_step(URL_STEP).await.is_err());
+        assert_eq!(worker.ctx.get_current_identity().unwrap(), "blocked");
+        assert_eq!(
+            server.requests()[0].header("user-agent"),
+            Some("blocked-agent")
+        );
+        assert_eq!(pool.scores()[0].failures, 1);
+        assert!(pool.scores()[0].score < 0.5);
+    }
+
+    #[tokio::test]
+    async fn try_step_should_account_proxied_traffic_per_provider() {
+        // http proxies are sent the request in absolute-form, which the test server answers
+        let proxy = TestServer::start(|_| TestResponse::ok("0123456789")).await;
+        let pool = Arc::new(IdentityPool::new(vec![Identity::new("resi")
+            .with_proxy(reqwest::Proxy::http(proxy.url("/")).unwrap())
+            .with_proxy_provider("residential")]));
+        let accounting = Arc::new(ProxyAccounting::new());
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: "http://shop.example/products".to_string(),
+        });
+        worker.set_identity_pool(Some(pool));
+        worker.set_proxy_accounting(Some(accounting.clone()));
+        worker.try_step(URL_STEP).await.unwrap();
+        worker.try_step(URL_STEP).await.unwrap();
+
+        assert_eq!(proxy.requests()[0].path, "http://shop.example/products");
+        let usage = accounting.usage("residential");
+        assert_eq!((usage.requests, usage.failures), (2, 0));
+        assert!(usage.bytes_sent > 2 * "http://shop.example/products".len() as u64);
+        assert!(usage.bytes_received > 20);
+        assert_eq!(accounting.report().len(), 1);
+
+        // direct requests aren't accounted
+        let direct = TestServer::start(|_| TestResponse::ok("ok")).await;
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: direct.url("/"),
+        });
+        worker.set_proxy_accounting(Some(accounting.clone()));
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(accounting.take_report()["residential"], usage);
+        assert!(accounting.report().is_empty());
+    }
+
+    #[tokio::test]
+    async fn try_step_should_warm_up_fresh_sessions_once() {
+        let server = TestServer::start(|_| TestResponse::ok("ok")).await;
+        let warm_up = WarmUp::new(vec![server.url("/"), server.url("/about")])
+            .with_delay(Duration::from_millis(1), Duration::from_millis(5));
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: server.url("/checkout"),
+        });
+        worker.set_warm_up(Some(warm_up));
+
+        worker.try_step(URL_STEP).await.unwrap();
+        worker.try_step(URL_STEP).await.unwrap();
+
+        let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(paths, vec!["/", "/about", "/checkout", "/checkout"]);
+
+        worker.reset_warm_up();
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(server.hits(), 7);
+    }
+
+    #[tokio::test]
+    async fn try_step_should_send_the_referrer_chain() {
+        let server = TestServer::start(|_| TestResponse::ok("ok")).await;
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: server.url("/landing"),
+        });
+        worker.set_referrer_chain(Some(
+            ReferrerChain::new().with_entry("http://search.example.com/search?q=shoes"),
+        ));
+
+        worker.try_step(URL_STEP).await.unwrap();
+        worker.try_step(URL_STEP).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].header("referer"),
+            Some("http://search.example.com/")
+        );
+        assert_eq!(
+            requests[1].header("referer"),
+            Some(server.url("/landing").as_str())
+        );
+        assert_eq!(
+            worker.referrer_chain().unwrap().current().unwrap(),
+            &server.url("/landing")
+        );
+    }
+
+    #[tokio::test]
+    async fn try_step_should_refuse_blocked_hosts() {
+        let server = TestServer::start(|_| TestResponse::ok("internal")).await;
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: server.url("/admin"),
+        });
+        worker.set_host_guard(Some(HostGuard::new()));
+
+        let err = worker.try_step(URL_STEP).await.unwrap_err();
+        assert!(err.to_string().starts_with("Blocked request"));
+        assert_eq!(server.hits(), 0);
+
+        worker.set_host_guard(Some(HostGuard::permissive().allow_host("127.0.0.1")));
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn try_step_should_check_the_address_requests_connect_to() {
+        let server = TestServer::start(|_| TestResponse::ok("internal")).await;
+        let addr = server
+            .url("")
+            .trim_start_matches("http://")
+            .parse()
+            .unwrap();
+        let request = Request::new(Method::GET, "http://shop.example/".to_string());
+
+        let mut worker = Worker::new();
+        worker.add_step(FetchPage::with_request(
+            URL_STEP,
+            request.with_connect_to(addr),
+        ));
+        worker.set_host_guard(Some(HostGuard::new()));
+        let err = worker.try_step(URL_STEP).await.unwrap_err();
+        assert!(err.to_string().starts_with("Blocked request"), "{}", err);
+        assert_eq!(server.hits(), 0);
+    }
+
+    #[tokio::test]
+    async fn try_step_should_refuse_redirects_to_blocked_hosts() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/go" => {
+                TestResponse::status(302, "").with_header("Location", "http://127.0.0.1:8080/admin")
+            }
+            _ => TestResponse::ok("internal"),
+        })
+        .await;
+        let port = server.url("").rsplit(':').next().unwrap().to_string();
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: format!("http://localhost:{}/go", port),
+        });
+        // lets the unresolved `localhost` through, but not the `127.0.0.1` it redirects to
+        worker.set_host_guard(Some(HostGuard::new().without_resolving()));
+
+        let err = worker.try_step(URL_STEP).await.unwrap_err();
+        assert!(err.to_string().starts_with("Blocked request"), "{}", err);
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn try_step_should_record_latency_by_host() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/slow" => TestResponse::ok("slow").with_delay(Duration::from_millis(60)),
+            _ => TestResponse::status(500, "error"),
+        })
+        .await;
+        let metrics = Arc::new(Metrics::new());
+
+        let mut worker = Worker::new();
+        worker.set_metrics(Some(metrics.clone()));
+        worker.add_step(UrlStep {
+            url: server.url("/slow"),
+        });
+        worker.try_step(URL_STEP).await.unwrap();
+        worker.add_step(UrlStep {
+            url: server.url("/broken"),
+        });
+        assert!(worker.try_step(URL_STEP).await.is_err());
+
+        let host = metrics.host_latency("127.0.0.1").unwrap();
+        assert_eq!(host.count(), 2);
+        assert_eq!(host.errors(), 1);
+        assert!(host.max_ms() >= 60);
+        assert_eq!(metrics.step_latency(URL_STEP).unwrap().count(), 2);
+
+        let counts = metrics.step_counts(URL_STEP).unwrap();
+        assert_eq!(
+            (counts.requests, counts.successes, counts.errors),
+            (2, 1, 1)
+        );
+        assert_eq!(counts.status_codes[&500], 1);
+        assert_eq!(
+            counts.bytes_received,
+            "slow".len() as u64 + "error".len() as u64
+        );
+        assert!(counts.bytes_sent > 0);
+        worker.metrics().unwrap().reset();
+        assert_eq!(metrics.total_counts().requests, 0);
+    }
+
+    #[tokio::test]
+    async fn try_step_should_rotate_sessions_and_log_in_again() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/login" => TestResponse::ok("welcome").with_header("Set-Cookie", "sid=abc; Path=/"),
+            _ => TestResponse::ok("data"),
+        })
+        .await;
+        let rotations = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: server.url("/data"),
+        });
+        worker.add_step(LoginStep {
+            url: server.url("/login"),
+        });
+        worker.set_session_rotation(Some(
+            SessionRotation::new()
+                .after_requests(2)
+                .with_login_step(LOGIN_STEP),
+        ));
+        let counter = rotations.clone();
+        worker.on_session_rotate(move |_ctx| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        });
+
+        worker.try_step(LOGIN_STEP).await.unwrap();
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(worker.session_requests(), 2);
+
+        // the third request rotates the session, which logs in before fetching the data
+        worker.try_step(URL_STEP).await.unwrap();
+        assert_eq!(rotations.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(worker.session_requests(), 2);
+
+        let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(paths, vec!["/login", "/data", "/login", "/data"]);
+        assert_eq!(server.requests()[2].header("cookie"), None);
+        assert_eq!(server.requests()[3].header("cookie"), Some("sid=abc"));
+    }
+
+    #[tokio::test]
+    async fn run_batch_should_collect_dead_letters_for_retry() {
+        let healthy = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let server = {
+            let healthy = healthy.clone();
+            TestServer::start(move |req| match req.path.as_str() {
+                "/login" => TestResponse::ok("ok"),
+                _ if healthy.load(std::sync::atomic::Ordering::SeqCst) => TestResponse::ok("ok"),
+                _ => TestResponse::status(503, "unavailable"),
+            })
+            .await
+        };
+
+        let mut worker = Worker::new();
+        worker.add_step(LoginStep {
+            url: server.url("/login"),
+        });
+        worker.add_step(UrlStep {
+            url: server.url("/data"),
+        });
+        worker.set_max_attempts(2);
+
+        let steps = vec![
+            LOGIN_STEP.to_string(),
+            URL_STEP.to_string(),
+            "Missing".to_string(),
+        ];
+        let report = worker.run_batch(steps).await;
+        assert_eq!(report.completed, vec![LOGIN_STEP]);
+        assert_eq!(report.failed_steps(), vec![URL_STEP, "Missing"]);
+        assert_eq!(report.dead_letters[0].attempts, 2);
+        assert_eq!(server.hits(), 3);
+
+        healthy.store(true, std::sync::atomic::Ordering::SeqCst);
+        let retried = worker.retry_failures(&report).await;
+        assert_eq!(retried.completed, vec![URL_STEP]);
+        assert_eq!(retried.failed_steps(), vec!["Missing"]);
+        assert_eq!(server.hits(), 4);
+    }
+
+    #[tokio::test]
+    async fn try_step_should_respect_step_concurrency_limits() {
+        let server =
+            TestServer::start(|_| TestResponse::ok("ok").with_delay(Duration::from_millis(100)))
+                .await;
+
+        let mut steps = StepManager::new();
+        steps.insert(UrlStep {
+            url: server.url("/login"),
+        });
+        steps.set_max_concurrency(URL_STEP, 1);
+
+        let start = std::time::Instant::now();
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let mut worker = Worker::with_steps(steps.clone());
+                tokio::spawn(async move { worker.try_step(URL_STEP).await.unwrap() })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(server.hits(), 3);
+    }
+
+    #[tokio::test]
+    async fn try_step_should_record_step_outputs_into_the_snapshot() {
+        let server = TestServer::start(|_| TestResponse::ok(r#"{"items": [1, 2]}"#)).await;
+        let snapshot = Arc::new(Snapshot::new());
+
+        let mut worker = Worker::new();
+        worker.add_step(UrlStep {
+            url: server.url("/items"),
+        });
+        worker.set_snapshot(Some(snapshot.clone()));
+        worker.try_step(URL_STEP).await.unwrap();
+
+        let json = snapshot.to_json();
+        assert_eq!(json[0]["label"], URL_STEP);
+        assert_eq!(json[0]["value"]["body"]["items"][1], 2);
+        assert_eq!(json[0]["value"]["url"], server.url("/items"));
     }
 
     #[test]